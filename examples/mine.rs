@@ -1,8 +1,14 @@
-use blockchaintree::static_values::BLOCKS_PER_EPOCH;
+use blockchaintree::dump_headers::Headers;
+use blockchaintree::miner::{Miner, MiningTarget};
 use blockchaintree::tools;
+use blockchaintree::wallet::Wallet;
 use blockchaintree::{blockchaintree::BlockChainTree, static_values};
-use primitive_types::U256;
-use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Private key of the example wallet
+static WALLET_PRIVATE: [u8; 32] = [
+    25, 53, 50, 224, 180, 250, 177, 186, 87, 47, 28, 80, 183, 208, 219, 119, 101, 60, 173, 157,
+    190, 29, 208, 231, 98, 69, 82, 211, 107, 185, 192, 224,
+];
 
 fn main() {
     let rt = tokio::runtime::Runtime::new().unwrap();
@@ -11,10 +17,11 @@ fn main() {
 
     let main_chain = tree.get_main_chain();
 
-    let wallet: [u8; 33] = [
-        2, 178, 140, 81, 31, 206, 208, 171, 143, 240, 128, 134, 115, 82, 188, 63, 146, 189, 14, 59,
-        85, 8, 11, 28, 137, 161, 145, 216, 251, 95, 93, 137, 159,
-    ];
+    let wallet = *Wallet::from_private_key(WALLET_PRIVATE)
+        .unwrap()
+        .get_address();
+
+    let miner = Miner::default();
 
     loop {
         println!("Current height: {}", main_chain.get_height());
@@ -27,56 +34,26 @@ fn main() {
             tree.get_amount(&static_values::ROOT_PUBLIC_ADDRESS)
                 .unwrap()
         );
-        let mut nonce = U256::zero();
-        let last_block = main_chain.get_last_block().unwrap().unwrap();
-        let prev_hash = last_block.hash().unwrap();
-        let difficulty = last_block.get_info().difficulty;
+        let template = rt.block_on(tree.block_template(&wallet)).unwrap();
         println!(
             "Current difficulty: {}",
-            tools::count_leading_zeros(&difficulty)
+            tools::count_leading_zeros(&template.difficulty)
         );
-        while nonce < U256::MAX {
-            let mut pow = [0u8; 32];
-            nonce.to_big_endian(&mut pow);
-            if tools::check_pow(&prev_hash, &difficulty, &pow) {
-                let timestamp = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-
-                println!("Found nonce! {}", nonce);
-
-                let transactions: &[[u8; 32]] =
-                    if ((last_block.get_info().height + 1) % BLOCKS_PER_EPOCH).is_zero() {
-                        println!("Cycle ended!");
-                        &[]
-                    } else {
-                        &[[25u8; 32]]
-                    };
+        let Some(result) = miner.mine(&MiningTarget::from(&template)) else {
+            continue;
+        };
+        println!("Found pow! {:.0} H/s", result.hashrate());
 
-                let block = rt
-                    .block_on(tree.emmit_new_main_block(&pow, &wallet, transactions, timestamp))
-                    .unwrap();
-
-                // Node should handle this
-                tree.send_amount(
-                    &static_values::ROOT_PUBLIC_ADDRESS,
-                    &wallet,
-                    *static_values::MAIN_CHAIN_PAYMENT,
-                )
-                .unwrap();
+        if template.kind == Headers::SummarizeBlock {
+            println!("Cycle ended!");
+        }
 
-                let fee = tools::recalculate_fee(&last_block.get_info().difficulty);
-                for _ in transactions {
-                    tree.add_amount(&wallet, fee).unwrap();
-                }
+        let block = rt
+            .block_on(tree.submit_template(&template, &result.pow))
+            .unwrap();
 
-                println!("Added new block! {:?}\n", block.hash().unwrap());
+        println!("Added new block! {:?}\n", block.hash().unwrap());
 
-                rt.block_on(tree.flush()).unwrap();
-                break;
-            }
-            nonce += U256::one();
-        }
+        rt.block_on(tree.flush()).unwrap();
     }
 }
@@ -0,0 +1,65 @@
+//! Blockchain tree node
+//!
+//! Usage: `blockchaintree-node [config.toml]`
+//!
+//! ```toml
+//! data_directory = "./BlockChainTree/"
+//! flush_interval = 60
+//!
+//! [pool]
+//! capacity = 100000
+//! sender_limit = 64
+//! expiration = 259200
+//!
+//! [miner]
+//! enabled = true
+//! threads = 0
+//! founder = "02b28c511fced0ab8ff080867352bc3f92bd0e3b55080b1c89a191d8fb5f5d899f"
+//!
+//! [rpc]
+//! enabled = true
+//! listen = "127.0.0.1:8335"
+//!
+//! [p2p]
+//! enabled = true
+//! listen = "0.0.0.0:8334"
+//! peers = ["192.0.2.1:8334"]
+//! max_inbound = 32
+//! target_outbound = 8
+//! ```
+
+use std::process::ExitCode;
+
+use blockchaintree::node::{Node, NodeConfig};
+use log::{error, info};
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let config = match std::env::args().nth(1) {
+        Some(path) => NodeConfig::load(path),
+        None => Ok(NodeConfig::default()),
+    };
+    let node = match config.and_then(Node::open) {
+        Ok(node) => node,
+        Err(report) => {
+            error!("{report:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    info!("node is running, press Ctrl-C to stop");
+    let shutdown = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            error!("failed to listen for Ctrl-C: {error}");
+        }
+    };
+    match node.run(shutdown).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(report) => {
+            error!("{report:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -4,9 +4,11 @@
 use crate::dump_headers::Headers;
 use crate::errors::*;
 use crate::merkletree;
+use crate::static_values::{BEGINNING_DIFFICULTY, MAIN_CHAIN_PAYMENT, MAX_FUTURE_TIMESTAMP};
 use crate::tools;
 use crate::tools::check_pow;
 use crate::tools::recalculate_difficulty;
+use crate::transaction::{Transaction, Transactionable};
 use crate::types::{Address, Hash};
 use byteorder::{BigEndian, ReadBytesExt};
 use error_stack::{Report, Result, ResultExt};
@@ -24,7 +26,7 @@ macro_rules! bytes_to_u64 {
     };
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BasicInfo {
     pub timestamp: u64,
     pub pow: [u8; 32],
@@ -85,10 +87,11 @@ impl BasicInfo {
     pub fn parse(data: &[u8]) -> Result<BasicInfo, BlockError> {
         let mut index: usize = 0;
 
-        if data.len() <= 105 {
+        // everything but the height has a fixed size
+        if data.len() < 137 {
             return Err(
                 Report::new(BlockError::BasicInfo(BasicInfoErrorKind::Parse))
-                    .attach_printable("data <= 105"),
+                    .attach_printable("data < 137"),
             );
         }
 
@@ -134,6 +137,100 @@ pub struct TransactionBlock {
     pub merkle_tree_root: Hash,
     pub default_info: BasicInfo,
     pub transactions: Vec<Hash>,
+    /// Blocks of the derivative chains checkpointed by the block
+    pub anchors: Vec<DerivativeAnchor>,
+}
+
+/// Blocks of a derivative chain committed into a main chain block
+///
+/// The anchor carries the blocks added to the chain since its previous anchor,
+/// so they can be checked against the previous anchor without the derivative chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivativeAnchor {
+    pub owner: Address,
+    /// Blocks following the previously anchored one, by height
+    ///
+    /// The owner is credited with the gas earned by the blocks when the main chain block
+    /// is applied, it's recomputed from the blocks and the previously anchored one
+    pub blocks: Vec<DerivativeBlock>,
+}
+
+impl DerivativeAnchor {
+    /// Height of the anchored tip, 0 for an anchor without blocks
+    pub fn height(&self) -> U256 {
+        self.blocks
+            .last()
+            .map_or(U256::zero(), |block| block.default_info.height)
+    }
+
+    /// Hash of the anchored tip
+    pub fn hash(&self) -> Result<Hash, BlockError> {
+        self.blocks
+            .last()
+            .ok_or(BlockError::TransactionBlock(TxBlockErrorKind::Dump))
+            .attach_printable("anchor without blocks")?
+            .hash()
+    }
+
+    pub fn get_dump_size(&self) -> usize {
+        33 + 2
+            + self
+                .blocks
+                .iter()
+                .map(|block| block.default_info.get_dump_size() + 32)
+                .sum::<usize>()
+    }
+
+    pub fn dump(&self, buffer: &mut Vec<u8>) -> Result<(), BlockError> {
+        buffer.extend(self.owner.iter());
+        let blocks_amount = u16::try_from(self.blocks.len())
+            .change_context(BlockError::TransactionBlock(TxBlockErrorKind::Dump))
+            .attach_printable("too many anchored blocks")?;
+        buffer.extend(blocks_amount.to_be_bytes());
+        for block in self.blocks.iter() {
+            block
+                .default_info
+                .dump(buffer)
+                .change_context(BlockError::TransactionBlock(TxBlockErrorKind::Dump))
+                .attach_printable("Error dumping anchored block")?;
+            buffer.extend(block.payment_transaction.iter());
+        }
+        Ok(())
+    }
+
+    /// Parses the anchor, returning it with the size of its dump
+    pub fn parse(data: &[u8]) -> Result<(Self, usize), BlockError> {
+        if data.len() < 35 {
+            return Err(
+                Report::new(BlockError::TransactionBlock(TxBlockErrorKind::Parse))
+                    .attach_printable("anchor data.len() < 35"),
+            );
+        }
+        let owner: Address = data[0..33].try_into().unwrap();
+        let blocks_amount = u16::from_be_bytes([data[33], data[34]]);
+        let mut index = 35;
+
+        let mut blocks = Vec::with_capacity(blocks_amount as usize);
+        for _ in 0..blocks_amount {
+            let default_info = BasicInfo::parse(&data[index..])
+                .change_context(BlockError::TransactionBlock(TxBlockErrorKind::Parse))
+                .attach_printable("Error parsing anchored block")?;
+            index += default_info.get_dump_size();
+            let payment_transaction: Hash = data
+                .get(index..index + 32)
+                .ok_or(BlockError::TransactionBlock(TxBlockErrorKind::Parse))
+                .attach_printable("no payment transaction of the anchored block")?
+                .try_into()
+                .unwrap();
+            index += 32;
+            blocks.push(DerivativeBlock {
+                default_info,
+                payment_transaction,
+            });
+        }
+
+        Ok((Self { owner, blocks }, index))
+    }
 }
 
 impl TransactionBlock {
@@ -148,13 +245,26 @@ impl TransactionBlock {
             default_info,
             merkle_tree_root,
             transactions,
+            anchors: Vec::new(),
         }
     }
 
+    /// Sets the derivative chain tips the block commits to
+    pub fn with_anchors(mut self, anchors: Vec<DerivativeAnchor>) -> TransactionBlock {
+        self.anchors = anchors;
+        self
+    }
+
     pub fn get_dump_size(&self) -> usize {
         1 + tools::u256_size(&self.fee)
             + 32
             + self.default_info.get_dump_size()
+            + 2
+            + self
+                .anchors
+                .iter()
+                .map(DerivativeAnchor::get_dump_size)
+                .sum::<usize>()
             + self.transactions.len() * 32
     }
 
@@ -180,6 +290,15 @@ impl TransactionBlock {
             .change_context(BlockError::TransactionBlock(TxBlockErrorKind::Dump))
             .attach_printable("Error dumping fee")?;
 
+        // anchors
+        let anchors_amount = u16::try_from(self.anchors.len())
+            .change_context(BlockError::TransactionBlock(TxBlockErrorKind::Dump))
+            .attach_printable("too many anchors")?;
+        to_return.extend(anchors_amount.to_be_bytes());
+        for anchor in self.anchors.iter() {
+            anchor.dump(&mut to_return)?;
+        }
+
         // transactions
         for transaction in self.transactions.iter() {
             to_return.extend(transaction.iter());
@@ -191,13 +310,26 @@ impl TransactionBlock {
     pub fn parse(data: &[u8]) -> Result<Self, BlockError> {
         let mut index: usize = 0;
 
-        let merkle_tree_root: Hash = unsafe { data[0..32].try_into().unwrap_unchecked() };
+        if data.len() < 32 {
+            return Err(
+                Report::new(BlockError::TransactionBlock(TxBlockErrorKind::Parse))
+                    .attach_printable("data.len() < 32"),
+            );
+        }
+
+        let merkle_tree_root: Hash = data[0..32].try_into().unwrap();
         index += 32;
 
         let default_info = BasicInfo::parse(&data[index..])
             .change_context(BlockError::TransactionBlock(TxBlockErrorKind::Parse))
             .attach_printable("Error parsing default data")?;
         index += default_info.get_dump_size();
+        if data.len() < index {
+            return Err(
+                Report::new(BlockError::TransactionBlock(TxBlockErrorKind::Parse))
+                    .attach_printable("no fee"),
+            );
+        }
 
         let (fee, fee_size) = tools::load_u256(&data[index..])
             .change_context(BlockError::TransactionBlock(TxBlockErrorKind::Parse))
@@ -205,6 +337,22 @@ impl TransactionBlock {
 
         index += fee_size + 1;
 
+        if data.len() < index + 2 {
+            return Err(
+                Report::new(BlockError::TransactionBlock(TxBlockErrorKind::Parse))
+                    .attach_printable("no amount of anchors"),
+            );
+        }
+        let anchors_amount = u16::from_be_bytes([data[index], data[index + 1]]);
+        index += 2;
+
+        let mut anchors = Vec::with_capacity(anchors_amount as usize);
+        for _ in 0..anchors_amount {
+            let (anchor, anchor_size) = DerivativeAnchor::parse(&data[index..])?;
+            index += anchor_size;
+            anchors.push(anchor);
+        }
+
         if (data.len() - index) % 32 != 0 {
             return Err(
                 Report::new(BlockError::TransactionBlock(TxBlockErrorKind::Parse))
@@ -212,18 +360,18 @@ impl TransactionBlock {
             );
         }
 
-        let mut transactions = Vec::<Hash>::with_capacity((data.len() - index) / 32);
-
-        while index < data.len() {
-            transactions.push(unsafe { data[index..index + 32].try_into().unwrap_unchecked() });
-            index += 32;
-        }
+        // the length is checked to be a multiple of 32 above
+        let transactions: Vec<Hash> = data[index..]
+            .chunks_exact(32)
+            .map(|hash| hash.try_into().unwrap())
+            .collect();
 
         Ok(Self {
             fee,
             merkle_tree_root,
             default_info,
             transactions,
+            anchors,
         })
     }
 
@@ -234,10 +382,11 @@ impl TransactionBlock {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DerivativeBlock {
     pub default_info: BasicInfo,
-    //pub payment_transaction: Hash,
+    /// Hash of the payload committed by the block, like the transaction paying for it
+    pub payment_transaction: Hash,
 }
 
 pub trait Block {
@@ -246,16 +395,121 @@ pub trait Block {
     fn dump(&self) -> Result<Vec<u8>, BlockError>;
     fn get_info(&self) -> &BasicInfo;
     fn get_merkle_root(&self) -> Hash;
+    /// Hash of the contents of the block committed by its pow, see `pow_preimage`
+    fn get_body_root(&self) -> Hash;
     fn verify_block(&self, prev_hash: &Hash) -> bool;
     fn get_founder(&self) -> &Address;
     fn get_fee(&self) -> U256;
     fn get_type(&self) -> Headers;
-    fn validate(&self, prev_block: Option<BlockArc>) -> Result<bool, BlockError>;
+    fn get_transactions(&self) -> &[Hash];
+    /// Derivative chain blocks committed by the block
+    fn get_anchors(&self) -> &[DerivativeAnchor];
+    /// Checks the block against the previous block of its chain
+    ///
+    /// Returns the reason of the rejection as `BlockError::Validation`
+    fn validate(&self, prev_block: Option<BlockArc>) -> Result<(), BlockError>;
+}
+
+/// Hash the pow of a main chain block is found for
+///
+/// Commits to the basic info of the block besides the pow and to the root of its contents,
+/// so the pow can't be reused for another block
+pub fn pow_preimage(info: &BasicInfo, body_root: &Hash) -> Hash {
+    let mut preimage = Vec::with_capacity(32 + 32 + 33 + 8 + 32);
+    preimage.extend(info.previous_hash.iter());
+    preimage.extend(body_root.iter());
+    preimage.extend(info.founder.iter());
+    preimage.extend(info.timestamp.to_be_bytes());
+    preimage.extend(info.difficulty.iter());
+    tools::hash(&preimage)
+}
+
+/// Checks the basic info of the block against the previous block
+pub fn validate_basic_info(
+    info: &BasicInfo,
+    body_root: &Hash,
+    prev_block: &BlockArc,
+) -> Result<(), BlockError> {
+    let prev_info = prev_block.get_info();
+    let prev_hash = prev_block.hash().attach_printable_lazy(|| {
+        format!("Error hashing block with height {}", prev_info.height)
+    })?;
+
+    validate_header(info, body_root, prev_info, &prev_hash)
+}
+
+/// Checks the basic info of the block with the root of its contents against the basic info
+/// and the hash of the previous block, so the headers can be checked before the blocks are known
+///
+/// Pow of the block is checked for `pow_preimage` with the difficulty of the previous block
+pub fn validate_header(
+    info: &BasicInfo,
+    body_root: &Hash,
+    prev_info: &BasicInfo,
+    prev_hash: &Hash,
+) -> Result<(), BlockError> {
+    if info.previous_hash != *prev_hash {
+        return Err(Report::new(BlockError::Validation(
+            ValidationErrorKind::PreviousHash,
+        )));
+    }
+
+    if info.height != prev_info.height + 1 {
+        return Err(Report::new(BlockError::Validation(
+            ValidationErrorKind::Height,
+        )))
+        .attach_printable(format!(
+            "expected height {}, got {}",
+            prev_info.height + 1,
+            info.height
+        ));
+    }
+
+    if info.timestamp < prev_info.timestamp {
+        return Err(Report::new(BlockError::Validation(
+            ValidationErrorKind::Timestamp,
+        )))
+        .attach_printable(format!(
+            "timestamp {} is earlier than the previous one {}",
+            info.timestamp, prev_info.timestamp
+        ));
+    }
+
+    let latest = tools::current_timestamp() + MAX_FUTURE_TIMESTAMP;
+    if info.timestamp > latest {
+        return Err(Report::new(BlockError::Validation(
+            ValidationErrorKind::FutureTimestamp,
+        )))
+        .attach_printable(format!(
+            "timestamp {} is later than {}",
+            info.timestamp, latest
+        ));
+    }
+
+    let mut difficulty = prev_info.difficulty;
+    recalculate_difficulty(prev_info.timestamp, info.timestamp, &mut difficulty);
+    if info.difficulty != difficulty {
+        return Err(Report::new(BlockError::Validation(
+            ValidationErrorKind::Difficulty,
+        )));
+    }
+
+    if !check_pow(
+        &pow_preimage(info, body_root),
+        &prev_info.difficulty,
+        &info.pow,
+    ) {
+        return Err(Report::new(BlockError::Validation(
+            ValidationErrorKind::Pow,
+        )));
+    }
+
+    Ok(())
 }
 
 impl Block for DerivativeBlock {
     fn get_dump_size(&self) -> usize {
-        self.default_info.get_dump_size() + 1
+        1 + self.default_info.get_dump_size() + 32
     }
     fn get_info(&self) -> &BasicInfo {
         &self.default_info
@@ -263,12 +517,22 @@ impl Block for DerivativeBlock {
     fn get_type(&self) -> Headers {
         Headers::DerivativeBlock
     }
+    fn get_transactions(&self) -> &[Hash] {
+        &[]
+    }
+    fn get_anchors(&self) -> &[DerivativeAnchor] {
+        &[]
+    }
     fn dump(&self) -> Result<Vec<u8>, BlockError> {
         let size = self.get_dump_size();
         let mut to_return = Vec::<u8>::with_capacity(size);
 
         to_return.push(Headers::DerivativeBlock as u8);
-        self.default_info.dump(&mut to_return)?;
+        self.default_info
+            .dump(&mut to_return)
+            .change_context(BlockError::DerivativeBlock(DerivativeBlockErrorKind::Dump))
+            .attach_printable("Error dumping default info")?;
+        to_return.extend(self.payment_transaction.iter());
 
         Ok(to_return)
     }
@@ -276,8 +540,10 @@ impl Block for DerivativeBlock {
         Ok(tools::hash(&self.dump()?))
     }
     fn get_merkle_root(&self) -> Hash {
-        unimplemented!()
-        //self.payment_transaction
+        self.payment_transaction
+    }
+    fn get_body_root(&self) -> Hash {
+        self.payment_transaction
     }
     fn verify_block(&self, prev_hash: &Hash) -> bool {
         self.default_info.previous_hash.eq(prev_hash)
@@ -288,59 +554,126 @@ impl Block for DerivativeBlock {
     fn get_fee(&self) -> U256 {
         U256::zero()
     }
-    fn validate(&self, prev_block: Option<BlockArc>) -> Result<bool, BlockError> {
-        if prev_block.is_none() {
-            return Ok(true);
-        }
-        let prev_block = unsafe { prev_block.unwrap_unchecked() };
-        if !self.default_info.previous_hash.eq(&prev_block
-            .hash()
-            .change_context(BlockError::SummarizeBlock(SummarizeBlockErrorKind::Hash))
+    /// Without the previous block the block is checked as the first one of its chain,
+    /// its previous hash has to be checked against the genesis block by the chain
+    fn validate(&self, prev_block: Option<BlockArc>) -> Result<(), BlockError> {
+        let info = &self.default_info;
+        let pow_difficulty = match &prev_block {
+            Some(prev_block) => {
+                let prev_info = prev_block.get_info();
+                let prev_hash = prev_block.hash().attach_printable_lazy(|| {
+                    format!("Error hashing block with height {}", prev_info.height)
+                })?;
+                if info.previous_hash != prev_hash {
+                    return Err(Report::new(BlockError::Validation(
+                        ValidationErrorKind::PreviousHash,
+                    )));
+                }
+                if info.timestamp < prev_info.timestamp {
+                    return Err(Report::new(BlockError::Validation(
+                        ValidationErrorKind::Timestamp,
+                    )))
+                    .attach_printable(format!(
+                        "timestamp {} is earlier than the previous one {}",
+                        info.timestamp, prev_info.timestamp
+                    ));
+                }
+                prev_info.difficulty
+            }
+            None => BEGINNING_DIFFICULTY,
+        };
+
+        let latest = tools::current_timestamp() + MAX_FUTURE_TIMESTAMP;
+        if info.timestamp > latest {
+            return Err(Report::new(BlockError::Validation(
+                ValidationErrorKind::FutureTimestamp,
+            )))
             .attach_printable(format!(
-                "Error hashing block with height {}",
-                prev_block.get_info().height
-            ))?)
-        {
-            return Ok(false);
+                "timestamp {} is later than {}",
+                info.timestamp, latest
+            ));
         }
 
-        let mut prev_difficulty = prev_block.get_info().difficulty;
-        recalculate_difficulty(
-            prev_block.get_info().timestamp,
-            self.default_info.timestamp,
-            &mut prev_difficulty,
-        );
+        let height = prev_block
+            .as_ref()
+            .map_or(U256::zero(), |prev_block| prev_block.get_info().height)
+            + 1;
+        if info.height != height {
+            return Err(Report::new(BlockError::Validation(
+                ValidationErrorKind::Height,
+            )))
+            .attach_printable(format!("expected height {}, got {}", height, info.height));
+        }
 
-        if self.default_info.difficulty != prev_difficulty {
-            return Ok(false);
+        let prev_info = prev_block.as_ref().map(|prev_block| prev_block.get_info());
+        if info.difficulty != DerivativeBlock::next_difficulty(prev_info, info.timestamp) {
+            return Err(Report::new(BlockError::Validation(
+                ValidationErrorKind::Difficulty,
+            )));
         }
 
-        if !check_pow(
-            &self.get_merkle_root(),
-            &prev_block.get_info().difficulty,
-            &self.default_info.pow,
-        ) {
-            return Ok(false);
+        let preimage = DerivativeBlock::pow_preimage(info, &self.payment_transaction);
+        if !check_pow(&preimage, &pow_difficulty, &info.pow) {
+            return Err(Report::new(BlockError::Validation(
+                ValidationErrorKind::Pow,
+            )));
         }
 
-        Ok(true)
+        Ok(())
     }
 }
 
 impl DerivativeBlock {
     pub fn parse(data: &[u8]) -> Result<Self, BlockError> {
-        if data.len() < 32 {
+        let default_info: BasicInfo = BasicInfo::parse(data)
+            .change_context(BlockError::DerivativeBlock(DerivativeBlockErrorKind::Parse))
+            .attach_printable("Error parsing default data")?;
+        let index = default_info.get_dump_size();
+        if data.len() != index + 32 {
             return Err(
                 Report::new(BlockError::DerivativeBlock(DerivativeBlockErrorKind::Parse))
-                    .attach_printable("data.len() < 32"),
+                    .attach_printable("wrong size of the payment transaction"),
             );
         }
-        let default_info: BasicInfo = BasicInfo::parse(data)?;
+        let payment_transaction: Hash = data[index..].try_into().unwrap();
         Ok(DerivativeBlock {
             default_info,
-            //payment_transaction,
+            payment_transaction,
         })
     }
+
+    /// Hash the pow of the block is found for
+    ///
+    /// Commits to the basic info of the block besides the pow and to the payload,
+    /// so the pow can't be reused for another block
+    pub fn pow_preimage(info: &BasicInfo, payment_transaction: &Hash) -> Hash {
+        let mut preimage = Vec::with_capacity(32 + 33 + 32 + 8 + 32);
+        preimage.extend(info.previous_hash.iter());
+        preimage.extend(info.founder.iter());
+        preimage.extend(payment_transaction.iter());
+        preimage.extend(info.timestamp.to_be_bytes());
+        preimage.extend(info.difficulty.iter());
+        tools::hash(&preimage)
+    }
+
+    /// Difficulty of the block following `prev_info` in its derivative chain,
+    /// the first block of the chain has `BEGINNING_DIFFICULTY`
+    ///
+    /// Retargeted by the time passed since the previous block of the same chain,
+    /// the pow of the block is checked against the difficulty of the previous block
+    pub fn next_difficulty(prev_info: Option<&BasicInfo>, timestamp: u64) -> Hash {
+        let Some(prev_info) = prev_info else {
+            return BEGINNING_DIFFICULTY;
+        };
+        let mut difficulty = prev_info.difficulty;
+        // earlier timestamps are rejected by the validation
+        recalculate_difficulty(
+            prev_info.timestamp,
+            timestamp.max(prev_info.timestamp),
+            &mut difficulty,
+        );
+        difficulty
+    }
 }
 
 impl Block for TransactionBlock {
@@ -359,6 +692,24 @@ impl Block for TransactionBlock {
     fn get_merkle_root(&self) -> Hash {
         self.merkle_tree_root
     }
+    /// Commits to the transactions, the fee and the anchors
+    fn get_body_root(&self) -> Hash {
+        let anchors_size: usize = self
+            .anchors
+            .iter()
+            .map(DerivativeAnchor::get_dump_size)
+            .sum();
+        let mut body = Vec::with_capacity(32 + 32 + anchors_size);
+        body.extend(self.merkle_tree_root.iter());
+        let mut fee = [0u8; 32];
+        self.fee.to_big_endian(&mut fee);
+        body.extend(fee);
+        for anchor in self.anchors.iter() {
+            // dumping an anchor can't fail
+            anchor.dump(&mut body).unwrap();
+        }
+        tools::hash(&body)
+    }
     fn verify_block(&self, prev_hash: &Hash) -> bool {
         self.default_info.previous_hash.eq(prev_hash)
     }
@@ -373,47 +724,28 @@ impl Block for TransactionBlock {
         Headers::TransactionBlock
     }
 
-    fn validate(&self, prev_block: Option<BlockArc>) -> Result<bool, BlockError> {
-        if prev_block.is_none() {
-            return Ok(true);
-        }
-        let prev_block = unsafe { prev_block.unwrap_unchecked() };
-        if !self.default_info.previous_hash.eq(&prev_block
-            .hash()
-            .change_context(BlockError::SummarizeBlock(SummarizeBlockErrorKind::Hash))
-            .attach_printable(format!(
-                "Error hashing block with height {}",
-                prev_block.get_info().height
-            ))?)
-        {
-            return Ok(false);
-        }
+    fn get_transactions(&self) -> &[Hash] {
+        &self.transactions
+    }
+
+    fn get_anchors(&self) -> &[DerivativeAnchor] {
+        &self.anchors
+    }
 
+    fn validate(&self, prev_block: Option<BlockArc>) -> Result<(), BlockError> {
         let merkle_tree = merkletree::MerkleTree::build_tree(&self.transactions);
         if !self.merkle_tree_root.eq(merkle_tree.get_root()) {
-            return Ok(false);
+            return Err(Report::new(BlockError::Validation(
+                ValidationErrorKind::MerkleRoot,
+            )));
         }
 
-        let mut prev_difficulty = prev_block.get_info().difficulty;
-        recalculate_difficulty(
-            prev_block.get_info().timestamp,
-            self.default_info.timestamp,
-            &mut prev_difficulty,
-        );
-
-        if self.default_info.difficulty != prev_difficulty {
-            return Ok(false);
+        match prev_block {
+            Some(prev_block) => {
+                validate_basic_info(&self.default_info, &self.get_body_root(), &prev_block)
+            }
+            None => Ok(()),
         }
-
-        if !check_pow(
-            &self.merkle_tree_root,
-            &prev_block.get_info().difficulty,
-            &self.default_info.pow,
-        ) {
-            return Ok(false);
-        }
-
-        Ok(true)
     }
 }
 
@@ -421,9 +753,32 @@ impl Block for TransactionBlock {
 pub struct SummarizeBlock {
     pub default_info: BasicInfo,
     pub merkle_tree_root: Hash,
+    /// Hash of the coinbase transaction, derived from the basic info
+    coinbase: Hash,
 }
 
 impl SummarizeBlock {
+    pub fn new(default_info: BasicInfo, merkle_tree_root: Hash) -> Self {
+        let coinbase = Self::coinbase(&default_info).hash();
+        SummarizeBlock {
+            default_info,
+            merkle_tree_root,
+            coinbase,
+        }
+    }
+
+    /// Coinbase transaction paying the fixed payment to the founder of the block
+    ///
+    /// The coinbase is not dumped with the block, every node derives it from the basic info
+    pub fn coinbase(info: &BasicInfo) -> Transaction {
+        Transaction::new_coinbase(
+            info.founder,
+            &info.height,
+            info.timestamp,
+            *MAIN_CHAIN_PAYMENT,
+        )
+    }
+
     pub fn parse(data: &[u8]) -> Result<Self, BlockError> {
         if data.len() <= 32 {
             return Err(
@@ -441,10 +796,7 @@ impl SummarizeBlock {
             .change_context(BlockError::SummarizeBlock(SummarizeBlockErrorKind::Parse))
             .attach_printable("Error parsing default data")?;
 
-        Ok(Self {
-            default_info,
-            merkle_tree_root,
-        })
+        Ok(Self::new(default_info, merkle_tree_root))
     }
 }
 
@@ -452,6 +804,13 @@ impl Block for SummarizeBlock {
     fn get_type(&self) -> Headers {
         Headers::SummarizeBlock
     }
+    /// Only the coinbase transaction
+    fn get_transactions(&self) -> &[Hash] {
+        std::slice::from_ref(&self.coinbase)
+    }
+    fn get_anchors(&self) -> &[DerivativeAnchor] {
+        &[]
+    }
     fn hash(&self) -> Result<Hash, BlockError> {
         let dump = self
             .dump()
@@ -492,6 +851,10 @@ impl Block for SummarizeBlock {
         self.merkle_tree_root
     }
 
+    fn get_body_root(&self) -> Hash {
+        self.merkle_tree_root
+    }
+
     fn verify_block(&self, prev_hash: &Hash) -> bool {
         self.default_info.previous_hash.eq(prev_hash)
     }
@@ -504,47 +867,14 @@ impl Block for SummarizeBlock {
         U256::zero()
     }
 
-    fn validate(&self, prev_block: Option<BlockArc>) -> Result<bool, BlockError> {
-        if prev_block.is_none() {
-            return Ok(true);
-        }
-        let prev_block = unsafe { prev_block.unwrap_unchecked() };
-        if !self.default_info.previous_hash.eq(&prev_block
-            .hash()
-            .change_context(BlockError::SummarizeBlock(SummarizeBlockErrorKind::Hash))
-            .attach_printable(format!(
-                "Error hashing block with height {}",
-                prev_block.get_info().height
-            ))?)
-        {
-            return Ok(false);
+    fn validate(&self, prev_block: Option<BlockArc>) -> Result<(), BlockError> {
+        // merkle root of the summary is checked against the summary databases on applying
+        match prev_block {
+            Some(prev_block) => {
+                validate_basic_info(&self.default_info, &self.merkle_tree_root, &prev_block)
+            }
+            None => Ok(()),
         }
-
-        // let merkle_tree = merkletree::MerkleTree::build_tree(&self.transactions);
-        // if !self.merkle_tree_root.eq(merkle_tree.get_root()) {
-        //     return Ok(false);
-        // }
-
-        let mut prev_difficulty = prev_block.get_info().difficulty;
-        recalculate_difficulty(
-            prev_block.get_info().timestamp,
-            self.default_info.timestamp,
-            &mut prev_difficulty,
-        );
-
-        if self.default_info.difficulty != prev_difficulty {
-            return Ok(false);
-        }
-
-        if !check_pow(
-            &self.merkle_tree_root,
-            &prev_block.get_info().difficulty,
-            &self.default_info.pow,
-        ) {
-            return Ok(false);
-        }
-
-        Ok(true)
     }
 }
 
@@ -586,7 +916,7 @@ impl PartialEq for dyn Block + Send + Sync {
 
 impl PartialOrd for dyn Block + Send + Sync {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.get_info().timestamp.cmp(&other.get_info().timestamp))
+        Some(self.cmp(other))
     }
 }
 
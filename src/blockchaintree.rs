@@ -1,60 +1,496 @@
-use std::{collections::HashMap, path::Path, sync::Arc};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use crate::{
     block::{self, Block as _, BlockArc},
-    chain,
-    errors::{BCTreeErrorKind, BlockChainTreeError, ChainErrorKind},
+    chain::{self, ChainUpdate},
+    dump_headers::Headers,
+    errors::{
+        BCTreeErrorKind, BlockChainTreeError, BlockError, ChainErrorKind, ValidationErrorKind,
+    },
+    events::{Balance, Event, EventReceiver, EventSender},
     merkletree,
+    miner::MiningTarget,
     static_values::{
-        self, AMMOUNT_SUMMARY, BLOCKS_PER_EPOCH, BYTE_GAS_PRICE, COINS_PER_CYCLE, GAS_SUMMARY,
-        MAIN_CHAIN_PAYMENT, OLD_AMMOUNT_SUMMARY, OLD_GAS_SUMMARY, ROOT_PUBLIC_ADDRESS,
+        self, AMMOUNT_SUMMARY, ANCHORS_TREE, ANCHOR_HEIGHTS_TREE, BLOCKCHAIN_DIRECTORY,
+        BLOCKS_PER_EPOCH, BYTE_GAS_PRICE, COINS_PER_CYCLE, DERIVATIVE_CHAINS_DIRECTORY,
+        EVENTS_CAPACITY, GAS_SUMMARY, MAX_ANCHORED_BLOCKS, MAX_BLOCK_TRANSACTIONS_SIZE,
+        NONCE_SUMMARY, OLD_AMMOUNT_SUMMARY, OLD_GAS_SUMMARY, ROOT_PUBLIC_ADDRESS, TOKENS_TREE,
+        TOKEN_BALANCES_TREE, TRANSACTIONS_POOL, UNDO_TREE,
     },
+    token::{Action, Token, TokenAction},
     tools,
-    transaction::Transaction,
-    transaction::Transactionable,
-    txpool,
-    types::Hash,
+    transaction::{self, Transaction},
+    transaction::{Transactionable, TransactionableItem},
+    txpool::{SharedTxPool, TxPool, TxPoolConfig},
+    types::{Address, Hash},
 };
 use error_stack::{Report, ResultExt};
 use primitive_types::U256;
-use sled::Db;
-use std::fs;
+use sled::{
+    transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree},
+    IVec, Tree,
+};
+use tokio::sync::{broadcast, RwLock};
+
+/// Balances changed by a single block, accumulated before being written
+#[derive(Default)]
+struct BalanceChanges {
+    amounts: HashMap<Address, U256>,
+    gas: HashMap<Address, U256>,
+    nonces: HashMap<Address, U256>,
+    /// Last anchored heights of the derivative chains
+    anchor_heights: HashMap<Address, U256>,
+    /// Token balances by owner and token hash
+    token_balances: HashMap<[u8; 65], U256>,
+    /// Tokens created or changed by the block
+    tokens: HashMap<Hash, Token>,
+}
+
+/// Sender of the transactions taken into a block template
+struct TemplateSender {
+    /// Nonce of the next transaction to take
+    nonce: u64,
+    /// Coins left after the taken transactions
+    amount: U256,
+    /// Gas left after the taken transactions
+    gas: U256,
+}
+
+/// Template of the next main chain block
+///
+/// The miner has to find pow for `pow_preimage` satisfying `difficulty`
+/// and submit it with `submit_template`
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pub previous_hash: Hash,
+    /// Hash the pow has to be found for, see `block::pow_preimage`
+    pub pow_preimage: Hash,
+    /// Difficulty the pow has to satisfy
+    pub difficulty: Hash,
+    pub height: U256,
+    /// `Headers::TransactionBlock` or `Headers::SummarizeBlock`
+    pub kind: Headers,
+    pub timestamp: u64,
+    /// Minimal fee of the transactions of the block
+    pub fee: U256,
+    pub founder: Address,
+    /// Transactions from the pool, without the coinbase
+    pub transactions: Vec<Hash>,
+    /// Sum of the fees of the transactions, paid to the founder with the block payment
+    pub transaction_fees: U256,
+    /// Blocks of the local derivative chains added since they were last anchored
+    pub anchors: Vec<block::DerivativeAnchor>,
+}
+
+/// Hash of the address with its balances, a leaf of the summary merkle tree
+fn summary_leaf(address: &[u8], amount: &[u8], gas_amount: Option<&[u8]>) -> Hash {
+    let gas_amount = gas_amount.unwrap_or_default();
+    let mut data_to_hash: Vec<u8> =
+        Vec::with_capacity(address.len() + amount.len() + gas_amount.len() + 2);
+    data_to_hash.extend(address.iter());
+    data_to_hash.push(b'|');
+    data_to_hash.extend(amount.iter());
+    data_to_hash.push(b'|');
+    data_to_hash.extend(gas_amount.iter());
+
+    tools::hash(&data_to_hash)
+}
+
+/// Keys of the amount and gas summaries, current and old
+type SummaryKeys = [HashSet<Vec<u8>>; 2];
+
+/// Summary trees as seen from inside of a database transaction
+struct StateView<'a> {
+    transactions: &'a TransactionalTree,
+    summary: &'a TransactionalTree,
+    gas: &'a TransactionalTree,
+    old_summary: &'a TransactionalTree,
+    old_gas: &'a TransactionalTree,
+    nonces: &'a TransactionalTree,
+    undo: &'a TransactionalTree,
+    anchors: &'a TransactionalTree,
+    anchor_heights: &'a TransactionalTree,
+    tokens: &'a TransactionalTree,
+    token_balances: &'a TransactionalTree,
+    /// Balances changed so far, reported once the transaction is committed
+    touched: &'a RefCell<HashSet<(Balance, Address)>>,
+    /// Taken before the transaction when the update can rotate the summaries
+    summary_keys: Option<&'a SummaryKeys>,
+}
+
+/// Wraps the report to abort the database transaction with it
+fn abort(
+    report: Report<BlockChainTreeError>,
+) -> ConflictableTransactionError<Report<BlockChainTreeError>> {
+    ConflictableTransactionError::Abort(report)
+}
+
+fn dump_balance(
+    amount: &U256,
+) -> ConflictableTransactionResult<Vec<u8>, Report<BlockChainTreeError>> {
+    let mut buf: Vec<u8> = Vec::with_capacity(tools::u256_size(amount));
+    tools::dump_u256(amount, &mut buf).map_err(|report| {
+        abort(report.change_context(BlockChainTreeError::BlockChainTree(
+            BCTreeErrorKind::ApplyBlock,
+        )))
+    })?;
+    Ok(buf)
+}
+
+fn load_balance(
+    tree: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<U256, Report<BlockChainTreeError>> {
+    match tree.get(key)? {
+        Some(value) => Ok(tools::load_u256(&value)
+            .map_err(|report| {
+                abort(report.change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetFunds,
+                )))
+            })?
+            .0),
+        None => Ok(U256::zero()),
+    }
+}
+
+/// Moves the amount between the balances of the tree in one database transaction,
+/// no sender mints the amount and no receiver burns it
+#[cfg(feature = "testing")]
+fn move_balance(
+    tree: &Tree,
+    from: Option<&[u8]>,
+    to: Option<&[u8]>,
+    amount: U256,
+) -> Result<(), Report<BlockChainTreeError>> {
+    tree.transaction(|db| {
+        if let Some(from) = from {
+            let balance = load_balance(db, from)?.checked_sub(amount).ok_or_else(|| {
+                abort(
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::DecreaseFunds,
+                    ))
+                    .attach_printable(format!("{} has insufficient funds", hex::encode(from))),
+                )
+            })?;
+            db.insert(from, dump_balance(&balance)?)?;
+        }
+        if let Some(to) = to {
+            let balance = load_balance(db, to)?.checked_add(amount).ok_or_else(|| {
+                abort(
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::AddFunds,
+                    ))
+                    .attach_printable(format!("balance of {} overflows", hex::encode(to))),
+                )
+            })?;
+            db.insert(to, dump_balance(&balance)?)?;
+        }
+        Ok(())
+    })
+    .map_err(|error| match error {
+        sled::transaction::TransactionError::Abort(report) => report,
+        sled::transaction::TransactionError::Storage(error) => {
+            let kind = if from.is_some() {
+                BCTreeErrorKind::DecreaseFunds
+            } else {
+                BCTreeErrorKind::AddFunds
+            };
+            Report::new(error).change_context(BlockChainTreeError::BlockChainTree(kind))
+        }
+    })
+}
+
+/// Gets the balance from `changes`, loading it from the tree the first time
+fn changed_balance<'a, K: Copy + Eq + std::hash::Hash + AsRef<[u8]>>(
+    changes: &'a mut HashMap<K, U256>,
+    tree: &TransactionalTree,
+    key: &K,
+) -> ConflictableTransactionResult<&'a mut U256, Report<BlockChainTreeError>> {
+    Ok(match changes.entry(*key) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(load_balance(tree, key.as_ref())?),
+    })
+}
+
+/// Gets the token from `changes`, loading it from the tree the first time
+fn changed_token<'a>(
+    changes: &'a mut HashMap<Hash, Token>,
+    tree: &TransactionalTree,
+    token_hash: &Hash,
+) -> ConflictableTransactionResult<Option<&'a mut Token>, Report<BlockChainTreeError>> {
+    if !changes.contains_key(token_hash) {
+        let Some(dump) = tree.get(token_hash)? else {
+            return Ok(None);
+        };
+        let token = Token::parse(&dump).map_err(|report| {
+            abort(report.change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::GetToken,
+            )))
+        })?;
+        changes.insert(*token_hash, token);
+    }
+    Ok(changes.get_mut(token_hash))
+}
+
+/// Aborts the database transaction with the token action rejected
+fn token_error(message: String) -> ConflictableTransactionError<Report<BlockChainTreeError>> {
+    abort(
+        Report::new(BlockChainTreeError::BlockChainTree(
+            BCTreeErrorKind::TokenAction,
+        ))
+        .attach_printable(message),
+    )
+}
+
+fn token_overflow(what: &str) -> Report<BlockChainTreeError> {
+    Report::new(BlockChainTreeError::BlockChainTree(
+        BCTreeErrorKind::TokenAction,
+    ))
+    .attach_printable(format!("{what} overflows"))
+}
+
+/// Adds the tokens to the balance, rejecting an overflow
+fn credit_tokens(
+    balance: &mut U256,
+    amount: U256,
+    token_hash: &Hash,
+) -> ConflictableTransactionResult<(), Report<BlockChainTreeError>> {
+    *balance = balance.checked_add(amount).ok_or_else(|| {
+        token_error(format!(
+            "balance of token {} overflows",
+            hex::encode(token_hash)
+        ))
+    })?;
+    Ok(())
+}
+
+/// Key of the anchor in the anchors tree, anchors of an owner are ordered by height
+fn anchor_key(owner: &Address, height: &U256) -> [u8; 65] {
+    let mut key = [0u8; 65];
+    key[..33].copy_from_slice(owner);
+    height.to_big_endian(&mut key[33..]);
+    key
+}
+
+/// Parses the anchored block stored in the anchors tree
+fn parse_anchor(value: &[u8]) -> Result<block::DerivativeBlock, Report<BlockChainTreeError>> {
+    // the dump of the block starts with its header
+    let block = value
+        .get(1..)
+        .ok_or(BlockChainTreeError::BlockChainTree(
+            BCTreeErrorKind::GetAnchors,
+        ))
+        .attach_printable("stored anchor is empty")?;
+    block::DerivativeBlock::parse(block).change_context(BlockChainTreeError::BlockChainTree(
+        BCTreeErrorKind::GetAnchors,
+    ))
+}
+
+/// Gas earned by the anchored blocks following `previous`, the previously anchored block
+///
+/// The reward of a block depends only on the difficulty its pow was checked against,
+/// the one of the previous block
+fn anchored_blocks_gas(
+    previous: Option<&block::DerivativeBlock>,
+    blocks: &[block::DerivativeBlock],
+) -> U256 {
+    let mut pow_difficulty = previous.map_or(static_values::BEGINNING_DIFFICULTY, |block| {
+        block.default_info.difficulty
+    });
+    let mut gas = U256::zero();
+    for block in blocks {
+        gas += tools::recalculate_gas_reward(&pow_difficulty);
+        pow_difficulty = block.default_info.difficulty;
+    }
+    gas
+}
+
+/// Key of the token balance in the token balances tree
+fn token_balance_key(owner: &Address, token_hash: &Hash) -> [u8; 65] {
+    let mut key = [0u8; 65];
+    key[..33].copy_from_slice(owner);
+    key[33..].copy_from_slice(token_hash);
+    key
+}
+
+/// Tree of the undo journal entry, see `BlockChainTree::commit_changes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JournalKind {
+    Summary = 0,
+    Gas = 1,
+    Nonce = 2,
+    AnchorHeight = 3,
+    TokenBalance = 4,
+    Token = 5,
+    OldSummary = 6,
+    OldGas = 7,
+}
+
+impl TryFrom<u8> for JournalKind {
+    type Error = Report<BlockChainTreeError>;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        Ok(match kind {
+            0 => JournalKind::Summary,
+            1 => JournalKind::Gas,
+            2 => JournalKind::Nonce,
+            3 => JournalKind::AnchorHeight,
+            4 => JournalKind::TokenBalance,
+            5 => JournalKind::Token,
+            6 => JournalKind::OldSummary,
+            7 => JournalKind::OldGas,
+            _ => {
+                return Err(Report::new(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::RevertBlock,
+                ))
+                .attach_printable(format!("unknown undo journal kind {}", kind)))
+            }
+        })
+    }
+}
+
+impl JournalKind {
+    /// Size of the key of the entry: owner + token hash for token balances,
+    /// token hash for tokens and owner for the others
+    fn key_size(self) -> usize {
+        match self {
+            JournalKind::TokenBalance => 65,
+            JournalKind::Token => 32,
+            _ => 33,
+        }
+    }
+
+    /// Balance stored in the tree, nonces and anchored heights are not balances
+    fn balance(self) -> Option<Balance> {
+        match self {
+            JournalKind::Summary => Some(Balance::Amount),
+            JournalKind::Gas => Some(Balance::Gas),
+            _ => None,
+        }
+    }
+
+    fn tree<'a>(self, view: &StateView<'a>) -> &'a TransactionalTree {
+        match self {
+            JournalKind::Summary => view.summary,
+            JournalKind::Gas => view.gas,
+            JournalKind::Nonce => view.nonces,
+            JournalKind::AnchorHeight => view.anchor_heights,
+            JournalKind::TokenBalance => view.token_balances,
+            JournalKind::Token => view.tokens,
+            JournalKind::OldSummary => view.old_summary,
+            JournalKind::OldGas => view.old_gas,
+        }
+    }
+}
+
+/// Takes the next `size` bytes of the undo journal, fails if it is truncated
+fn journal_bytes<'a>(
+    journal: &'a [u8],
+    index: &mut usize,
+    size: usize,
+) -> ConflictableTransactionResult<&'a [u8], Report<BlockChainTreeError>> {
+    let bytes = journal.get(*index..*index + size).ok_or_else(|| {
+        abort(
+            Report::new(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::RevertBlock,
+            ))
+            .attach_printable("undo journal is truncated"),
+        )
+    })?;
+    *index += size;
+    Ok(bytes)
+}
+
+/// Appends the journal entry of the value replaced in the tree of the kind,
+/// see `BlockChainTree::commit_changes`
+fn push_journal_entry(
+    journal: &mut Vec<u8>,
+    kind: JournalKind,
+    key: &[u8],
+    previous: Option<IVec>,
+) {
+    journal.push(kind as u8);
+    journal.extend(key.iter());
+    match previous {
+        Some(previous) => {
+            journal.push(1);
+            journal.extend(previous.iter());
+        }
+        None => journal.push(0),
+    }
+}
 
 pub struct BlockChainTree {
+    root: PathBuf,
     main_chain: chain::MainChain,
     derivative_chains: HashMap<[u8; 33], chain::DerivativeChain>,
-    summary_db: Db,
-    old_summary_db: Db,
-    gas_db: Db,
-    old_gas_db: Db,
+    summary_db: Tree,
+    old_summary_db: Tree,
+    gas_db: Tree,
+    old_gas_db: Tree,
+    nonce_db: Tree,
+    undo_db: Tree,
+    anchors_db: Tree,
+    anchor_heights_db: Tree,
+    tokens_db: Tree,
+    token_balances_db: Tree,
+    tx_pool: SharedTxPool,
+    events: EventSender,
 }
 
 impl BlockChainTree {
+    /// Opens the tree in the default data directory
     pub fn new() -> Result<Self, Report<BlockChainTreeError>> {
-        let path_summary = Path::new(AMMOUNT_SUMMARY);
-        let path_summary_old = Path::new(OLD_AMMOUNT_SUMMARY);
-        let path_gas = Path::new(GAS_SUMMARY);
-        let path_gas_old = Path::new(OLD_GAS_SUMMARY);
+        Self::open(BLOCKCHAIN_DIRECTORY)
+    }
 
-        // open summary DB
-        let summary_db = sled::open(path_summary)
-            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::Init))
-            .attach_printable("failed to open summary db")?;
+    /// Opens the tree with all the databases and configs rooted at `data_directory`
+    ///
+    /// Every tree opened on a distinct directory is fully independent
+    pub fn open(data_directory: impl AsRef<Path>) -> Result<Self, Report<BlockChainTreeError>> {
+        Self::open_with_pool_config(data_directory, TxPoolConfig::default())
+    }
 
-        // open old summary DB
-        let old_summary_db = sled::open(path_summary_old)
-            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::Init))
-            .attach_printable("failed to open old summary db")?;
+    /// Opens the tree with the limits of the transactions pool
+    pub fn open_with_pool_config(
+        data_directory: impl AsRef<Path>,
+        pool_config: TxPoolConfig,
+    ) -> Result<Self, Report<BlockChainTreeError>> {
+        let root = data_directory.as_ref().to_path_buf();
 
-        // open gas DB
-        let gas_db = sled::open(path_gas)
-            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::Init))
-            .attach_printable("failed to open gas db")?;
+        // summaries share the database with the main chain to be updated together with it
+        let main_chain = chain::MainChain::open(&root)?;
 
-        let old_gas_db = sled::open(path_gas_old)
-            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::Init))
-            .attach_printable("failed to open old gas db")?;
-        let main_chain = chain::MainChain::new()?;
+        let summary_db = main_chain.open_tree(AMMOUNT_SUMMARY)?;
+        let old_summary_db = main_chain.open_tree(OLD_AMMOUNT_SUMMARY)?;
+        let gas_db = main_chain.open_tree(GAS_SUMMARY)?;
+        let old_gas_db = main_chain.open_tree(OLD_GAS_SUMMARY)?;
+        let nonce_db = main_chain.open_tree(NONCE_SUMMARY)?;
+        // undo journal of the applied blocks
+        let undo_db = main_chain.open_tree(UNDO_TREE)?;
+        let anchors_db = main_chain.open_tree(ANCHORS_TREE)?;
+        let anchor_heights_db = main_chain.open_tree(ANCHOR_HEIGHTS_TREE)?;
+        let tokens_db = main_chain.open_tree(TOKENS_TREE)?;
+        let token_balances_db = main_chain.open_tree(TOKEN_BALANCES_TREE)?;
+
+        let derivative_chains = Self::load_derivative_chains(&root)?;
+
+        let path_pool = root.join(TRANSACTIONS_POOL);
+        let tx_pool = if path_pool.exists() {
+            let pool_dump = tools::decompress_from_file(path_pool.to_string_lossy().into_owned())
+                .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::Init))
+                .attach_printable("failed to read transactions pool")?;
+            TxPool::parse(&pool_dump, pool_config)
+                .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::Init))
+                .attach_printable("failed to parse transactions pool")?
+        } else {
+            TxPool::with_config(pool_config)
+        };
 
         if main_chain.get_height() == U256::one() {
             summary_db
@@ -70,101 +506,290 @@ impl BlockChainTree {
                 .unwrap();
         }
         Ok(Self {
+            root,
             main_chain,
-            derivative_chains: HashMap::new(),
+            derivative_chains,
             summary_db,
             old_summary_db,
             gas_db,
             old_gas_db,
+            nonce_db,
+            undo_db,
+            anchors_db,
+            anchor_heights_db,
+            tokens_db,
+            token_balances_db,
+            tx_pool: Arc::new(RwLock::new(tx_pool)),
+            events: broadcast::channel(EVENTS_CAPACITY).0,
         })
     }
 
+    /// Opens all the derivative chains stored under the data directory
+    ///
+    /// Directories not named after an address are skipped
+    fn load_derivative_chains(
+        root: &Path,
+    ) -> Result<HashMap<Address, chain::DerivativeChain>, Report<BlockChainTreeError>> {
+        let mut chains = HashMap::new();
+        let directory = root.join(DERIVATIVE_CHAINS_DIRECTORY);
+        if !directory.exists() {
+            return Ok(chains);
+        }
+        let entries = std::fs::read_dir(&directory)
+            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::Init))
+            .attach_printable("failed to list derivative chains")?;
+        for entry in entries {
+            let entry = entry
+                .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::Init))
+                .attach_printable("failed to list derivative chains")?;
+            let name = entry.file_name();
+            let Some(owner) = name
+                .to_str()
+                .and_then(|name| hex::decode(name).ok())
+                .and_then(|owner| Address::try_from(owner).ok())
+            else {
+                continue;
+            };
+            if let Some(chain) = chain::DerivativeChain::open_existing(root, &hex::encode(owner))? {
+                chains.insert(owner, chain);
+            }
+        }
+        Ok(chains)
+    }
+
+    /// Owners of all the derivative chains, in ascending order
+    pub fn list_derivative_chains(&self) -> Vec<Address> {
+        let mut owners: Vec<_> = self.derivative_chains.keys().copied().collect();
+        owners.sort();
+        owners
+    }
+
+    /// Gets the derivative chain of the owner, creating it if the owner has none
+    ///
+    /// A new chain is anchored to the current top of the main chain
     pub fn get_derivative_chain(
         &mut self,
         owner: &[u8; 33],
     ) -> Result<chain::DerivativeChain, Report<BlockChainTreeError>> {
-        if let Some(chain) = self.derivative_chains.get(owner) {
-            return Ok(chain.clone());
+        match self.find_derivative_chain(owner) {
+            Some(chain) => Ok(chain),
+            None => self.create_derivative_chain(owner),
+        }
+    }
+
+    /// Gets the derivative chain of the owner only if it was already created
+    pub fn find_derivative_chain(&self, owner: &[u8; 33]) -> Option<chain::DerivativeChain> {
+        self.derivative_chains.get(owner).cloned()
+    }
+
+    /// Gets the derivative chain of the owner, fails if the owner has none
+    fn existing_derivative_chain(
+        &self,
+        owner: &[u8; 33],
+    ) -> Result<chain::DerivativeChain, Report<BlockChainTreeError>> {
+        self.find_derivative_chain(owner)
+            .ok_or(Report::new(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::GetDerivChain,
+            )))
+            .attach_printable(format!(
+                "{} has no derivative chain, it has to be created first",
+                hex::encode(owner)
+            ))
+    }
+
+    /// Creates the derivative chain of the owner on top of the main chain
+    ///
+    /// Fails if the owner already has one
+    pub fn create_derivative_chain(
+        &mut self,
+        owner: &[u8; 33],
+    ) -> Result<chain::DerivativeChain, Report<BlockChainTreeError>> {
+        if self.derivative_chains.contains_key(owner) {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::CreateDerivChain,
+            ))
+            .attach_printable(format!(
+                "derivative chain of {} already exists",
+                hex::encode(owner)
+            ));
         }
         let last_block = self.main_chain.get_last_block()?.unwrap(); // practically cannot fail
-        let derivative_chain =
-            chain::DerivativeChain::new(&hex::encode(owner), &last_block.hash().unwrap())?;
+        let derivative_chain = chain::DerivativeChain::create(
+            &self.root,
+            &hex::encode(owner),
+            &last_block.hash().unwrap(),
+        )
+        .change_context(BlockChainTreeError::BlockChainTree(
+            BCTreeErrorKind::CreateDerivChain,
+        ))?;
         self.derivative_chains
             .insert(*owner, derivative_chain.clone());
+        self.notify(Event::DerivativeChainCreated {
+            owner: *owner,
+            genesis_hash: *derivative_chain.genesis_hash,
+        });
         Ok(derivative_chain)
     }
 
+    /// Highest height of the derivative chain anchored into the main chain, 0 if none is
+    pub fn last_anchored_height(
+        &self,
+        owner: &Address,
+    ) -> Result<U256, Report<BlockChainTreeError>> {
+        match self.anchor_heights_db.get(owner).change_context(
+            BlockChainTreeError::BlockChainTree(BCTreeErrorKind::GetAnchors),
+        )? {
+            Some(dump) => Ok(tools::load_u256(&dump)
+                .change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetAnchors,
+                ))?
+                .0),
+            None => Ok(U256::zero()),
+        }
+    }
+
+    /// Heights and hashes of the derivative chain anchored into the main chain, by height
+    pub fn get_anchors(
+        &self,
+        owner: &Address,
+    ) -> Result<Vec<(U256, Hash)>, Report<BlockChainTreeError>> {
+        self.anchors_db
+            .scan_prefix(owner)
+            .map(|entry| {
+                let (key, value) = entry.change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetAnchors,
+                ))?;
+                let block = parse_anchor(&value)?;
+                let hash = block
+                    .hash()
+                    .change_context(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::GetAnchors,
+                    ))?;
+                Ok((U256::from_big_endian(&key[33..]), hash))
+            })
+            .collect()
+    }
+
+    /// Checks the local derivative chain against its anchors in the main chain
+    ///
+    /// Returns the highest height checkpointed by the main chain, 0 if nothing is anchored yet
+    pub fn verify_derivative_chain(
+        &self,
+        owner: &Address,
+    ) -> Result<U256, Report<BlockChainTreeError>> {
+        let chain = self
+            .find_derivative_chain(owner)
+            .ok_or(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::GetDerivChain,
+            ))
+            .attach_printable_lazy(|| format!("no derivative chain of {}", hex::encode(owner)))?;
+        let mut checkpointed = U256::zero();
+        for (height, anchored_hash) in self.get_anchors(owner)? {
+            // the block of height `h` is stored at `h - 1`
+            let block = chain
+                .find_by_height(&(height - 1))?
+                .ok_or(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::VerifyDerivChain,
+                ))
+                .attach_printable_lazy(|| format!("anchored block {} is missing", height))?;
+            let hash = block
+                .hash()
+                .change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::VerifyDerivChain,
+                ))?;
+            if hash != anchored_hash {
+                return Err(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::VerifyDerivChain,
+                ))
+                .attach_printable(format!("block {} differs from the anchored one", height));
+            }
+            checkpointed = height;
+        }
+        Ok(checkpointed)
+    }
+
+    /// Blocks of the local derivative chains added since they were last anchored, by owner
+    ///
+    /// At most `MAX_ANCHORED_BLOCKS` blocks of a chain are anchored at once
+    fn pending_anchors(&self) -> Result<Vec<block::DerivativeAnchor>, Report<BlockChainTreeError>> {
+        let mut anchors = Vec::new();
+        for owner in self.list_derivative_chains() {
+            let chain = &self.derivative_chains[&owner];
+            let anchored = self.last_anchored_height(&owner)?;
+            let height = chain.get_height().min(anchored + MAX_ANCHORED_BLOCKS);
+            if height <= anchored {
+                continue;
+            }
+            let missing = || {
+                Report::new(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetAnchors,
+                ))
+                .attach_printable(format!("derivative chain {} has gaps", hex::encode(owner)))
+            };
+            let mut blocks = Vec::new();
+            // the block of height `h` is stored at `h - 1`
+            let mut next = anchored;
+            while next < height {
+                blocks.push(
+                    chain
+                        .find_by_height(&next)?
+                        .ok_or_else(missing)?
+                        .as_ref()
+                        .clone(),
+                );
+                next += U256::one();
+            }
+            anchors.push(block::DerivativeAnchor { owner, blocks });
+        }
+        Ok(anchors)
+    }
+
     pub fn get_main_chain(&self) -> chain::MainChain {
         self.main_chain.clone()
     }
 
+    /// Mints the coins to the owner bypassing the blocks, for the tests only
+    #[cfg(feature = "testing")]
     pub fn add_amount(
         &self,
         owner: &[u8],
         amount: U256,
     ) -> Result<(), Report<BlockChainTreeError>> {
-        self.summary_db
-            .transaction(
-                |db| -> Result<(), sled::transaction::ConflictableTransactionError<()>> {
-                    let prev_amount = match db.get(owner)? {
-                        Some(v) => tools::load_u256(&v).unwrap().0,
-                        None => U256::zero(),
-                    };
-                    let new_amount = prev_amount + amount;
-                    let mut buf: Vec<u8> = Vec::with_capacity(tools::u256_size(&new_amount));
-                    tools::dump_u256(&new_amount, &mut buf).unwrap();
-                    db.insert(owner, buf)?;
-                    Ok(())
-                },
-            )
-            .unwrap();
-
+        move_balance(&self.summary_db, None, Some(owner), amount)?;
+        self.notify_balance(Balance::Amount, owner);
         Ok(())
     }
 
+    /// Overwrites the coins of the owner bypassing the blocks, for the tests only
+    #[cfg(feature = "testing")]
     pub fn set_amount(
         &self,
         owner: &[u8],
         amount: U256,
     ) -> Result<(), Report<BlockChainTreeError>> {
+        let mut buf: Vec<u8> = Vec::with_capacity(tools::u256_size(&amount));
+        tools::dump_u256(&amount, &mut buf).change_context(BlockChainTreeError::BlockChainTree(
+            BCTreeErrorKind::AddFunds,
+        ))?;
         self.summary_db
-            .transaction(
-                |db| -> Result<(), sled::transaction::ConflictableTransactionError<()>> {
-                    let mut buf: Vec<u8> = Vec::with_capacity(tools::u256_size(&amount));
-                    tools::dump_u256(&amount, &mut buf).unwrap();
-                    db.insert(owner, buf)?;
-                    Ok(())
-                },
-            )
-            .unwrap();
+            .insert(owner, buf)
+            .change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::AddFunds,
+            ))?;
 
+        self.notify_balance(Balance::Amount, owner);
         Ok(())
     }
 
+    /// Burns the coins of the owner bypassing the blocks, for the tests only
+    #[cfg(feature = "testing")]
     pub fn sub_amount(
         &self,
         owner: &[u8],
         amount: U256,
     ) -> Result<(), Report<BlockChainTreeError>> {
-        self.summary_db
-            .transaction(
-                |db| -> Result<(), sled::transaction::ConflictableTransactionError<()>> {
-                    let prev_amount = match db.get(owner)? {
-                        Some(v) => tools::load_u256(&v).unwrap().0,
-                        None => U256::zero(),
-                    };
-                    if prev_amount < amount {
-                        return Err(sled::transaction::ConflictableTransactionError::Abort(()));
-                    }
-                    let new_amount = prev_amount - amount;
-                    let mut buf: Vec<u8> = Vec::with_capacity(tools::u256_size(&new_amount));
-                    tools::dump_u256(&new_amount, &mut buf).unwrap();
-                    db.insert(owner, buf)?;
-                    Ok(())
-                },
-            )
-            .unwrap();
-
+        move_balance(&self.summary_db, Some(owner), None, amount)?;
+        self.notify_balance(Balance::Amount, owner);
         Ok(())
     }
     pub fn get_amount(&self, owner: &[u8; 33]) -> Result<U256, Report<BlockChainTreeError>> {
@@ -181,84 +806,32 @@ impl BlockChainTree {
         }
     }
 
+    /// Moves the coins between the accounts bypassing the blocks, for the tests only
+    #[cfg(feature = "testing")]
     pub fn send_amount(
         &self,
         from: &[u8],
         to: &[u8],
         amount: U256,
     ) -> Result<(), Report<BlockChainTreeError>> {
-        self.summary_db
-            .transaction(
-                |db| -> Result<(), sled::transaction::ConflictableTransactionError<()>> {
-                    let mut from_amount = match db.get(from)? {
-                        Some(v) => tools::load_u256(&v).unwrap().0,
-                        None => U256::zero(),
-                    };
-                    let mut to_amount = match db.get(to)? {
-                        Some(v) => tools::load_u256(&v).unwrap().0,
-                        None => U256::zero(),
-                    };
-                    if from_amount < amount {
-                        return Err(sled::transaction::ConflictableTransactionError::Abort(()));
-                    }
-
-                    from_amount -= amount;
-                    to_amount += amount;
-
-                    let mut buf: Vec<u8> = Vec::with_capacity(tools::u256_size(&from_amount));
-                    tools::dump_u256(&from_amount, &mut buf).unwrap();
-                    db.insert(from, buf)?;
-
-                    let mut buf: Vec<u8> = Vec::with_capacity(tools::u256_size(&to_amount));
-                    tools::dump_u256(&to_amount, &mut buf).unwrap();
-                    db.insert(to, buf)?;
-                    Ok(())
-                },
-            )
-            .unwrap();
-
+        move_balance(&self.summary_db, Some(from), Some(to), amount)?;
+        self.notify_balance(Balance::Amount, from);
+        self.notify_balance(Balance::Amount, to);
         Ok(())
     }
 
+    /// Mints the gas to the owner bypassing the blocks, for the tests only
+    #[cfg(feature = "testing")]
     pub fn add_gas(&self, owner: &[u8], amount: U256) -> Result<(), Report<BlockChainTreeError>> {
-        self.gas_db
-            .transaction(
-                |db| -> Result<(), sled::transaction::ConflictableTransactionError<()>> {
-                    let prev_amount = match db.get(owner)? {
-                        Some(v) => tools::load_u256(&v).unwrap().0,
-                        None => U256::zero(),
-                    };
-                    let new_amount = prev_amount + amount;
-                    let mut buf: Vec<u8> = Vec::with_capacity(tools::u256_size(&new_amount));
-                    tools::dump_u256(&new_amount, &mut buf).unwrap();
-                    db.insert(owner, buf)?;
-                    Ok(())
-                },
-            )
-            .unwrap();
-
+        move_balance(&self.gas_db, None, Some(owner), amount)?;
+        self.notify_balance(Balance::Gas, owner);
         Ok(())
     }
+    /// Burns the gas of the owner bypassing the blocks, for the tests only
+    #[cfg(feature = "testing")]
     pub fn sub_gas(&self, owner: &[u8], amount: U256) -> Result<(), Report<BlockChainTreeError>> {
-        self.gas_db
-            .transaction(
-                |db| -> Result<(), sled::transaction::ConflictableTransactionError<()>> {
-                    let prev_amount = match db.get(owner)? {
-                        Some(v) => tools::load_u256(&v).unwrap().0,
-                        None => U256::zero(),
-                    };
-                    if prev_amount < amount {
-                        return Err(sled::transaction::ConflictableTransactionError::Abort(()));
-                    }
-                    let new_amount = prev_amount - amount;
-                    let mut buf: Vec<u8> = Vec::with_capacity(tools::u256_size(&new_amount));
-                    tools::dump_u256(&new_amount, &mut buf).unwrap();
-                    db.insert(owner, buf)?;
-                    Ok(())
-                },
-            )
-            .unwrap();
-
+        move_balance(&self.gas_db, Some(owner), None, amount)?;
+        self.notify_balance(Balance::Gas, owner);
         Ok(())
     }
     pub fn get_gas(&self, owner: &[u8; 33]) -> Result<U256, Report<BlockChainTreeError>> {
@@ -275,151 +848,1609 @@ impl BlockChainTree {
         }
     }
 
+    /// Gets the owner's balance of the token
+    pub fn get_token_balance(
+        &self,
+        owner: &[u8; 33],
+        token_hash: &Hash,
+    ) -> Result<U256, Report<BlockChainTreeError>> {
+        match self
+            .token_balances_db
+            .get(token_balance_key(owner, token_hash))
+            .change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::GetFunds,
+            ))
+            .attach_printable("failed to get token balance")?
+        {
+            Some(v) => Ok(tools::load_u256(&v)
+                .change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetFunds,
+                ))?
+                .0),
+            None => Ok(U256::zero()),
+        }
+    }
+
+    /// Gets the registered token, assigned or not
+    pub fn get_token(
+        &self,
+        token_hash: &Hash,
+    ) -> Result<Option<Token>, Report<BlockChainTreeError>> {
+        let Some(dump) =
+            self.tokens_db
+                .get(token_hash)
+                .change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetToken,
+                ))?
+        else {
+            return Ok(None);
+        };
+        Token::parse(&dump)
+            .change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::GetToken,
+            ))
+            .map(Some)
+    }
+
+    /// Lists all the registered tokens
+    pub fn list_tokens(&self) -> Result<Vec<Token>, Report<BlockChainTreeError>> {
+        self.tokens_db
+            .iter()
+            .values()
+            .map(|dump| {
+                let dump = dump.change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetToken,
+                ))?;
+                Token::parse(&dump).change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetToken,
+                ))
+            })
+            .collect()
+    }
+
+    /// Nonzero token balances of the owner by token hash
+    pub fn get_token_holdings(
+        &self,
+        owner: &Address,
+    ) -> Result<Vec<(Hash, U256)>, Report<BlockChainTreeError>> {
+        let mut holdings = Vec::new();
+        for entry in self.token_balances_db.scan_prefix(owner) {
+            let (key, value) = entry.change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::GetFunds,
+            ))?;
+            let amount = tools::load_u256(&value)
+                .change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetFunds,
+                ))?
+                .0;
+            if !amount.is_zero() {
+                holdings.push((key[33..].try_into().unwrap(), amount));
+            }
+        }
+        Ok(holdings)
+    }
+
+    /// Checks that the balances of the token sum up to its coin supply
+    ///
+    /// Returns the coin supply
+    pub fn audit_token(&self, token_hash: &Hash) -> Result<U256, Report<BlockChainTreeError>> {
+        let token = self
+            .get_token(token_hash)?
+            .ok_or(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::AuditToken,
+            ))
+            .attach_printable_lazy(|| format!("no token {}", hex::encode(token_hash)))?;
+        let mut total = U256::zero();
+        // balances are keyed by the owner first, so all of them are scanned
+        for entry in self.token_balances_db.iter() {
+            let (key, value) = entry.change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::AuditToken,
+            ))?;
+            if key[33..] != token_hash[..] {
+                continue;
+            }
+            let amount = tools::load_u256(&value)
+                .change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::AuditToken,
+                ))?
+                .0;
+            total = total
+                .checked_add(amount)
+                .ok_or(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::AuditToken,
+                ))
+                .attach_printable("balances overflow")?;
+        }
+        if total != token.parameters.coin_supply {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::AuditToken,
+            ))
+            .attach_printable(format!(
+                "coin supply is {}, balances sum up to {}",
+                token.parameters.coin_supply, total
+            ));
+        }
+        Ok(total)
+    }
+
+    /// Gets the nonce the next transaction of the owner has to use
+    pub fn get_nonce(&self, owner: &[u8; 33]) -> Result<u64, Report<BlockChainTreeError>> {
+        match self
+            .nonce_db
+            .get(owner)
+            .change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::GetNonce,
+            ))? {
+            Some(v) => Ok(tools::load_u256(&v)
+                .change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetNonce,
+                ))?
+                .0
+                .as_u64()),
+            None => Ok(0),
+        }
+    }
+
+    /// Gets the nonce the next transaction of the owner has to use with its pending transactions
+    pub async fn next_nonce(&self, owner: &[u8; 33]) -> Result<u64, Report<BlockChainTreeError>> {
+        self.pending_nonce(&*self.tx_pool.read().await, owner)
+    }
+
+    /// Pending transactions of the owner take the nonces right after the account's one
+    fn pending_nonce(
+        &self,
+        pool: &TxPool,
+        owner: &[u8; 33],
+    ) -> Result<u64, Report<BlockChainTreeError>> {
+        let nonce = self.get_nonce(owner)?;
+        Ok(pool
+            .next_nonce(owner)
+            .map_or(nonce, |pending| pending.max(nonce)))
+    }
+
+    /// Moves the gas between the accounts bypassing the blocks, for the tests only
+    #[cfg(feature = "testing")]
     pub fn send_gas(
         &self,
         from: &[u8],
         to: &[u8],
         amount: U256,
     ) -> Result<(), Report<BlockChainTreeError>> {
-        self.gas_db
-            .transaction(
-                |db| -> Result<(), sled::transaction::ConflictableTransactionError<()>> {
-                    let mut from_amount = match db.get(from)? {
-                        Some(v) => tools::load_u256(&v).unwrap().0,
-                        None => U256::zero(),
-                    };
-                    let mut to_amount = match db.get(to)? {
-                        Some(v) => tools::load_u256(&v).unwrap().0,
-                        None => U256::zero(),
-                    };
-                    if from_amount < amount {
-                        return Err(sled::transaction::ConflictableTransactionError::Abort(()));
-                    }
-
-                    from_amount -= amount;
-                    to_amount += amount;
-
-                    let mut buf: Vec<u8> = Vec::with_capacity(tools::u256_size(&from_amount));
-                    tools::dump_u256(&from_amount, &mut buf).unwrap();
-                    db.insert(from, buf)?;
-
-                    let mut buf: Vec<u8> = Vec::with_capacity(tools::u256_size(&to_amount));
-                    tools::dump_u256(&to_amount, &mut buf).unwrap();
-                    db.insert(to, buf)?;
-                    Ok(())
-                },
-            )
-            .unwrap();
-
+        move_balance(&self.gas_db, Some(from), Some(to), amount)?;
+        self.notify_balance(Balance::Gas, from);
+        self.notify_balance(Balance::Gas, to);
         Ok(())
     }
 
-    pub fn add_new_block(
+    /// Adds block received from outside to the main chain
+    ///
+    /// The block, its transactions and all the balance changes are committed atomically.
+    /// Transactions included into the chain are removed from the pool
+    pub async fn add_new_block(
         &self,
         block: BlockArc,
         transactions: &[Transaction],
     ) -> Result<(), Report<BlockChainTreeError>> {
-        self.main_chain.add_block(block)?;
+        let transactions: Vec<&dyn Transactionable> = transactions
+            .iter()
+            .map(|transaction| transaction as &dyn Transactionable)
+            .collect();
+        self.add_block_transactions(block, &transactions).await
+    }
 
-        self.main_chain.add_transactions(transactions)
+    /// Adds block received from outside to the main chain, its transactions can be token actions
+    pub async fn add_new_block_items(
+        &self,
+        block: BlockArc,
+        transactions: &[TransactionableItem],
+    ) -> Result<(), Report<BlockChainTreeError>> {
+        let transactions: Vec<&dyn Transactionable> = transactions
+            .iter()
+            .map(|transaction| transaction.as_ref() as &dyn Transactionable)
+            .collect();
+        self.add_block_transactions(block, &transactions).await
     }
 
-    fn summarize(&self) -> Result<[u8; 32], Report<BlockChainTreeError>> {
-        let mut hashes: Vec<[u8; 32]> = Vec::with_capacity(self.summary_db.len());
-        for res in self.summary_db.iter() {
-            let (address, amount) = res
-                .change_context(BlockChainTreeError::BlockChainTree(
-                    BCTreeErrorKind::GetFunds,
-                ))
-                .attach_printable("failed to get funds from summary_db")?;
-            let gas_amount = self
-                .gas_db
-                .get(&address)
-                .change_context(BlockChainTreeError::BlockChainTree(
-                    BCTreeErrorKind::GetFunds,
+    async fn add_block_transactions(
+        &self,
+        block: BlockArc,
+        transactions: &[&dyn Transactionable],
+    ) -> Result<(), Report<BlockChainTreeError>> {
+        // the coinbase is derived from the block itself, the other transactions
+        // have to be provided and signed by their senders
+        let provided: HashMap<Hash, &dyn Transactionable> = transactions
+            .iter()
+            .map(|transaction| (transaction.hash(), *transaction))
+            .collect();
+        let mut block_transactions = Vec::with_capacity(provided.len());
+        for hash in block.get_transactions().iter().skip(1) {
+            let Some(transaction) = provided.get(hash) else {
+                return Err(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::ApplyBlock,
                 ))
-                .attach_printable("failed to get funds from summary_db")?
-                .map(|val| val.to_vec())
-                .unwrap_or(Vec::with_capacity(0));
-            let mut data_to_hash: Vec<u8> =
-                Vec::with_capacity(address.len() + amount.len() + gas_amount.len() + 2);
-            data_to_hash.extend(address.iter());
-            data_to_hash.push(b'|');
-            data_to_hash.extend(amount.iter());
-            data_to_hash.push(b'|');
-            data_to_hash.extend(gas_amount.iter());
+                .attach_printable(format!("transaction {:?} of the block is missing", hash));
+            };
+            if !matches!(transaction.verify(), Ok(true)) {
+                return Err(Report::new(BlockError::Validation(
+                    ValidationErrorKind::Signature,
+                )))
+                .change_context(BlockChainTreeError::Chain(ChainErrorKind::FailedToVerify))
+                .attach_printable(format!("transaction {:?} has an invalid signature", hash));
+            }
+            block_transactions.push(*transaction);
+        }
+        let update = self.add_main_block(block, &block_transactions)?;
 
-            hashes.push(tools::hash(&data_to_hash));
+        let mut pool = self.tx_pool.write().await;
+        self.return_to_pool(&mut pool, &update.reverted);
+        for block in update.applied.iter() {
+            for hash in block.get_transactions() {
+                pool.remove(hash);
+            }
         }
 
-        let merkle_tree = merkletree::MerkleTree::build_tree(&hashes);
+        Ok(())
+    }
 
-        Ok(*merkle_tree.get_root())
+    /// Puts the transactions of the blocks undone by a reorganization back into the pool,
+    /// the coinbase transactions are dropped
+    ///
+    /// The blocks are already replaced, so the transactions that can't be loaded
+    /// or don't fit into the pool are skipped
+    fn return_to_pool(&self, pool: &mut TxPool, reverted: &[BlockArc]) {
+        let now = tools::current_timestamp();
+        for block in reverted {
+            for hash in block.get_transactions().iter().skip(1) {
+                let Ok(Some(dump)) = self.main_chain.find_raw_side_transaction(hash) else {
+                    continue;
+                };
+                if let Ok(transaction) = transaction::parse_transactionable(&dump) {
+                    let _ = pool.push(transaction, now);
+                }
+            }
+        }
     }
 
-    pub async fn emmit_new_derivative_block(
-        &mut self,
-        pow: &[u8; 32],
-        founder: &[u8; 33],
-        timestamp: u64,
-    ) -> Result<block::BlockArc, Report<BlockChainTreeError>> {
-        let derivative_chain = self.get_derivative_chain(founder)?;
-        let (prev_hash, mut difficulty, prev_timestamp, height) =
-            if let Some(block) = derivative_chain.get_last_block()? {
-                (
-                    block.hash().unwrap(),
-                    block.get_info().difficulty,
-                    block.get_info().timestamp,
-                    block.get_info().height,
-                )
-            } else {
-                let block = self
-                    .main_chain
-                    .find_by_hash(&derivative_chain.genesis_hash)?
-                    .ok_or(BlockChainTreeError::Chain(ChainErrorKind::FindByHashE))?;
-                (
-                    block.hash().unwrap(),
-                    static_values::BEGINNING_DIFFICULTY,
-                    block.get_info().timestamp,
-                    U256::zero(),
-                )
-            };
+    /// Subscribes to the changes of the chain state
+    ///
+    /// Only the events sent after the subscription are received
+    pub fn subscribe(&self) -> EventReceiver {
+        self.events.subscribe()
+    }
 
-        if !tools::check_pow(&prev_hash, &difficulty, pow) {
-            return Err(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::WrongPow).into());
+    fn notify(&self, event: Event) {
+        // fails only if nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    /// Sends the current value of the balance, if anybody is subscribed
+    ///
+    /// The change is already committed, so the event is skipped if the balance can't be read
+    fn notify_balance(&self, balance: Balance, owner: &[u8]) {
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+        let Ok(owner) = Address::try_from(owner) else {
+            return;
         };
-        tools::recalculate_difficulty(prev_timestamp, timestamp, &mut difficulty);
-        let default_info = block::BasicInfo {
+        let value = match balance {
+            Balance::Amount => self.get_amount(&owner),
+            Balance::Gas => self.get_gas(&owner),
+        };
+        if let Ok(value) = value {
+            self.notify(Event::BalanceChanged {
+                owner,
+                balance,
+                value,
+            });
+        }
+    }
+
+    /// Adds block to the main chain and applies the resulting chain update
+    /// to the summary databases in the same database transaction
+    ///
+    /// Subscribers are notified about the update once it is committed
+    fn add_main_block(
+        &self,
+        block: BlockArc,
+        transactions: &[&dyn Transactionable],
+    ) -> Result<ChainUpdate, Report<BlockChainTreeError>> {
+        self.check_anchors(&block)?;
+        let summary_keys = self.collect_summary_keys(&block)?;
+        let touched = RefCell::new(HashSet::new());
+        let update = self.main_chain.add_block_with(
+            block,
+            transactions,
+            &[
+                &self.summary_db,
+                &self.gas_db,
+                &self.old_summary_db,
+                &self.old_gas_db,
+                &self.nonce_db,
+                &self.undo_db,
+                &self.anchors_db,
+                &self.anchor_heights_db,
+                &self.tokens_db,
+                &self.token_balances_db,
+            ],
+            |update, transactions, state| {
+                let view = StateView {
+                    transactions,
+                    summary: &state[0],
+                    gas: &state[1],
+                    old_summary: &state[2],
+                    old_gas: &state[3],
+                    nonces: &state[4],
+                    undo: &state[5],
+                    anchors: &state[6],
+                    anchor_heights: &state[7],
+                    tokens: &state[8],
+                    token_balances: &state[9],
+                    touched: &touched,
+                    summary_keys: summary_keys.as_ref(),
+                };
+                // the database transaction can be retried
+                touched.borrow_mut().clear();
+                self.apply_update(&view, update)
+            },
+        )?;
+
+        for block in update.reverted.iter() {
+            self.notify(Event::BlockReverted(block.clone()));
+        }
+        for block in update.applied.iter() {
+            self.notify(Event::BlockAdded(block.clone()));
+            if matches!(block.get_type(), Headers::SummarizeBlock) {
+                self.notify(Event::EpochRotated(block.clone()));
+            }
+        }
+        for (balance, owner) in touched.into_inner() {
+            self.notify_balance(balance, &owner);
+        }
+
+        Ok(update)
+    }
+
+    fn apply_update(
+        &self,
+        view: &StateView,
+        update: &ChainUpdate,
+    ) -> ConflictableTransactionResult<(), Report<BlockChainTreeError>> {
+        for block in update.reverted.iter() {
+            self.revert_block_effects(view, block)?;
+        }
+
+        for block in update.applied.iter() {
+            if matches!(block.get_type(), Headers::SummarizeBlock) {
+                self.rotate_summaries(view, block)?;
+            } else {
+                self.apply_block_effects(view, block)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves funds and gas according to the transactions of the block
+    ///
+    /// The first transaction of the block has to be its coinbase, which pays the reward
+    /// from the root address to the founder. The other transactions can't be sent
+    /// from the root address and have to offer at least the fee of the block,
+    /// their fees go to it and are paid to the founder with the reward.
+    ///
+    /// Previous values of all the touched balances are saved into the undo journal
+    /// under the block's hash
+    fn apply_block_effects(
+        &self,
+        view: &StateView,
+        block: &BlockArc,
+    ) -> ConflictableTransactionResult<(), Report<BlockChainTreeError>> {
+        let hash = block.hash().map_err(|report| {
+            abort(report.change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::ApplyBlock,
+            )))
+        })?;
+        let minimal_fee = block.get_fee();
+
+        let Some((coinbase_hash, transactions)) = block.get_transactions().split_first() else {
+            return Err(abort(
+                Report::new(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::ApplyBlock,
+                ))
+                .attach_printable("block has no coinbase transaction"),
+            ));
+        };
+
+        let mut changes = BalanceChanges::default();
+        let mut transaction_fees = U256::zero();
+        for transaction_hash in transactions {
+            let transaction = match view.transactions.get(transaction_hash)? {
+                Some(dump) => transaction::parse_transactionable(&dump).map_err(|report| {
+                    abort(report.change_context(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    )))
+                })?,
+                None => {
+                    return Err(abort(
+                        Report::new(BlockChainTreeError::BlockChainTree(
+                            BCTreeErrorKind::ApplyBlock,
+                        ))
+                        .attach_printable(format!("transaction {:?} is missing", transaction_hash)),
+                    ))
+                }
+            };
+            if *transaction.get_sender() == ROOT_PUBLIC_ADDRESS {
+                return Err(abort(
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    ))
+                    .attach_printable(format!(
+                        "transaction {:?} is sent from the root address",
+                        transaction_hash
+                    )),
+                ));
+            }
+            let sender_nonce =
+                changed_balance(&mut changes.nonces, view.nonces, transaction.get_sender())?;
+            if *sender_nonce != U256::from(transaction.get_nonce()) {
+                return Err(abort(
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    ))
+                    .attach_printable(format!(
+                        "wrong nonce of the transaction {:?}",
+                        transaction_hash
+                    )),
+                ));
+            }
+            *sender_nonce += U256::one();
+
+            let fee = transaction.get_fee();
+            if fee < minimal_fee {
+                return Err(abort(
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    ))
+                    .attach_printable(format!(
+                        "fee of the transaction {:?} is lower than the fee of the block",
+                        transaction_hash
+                    )),
+                ));
+            }
+            let amount = transaction.get_amount().unwrap_or_default();
+            let gas_required = *BYTE_GAS_PRICE * transaction.get_dump_size();
+            let Some(total) = amount.checked_add(fee) else {
+                return Err(abort(
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    ))
+                    .attach_printable(format!(
+                        "amount of the transaction {:?} overflows with the fee",
+                        transaction_hash
+                    )),
+                ));
+            };
+
+            let sender_amount =
+                changed_balance(&mut changes.amounts, view.summary, transaction.get_sender())?;
+            if *sender_amount < total {
+                return Err(abort(
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    ))
+                    .attach_printable(format!(
+                        "not enough coins for the transaction {:?}",
+                        transaction_hash
+                    )),
+                ));
+            }
+            *sender_amount -= total;
+
+            let sender_gas = changed_balance(&mut changes.gas, view.gas, transaction.get_sender())?;
+            if *sender_gas < gas_required {
+                return Err(abort(
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    ))
+                    .attach_printable(format!(
+                        "not enough gas for the transaction {:?}",
+                        transaction_hash
+                    )),
+                ));
+            }
+            *sender_gas -= gas_required;
+
+            if transaction.get_amount().is_some() {
+                *changed_balance(
+                    &mut changes.amounts,
+                    view.summary,
+                    transaction.get_receiver(),
+                )? += amount;
+            }
+            *changed_balance(&mut changes.amounts, view.summary, &ROOT_PUBLIC_ADDRESS)? += fee;
+            // the fees are covered by the balances, so their sum can't overflow
+            transaction_fees += fee;
+
+            if let Some(action) = transaction.as_token_action() {
+                Self::apply_token_action(view, &mut changes, action)?;
+            }
+        }
+
+        let Some(reward) = tools::block_reward(&transaction_fees) else {
+            return Err(abort(
+                Report::new(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::ApplyBlock,
+                ))
+                .attach_printable("reward of the block overflows"),
+            ));
+        };
+        let coinbase = Transaction::new_coinbase(
+            *block.get_founder(),
+            &block.get_info().height,
+            block.get_info().timestamp,
+            reward,
+        );
+        if coinbase.hash() != *coinbase_hash {
+            return Err(abort(
+                Report::new(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::ApplyBlock,
+                ))
+                .attach_printable("coinbase transaction doesn't match the block"),
+            ));
+        }
+        let coinbase_dump = coinbase.dump().map_err(|report| {
+            abort(report.change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::ApplyBlock,
+            )))
+        })?;
+        view.transactions.insert(coinbase_hash, coinbase_dump)?;
+
+        let root_amount =
+            changed_balance(&mut changes.amounts, view.summary, &ROOT_PUBLIC_ADDRESS)?;
+        if *root_amount < reward {
+            return Err(abort(
+                Report::new(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::ApplyBlock,
+                ))
+                .attach_printable("not enough coins on the root address to pay the reward"),
+            ));
+        }
+        *root_amount -= reward;
+        *changed_balance(&mut changes.amounts, view.summary, block.get_founder())? += reward;
+
+        for anchor in block.get_anchors() {
+            if changes.anchor_heights.contains_key(&anchor.owner) {
+                return Err(abort(
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    ))
+                    .attach_printable(format!(
+                        "derivative chain {} is anchored twice",
+                        hex::encode(anchor.owner)
+                    )),
+                ));
+            }
+            let height = anchor.height();
+            let anchored = load_balance(view.anchor_heights, &anchor.owner)?;
+            if height <= anchored {
+                return Err(abort(
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    ))
+                    .attach_printable(format!(
+                        "derivative chain {} is already anchored at height {}",
+                        hex::encode(anchor.owner),
+                        anchored
+                    )),
+                ));
+            }
+            let previous = if anchored.is_zero() {
+                None
+            } else {
+                let value = view
+                    .anchors
+                    .get(anchor_key(&anchor.owner, &anchored).as_slice())?
+                    .ok_or_else(|| {
+                        abort(
+                            Report::new(BlockChainTreeError::BlockChainTree(
+                                BCTreeErrorKind::ApplyBlock,
+                            ))
+                            .attach_printable(format!(
+                                "anchored block {} of the derivative chain {} is missing",
+                                anchored,
+                                hex::encode(anchor.owner)
+                            )),
+                        )
+                    })?;
+                Some(parse_anchor(&value).map_err(abort)?)
+            };
+            // the anchored blocks were checked to follow each other by `check_anchors`
+            let first = &anchor.blocks[0];
+            let follows = match &previous {
+                Some(previous) => first.validate(Some(Arc::new(previous.clone()))),
+                None => first.validate(None),
+            };
+            follows.map_err(|report| {
+                abort(
+                    report
+                        .change_context(BlockChainTreeError::BlockChainTree(
+                            BCTreeErrorKind::ApplyBlock,
+                        ))
+                        .attach_printable(format!(
+                            "blocks of the derivative chain {} don't follow the anchored height {}",
+                            hex::encode(anchor.owner),
+                            anchored
+                        )),
+                )
+            })?;
+
+            // the owner is credited with the gas earned since the previous anchor
+            *changed_balance(&mut changes.gas, view.gas, &anchor.owner)? +=
+                anchored_blocks_gas(previous.as_ref(), &anchor.blocks);
+            changes.anchor_heights.insert(anchor.owner, height);
+            let tip = anchor.blocks[anchor.blocks.len() - 1]
+                .dump()
+                .map_err(|report| {
+                    abort(report.change_context(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    )))
+                })?;
+            view.anchors
+                .insert(anchor_key(&anchor.owner, &height).as_slice(), tip)?;
+        }
+
+        self.commit_changes(view, &hash, changes)
+    }
+
+    /// Checks the anchored blocks apart from the anchors already in the chain
+    ///
+    /// The blocks of an anchor have to follow each other, and the first block of
+    /// a derivative chain has to be built on top of a main chain block below the anchoring one.
+    /// The first anchored block is checked against the previous anchor once the block
+    /// is applied. It's done before the database transaction, the main chain blocks
+    /// aren't part of it
+    fn check_anchors(&self, block: &BlockArc) -> Result<(), Report<BlockChainTreeError>> {
+        for anchor in block.get_anchors() {
+            let describe = |reason: &str| {
+                format!(
+                    "anchor of the derivative chain {} is invalid: {reason}",
+                    hex::encode(anchor.owner)
+                )
+            };
+            let invalid = |reason: &str| {
+                Report::new(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::ApplyBlock,
+                ))
+                .attach_printable(describe(reason))
+            };
+            let Some(first) = anchor.blocks.first() else {
+                return Err(invalid("no blocks are anchored"));
+            };
+            if anchor.blocks.len() > MAX_ANCHORED_BLOCKS {
+                return Err(invalid("too many blocks are anchored"));
+            }
+            if anchor
+                .blocks
+                .iter()
+                .any(|block| block.default_info.founder != anchor.owner)
+            {
+                return Err(invalid("the blocks are founded by another address"));
+            }
+            for blocks in anchor.blocks.windows(2) {
+                blocks[1]
+                    .validate(Some(Arc::new(blocks[0].clone())))
+                    .change_context(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    ))
+                    .attach_printable_lazy(|| {
+                        describe(&format!(
+                            "block {} doesn't follow the previous one",
+                            blocks[1].default_info.height
+                        ))
+                    })?;
+            }
+            if first.default_info.height == U256::one() {
+                first
+                    .validate(None)
+                    .change_context(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::ApplyBlock,
+                    ))
+                    .attach_printable_lazy(|| describe("wrong first block"))?;
+                // derivative chains are created on top of the main chain
+                if !self.main_chain.is_ancestor(
+                    &first.default_info.previous_hash,
+                    &block.get_info().previous_hash,
+                )? {
+                    return Err(invalid(
+                        "the chain isn't created on top of the blocks below the anchoring one",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the tokens and the token balances according to the action
+    fn apply_token_action(
+        view: &StateView,
+        changes: &mut BalanceChanges,
+        action: &TokenAction,
+    ) -> ConflictableTransactionResult<(), Report<BlockChainTreeError>> {
+        let sender = action.get_sender();
+        if action.get_action() == Action::Create {
+            if *action.get_token_hash() != [0; 32] {
+                return Err(token_error(
+                    "created token can't reference a token hash".to_string(),
+                ));
+            }
+            let token_hash = action.hash();
+            if changed_token(&mut changes.tokens, view.tokens, &token_hash)?.is_some() {
+                return Err(token_error(format!(
+                    "token {} already exists",
+                    hex::encode(token_hash)
+                )));
+            }
+            changes
+                .tokens
+                .insert(token_hash, Token::new(token_hash, *sender));
+            return Ok(());
+        }
+
+        let token_hash = action.get_token_hash();
+        let Some(token) = changed_token(&mut changes.tokens, view.tokens, token_hash)? else {
+            return Err(token_error(format!(
+                "token {} doesn't exist",
+                hex::encode(token_hash)
+            )));
+        };
+        match action.get_action() {
+            Action::Assign => {
+                if token.current_owner != *sender {
+                    return Err(token_error(format!(
+                        "token {} is assigned not by its owner",
+                        hex::encode(token_hash)
+                    )));
+                }
+                if token.assigned {
+                    return Err(token_error(format!(
+                        "token {} is already assigned",
+                        hex::encode(token_hash)
+                    )));
+                }
+                token.parameters = action.get_parameters().cloned().unwrap_or_default();
+                token.assigned = true;
+                let coin_supply = token.parameters.coin_supply;
+                credit_tokens(
+                    changed_balance(
+                        &mut changes.token_balances,
+                        view.token_balances,
+                        &token_balance_key(sender, token_hash),
+                    )?,
+                    coin_supply,
+                    token_hash,
+                )?;
+            }
+            Action::Send | Action::Burn => {
+                if !token.assigned {
+                    return Err(token_error(format!(
+                        "token {} is not assigned",
+                        hex::encode(token_hash)
+                    )));
+                }
+                let amount = *action.get_token_amount();
+                let fee = match action.get_action() {
+                    Action::Send => token.transfer_fee_of(sender),
+                    _ => U256::zero(),
+                };
+                let Some(total) = amount.checked_add(fee) else {
+                    return Err(token_error(format!(
+                        "amount of the action {} overflows with the fee",
+                        hex::encode(action.hash())
+                    )));
+                };
+                let sender_balance = changed_balance(
+                    &mut changes.token_balances,
+                    view.token_balances,
+                    &token_balance_key(sender, token_hash),
+                )?;
+                if *sender_balance < total {
+                    return Err(token_error(format!(
+                        "not enough tokens {} for the action {}",
+                        hex::encode(token_hash),
+                        hex::encode(action.hash())
+                    )));
+                }
+                *sender_balance -= total;
+                if action.get_action() == Action::Send {
+                    credit_tokens(
+                        changed_balance(
+                            &mut changes.token_balances,
+                            view.token_balances,
+                            &token_balance_key(action.get_receiver(), token_hash),
+                        )?,
+                        amount,
+                        token_hash,
+                    )?;
+                    if !fee.is_zero() {
+                        credit_tokens(
+                            changed_balance(
+                                &mut changes.token_balances,
+                                view.token_balances,
+                                &token_balance_key(&token.current_owner, token_hash),
+                            )?,
+                            fee,
+                            token_hash,
+                        )?;
+                    }
+                } else {
+                    // balances never exceed the supply
+                    token.parameters.coin_supply -= amount;
+                }
+            }
+            Action::Create => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    /// Restores balances touched by the block from the undo journal
+    fn revert_block_effects(
+        &self,
+        view: &StateView,
+        block: &BlockArc,
+    ) -> ConflictableTransactionResult<(), Report<BlockChainTreeError>> {
+        let hash = block.hash().map_err(|report| {
+            abort(report.change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::RevertBlock,
+            )))
+        })?;
+        let journal = match view.undo.remove(&hash)? {
+            Some(journal) => journal,
+            None => {
+                return Err(abort(
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::RevertBlock,
+                    ))
+                    .attach_printable(format!("no undo journal for the block {:?}", hash)),
+                ))
+            }
+        };
+
+        for anchor in block.get_anchors() {
+            view.anchors
+                .remove(anchor_key(&anchor.owner, &anchor.height()).as_slice())?;
+        }
+
+        let mut index = 0;
+        while index < journal.len() {
+            let kind =
+                JournalKind::try_from(journal_bytes(&journal, &mut index, 1)?[0]).map_err(abort)?;
+            let tree = kind.tree(view);
+            let key = journal_bytes(&journal, &mut index, kind.key_size())?;
+            if let (Some(balance), Ok(owner)) = (kind.balance(), Address::try_from(key)) {
+                view.touched.borrow_mut().insert((balance, owner));
+            }
+            if journal_bytes(&journal, &mut index, 1)?[0] == 0 {
+                tree.remove(key)?;
+            } else if kind == JournalKind::Token {
+                let size = journal_bytes(&journal, &mut index, 4)?;
+                let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+                tree.insert(key, journal_bytes(&journal, &mut index, size)?)?;
+            } else {
+                let (_, size) = tools::load_u256(&journal[index..]).map_err(|report| {
+                    abort(report.change_context(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::RevertBlock,
+                    )))
+                })?;
+                tree.insert(key, journal_bytes(&journal, &mut index, size + 1)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes changed balances and the undo journal for the block
+    ///
+    /// Journal entry: `JournalKind` | key | 0 or 1 + previous value.
+    /// Keys are owners, owner + token hash for token balances and token hashes for tokens.
+    /// Previous tokens are prefixed with their u32 size
+    fn commit_changes(
+        &self,
+        view: &StateView,
+        hash: &Hash,
+        changes: BalanceChanges,
+    ) -> ConflictableTransactionResult<(), Report<BlockChainTreeError>> {
+        let mut journal: Vec<u8> = Vec::new();
+        let changed =
+            changes
+                .amounts
+                .into_iter()
+                .map(|(owner, amount)| (JournalKind::Summary, view.summary, owner.to_vec(), amount))
+                .chain(
+                    changes.gas.into_iter().map(|(owner, amount)| {
+                        (JournalKind::Gas, view.gas, owner.to_vec(), amount)
+                    }),
+                )
+                .chain(
+                    changes.nonces.into_iter().map(|(owner, nonce)| {
+                        (JournalKind::Nonce, view.nonces, owner.to_vec(), nonce)
+                    }),
+                )
+                .chain(changes.anchor_heights.into_iter().map(|(owner, height)| {
+                    (
+                        JournalKind::AnchorHeight,
+                        view.anchor_heights,
+                        owner.to_vec(),
+                        height,
+                    )
+                }))
+                .chain(changes.token_balances.into_iter().map(|(key, amount)| {
+                    (
+                        JournalKind::TokenBalance,
+                        view.token_balances,
+                        key.to_vec(),
+                        amount,
+                    )
+                }));
+
+        for (kind, tree, key, amount) in changed {
+            if let (Some(balance), Ok(owner)) = (kind.balance(), Address::try_from(key.as_slice()))
+            {
+                view.touched.borrow_mut().insert((balance, owner));
+            }
+            let previous = tree.insert(key.as_slice(), dump_balance(&amount)?)?;
+            push_journal_entry(&mut journal, kind, &key, previous);
+        }
+
+        for (token_hash, token) in changes.tokens {
+            let dump = token.dump().map_err(|report| {
+                abort(report.change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::ApplyBlock,
+                )))
+            })?;
+            journal.push(JournalKind::Token as u8);
+            journal.extend(token_hash);
+            match view.tokens.insert(token_hash.as_slice(), dump)? {
+                Some(previous) => {
+                    journal.push(1);
+                    journal.extend((previous.len() as u32).to_be_bytes());
+                    journal.extend(previous.iter());
+                }
+                None => journal.push(0),
+            }
+        }
+
+        view.undo.insert(hash, journal)?;
+
+        Ok(())
+    }
+
+    /// Collects the keys of the summaries if adding the block can apply a summarization block
+    ///
+    /// Trees can't be iterated inside of a database transaction, so the rotation
+    /// takes its keys from here
+    fn collect_summary_keys(
+        &self,
+        block: &BlockArc,
+    ) -> Result<Option<SummaryKeys>, Report<BlockChainTreeError>> {
+        let rotates = self
+            .main_chain
+            .side_branch(block.clone())?
+            .iter()
+            .any(|block| matches!(block.get_type(), Headers::SummarizeBlock));
+        if !rotates {
+            return Ok(None);
+        }
+
+        let mut summary_keys = [HashSet::new(), HashSet::new()];
+        let trees = [
+            [&self.summary_db, &self.old_summary_db],
+            [&self.gas_db, &self.old_gas_db],
+        ];
+        for (keys, trees) in summary_keys.iter_mut().zip(trees) {
+            for key in trees.into_iter().flat_map(|tree| tree.iter().keys()) {
+                let key = key.change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::MoveSummaryDB,
+                ))?;
+                keys.insert(key.to_vec());
+            }
+        }
+        Ok(Some(summary_keys))
+    }
+
+    /// Checks the merkle root of the summarization block against the summary,
+    /// copies current summaries into the old ones, refills the root address
+    /// and pays the founder of the block by the coinbase transaction
+    ///
+    /// Done on every summarization block. The replaced old summaries and balances
+    /// are saved into the undo journal, so the block can be reverted like the others
+    fn rotate_summaries(
+        &self,
+        view: &StateView,
+        block: &BlockArc,
+    ) -> ConflictableTransactionResult<(), Report<BlockChainTreeError>> {
+        let hash = block.hash().map_err(|report| {
+            abort(report.change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::MoveSummaryDB,
+            )))
+        })?;
+        let summary_keys = view.summary_keys.ok_or_else(|| {
+            abort(
+                Report::new(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::MoveSummaryDB,
+                ))
+                .attach_printable("Summary keys were not collected before the transaction"),
+            )
+        })?;
+        if Self::view_summary_root(view, summary_keys)? != block.get_merkle_root() {
+            return Err(abort(
+                Report::new(BlockError::Validation(ValidationErrorKind::MerkleRoot))
+                    .change_context(BlockChainTreeError::Chain(ChainErrorKind::FailedToVerify))
+                    .attach_printable("merkle root doesn't match the summary"),
+            ));
+        }
+        let mut journal: Vec<u8> = Vec::new();
+
+        let rotations = [
+            (
+                JournalKind::OldSummary,
+                Balance::Amount,
+                view.summary,
+                view.old_summary,
+            ),
+            (JournalKind::OldGas, Balance::Gas, view.gas, view.old_gas),
+        ];
+        for (index, (kind, balance, current_view, old_view)) in rotations.into_iter().enumerate() {
+            // the balances created earlier in the same transaction are not in the collected keys
+            let mut keys = summary_keys[index].clone();
+            keys.extend(
+                view.touched
+                    .borrow()
+                    .iter()
+                    .filter(|(touched, _)| *touched == balance)
+                    .map(|(_, owner)| owner.to_vec()),
+            );
+            for key in keys {
+                let value = current_view.get(&key)?;
+                let previous = match &value {
+                    Some(value) => old_view.insert(key.as_slice(), value)?,
+                    None => old_view.remove(key.as_slice())?,
+                };
+                if previous != value {
+                    push_journal_entry(&mut journal, kind, &key, previous);
+                }
+            }
+        }
+
+        // the root address gets the coins of the new cycle and pays the coinbase from them
+        let coinbase = block::SummarizeBlock::coinbase(block.get_info());
+        let coinbase_dump = coinbase.dump().map_err(|report| {
+            abort(report.change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::MoveSummaryDB,
+            )))
+        })?;
+        view.transactions.insert(&coinbase.hash(), coinbase_dump)?;
+        let reward = *coinbase.get_amount();
+
+        let previous = view.summary.insert(
+            &ROOT_PUBLIC_ADDRESS as &[u8],
+            dump_balance(&(*COINS_PER_CYCLE - reward))?,
+        )?;
+        push_journal_entry(
+            &mut journal,
+            JournalKind::Summary,
+            &ROOT_PUBLIC_ADDRESS,
+            previous,
+        );
+
+        let founder = block.get_founder();
+        let founder_amount = load_balance(view.summary, founder)?;
+        let previous = view.summary.insert(
+            founder.as_slice(),
+            dump_balance(&(founder_amount + reward))?,
+        )?;
+        // the journal keeps the first replaced value of the root
+        if *founder != ROOT_PUBLIC_ADDRESS {
+            push_journal_entry(&mut journal, JournalKind::Summary, founder, previous);
+        }
+
+        let mut touched = view.touched.borrow_mut();
+        touched.insert((Balance::Amount, ROOT_PUBLIC_ADDRESS));
+        touched.insert((Balance::Amount, *founder));
+
+        view.undo.insert(&hash, journal)?;
+
+        Ok(())
+    }
+
+    /// Merkle root of the current summary, committed by the summarization blocks
+    pub fn summarize(&self) -> Result<[u8; 32], Report<BlockChainTreeError>> {
+        let mut hashes: Vec<[u8; 32]> = Vec::with_capacity(self.summary_db.len());
+        for res in self.summary_db.iter() {
+            let (address, amount) = res
+                .change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetFunds,
+                ))
+                .attach_printable("failed to get funds from summary_db")?;
+            let gas_amount = self
+                .gas_db
+                .get(&address)
+                .change_context(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::GetFunds,
+                ))
+                .attach_printable("failed to get funds from summary_db")?;
+
+            hashes.push(summary_leaf(&address, &amount, gas_amount.as_deref()));
+        }
+
+        let merkle_tree = merkletree::MerkleTree::build_tree(&hashes);
+
+        Ok(*merkle_tree.get_root())
+    }
+
+    /// Merkle root of the summary as seen from inside of the transaction, same as `summarize`
+    ///
+    /// The summary can't be iterated there, so the addresses are the collected keys
+    /// and the ones changed earlier in the transaction
+    fn view_summary_root(
+        view: &StateView,
+        summary_keys: &SummaryKeys,
+    ) -> ConflictableTransactionResult<Hash, Report<BlockChainTreeError>> {
+        // the summary is iterated in the order of the keys
+        let mut keys: BTreeSet<Vec<u8>> = summary_keys[0].iter().cloned().collect();
+        keys.extend(
+            view.touched
+                .borrow()
+                .iter()
+                .filter(|(touched, _)| *touched == Balance::Amount)
+                .map(|(_, owner)| owner.to_vec()),
+        );
+
+        let mut hashes: Vec<[u8; 32]> = Vec::with_capacity(keys.len());
+        for address in keys {
+            let Some(amount) = view.summary.get(&address)? else {
+                continue;
+            };
+            let gas_amount = view.gas.get(&address)?;
+            hashes.push(summary_leaf(&address, &amount, gas_amount.as_deref()));
+        }
+
+        Ok(*merkletree::MerkleTree::build_tree(&hashes).get_root())
+    }
+
+    /// Hash and basic info of the last block of the founder's derivative chain
+    ///
+    /// The first block of the chain is mined on top of its genesis block from the main chain,
+    /// there is no basic info for it
+    fn derivative_tip(
+        &self,
+        founder: &[u8; 33],
+    ) -> Result<(Hash, Option<block::BasicInfo>), Report<BlockChainTreeError>> {
+        let derivative_chain = self.existing_derivative_chain(founder)?;
+        match derivative_chain.get_last_block()? {
+            Some(block) => Ok((
+                block
+                    .hash()
+                    .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::DumpDb))
+                    .attach_printable("failed to hash block")?,
+                Some(block.default_info),
+            )),
+            None => Ok((*derivative_chain.genesis_hash, None)),
+        }
+    }
+
+    /// Basic info of the next block of the founder's derivative chain without the pow,
+    /// with the difficulty the pow has to satisfy
+    fn next_derivative_info(
+        &self,
+        founder: &[u8; 33],
+        timestamp: u64,
+    ) -> Result<(block::BasicInfo, Hash), Report<BlockChainTreeError>> {
+        let (previous_hash, prev_info) = self.derivative_tip(founder)?;
+        let default_info = block::BasicInfo {
             timestamp,
-            pow: *pow,
-            previous_hash: prev_hash,
-            height: height + 1,
-            difficulty,
+            pow: [0; 32],
+            previous_hash,
+            height: prev_info.as_ref().map_or(U256::zero(), |info| info.height) + 1,
+            difficulty: block::DerivativeBlock::next_difficulty(prev_info.as_ref(), timestamp),
             founder: *founder,
         };
+        let pow_difficulty =
+            prev_info.map_or(static_values::BEGINNING_DIFFICULTY, |info| info.difficulty);
+        Ok((default_info, pow_difficulty))
+    }
+
+    /// Target the pow of the next block of the founder's derivative chain has to satisfy,
+    /// committing to the payment transaction and the timestamp of the block
+    ///
+    /// Fails if the founder has no derivative chain
+    pub fn derivative_target(
+        &self,
+        founder: &[u8; 33],
+        payment_transaction: &Hash,
+        timestamp: u64,
+    ) -> Result<MiningTarget, Report<BlockChainTreeError>> {
+        let (default_info, difficulty) = self.next_derivative_info(founder, timestamp)?;
+        Ok(MiningTarget {
+            pow_preimage: block::DerivativeBlock::pow_preimage(&default_info, payment_transaction),
+            difficulty,
+        })
+    }
+
+    /// Creates new block on top of the founder's derivative chain and adds it
+    ///
+    /// The pow has to be found for the target of `derivative_target` with the same timestamp
+    pub async fn emmit_new_derivative_block(
+        &self,
+        pow: &[u8; 32],
+        founder: &[u8; 33],
+        payment_transaction: &Hash,
+        timestamp: u64,
+    ) -> Result<block::BlockArc, Report<BlockChainTreeError>> {
+        let (mut default_info, pow_difficulty) = self.next_derivative_info(founder, timestamp)?;
+        let preimage = block::DerivativeBlock::pow_preimage(&default_info, payment_transaction);
+        if !tools::check_pow(&preimage, &pow_difficulty, pow) {
+            return Err(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::WrongPow).into());
+        };
+        default_info.pow = *pow;
 
-        let block = block::DerivativeBlock { default_info };
-        derivative_chain.add_block(&block)?;
+        let block = block::DerivativeBlock {
+            default_info,
+            payment_transaction: *payment_transaction,
+        };
+        self.add_derivative_block(&block)?;
         Ok(Arc::new(block))
     }
 
+    /// Adds block to the derivative chain of its founder
+    ///
+    /// The founder is credited with gas once the block is anchored into the main chain,
+    /// so the reward is reverted together with the anchoring block.
+    /// Fails if the founder has no derivative chain
+    pub fn add_derivative_block(
+        &self,
+        block: &block::DerivativeBlock,
+    ) -> Result<(), Report<BlockChainTreeError>> {
+        let derivative_chain = self.existing_derivative_chain(block.get_founder())?;
+        derivative_chain.add_block(block)?;
+        self.notify(Event::DerivativeBlockAdded(Arc::new(block.clone())));
+        Ok(())
+    }
+
+    /// Prepares the next main chain block for the founder
+    ///
+    /// Transactions are taken from the pool by the best fee per byte, keeping the nonces
+    /// of every sender in order, until `MAX_BLOCK_TRANSACTIONS_SIZE` is reached.
+    /// The fee of the template may differ from the one the transactions were accepted with,
+    /// so the transactions offering less than it and the ones their senders can't afford
+    /// anymore are left in the pool
+    pub async fn block_template(
+        &self,
+        founder: &Address,
+    ) -> Result<BlockTemplate, Report<BlockChainTreeError>> {
+        let last_block = self.main_chain.get_last_block()?.unwrap(); // practically cannot fail
+        let timestamp = tools::current_timestamp().max(last_block.get_info().timestamp);
+        let mut template = self.empty_template(&last_block, founder, timestamp)?;
+        if template.kind == Headers::TransactionBlock {
+            self.take_pool_transactions(&mut template).await?;
+        }
+        template.pow_preimage = self.template_preimage(&last_block, &template)?;
+        Ok(template)
+    }
+
+    /// Prepares the next main chain block for the founder with the listed pool transactions
+    /// and the timestamp
+    ///
+    /// The transactions have to be in the pool, they are checked once the template is submitted
+    pub async fn block_template_with(
+        &self,
+        founder: &Address,
+        transactions: Vec<Hash>,
+        timestamp: u64,
+    ) -> Result<BlockTemplate, Report<BlockChainTreeError>> {
+        let last_block = self.main_chain.get_last_block()?.unwrap(); // practically cannot fail
+        let mut template = self.empty_template(&last_block, founder, timestamp)?;
+        template.transaction_fees = Self::pool_fees(&*self.tx_pool.read().await, &transactions)?;
+        template.transactions = transactions;
+        template.pow_preimage = self.template_preimage(&last_block, &template)?;
+        Ok(template)
+    }
+
+    /// Sum of the fees of the pool transactions, fails if any of them is not in the pool
+    fn pool_fees(
+        pool: &TxPool,
+        transactions: &[Hash],
+    ) -> Result<U256, Report<BlockChainTreeError>> {
+        let mut fees = U256::zero();
+        for hash in transactions {
+            let Some(entry) = pool.get(hash) else {
+                return Err(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::CreateMainChainBlock,
+                ))
+                .attach_printable(format!(
+                    "transaction {} is not in the pool",
+                    hex::encode(hash)
+                ));
+            };
+            fees = fees
+                .checked_add(entry.transaction.get_fee())
+                .ok_or_else(|| {
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::CreateMainChainBlock,
+                    ))
+                    .attach_printable("fees of the transactions overflow")
+                })?;
+        }
+        Ok(fees)
+    }
+
+    /// Fails if the block with the timestamp would be earlier than the last block,
+    /// the difficulty is retargeted by the time passed since it
+    fn check_timestamp(
+        last_block: &BlockArc,
+        timestamp: u64,
+    ) -> Result<(), Report<BlockChainTreeError>> {
+        let last_timestamp = last_block.get_info().timestamp;
+        if timestamp < last_timestamp {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::CreateMainChainBlock,
+            ))
+            .attach_printable(format!(
+                "timestamp {} is earlier than the timestamp of the last block {}",
+                timestamp, last_timestamp
+            ));
+        }
+        Ok(())
+    }
+
+    /// Template on top of the block without the transactions and the pow preimage
+    fn empty_template(
+        &self,
+        last_block: &BlockArc,
+        founder: &Address,
+        timestamp: u64,
+    ) -> Result<BlockTemplate, Report<BlockChainTreeError>> {
+        let previous_hash = last_block
+            .hash()
+            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::DumpDb))
+            .attach_printable("failed to hash block")?;
+        let height = last_block.get_info().height + 1;
+
+        Self::check_timestamp(last_block, timestamp)?;
+        let mut difficulty = last_block.get_info().difficulty;
+        tools::recalculate_difficulty(last_block.get_info().timestamp, timestamp, &mut difficulty);
+        let fee = tools::recalculate_fee(&difficulty);
+
+        let mut template = BlockTemplate {
+            previous_hash,
+            pow_preimage: [0; 32],
+            difficulty: last_block.get_info().difficulty,
+            height,
+            kind: Headers::TransactionBlock,
+            timestamp,
+            fee,
+            founder: *founder,
+            transactions: Vec::new(),
+            transaction_fees: U256::zero(),
+            anchors: Vec::new(),
+        };
+        if (height % BLOCKS_PER_EPOCH).is_zero() {
+            template.kind = Headers::SummarizeBlock;
+        } else {
+            template.anchors = self.pending_anchors()?;
+        }
+        Ok(template)
+    }
+
+    /// Pow preimage of the block built from the template on top of the block
+    fn template_preimage(
+        &self,
+        last_block: &BlockArc,
+        template: &BlockTemplate,
+    ) -> Result<Hash, Report<BlockChainTreeError>> {
+        let block = self.build_main_block(
+            last_block,
+            &[0; 32],
+            &template.founder,
+            &template.transactions,
+            template.transaction_fees,
+            template.anchors.clone(),
+            template.timestamp,
+        )?;
+        Ok(block::pow_preimage(
+            block.get_info(),
+            &block.get_body_root(),
+        ))
+    }
+
+    /// Fills the template with the pool transactions
+    async fn take_pool_transactions(
+        &self,
+        template: &mut BlockTemplate,
+    ) -> Result<(), Report<BlockChainTreeError>> {
+        let fee = template.fee;
+        let pool = self.tx_pool.read().await;
+        let mut senders: HashMap<Address, TemplateSender> = HashMap::new();
+        let mut size = 0;
+        let mut candidates: Vec<_> = pool.entries_iter().collect();
+        // transactions waiting for a previous nonce are retried once it is taken,
+        // the ones offering less than the template's fee or the sender can't afford
+        // are skipped together with the later nonces
+        loop {
+            let mut waiting = Vec::new();
+            let mut taken = false;
+            for entry in candidates {
+                let transaction = &entry.transaction;
+                let sender = transaction.get_sender();
+                let sender = match senders.entry(*sender) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(TemplateSender {
+                        nonce: self.get_nonce(sender)?,
+                        amount: self.get_amount(sender)?,
+                        gas: self.get_gas(sender)?,
+                    }),
+                };
+                if transaction.get_nonce() > sender.nonce {
+                    waiting.push(entry);
+                    continue;
+                }
+                if transaction.get_fee() < fee {
+                    continue;
+                }
+                // an overflowing amount can't be afforded either
+                let Some(amount) = transaction
+                    .get_fee()
+                    .checked_add(transaction.get_amount().unwrap_or_default())
+                else {
+                    continue;
+                };
+                let Some(transaction_fees) =
+                    template.transaction_fees.checked_add(transaction.get_fee())
+                else {
+                    continue;
+                };
+                let gas = *BYTE_GAS_PRICE * transaction.get_dump_size();
+                if transaction.get_nonce() == sender.nonce
+                    && sender.amount >= amount
+                    && sender.gas >= gas
+                    && size + transaction.get_dump_size() <= MAX_BLOCK_TRANSACTIONS_SIZE
+                {
+                    sender.nonce += 1;
+                    sender.amount -= amount;
+                    sender.gas -= gas;
+                    size += transaction.get_dump_size();
+                    template.transactions.push(transaction.hash());
+                    template.transaction_fees = transaction_fees;
+                    taken = true;
+                }
+            }
+            if !taken || waiting.is_empty() {
+                break;
+            }
+            candidates = waiting;
+        }
+
+        Ok(())
+    }
+
+    /// Creates the block from the template with the found pow and applies it
+    pub async fn submit_template(
+        &mut self,
+        template: &BlockTemplate,
+        pow: &[u8; 32],
+    ) -> Result<block::BlockArc, Report<BlockChainTreeError>> {
+        let last_block = self.main_chain.get_last_block()?.unwrap(); // practically cannot fail
+        let last_hash = last_block
+            .hash()
+            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::DumpDb))
+            .attach_printable("failed to hash block")?;
+        if last_hash != template.previous_hash {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::CreateMainChainBlock,
+            ))
+            .attach_printable("the template is stale, the main chain has moved on");
+        }
+        self.emmit_block(
+            pow,
+            &template.founder,
+            &template.transactions,
+            template.anchors.clone(),
+            template.timestamp,
+        )
+        .await
+    }
+
+    /// Creates new block on top of the main chain and applies it
+    ///
+    /// The founder is paid by the coinbase transaction put first into the block,
+    /// the summarization block derives its coinbase from the block info
+    ///
+    /// Transaction blocks anchor the blocks of the local derivative chains added since
+    /// they were last anchored
+    ///
+    /// The pow has to be found for the preimage of the same block, see `block_template_with`
     pub async fn emmit_new_main_block(
         &mut self,
         pow: &[u8; 32],
         founder: &[u8; 33],
         transactions: &[Hash],
         timestamp: u64,
+    ) -> Result<block::BlockArc, Report<BlockChainTreeError>> {
+        let anchors = self.pending_anchors()?;
+        self.emmit_block(pow, founder, transactions, anchors, timestamp)
+            .await
+    }
+
+    /// Creates new block with the anchors on top of the main chain and applies it,
+    /// anchors are left for the next block by the summarization block
+    async fn emmit_block(
+        &mut self,
+        pow: &[u8; 32],
+        founder: &[u8; 33],
+        transactions: &[Hash],
+        anchors: Vec<block::DerivativeAnchor>,
+        timestamp: u64,
     ) -> Result<block::BlockArc, Report<BlockChainTreeError>> {
         let last_block = self.main_chain.get_last_block()?.unwrap(); // practically cannot fail
+
+        // listed transactions are taken out of the pool, and returned back if the block fails
+        let mut pool = self.tx_pool.write().await;
+        let transaction_fees = Self::pool_fees(&pool, transactions)?;
+        let new_block = self.build_main_block(
+            &last_block,
+            pow,
+            founder,
+            transactions,
+            transaction_fees,
+            anchors,
+            timestamp,
+        )?;
+        let preimage = block::pow_preimage(new_block.get_info(), &new_block.get_body_root());
+        if !tools::check_pow(&preimage, &last_block.get_info().difficulty, pow) {
+            return Err(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::WrongPow).into());
+        };
+
+        let pending: Vec<_> = transactions
+            .iter()
+            .filter_map(|hash| pool.remove(hash))
+            .collect();
+        let included: Vec<&dyn Transactionable> = pending
+            .iter()
+            .map(|entry| entry.transaction.as_ref() as &dyn Transactionable)
+            .collect();
+        if let Err(report) = self.add_main_block(new_block.clone(), &included) {
+            for entry in pending {
+                // the pool can't be full, the transactions were just taken out of it
+                let _ = pool.push(entry.transaction, entry.received);
+            }
+            return Err(report);
+        }
+
+        Ok(new_block)
+    }
+
+    /// Builds the block with the pow on top of the block, the founder is paid
+    /// by the coinbase transaction
+    ///
+    /// `transaction_fees` is the sum of the fees of the transactions
+    #[allow(clippy::too_many_arguments)]
+    fn build_main_block(
+        &self,
+        last_block: &BlockArc,
+        pow: &[u8; 32],
+        founder: &[u8; 33],
+        transactions: &[Hash],
+        transaction_fees: U256,
+        anchors: Vec<block::DerivativeAnchor>,
+        timestamp: u64,
+    ) -> Result<block::BlockArc, Report<BlockChainTreeError>> {
         let prev_hash = last_block
             .hash()
             .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::DumpDb))
             .attach_printable("failed to hash block")?;
 
+        Self::check_timestamp(last_block, timestamp)?;
         let mut difficulty = last_block.get_info().difficulty;
-        if !tools::check_pow(&prev_hash, &difficulty, pow) {
-            return Err(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::WrongPow).into());
-        };
         tools::recalculate_difficulty(last_block.get_info().timestamp, timestamp, &mut difficulty);
         let fee = tools::recalculate_fee(&difficulty);
         let default_info = block::BasicInfo {
@@ -441,149 +2472,307 @@ impl BlockChainTree {
 
                 let merkle_tree_root = self.summarize()?;
 
-                let summarize_block = Arc::new(block::SummarizeBlock {
-                    default_info,
-                    merkle_tree_root,
-                });
-                self.rotate_dbs().await?;
-
-                self.set_amount(&ROOT_PUBLIC_ADDRESS as &[u8], *COINS_PER_CYCLE)?;
-
-                summarize_block
+                // summaries are rotated when the block is applied
+                Arc::new(block::SummarizeBlock::new(default_info, merkle_tree_root))
             } else {
-                if transactions.is_empty() {
-                    return Err(BlockChainTreeError::BlockChainTree(
+                // the reward is paid by the coinbase transaction, derived from the block itself
+                let reward = tools::block_reward(&transaction_fees).ok_or_else(|| {
+                    Report::new(BlockChainTreeError::BlockChainTree(
                         BCTreeErrorKind::CreateMainChainBlock,
-                    )
-                    .into());
-                }
+                    ))
+                    .attach_printable("reward of the block overflows")
+                })?;
+                let coinbase =
+                    Transaction::new_coinbase(*founder, &default_info.height, timestamp, reward);
+                let mut block_transactions = Vec::with_capacity(transactions.len() + 1);
+                block_transactions.push(coinbase.hash());
+                block_transactions.extend_from_slice(transactions);
 
-                let merkle_tree = merkletree::MerkleTree::build_tree(transactions);
-                let transaction_block = Arc::new(block::TransactionBlock::new(
-                    fee,
-                    default_info,
-                    *merkle_tree.get_root(),
-                    Vec::from_iter(transactions.iter().cloned()),
-                ));
-                transaction_block
+                let merkle_tree = merkletree::MerkleTree::build_tree(&block_transactions);
+                Arc::new(
+                    block::TransactionBlock::new(
+                        fee,
+                        default_info,
+                        *merkle_tree.get_root(),
+                        block_transactions,
+                    )
+                    .with_anchors(anchors),
+                )
             };
 
-        self.main_chain.add_block(new_block.clone())?;
         Ok(new_block)
     }
 
-    pub fn send_transaction(
+    /// Fee the transactions have to offer to be accepted into the pool,
+    /// the fee of the blocks on top of the last one
+    pub fn minimal_fee(&self) -> Result<U256, Report<BlockChainTreeError>> {
+        let last_block = self.main_chain.get_last_block()?.unwrap(); // practically cannot fail
+        Ok(tools::recalculate_fee(&last_block.get_info().difficulty))
+    }
+
+    /// Verifies the transaction and puts it into the pool
+    ///
+    /// Balances of the sender have to cover all of its pending transactions,
+    /// funds are moved once the transaction is included into a block
+    pub async fn send_transaction(
+        &self,
+        transaction: impl Transactionable + 'static,
+    ) -> Result<(), Report<BlockChainTreeError>> {
+        self.send_transaction_item(Box::new(transaction)).await
+    }
+
+    /// Verifies the transaction or the token action and puts it into the pool
+    ///
+    /// The root address sends only the coinbase transactions, so its transactions are rejected.
+    /// Token actions are checked against the tokens with the sender's pending actions applied
+    pub async fn send_transaction_item(
         &self,
-        transaction: &dyn Transactionable,
+        transaction: TransactionableItem,
     ) -> Result<(), Report<BlockChainTreeError>> {
-        let sender_gas_amount = self.get_gas(transaction.get_sender())?;
-        let sender_amount = self.get_amount(transaction.get_sender())?;
-        let amount_of_bytes = transaction.get_dump_size();
-        let gas_required = *BYTE_GAS_PRICE * amount_of_bytes;
-        if sender_gas_amount < gas_required {
+        let hash = transaction.hash();
+        if *transaction.get_sender() == ROOT_PUBLIC_ADDRESS {
             return Err(BlockChainTreeError::BlockChainTree(
                 BCTreeErrorKind::NewTransaction,
             ))
-            .attach_printable("not enough gas for the transaction");
+            .attach_printable("transactions can't be sent from the root address");
         }
-        let last_block = self.main_chain.get_last_block()?.unwrap(); // practically cannot fail
-        let fee = tools::recalculate_fee(&last_block.get_info().difficulty);
-        if sender_amount < fee + transaction.get_amount().unwrap_or(U256::zero()) {
+        if !transaction
+            .verify()
+            .change_context(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::NewTransaction,
+            ))?
+        {
             return Err(BlockChainTreeError::BlockChainTree(
                 BCTreeErrorKind::NewTransaction,
             ))
-            .attach_printable("not enough coins to pay the fee");
+            .attach_printable("wrong signature of the transaction");
         }
-        self.main_chain.add_transaction(transaction)?;
-        if let Some(amount) = transaction.get_amount() {
-            // TODO: make into sled transaction
-            self.send_amount(transaction.get_sender(), transaction.get_receiver(), amount)?;
-            self.sub_amount(transaction.get_sender(), fee)?;
-            self.sub_gas(transaction.get_sender(), gas_required)?;
+        if self.main_chain.transaction_exists(&hash)? {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::NewTransaction,
+            ))
+            .attach_printable("the transaction is already in the chain");
         }
-        Ok(())
-    }
-
-    pub async fn flush(&self) -> Result<(), Report<BlockChainTreeError>> {
-        self.main_chain.flush().await?;
-        self.summary_db
-            .flush_async()
-            .await
-            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::DumpDb))
-            .attach_printable("failed to flush summary db")?;
-
-        self.old_summary_db
-            .flush_async()
-            .await
-            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::DumpDb))
-            .attach_printable("failed to flush old summary db")?;
-
-        self.gas_db
-            .flush_async()
-            .await
-            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::DumpDb))
-            .attach_printable("failed to flush old summary db")?;
-
-        self.old_gas_db
-            .flush_async()
-            .await
-            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::DumpDb))
-            .attach_printable("failed to flush old summary db")?;
 
-        Ok(())
-    }
-
-    async fn rotate_dbs(&mut self) -> Result<(), Report<BlockChainTreeError>> {
-        self.flush().await?;
-
-        let path_summary = Path::new(AMMOUNT_SUMMARY);
-        let path_summary_old = Path::new(OLD_AMMOUNT_SUMMARY);
-        let path_gas = Path::new(GAS_SUMMARY);
-        let path_gas_old = Path::new(OLD_GAS_SUMMARY);
-
-        fs::remove_dir_all(path_summary_old)
-            .change_context(BlockChainTreeError::BlockChainTree(
-                BCTreeErrorKind::MoveSummaryDB,
+        let minimal_fee = self.minimal_fee()?;
+        let fee = transaction.get_fee();
+        if fee < minimal_fee {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::NewTransaction,
             ))
-            .attach_printable("failed to remove previous summary database")?;
+            .attach_printable(format!(
+                "the fee is lower than the current fee {}",
+                minimal_fee
+            ));
+        }
 
-        fs::create_dir(path_summary_old)
-            .change_context(BlockChainTreeError::BlockChainTree(
-                BCTreeErrorKind::MoveSummaryDB,
+        let mut pool = self.tx_pool.write().await;
+        pool.remove_expired(tools::current_timestamp());
+        if pool.transaction_exists(&hash) {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::NewTransaction,
             ))
-            .attach_printable("failed to create previous summary database folder")?;
+            .attach_printable("the transaction is already in the pool");
+        }
 
-        fs::remove_dir_all(path_gas_old)
-            .change_context(BlockChainTreeError::BlockChainTree(
-                BCTreeErrorKind::MoveSummaryDB,
+        let sender = transaction.get_sender();
+        let expected_nonce = self.pending_nonce(&pool, sender)?;
+        if transaction.get_nonce() < expected_nonce {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::NewTransaction,
             ))
-            .attach_printable("failed to remove previous gas database")?;
+            .attach_printable("the nonce is already used");
+        }
+        if transaction.get_nonce() > expected_nonce {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::NewTransaction,
+            ))
+            .attach_printable(format!(
+                "the nonce is out of order, expected {}",
+                expected_nonce
+            ));
+        }
 
-        fs::create_dir(path_gas_old)
-            .change_context(BlockChainTreeError::BlockChainTree(
-                BCTreeErrorKind::MoveSummaryDB,
+        let mut gas_required = *BYTE_GAS_PRICE * transaction.get_dump_size();
+        let Some(mut amount_required) =
+            fee.checked_add(transaction.get_amount().unwrap_or_default())
+        else {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::NewTransaction,
             ))
-            .attach_printable("failed to remove previous gas database folder")?;
+            .attach_printable("the amount overflows with the fee");
+        };
+        for pending in pool
+            .entries_iter()
+            .filter(|pending| pending.transaction.get_sender() == sender)
+        {
+            gas_required += *BYTE_GAS_PRICE * pending.transaction.get_dump_size();
+            amount_required = pending
+                .transaction
+                .get_fee()
+                .checked_add(pending.transaction.get_amount().unwrap_or_default())
+                .and_then(|amount| amount_required.checked_add(amount))
+                .ok_or_else(|| {
+                    Report::new(BlockChainTreeError::BlockChainTree(
+                        BCTreeErrorKind::NewTransaction,
+                    ))
+                    .attach_printable("the amount of the pending transactions overflows")
+                })?;
+        }
 
-        tools::copy_dir_all(path_summary, path_summary_old)
-            .change_context(BlockChainTreeError::BlockChainTree(
-                BCTreeErrorKind::MoveSummaryDB,
+        if self.get_gas(sender)? < gas_required {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::NewTransaction,
+            ))
+            .attach_printable("not enough gas for the transaction");
+        }
+        if self.get_amount(sender)? < amount_required {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::NewTransaction,
             ))
-            .attach_printable("failed to copy summary database")?;
+            .attach_printable("not enough coins to pay the fee");
+        }
+        if let Some(action) = transaction.as_token_action() {
+            let mut pending: Vec<&TokenAction> = pool
+                .entries_iter()
+                .filter(|pending| pending.transaction.get_sender() == sender)
+                .filter_map(|pending| pending.transaction.as_token_action())
+                .collect();
+            pending.sort_by_key(|pending| pending.get_nonce());
+            self.check_token_action(action, &pending)?;
+        }
 
-        tools::copy_dir_all(path_gas, path_gas_old)
+        let event = Event::TransactionAccepted {
+            hash,
+            sender: *sender,
+            nonce: transaction.get_nonce(),
+            fee,
+        };
+        pool.push(transaction, tools::current_timestamp())
             .change_context(BlockChainTreeError::BlockChainTree(
-                BCTreeErrorKind::MoveSummaryDB,
+                BCTreeErrorKind::NewTransaction,
+            ))?;
+        self.notify(event);
+        Ok(())
+    }
+
+    /// Checks that the action can be applied after the pending actions of its sender
+    ///
+    /// Tokens received from the pending actions of the others are not counted
+    fn check_token_action(
+        &self,
+        action: &TokenAction,
+        pending: &[&TokenAction],
+    ) -> Result<(), Report<BlockChainTreeError>> {
+        if action.get_action() == Action::Create {
+            if *action.get_token_hash() != [0; 32] {
+                return Err(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::TokenAction,
+                ))
+                .attach_printable("created token can't reference a token hash");
+            }
+            return Ok(());
+        }
+
+        let sender = action.get_sender();
+        let token_hash = action.get_token_hash();
+        let mut token = self.get_token(token_hash)?;
+        let mut balance = self.get_token_balance(sender, token_hash)?;
+        for pending in pending {
+            match pending.get_action() {
+                Action::Create if pending.hash() == *token_hash => {
+                    token = Some(Token::new(*token_hash, *sender));
+                }
+                Action::Create => {}
+                _ if pending.get_token_hash() != token_hash => {}
+                Action::Assign => {
+                    if let Some(token) = token.as_mut() {
+                        token.parameters = pending.get_parameters().cloned().unwrap_or_default();
+                        token.assigned = true;
+                        balance = balance
+                            .checked_add(token.parameters.coin_supply)
+                            .ok_or_else(|| token_overflow("balance"))?;
+                    }
+                }
+                Action::Send => {
+                    let fee = token
+                        .as_ref()
+                        .map_or(U256::zero(), |token| token.transfer_fee_of(sender));
+                    let total = pending
+                        .get_token_amount()
+                        .checked_add(fee)
+                        .ok_or_else(|| token_overflow("amount of a pending action"))?;
+                    balance = balance.saturating_sub(total);
+                }
+                Action::Burn => {
+                    balance = balance.saturating_sub(*pending.get_token_amount());
+                }
+            }
+        }
+
+        let Some(token) = token else {
+            return Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::TokenAction,
+            ))
+            .attach_printable(format!("token {} doesn't exist", hex::encode(token_hash)));
+        };
+        match action.get_action() {
+            Action::Assign if token.current_owner != *sender => Err(
+                BlockChainTreeError::BlockChainTree(BCTreeErrorKind::TokenAction),
+            )
+            .attach_printable("token can be assigned only by its owner"),
+            Action::Assign if token.assigned => Err(BlockChainTreeError::BlockChainTree(
+                BCTreeErrorKind::TokenAction,
             ))
-            .attach_printable("failed to copy gas database")?;
+            .attach_printable("token is already assigned"),
+            Action::Send | Action::Burn if !token.assigned => Err(
+                BlockChainTreeError::BlockChainTree(BCTreeErrorKind::TokenAction),
+            )
+            .attach_printable("token is not assigned"),
+            Action::Send => match action
+                .get_token_amount()
+                .checked_add(token.transfer_fee_of(sender))
+            {
+                None => Err(token_overflow("amount with the transfer fee")),
+                Some(total) if balance < total => Err(BlockChainTreeError::BlockChainTree(
+                    BCTreeErrorKind::TokenAction,
+                ))
+                .attach_printable("not enough tokens to pay the transfer fee"),
+                Some(_) => Ok(()),
+            },
+            Action::Burn if balance < *action.get_token_amount() => Err(
+                BlockChainTreeError::BlockChainTree(BCTreeErrorKind::TokenAction),
+            )
+            .attach_printable("not enough tokens"),
+            _ => Ok(()),
+        }
+    }
 
-        self.old_summary_db = sled::open(path_summary_old)
-            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::Init))
-            .attach_printable("failed to open old summary db")?;
+    pub fn get_pool(&self) -> SharedTxPool {
+        self.tx_pool.clone()
+    }
 
-        self.old_gas_db = sled::open(path_gas_old)
-            .change_context(BlockChainTreeError::BlockChainTree(BCTreeErrorKind::Init))
-            .attach_printable("failed to open old gas db")?;
+    pub async fn flush(&self) -> Result<(), Report<BlockChainTreeError>> {
+        // summary trees are flushed together with the main chain database
+        self.main_chain.flush().await?;
+        for chain in self.derivative_chains.values() {
+            chain.flush().await?;
+        }
+
+        let pool_dump = self.tx_pool.read().await.dump().change_context(
+            BlockChainTreeError::BlockChainTree(BCTreeErrorKind::DumpPool),
+        )?;
+        tools::compress_to_file(
+            self.root
+                .join(TRANSACTIONS_POOL)
+                .to_string_lossy()
+                .into_owned(),
+            &pool_dump,
+        )
+        .change_context(BlockChainTreeError::BlockChainTree(
+            BCTreeErrorKind::DumpPool,
+        ))
+        .attach_printable("failed to write transactions pool")?;
 
         Ok(())
     }
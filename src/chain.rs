@@ -1,23 +1,50 @@
-use std::{fs::File, io::Read, path::Path, sync::Arc};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use error_stack::{Report, ResultExt};
 use parking_lot::RwLock;
 use primitive_types::U256;
-use sled::Db;
+use sled::{
+    transaction::{
+        ConflictableTransactionResult, TransactionError, Transactional, TransactionalTree,
+        UnabortableTransactionError,
+    },
+    Db, Tree,
+};
 use tokio::{fs::OpenOptions, io::AsyncWriteExt};
 
 use crate::block::{BlockArc, DerivativeBlock};
 use crate::dump_headers::Headers;
 use crate::{
     block::{self, BasicInfo, Block, SummarizeBlock},
-    errors::{BlockChainTreeError, ChainErrorKind},
+    errors::{BlockChainTreeError, ChainErrorKind, DerivChainErrorKind},
     merkletree::MerkleTree,
     tools,
     transaction::Transactionable,
+    types::Hash,
 };
 use crate::{static_values::*, transaction};
 
+/// Flushes the db on a blocking thread
+///
+/// `flush_async` releases its handle on the sled thread pool only after the flush is
+/// reported, so the db could stay locked for a while after being dropped
+async fn flush_db(db: &Db) -> Result<(), Report<BlockChainTreeError>> {
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || db.flush())
+        .await
+        .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))?
+        .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))?;
+
+    Ok(())
+}
+
 #[async_trait]
 pub trait Chain {
     async fn dump_config(&self) -> Result<(), Report<BlockChainTreeError>>;
@@ -48,67 +75,215 @@ pub trait Chain {
     ) -> Result<Option<Arc<dyn Block + Send + Sync>>, Report<BlockChainTreeError>>;
 }
 
+/// Blocks removed from the chain (newest first) and added to it (oldest first) by a single update
+#[derive(Default)]
+pub struct ChainUpdate {
+    pub reverted: Vec<BlockArc>,
+    pub applied: Vec<BlockArc>,
+}
+
+impl ChainUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.reverted.is_empty() && self.applied.is_empty()
+    }
+}
+
+/// Main chain trees as seen from inside of a database transaction
+struct ChainTrees<'a> {
+    blocks: &'a TransactionalTree,
+    height_reference: &'a TransactionalTree,
+    transactions: &'a TransactionalTree,
+    branches: &'a TransactionalTree,
+    branch_children: &'a TransactionalTree,
+    branch_transactions: &'a TransactionalTree,
+    chain_work: &'a TransactionalTree,
+}
+
+impl ChainTrees<'_> {
+    /// Puts block on top of the chain
+    fn append_block(
+        &self,
+        height: &mut U256,
+        hash: &Hash,
+        dump: &[u8],
+        work: &U256,
+    ) -> Result<(), UnabortableTransactionError> {
+        let mut height_bytes = [0u8; 32];
+        height.to_big_endian(&mut height_bytes);
+
+        self.blocks.insert(&height_bytes, dump)?;
+        self.height_reference.insert(hash, &height_bytes)?;
+        self.set_chain_work(hash, work)?;
+
+        *height += U256::one();
+
+        Ok(())
+    }
+
+    /// Removes the top block of the chain
+    fn pop_block(&self, height: &mut U256, hash: &Hash) -> Result<(), UnabortableTransactionError> {
+        *height -= U256::one();
+        let mut height_bytes = [0u8; 32];
+        height.to_big_endian(&mut height_bytes);
+
+        self.blocks.remove(&height_bytes)?;
+        self.height_reference.remove(hash)?;
+
+        Ok(())
+    }
+
+    fn insert_side_block(
+        &self,
+        hash: &Hash,
+        previous_hash: &Hash,
+        dump: &[u8],
+    ) -> Result<(), UnabortableTransactionError> {
+        self.branches.insert(hash, dump)?;
+        self.branch_children
+            .insert([previous_hash.as_slice(), hash.as_slice()].concat(), &[])?;
+
+        Ok(())
+    }
+
+    fn remove_side_block(
+        &self,
+        hash: &Hash,
+        previous_hash: &Hash,
+    ) -> Result<(), UnabortableTransactionError> {
+        self.branches.remove(hash)?;
+        self.branch_children
+            .remove([previous_hash.as_slice(), hash.as_slice()].concat())?;
+
+        Ok(())
+    }
+
+    /// Moves the transactions of the block removed from the chain to the side branches
+    fn move_to_branch(&self, block: &BlockArc) -> Result<(), UnabortableTransactionError> {
+        for hash in block.get_transactions() {
+            if let Some(dump) = self.transactions.remove(hash)? {
+                self.branch_transactions.insert(hash, dump)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the transactions of the side block put into the chain back to the chain
+    fn move_from_branch(&self, block: &BlockArc) -> Result<(), UnabortableTransactionError> {
+        for hash in block.get_transactions() {
+            if let Some(dump) = self.branch_transactions.remove(hash)? {
+                self.transactions.insert(hash, dump)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_chain_work(&self, hash: &Hash, work: &U256) -> Result<(), UnabortableTransactionError> {
+        let mut work_bytes = [0u8; 32];
+        work.to_big_endian(&mut work_bytes);
+        self.chain_work.insert(hash, &work_bytes)?;
+
+        Ok(())
+    }
+}
+
+/// Changes to the chain planned for a new block, applied in a single transaction
+#[derive(Default)]
+struct ChainChanges {
+    /// blocks to remove from the top of the chain, newest first
+    reverted: Vec<(Hash, Vec<u8>, BlockArc)>,
+    /// blocks to put on top of the chain with their cumulative work, oldest first
+    applied: Vec<(Hash, Vec<u8>, U256, BlockArc)>,
+    /// block to store in a side branch with its previous hash and cumulative work
+    side: Option<(Hash, Hash, Vec<u8>, U256)>,
+}
+
+/// Maps the error of the database transaction into the report
+fn transaction_report(
+    error: TransactionError<Report<BlockChainTreeError>>,
+    context: ChainErrorKind,
+) -> Report<BlockChainTreeError> {
+    match error {
+        TransactionError::Abort(report) => report,
+        TransactionError::Storage(error) => {
+            Report::new(error).change_context(BlockChainTreeError::Chain(context))
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct MainChain {
-    blocks: Db,
-    height_reference: Db,
-    transactions: Db,
+    root: PathBuf,
+    db: Db,
+    blocks: Tree,
+    height_reference: Tree,
+    transactions: Tree,
+    branches: Tree,
+    branch_children: Tree,
+    branch_transactions: Tree,
+    chain_work: Tree,
     height: Arc<RwLock<U256>>,
     difficulty: Arc<RwLock<[u8; 32]>>,
 }
 
 impl MainChain {
+    /// Opens the main chain in the default data directory
     pub fn new() -> Result<Self, Report<BlockChainTreeError>> {
-        let root = String::from(MAIN_CHAIN_DIRECTORY);
-
-        let path_blocks_st = root.clone() + BLOCKS_FOLDER;
-        let path_references_st = root.clone() + REFERENCES_FOLDER;
-        let path_transactions_st = root.clone() + TRANSACTIONS_FOLDER;
-        let path_height_st = root + CONFIG_FILE;
-
-        let path_blocks = Path::new(&path_blocks_st);
-        let path_reference = Path::new(&path_references_st);
-        let path_transactions = Path::new(&path_transactions_st);
-        let path_height = Path::new(&path_height_st);
+        Self::open(BLOCKCHAIN_DIRECTORY)
+    }
 
-        // open blocks DB
-        let blocks = sled::open(path_blocks)
-            .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
-            .attach_printable("failed to open blocks db")?;
+    /// Opens the main chain stored under the provided data directory
+    pub fn open(data_directory: impl AsRef<Path>) -> Result<Self, Report<BlockChainTreeError>> {
+        let root = data_directory.as_ref().join(MAIN_CHAIN_DIRECTORY);
+        Self::check_layout(&root)?;
 
-        // open height references DB
-        let height_reference = sled::open(path_reference)
+        // all the trees are kept in a single DB, so they can be updated in one transaction
+        let db = sled::open(root.join(DATABASE_FOLDER))
             .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
-            .attach_printable("failed to open references db")?;
-
-        // open transactions DB
-        let transactions = sled::open(path_transactions)
-            .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
-            .attach_printable("failed to open transactions db")?;
-
-        let file = File::open(path_height);
+            .attach_printable("failed to open main chain db")?;
 
-        let (height, difficulty) = if let Ok(mut file) = file {
-            let mut height_bytes: [u8; 32] = [0; 32];
-            file.read_exact(&mut height_bytes)
-                .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
-                .attach_printable("failed to read config")?;
-
-            // read difficulty
-            let mut difficulty: [u8; 32] = [0; 32];
-            file.read_exact(&mut difficulty)
+        let open_tree = |name: &str| {
+            db.open_tree(name)
                 .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
-                .attach_printable("failed to read difficulty")?;
+                .attach_printable_lazy(|| format!("failed to open {} tree", name))
+        };
 
-            (U256::from_big_endian(&height_bytes), difficulty)
-        } else {
-            (U256::zero(), BEGINNING_DIFFICULTY)
+        let blocks = open_tree(BLOCKS_TREE)?;
+        let height_reference = open_tree(REFERENCES_TREE)?;
+        let transactions = open_tree(TRANSACTIONS_TREE)?;
+        let branches = open_tree(BRANCHES_TREE)?;
+        // side blocks keyed by previous hash
+        let branch_children = open_tree(BRANCH_CHILDREN_TREE)?;
+        // transactions of the side blocks, the transactions tree only has the chain ones
+        let branch_transactions = open_tree(BRANCH_TRANSACTIONS_TREE)?;
+        let chain_work = open_tree(WORK_TREE)?;
+
+        // the height and difficulty are taken from the blocks, they are committed together
+        // with the balances while the config is only written on flush
+        let last = blocks
+            .last()
+            .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))?;
+        let (height, difficulty) = match last {
+            Some((key, dump)) => {
+                let block = block::deserialize_main_chain_block(&dump)
+                    .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
+                    .attach_printable("failed to recover the chain from its blocks")?;
+                (U256::from_big_endian(&key) + 1, block.get_info().difficulty)
+            }
+            None => (U256::zero(), BEGINNING_DIFFICULTY),
         };
 
         let chain = Self {
+            root,
+            db,
             blocks,
             height_reference,
             transactions,
+            branches,
+            branch_children,
+            branch_transactions,
+            chain_work,
             height: Arc::new(RwLock::new(height)),
             difficulty: Arc::new(RwLock::new(difficulty)),
         };
@@ -130,22 +305,49 @@ impl MainChain {
 
             let merkle_tree = MerkleTree::build_tree(&[tools::hash(&initial_amount)]);
             chain
-                .add_block(Arc::new(SummarizeBlock {
-                    default_info: info,
-                    merkle_tree_root: *merkle_tree.get_root(),
-                }))
+                .add_block(Arc::new(SummarizeBlock::new(info, *merkle_tree.get_root())))
                 .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
                 .attach_printable("Failed to insert inception block")?;
         }
 
         Ok(chain)
     }
+
+    /// Fails if the main chain was stored in separate databases by the older versions
+    ///
+    /// Opening such a directory would silently start a new chain next to the old one
+    fn check_layout(root: &Path) -> Result<(), Report<BlockChainTreeError>> {
+        if root.join(DATABASE_FOLDER).exists() {
+            return Ok(());
+        }
+        let legacy = [BLOCKS_FOLDER, REFERENCES_FOLDER, TRANSACTIONS_FOLDER]
+            .into_iter()
+            .find(|folder| root.join(folder).exists());
+        if let Some(folder) = legacy {
+            return Err(BlockChainTreeError::Chain(ChainErrorKind::OutdatedLayout))
+                .attach_printable(format!(
+                    "found {} in {}, the data directory has to be removed and synced again",
+                    folder,
+                    root.display()
+                ));
+        }
+        Ok(())
+    }
+
+    /// Opens another tree in the main chain database
+    ///
+    /// Trees opened this way can be updated together with the chain in `add_block_with`
+    pub fn open_tree(&self, name: &str) -> Result<Tree, Report<BlockChainTreeError>> {
+        self.db
+            .open_tree(name)
+            .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
+            .attach_printable_lazy(|| format!("failed to open {} tree", name))
+    }
     /// Dump config
     ///
     /// Dumps chain's config
     async fn dump_config(&self) -> Result<(), Report<BlockChainTreeError>> {
-        let root = String::from(MAIN_CHAIN_DIRECTORY);
-        let path_config = root + CONFIG_FILE;
+        let path_config = self.root.join(CONFIG_FILE);
 
         let mut file = OpenOptions::new()
             .write(true)
@@ -161,7 +363,8 @@ impl MainChain {
             .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))
             .attach_printable("failed to write height")?;
 
-        file.write_all(self.difficulty.read().as_ref())
+        let difficulty = *self.difficulty.read();
+        file.write_all(&difficulty)
             .await
             .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))
             .attach_printable("failed to write difficulty")?;
@@ -177,24 +380,10 @@ impl MainChain {
     pub async fn flush(&self) -> Result<(), Report<BlockChainTreeError>> {
         self.dump_config().await?;
 
-        self.blocks
-            .flush_async()
+        flush_db(&self.db)
             .await
-            .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))
             .attach_printable("failed to flush db")?;
 
-        self.height_reference
-            .flush_async()
-            .await
-            .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))
-            .attach_printable("failed to flush height references")?;
-
-        self.transactions
-            .flush_async()
-            .await
-            .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))
-            .attach_printable("failed to flush transactions")?;
-
         Ok(())
     }
 
@@ -277,40 +466,424 @@ impl MainChain {
 
     /// Adds new block to the chain db
     ///
-    /// Adds block and sets height reference for it
+    /// A block extending the current tip is appended directly. A block extending any other
+    /// known block is stored as a side block; if the branch it ends has more cumulative work
+    /// than the current tip, the chain is reorganized onto that branch.
+    ///
+    /// Every block except the inception one is validated against its parent first.
+    ///
+    /// Returns the blocks that were removed from and added to the chain
+    pub fn add_block(&self, block: BlockArc) -> Result<ChainUpdate, Report<BlockChainTreeError>> {
+        self.add_block_with(block, &[], &[], |_, _, _| Ok(()))
+    }
+
+    /// Adds new block to the chain db together with its transactions, see `add_block`
+    ///
+    /// All the changes are made in a single database transaction with the `state` trees
+    /// (opened with `open_tree`): `effects` receives the update, the transactions tree and
+    /// the `state` trees in the same order and either applies its changes or aborts
+    /// the whole transaction, so the chain is never left partially updated
     ///
-    /// Checks for blocks validity, adds it directly to the end of the chain
-    pub fn add_block(&self, block: BlockArc) -> Result<(), Report<BlockChainTreeError>> {
+    /// The transactions tree only holds the transactions of the chain blocks, the ones
+    /// of the side blocks are kept apart and moved over when their block joins the chain.
+    /// The provided transactions the block doesn't list are skipped
+    pub fn add_block_with<F>(
+        &self,
+        block: BlockArc,
+        transactions: &[&dyn transaction::Transactionable],
+        state: &[&Tree],
+        effects: F,
+    ) -> Result<ChainUpdate, Report<BlockChainTreeError>>
+    where
+        F: Fn(
+            &ChainUpdate,
+            &TransactionalTree,
+            &[TransactionalTree],
+        ) -> ConflictableTransactionResult<(), Report<BlockChainTreeError>>,
+    {
+        // only the transactions of the block are stored with it
+        let listed: HashSet<&Hash> = block.get_transactions().iter().collect();
+        let mut transaction_dumps = Vec::with_capacity(transactions.len());
+        for transaction in transactions {
+            let dump = transaction
+                .dump()
+                .change_context(BlockChainTreeError::Chain(
+                    ChainErrorKind::AddingTransaction,
+                ))?;
+            let hash = tools::hash(&dump);
+            if listed.contains(&hash) {
+                transaction_dumps.push((hash, dump));
+            }
+        }
+
+        let mut height = self.height.write();
+
+        let changes = self.plan_block(&height, block)?;
+        let update = ChainUpdate {
+            reverted: changes
+                .reverted
+                .iter()
+                .map(|(_, _, block)| block.clone())
+                .collect(),
+            applied: changes
+                .applied
+                .iter()
+                .map(|(_, _, _, block)| block.clone())
+                .collect(),
+        };
+
+        let mut trees = vec![
+            &self.blocks,
+            &self.height_reference,
+            &self.transactions,
+            &self.branches,
+            &self.branch_children,
+            &self.branch_transactions,
+            &self.chain_work,
+        ];
+        trees.extend_from_slice(state);
+
+        let new_height = trees
+            .as_slice()
+            .transaction(|view| {
+                let chain = ChainTrees {
+                    blocks: &view[0],
+                    height_reference: &view[1],
+                    transactions: &view[2],
+                    branches: &view[3],
+                    branch_children: &view[4],
+                    branch_transactions: &view[5],
+                    chain_work: &view[6],
+                };
+                let mut new_height = *height;
+
+                for (hash, dump, block) in changes.reverted.iter() {
+                    chain.pop_block(&mut new_height, hash)?;
+                    chain.insert_side_block(hash, &block.get_info().previous_hash, dump)?;
+                    chain.move_to_branch(block)?;
+                }
+
+                let new_transactions = if changes.side.is_some() {
+                    chain.branch_transactions
+                } else {
+                    chain.transactions
+                };
+                for (hash, dump) in transaction_dumps.iter() {
+                    new_transactions.insert(hash, dump.as_slice())?;
+                }
+
+                for (hash, dump, work, block) in changes.applied.iter() {
+                    chain.remove_side_block(hash, &block.get_info().previous_hash)?;
+                    chain.move_from_branch(block)?;
+                    chain.append_block(&mut new_height, hash, dump, work)?;
+                }
+
+                if let Some((hash, previous_hash, dump, work)) = changes.side.as_ref() {
+                    chain.insert_side_block(hash, previous_hash, dump)?;
+                    chain.set_chain_work(hash, work)?;
+                }
+
+                effects(&update, chain.transactions, &view[7..])?;
+
+                Ok(new_height)
+            })
+            .map_err(|error| transaction_report(error, ChainErrorKind::AddingBlock))?;
+
+        *height = new_height;
+        if let Some((_, _, _, block)) = changes.applied.last() {
+            *self.difficulty.write() = block.get_info().difficulty;
+        }
+
+        Ok(update)
+    }
+
+    /// Decides where the block goes: on top of the chain, into a side branch
+    /// or to the top of the chain after reorganization
+    fn plan_block(
+        &self,
+        height: &U256,
+        block: BlockArc,
+    ) -> Result<ChainChanges, Report<BlockChainTreeError>> {
         let dump = block
             .dump()
             .change_context(BlockChainTreeError::Chain(ChainErrorKind::AddingBlock))?;
 
         let hash = tools::hash(&dump);
 
-        let mut height = self.height.write();
+        if height.is_zero() {
+            // inception block
+            let work = tools::block_work(&block.get_info().difficulty);
+            return Ok(ChainChanges {
+                applied: vec![(hash, dump, work, block)],
+                ..Default::default()
+            });
+        }
 
-        if block.get_info().height != *height {
-            return Err(BlockChainTreeError::Chain(ChainErrorKind::AddingBlock)).attach_printable(
-                "The height of the chain is different from the height of the block",
-            );
+        if self.get_chain_work(&hash)?.is_some() {
+            return Err(BlockChainTreeError::Chain(ChainErrorKind::AddingBlock))
+                .attach_printable("The block is already known");
         }
 
-        let mut height_bytes = [0u8; 32];
-        height.to_big_endian(&mut height_bytes);
+        let previous_hash = block.get_info().previous_hash;
+        let previous_work = self
+            .get_chain_work(&previous_hash)?
+            .ok_or(BlockChainTreeError::Chain(ChainErrorKind::AddingBlock))
+            .attach_printable("The previous block is unknown")?;
+        let previous_block = self
+            .find_known_block(&previous_hash)?
+            .ok_or(BlockChainTreeError::Chain(ChainErrorKind::AddingBlock))
+            .attach_printable("The previous block is unknown")?;
+
+        // the pow of the block is checked against the difficulty of its parent
+        let work = previous_work + tools::block_work(&previous_block.get_info().difficulty);
+
+        block
+            .validate(Some(previous_block))
+            .change_context(BlockChainTreeError::Chain(ChainErrorKind::FailedToVerify))?;
+        let tip_hash = self.tip_hash(height)?;
+
+        if previous_hash == tip_hash {
+            return Ok(ChainChanges {
+                applied: vec![(hash, dump, work, block)],
+                ..Default::default()
+            });
+        }
 
-        self.blocks
-            .insert(height_bytes, dump)
-            .change_context(BlockChainTreeError::Chain(ChainErrorKind::AddingBlock))
-            .attach_printable("Failed to insert block to blocks db")?;
+        let tip_work = self.get_chain_work(&tip_hash)?.unwrap_or_default();
+        if work <= tip_work {
+            return Ok(ChainChanges {
+                side: Some((hash, previous_hash, dump, work)),
+                ..Default::default()
+            });
+        }
 
-        self.height_reference
-            .insert(hash, &height_bytes)
-            .change_context(BlockChainTreeError::Chain(ChainErrorKind::AddingBlock))
-            .attach_printable("Failed to insert height reference for the block")?;
+        self.plan_reorganization(height, (hash, dump, work, block))
+    }
 
-        *height += U256::one();
+    /// Plans switching the chain onto the side branch ending with `tip`
+    fn plan_reorganization(
+        &self,
+        height: &U256,
+        tip: (Hash, Vec<u8>, U256, BlockArc),
+    ) -> Result<ChainChanges, Report<BlockChainTreeError>> {
+        // collect the side branch down to the fork point
+        let mut branch = vec![tip];
+        let fork_height = loop {
+            let previous_hash = branch[branch.len() - 1].3.get_info().previous_hash;
+            if let Some(height) = self.get_height_reference(&previous_hash)? {
+                break height;
+            }
+            let previous_block = self
+                .find_side_block(&previous_hash)?
+                .ok_or(BlockChainTreeError::Chain(ChainErrorKind::Reorganize))
+                .attach_printable("Side branch is not connected to the chain")?;
+            let dump = previous_block
+                .dump()
+                .change_context(BlockChainTreeError::Chain(ChainErrorKind::Reorganize))?;
+            let work = self
+                .get_chain_work(&previous_hash)?
+                .ok_or(BlockChainTreeError::Chain(ChainErrorKind::Reorganize))?;
+            branch.push((previous_hash, dump, work, previous_block));
+        };
 
-        Ok(())
+        let mut reverted = Vec::new();
+        let mut current_height = *height - 1;
+        while current_height > fork_height {
+            let dump = self
+                .get_raw_at(&current_height)?
+                .ok_or(BlockChainTreeError::Chain(ChainErrorKind::Reorganize))
+                .attach_printable(format!("Block with height {} is missing", current_height))?;
+            let block = block::deserialize_main_chain_block(&dump)
+                .change_context(BlockChainTreeError::Chain(ChainErrorKind::Reorganize))?;
+            reverted.push((tools::hash(&dump), dump, block));
+            current_height -= U256::one();
+        }
+
+        branch.reverse();
+        Ok(ChainChanges {
+            reverted,
+            applied: branch,
+            side: None,
+        })
+    }
+
+    /// Get serialized side block by it's hash
+    pub fn find_raw_side_block(
+        &self,
+        hash: &Hash,
+    ) -> Result<Option<Vec<u8>>, Report<BlockChainTreeError>> {
+        Ok(self
+            .branches
+            .get(hash)
+            .change_context(BlockChainTreeError::Chain(ChainErrorKind::FindByHashE))?
+            .map(|dump| dump.to_vec()))
+    }
+
+    /// Get serialized transaction of a side block by it's hash
+    ///
+    /// Transactions of the blocks removed from the chain are kept there too
+    pub fn find_raw_side_transaction(
+        &self,
+        transaction_hash: &Hash,
+    ) -> Result<Option<Vec<u8>>, Report<BlockChainTreeError>> {
+        Ok(self
+            .branch_transactions
+            .get(transaction_hash)
+            .change_context(BlockChainTreeError::Chain(ChainErrorKind::FindByHashE))?
+            .map(|dump| dump.to_vec()))
+    }
+
+    /// Get the blocks that adding `block` could apply, from `block` down to the fork point
+    ///
+    /// The walk stops at the first parent that is neither in the chain nor a known side block
+    pub fn side_branch(
+        &self,
+        block: BlockArc,
+    ) -> Result<Vec<BlockArc>, Report<BlockChainTreeError>> {
+        let mut branch = vec![block];
+        loop {
+            let previous_hash = branch[branch.len() - 1].get_info().previous_hash;
+            if self.get_height_reference(&previous_hash)?.is_some() {
+                return Ok(branch);
+            }
+            match self.find_side_block(&previous_hash)? {
+                Some(previous_block) => branch.push(previous_block),
+                None => return Ok(branch),
+            }
+        }
+    }
+
+    /// Get deserialized side block by it's hash
+    ///
+    /// Side blocks are the blocks of the known branches that are not part of the chain
+    pub fn find_side_block(
+        &self,
+        hash: &Hash,
+    ) -> Result<Option<BlockArc>, Report<BlockChainTreeError>> {
+        let dump = self.find_raw_side_block(hash)?;
+
+        dump.map(|dump| {
+            block::deserialize_main_chain_block(&dump)
+                .change_context(BlockChainTreeError::Chain(ChainErrorKind::FindByHashE))
+                .attach_printable(format!(
+                    "Failed to deserialize side block with hash {:?}",
+                    hash
+                ))
+        })
+        .transpose()
+    }
+
+    /// Get hashes of the side blocks built on top of the block with `previous_hash`
+    pub fn find_side_children(
+        &self,
+        previous_hash: &Hash,
+    ) -> Result<Vec<Hash>, Report<BlockChainTreeError>> {
+        let mut children = Vec::new();
+        for entry in self.branch_children.scan_prefix(previous_hash) {
+            let (key, _) =
+                entry.change_context(BlockChainTreeError::Chain(ChainErrorKind::FindByHashE))?;
+            let child = key
+                .get(32..64)
+                .and_then(|child| child.try_into().ok())
+                .ok_or(BlockChainTreeError::Chain(ChainErrorKind::FindByHashE))
+                .attach_printable("Malformed key in the branch children tree")?;
+            children.push(child);
+        }
+        Ok(children)
+    }
+
+    /// Get cumulative work of the known block, either in the chain or in a side branch
+    pub fn get_chain_work(&self, hash: &Hash) -> Result<Option<U256>, Report<BlockChainTreeError>> {
+        let work = self
+            .chain_work
+            .get(hash)
+            .change_context(BlockChainTreeError::Chain(ChainErrorKind::FindByHashE))?;
+
+        Ok(work.map(|work| U256::from_big_endian(&work)))
+    }
+
+    fn get_height_reference(
+        &self,
+        hash: &Hash,
+    ) -> Result<Option<U256>, Report<BlockChainTreeError>> {
+        let height = self
+            .height_reference
+            .get(hash)
+            .change_context(BlockChainTreeError::Chain(ChainErrorKind::FindByHashE))?;
+
+        Ok(height.map(|height| U256::from_big_endian(&height)))
+    }
+
+    /// Get block dump at height without taking the height lock
+    fn get_raw_at(&self, height: &U256) -> Result<Option<Vec<u8>>, Report<BlockChainTreeError>> {
+        let mut height_serialized = [0u8; 32];
+        height.to_big_endian(&mut height_serialized);
+        let dump = self
+            .blocks
+            .get(height_serialized)
+            .change_context(BlockChainTreeError::Chain(ChainErrorKind::FindByHeight))?;
+
+        Ok(dump.map(|dump| dump.to_vec()))
+    }
+
+    fn tip_hash(&self, height: &U256) -> Result<Hash, Report<BlockChainTreeError>> {
+        let dump = self
+            .get_raw_at(&(*height - 1))?
+            .ok_or(BlockChainTreeError::Chain(ChainErrorKind::FindByHeight))
+            .attach_printable("The last block of the chain is missing")?;
+
+        Ok(tools::hash(&dump))
+    }
+
+    /// Finds block either in the chain or in a side branch without taking the height lock
+    fn find_known_block(
+        &self,
+        hash: &Hash,
+    ) -> Result<Option<BlockArc>, Report<BlockChainTreeError>> {
+        if let Some(height) = self.get_height_reference(hash)? {
+            return self
+                .get_raw_at(&height)?
+                .map(|dump| {
+                    block::deserialize_main_chain_block(&dump)
+                        .change_context(BlockChainTreeError::Chain(ChainErrorKind::FindByHashE))
+                })
+                .transpose();
+        }
+        self.find_side_block(hash)
+    }
+
+    /// Checks whether the block with the `ancestor` hash is the block with the `descendant`
+    /// hash or one of the blocks below it, the blocks can be in the chain or in side branches
+    pub fn is_ancestor(
+        &self,
+        ancestor: &Hash,
+        descendant: &Hash,
+    ) -> Result<bool, Report<BlockChainTreeError>> {
+        let Some(ancestor_block) = self.find_known_block(ancestor)? else {
+            return Ok(false);
+        };
+        let ancestor_height = ancestor_block.get_info().height;
+        let ancestor_reference = self.get_height_reference(ancestor)?;
+
+        let mut hash = *descendant;
+        loop {
+            if hash == *ancestor {
+                return Ok(true);
+            }
+            // blocks of the chain are below each other
+            if let (Some(ancestor_reference), Some(reference)) =
+                (ancestor_reference, self.get_height_reference(&hash)?)
+            {
+                return Ok(ancestor_reference < reference);
+            }
+            let Some(block) = self.find_known_block(&hash)? else {
+                return Ok(false);
+            };
+            if block.get_info().height <= ancestor_height {
+                return Ok(false);
+            }
+            hash = block.get_info().previous_hash;
+        }
     }
 
     /// Get serialized block by it's height
@@ -434,8 +1007,12 @@ impl MainChain {
     }
 }
 
+/// Height, difficulty and genesis hash of a derivative chain
+type DerivativeState = (U256, [u8; 32], [u8; 32]);
+
 #[derive(Clone)]
 pub struct DerivativeChain {
+    root: PathBuf,
     blocks: Db,
     height_reference: Db,
     height: Arc<RwLock<U256>>,
@@ -445,68 +1022,197 @@ pub struct DerivativeChain {
 }
 
 impl DerivativeChain {
+    /// Opens the derivative chain in the default data directory
     pub fn new(
         chain_owner: &str,
         provided_genesis_hash: &[u8; 32],
     ) -> Result<Self, Report<BlockChainTreeError>> {
-        let root = String::from(DERIVATIVE_CHAINS_DIRECTORY) + chain_owner + "/";
+        Self::open(BLOCKCHAIN_DIRECTORY, chain_owner, provided_genesis_hash)
+    }
 
-        let path_blocks_st = root.clone() + BLOCKS_FOLDER;
-        let path_references_st = root.clone() + REFERENCES_FOLDER;
-        let path_height_st = root + CONFIG_FILE;
+    /// Opens the derivative chain stored under the provided data directory,
+    /// creating it on top of `provided_genesis_hash` if it doesn't exist
+    ///
+    /// The genesis hash of an existing chain is kept
+    pub fn open(
+        data_directory: impl AsRef<Path>,
+        chain_owner: &str,
+        provided_genesis_hash: &[u8; 32],
+    ) -> Result<Self, Report<BlockChainTreeError>> {
+        let root = Self::chain_root(data_directory.as_ref(), chain_owner);
+        let (blocks, height_reference) = Self::open_databases(&root)?;
+        match Self::load_state(&root, &blocks)? {
+            Some(state) => Ok(Self::assemble(
+                root,
+                blocks,
+                height_reference,
+                state,
+                chain_owner,
+            )),
+            None => Self::init(
+                root,
+                blocks,
+                height_reference,
+                chain_owner,
+                provided_genesis_hash,
+            ),
+        }
+    }
 
-        let path_blocks = Path::new(&path_blocks_st);
-        let path_reference = Path::new(&path_references_st);
-        let path_height = Path::new(&path_height_st);
+    /// Opens the derivative chain only if it was created before
+    pub fn open_existing(
+        data_directory: impl AsRef<Path>,
+        chain_owner: &str,
+    ) -> Result<Option<Self>, Report<BlockChainTreeError>> {
+        let root = Self::chain_root(data_directory.as_ref(), chain_owner);
+        if !root.exists() {
+            return Ok(None);
+        }
+        let (blocks, height_reference) = Self::open_databases(&root)?;
+        Ok(Self::load_state(&root, &blocks)?
+            .map(|state| Self::assemble(root, blocks, height_reference, state, chain_owner)))
+    }
 
+    /// Creates new derivative chain on top of the main chain block with `genesis_hash`
+    ///
+    /// Fails if the chain already exists. The genesis hash is written to the disk right away
+    pub fn create(
+        data_directory: impl AsRef<Path>,
+        chain_owner: &str,
+        genesis_hash: &[u8; 32],
+    ) -> Result<Self, Report<BlockChainTreeError>> {
+        let root = Self::chain_root(data_directory.as_ref(), chain_owner);
+        let (blocks, height_reference) = Self::open_databases(&root)?;
+        if Self::load_state(&root, &blocks)?.is_some() {
+            return Err(BlockChainTreeError::DerivativeChain(
+                DerivChainErrorKind::Init,
+            ))
+            .attach_printable(format!("derivative chain {} already exists", chain_owner));
+        }
+        Self::init(root, blocks, height_reference, chain_owner, genesis_hash)
+    }
+
+    fn chain_root(data_directory: &Path, chain_owner: &str) -> PathBuf {
+        data_directory
+            .join(DERIVATIVE_CHAINS_DIRECTORY)
+            .join(chain_owner)
+    }
+
+    fn open_databases(root: &Path) -> Result<(Db, Db), Report<BlockChainTreeError>> {
         // open blocks DB
-        let blocks = sled::open(path_blocks)
+        let blocks = sled::open(root.join(BLOCKS_FOLDER))
             .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
             .attach_printable("failed to open blocks db")?;
 
         // open height references DB
-        let height_reference = sled::open(path_reference)
+        let height_reference = sled::open(root.join(REFERENCES_FOLDER))
             .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
             .attach_printable("failed to open references db")?;
 
-        let file = File::open(path_height);
+        Ok((blocks, height_reference))
+    }
+
+    /// Reads height, difficulty and genesis hash of the chain, `None` if it was never created
+    ///
+    /// The height and difficulty are taken from the blocks, the config could be written before the last
+    /// blocks were added. A chain without the config is recovered from its first block,
+    /// which follows the genesis block
+    fn load_state(
+        root: &Path,
+        blocks: &Db,
+    ) -> Result<Option<DerivativeState>, Report<BlockChainTreeError>> {
+        let parse = |dump: &[u8]| {
+            DerivativeBlock::parse(dump.get(1..).unwrap_or_default())
+                .change_context(BlockChainTreeError::DerivativeChain(
+                    DerivChainErrorKind::InitWithoutConfig,
+                ))
+                .attach_printable("failed to recover the chain from its blocks")
+        };
+        let last = blocks
+            .last()
+            .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))?;
+        let height = match &last {
+            Some((key, _)) => U256::from_big_endian(key) + 1,
+            None => U256::zero(),
+        };
 
-        let (height, difficulty, genesis_hash) = if let Ok(mut file) = file {
+        if let Ok(mut file) = File::open(root.join(CONFIG_FILE)) {
+            // the height of the config is superseded by the blocks
             let mut height_bytes: [u8; 32] = [0; 32];
             file.read_exact(&mut height_bytes)
                 .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
                 .attach_printable("failed to read config")?;
 
-            // read difficulty
+            // read difficulty, superseded by the last block as well
             let mut difficulty: [u8; 32] = [0; 32];
             file.read_exact(&mut difficulty)
                 .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
                 .attach_printable("failed to read difficulty")?;
+            if let Some((_, last)) = &last {
+                difficulty = parse(last)?.get_info().difficulty;
+            }
 
-            // read difficulty
+            // read genesis hash
             let mut genesis_hash: [u8; 32] = [0; 32];
             file.read_exact(&mut genesis_hash)
                 .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))
                 .attach_printable("failed to read genesis hash")?;
 
-            (
-                U256::from_big_endian(&height_bytes),
-                difficulty,
-                genesis_hash,
-            )
-        } else {
-            (U256::zero(), BEGINNING_DIFFICULTY, *provided_genesis_hash)
+            return Ok(Some((height, difficulty, genesis_hash)));
+        }
+
+        let (Some((_, first)), Some((_, last))) = (
+            blocks
+                .first()
+                .change_context(BlockChainTreeError::Chain(ChainErrorKind::Init))?,
+            last,
+        ) else {
+            return Ok(None);
         };
+        let genesis_hash = parse(&first)?.get_info().previous_hash;
+        let difficulty = parse(&last)?.get_info().difficulty;
+        Ok(Some((height, difficulty, genesis_hash)))
+    }
 
-        let chain = Self {
+    fn assemble(
+        root: PathBuf,
+        blocks: Db,
+        height_reference: Db,
+        (height, difficulty, genesis_hash): DerivativeState,
+        chain_owner: &str,
+    ) -> Self {
+        Self {
+            root,
             blocks,
             height_reference,
             height: Arc::new(RwLock::new(height)),
             difficulty: Arc::new(RwLock::new(difficulty)),
             genesis_hash: Arc::new(genesis_hash),
             chain_owner: chain_owner.to_string(),
-        };
+        }
+    }
 
+    /// Sets up the empty chain and writes its config,
+    /// so the genesis hash survives even without a flush
+    fn init(
+        root: PathBuf,
+        blocks: Db,
+        height_reference: Db,
+        chain_owner: &str,
+        genesis_hash: &[u8; 32],
+    ) -> Result<Self, Report<BlockChainTreeError>> {
+        let chain = Self::assemble(
+            root,
+            blocks,
+            height_reference,
+            (U256::zero(), BEGINNING_DIFFICULTY, *genesis_hash),
+            chain_owner,
+        );
+        std::fs::write(chain.root.join(CONFIG_FILE), chain.config_dump())
+            .change_context(BlockChainTreeError::DerivativeChain(
+                DerivChainErrorKind::Init,
+            ))
+            .attach_printable("failed to write config")?;
         Ok(chain)
     }
 
@@ -514,12 +1220,22 @@ impl DerivativeChain {
         *self.height.read()
     }
 
+    /// Height, difficulty and genesis hash, as stored in the config
+    fn config_dump(&self) -> Vec<u8> {
+        let mut dump = Vec::with_capacity(96);
+        let mut buffer_32_bytes: [u8; 32] = [0; 32];
+        self.height.read().to_big_endian(&mut buffer_32_bytes);
+        dump.extend(buffer_32_bytes);
+        dump.extend(*self.difficulty.read());
+        dump.extend(self.genesis_hash.as_ref());
+        dump
+    }
+
     /// Dump config
     ///
     /// Dumps chain's config
     async fn dump_config(&self) -> Result<(), Report<BlockChainTreeError>> {
-        let root = String::from(DERIVATIVE_CHAINS_DIRECTORY) + &self.chain_owner + "/";
-        let path_config = root + CONFIG_FILE;
+        let path_config = self.root.join(CONFIG_FILE);
 
         let mut file = OpenOptions::new()
             .write(true)
@@ -528,22 +1244,10 @@ impl DerivativeChain {
             .open(path_config)
             .await
             .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))?;
-        let mut buffer_32_bytes: [u8; 32] = [0; 32];
-        self.height.read().to_big_endian(&mut buffer_32_bytes);
-        file.write_all(&buffer_32_bytes)
+        file.write_all(&self.config_dump())
             .await
             .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))
-            .attach_printable("failed to write height")?;
-
-        file.write_all(self.difficulty.read().as_ref())
-            .await
-            .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))
-            .attach_printable("failed to write difficulty")?;
-
-        file.write_all(self.genesis_hash.as_ref())
-            .await
-            .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))
-            .attach_printable("failed to write genesis hash")?;
+            .attach_printable("failed to write config")?;
 
         Ok(())
     }
@@ -552,16 +1256,12 @@ impl DerivativeChain {
     pub async fn flush(&self) -> Result<(), Report<BlockChainTreeError>> {
         self.dump_config().await?;
 
-        self.blocks
-            .flush_async()
+        flush_db(&self.blocks)
             .await
-            .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))
             .attach_printable("failed to flush db")?;
 
-        self.height_reference
-            .flush_async()
+        flush_db(&self.height_reference)
             .await
-            .change_context(BlockChainTreeError::Chain(ChainErrorKind::DumpConfig))
             .attach_printable("failed to flush height references")?;
 
         Ok(())
@@ -571,7 +1271,8 @@ impl DerivativeChain {
     ///
     /// Adds block and sets heigh reference for it
     ///
-    /// Checks for blocks validity, adds it directly to the end of the chain
+    /// Validates the block against the last block of the chain (or the genesis block
+    /// for the first one), adds it directly to the end of the chain
     pub fn add_block(&self, block: &DerivativeBlock) -> Result<(), Report<BlockChainTreeError>> {
         let dump = block
             .dump()
@@ -587,6 +1288,32 @@ impl DerivativeChain {
             );
         }
 
+        let last_block = if height.is_zero() {
+            // the first block is mined on top of the genesis block from the main chain
+            if block.get_info().previous_hash != *self.genesis_hash {
+                return Err(BlockChainTreeError::Chain(ChainErrorKind::FailedToVerify))
+                    .attach_printable("The first block doesn't follow the genesis block");
+            }
+            None
+        } else {
+            let mut last_height_bytes = [0u8; 32];
+            (*height - 1).to_big_endian(&mut last_height_bytes);
+            let last_dump = self
+                .blocks
+                .get(last_height_bytes)
+                .change_context(BlockChainTreeError::Chain(ChainErrorKind::FindByHeight))?
+                .ok_or(BlockChainTreeError::Chain(ChainErrorKind::FindByHeight))
+                .attach_printable("The last block of the chain is missing")?;
+            let last_block: BlockArc = Arc::new(
+                DerivativeBlock::parse(&last_dump[1..])
+                    .change_context(BlockChainTreeError::Chain(ChainErrorKind::FindByHeight))?,
+            );
+            Some(last_block)
+        };
+        block
+            .validate(last_block)
+            .change_context(BlockChainTreeError::Chain(ChainErrorKind::FailedToVerify))?;
+
         let mut height_bytes = [0u8; 32];
         height.to_big_endian(&mut height_bytes);
 
@@ -601,10 +1328,16 @@ impl DerivativeChain {
             .attach_printable("Failed to insert height reference for the block")?;
 
         *height += U256::one();
+        *self.difficulty.write() = block.get_info().difficulty;
 
         Ok(())
     }
 
+    /// Difficulty of the last block, the pow of the next block has to satisfy it
+    pub fn get_difficulty(&self) -> [u8; 32] {
+        *self.difficulty.read()
+    }
+
     /// Get serialized block by it's height
     pub fn find_raw_by_height(
         &self,
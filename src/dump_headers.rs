@@ -2,6 +2,7 @@ use crate::errors::{DumpHeadersError, DumpHeadersErrorKind};
 use error_stack::{Report, Result};
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Headers {
     Transaction = 0,
     Token = 1,
@@ -62,7 +62,8 @@ root_errors![
     },
 
     TransactionError : "Error ocurred while operating on a transaction" {
-        Tx(TxErrorKind)
+        Tx(TxErrorKind),
+        Token(TokenErrorKind)
     },
 
     MerkleTreeError : "Error ocurred while operating on the merkletree" {
@@ -76,7 +77,7 @@ root_errors![
         DerivativeBlock(DerivativeBlockErrorKind),
         SummarizeBlock(SummarizeBlockErrorKind),
         HeaderError(DumpHeadersErrorKind),
-        NotImplemented(NotImplementedKind)
+        Validation(ValidationErrorKind)
     },
 
     BlockChainTreeError : "Error ocurred while operating on the blockchain tree" {
@@ -85,15 +86,28 @@ root_errors![
         BlockChainTree(BCTreeErrorKind)
     },
 
+    NodeError : "Error ocurred while running the node" {
+        Node(NodeErrorKind)
+    },
+
+    RpcError : "Error ocurred in the RPC server" {
+        Rpc(RpcErrorKind)
+    },
+
+    P2pError : "Error ocurred in the peer-to-peer network" {
+        P2p(P2pErrorKind)
+    },
+
+    WalletError : "Error ocurred while operating on the wallet" {
+        Wallet(WalletErrorKind)
+    },
+
     DumpHeadersError : "Error with dump header"{
         DumpHeadersError(DumpHeadersErrorKind)
     }
 ];
 
 sub_errors![
-    NotImplementedKind {
-        Token: "Token is not implemented yet"
-    },
     DumpHeadersErrorKind {
         UknownHeader: "Uknown header",
         WrongHeader: "Wrong header"
@@ -109,7 +123,17 @@ sub_errors![
     TxErrorKind {
         Verify: "failed to verify transaction",
         Dump: "failed to dump transaction (amount)",
-        Parse: "failed to parse transaction"
+        Parse: "failed to parse transaction",
+        AlreadyInPool: "transaction is already in the pool",
+        SenderLimit: "too many pending transactions of the sender",
+        LowFee: "fee per byte is too low to enter the full pool"
+    },
+    TokenErrorKind {
+        Dump: "failed to dump token action",
+        Parse: "failed to parse token action",
+        ParseToken: "failed to parse token",
+        UnknownAction: "unknown token action",
+        Sign: "failed to sign token action"
     },
     MerkleTreeErrorKind {
         GettingProof: "failed to get proof"
@@ -137,6 +161,16 @@ sub_errors![
         Parse: "failed to parse",
         Hash: "failed to hash (couldn't dump)"
     },
+    ValidationErrorKind {
+        PreviousHash: "previous hash doesn't match the hash of the previous block",
+        MerkleRoot: "merkle root doesn't match the transactions",
+        Difficulty: "difficulty doesn't follow the retarget rules",
+        Pow: "pow doesn't satisfy the difficulty of the previous block",
+        Timestamp: "timestamp is earlier than the timestamp of the previous block",
+        FutureTimestamp: "timestamp is too far ahead of the local clock",
+        Height: "height doesn't follow the height of the previous block",
+        Signature: "transaction of the block has an invalid signature"
+    },
     ChainErrorKind {
         Init: "failed to create a new chain",
         AddingBlock: "failed to add block",
@@ -149,7 +183,9 @@ sub_errors![
         FailedToVerify: "failed to verify block",
         FailedToHashBlock: "failed to hash block",
         FailedToRemoveHeighReference: "failed to remove height reference",
-        FailedToRemoveTransaction: "failed to remove transaction"
+        FailedToRemoveTransaction: "failed to remove transaction",
+        Reorganize: "failed to reorganize the chain",
+        OutdatedLayout: "data directory has an outdated layout"
     },
     DerivChainErrorKind {
         Init: "failed to create a new derivative chain",
@@ -159,6 +195,40 @@ sub_errors![
         DumpConfig: "failed to dump config",
         InitWithoutConfig: "failed to create a new chain without config"
     },
+    NodeErrorKind {
+        Config: "failed to load the node config",
+        Open: "failed to open the blockchain tree",
+        Mining: "failed to mine a block",
+        Flush: "failed to flush the blockchain tree",
+        Task: "node task has failed",
+        Rpc: "failed to start the RPC server",
+        P2p: "failed to start the peer-to-peer network"
+    },
+    RpcErrorKind {
+        Register: "failed to register RPC method",
+        Bind: "failed to bind the RPC server"
+    },
+    WalletErrorKind {
+        PrivateKey: "invalid private key"
+    },
+    P2pErrorKind {
+        Bind: "failed to listen for peers",
+        Connect: "failed to connect to the peer",
+        Handshake: "handshake with the peer failed",
+        Read: "failed to read a message from the peer",
+        Write: "failed to send a message to the peer",
+        Parse: "failed to parse the message",
+        TooLarge: "message exceeds the size limit",
+        Disconnected: "peer is disconnected",
+        Timeout: "peer didn't respond in time",
+        InvalidData: "peer sent invalid data",
+        Malformed: "peer sent data that can't be parsed",
+        InvalidBlock: "peer sent a block failing the validation",
+        BadSignature: "peer sent a transaction with a wrong signature",
+        Rejected: "item of the peer is rejected by the blockchain tree",
+        Banned: "peer is banned",
+        Tree: "failed to access the blockchain tree"
+    },
     BCTreeErrorKind {
         Init: "failed to init the blockchain tree (with config)",
         InitWithoutConfig: "failed to init the blockchain tree (with config)",
@@ -171,10 +241,18 @@ sub_errors![
         DecreaseFunds: "failed to decrease funds",
         GetFunds: "failed to get funds",
         GetOldFunds: "failed to get funds from old summary db",
+        GetNonce: "failed to get nonce",
         MoveSummaryDB: "failed to move summary database",
         NewTransaction: "failed to create new transaction",
         CreateMainChainBlock: "failed to create new block for the main chain",
         WrongPow: "supplied pow does not satisfy requirements",
-        SummarizeBlockWrongTransactionsAmount: "summarization block should not have transactions"
+        SummarizeBlockWrongTransactionsAmount: "summarization block should not have transactions",
+        ApplyBlock: "failed to apply block to the summary databases",
+        RevertBlock: "failed to revert block from the summary databases",
+        GetAnchors: "failed to get the anchors of the derivative chain",
+        GetToken: "failed to get the token",
+        TokenAction: "token action can't be applied",
+        AuditToken: "token supply doesn't match the balances",
+        VerifyDerivChain: "derivative chain doesn't match its anchors"
     }
 ];
@@ -0,0 +1,53 @@
+//! Changes of the chain state broadcast by `BlockChainTree`
+//!
+//! Subscribers get every event sent after `BlockChainTree::subscribe`.
+//! A subscriber falling behind by more than `EVENTS_CAPACITY` events
+//! loses the oldest ones and gets `RecvError::Lagged` from the receiver
+
+use primitive_types::U256;
+use tokio::sync::broadcast;
+
+use crate::block::BlockArc;
+use crate::types::{Address, Hash};
+
+/// Kind of the balance of an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Balance {
+    Amount,
+    Gas,
+}
+
+/// Change of the chain state made by `BlockChainTree`
+///
+/// Events are sent after the change is committed, in the order of the changes
+#[derive(Clone)]
+pub enum Event {
+    /// Block applied to the main chain, including the summarization blocks
+    BlockAdded(BlockArc),
+    /// Block removed from the main chain by a reorganization
+    ///
+    /// Sent before the blocks of the new branch are added
+    BlockReverted(BlockArc),
+    /// Block added to the derivative chain of its founder
+    DerivativeBlockAdded(BlockArc),
+    /// Transaction admitted into the pool
+    TransactionAccepted {
+        hash: Hash,
+        sender: Address,
+        nonce: u64,
+        fee: U256,
+    },
+    /// New value of the balance, sent once per change of the chain
+    BalanceChanged {
+        owner: Address,
+        balance: Balance,
+        value: U256,
+    },
+    /// Summaries were rotated by the summarization block
+    EpochRotated(BlockArc),
+    /// Derivative chain of the owner was created on top of the main chain block
+    DerivativeChainCreated { owner: Address, genesis_hash: Hash },
+}
+
+pub type EventSender = broadcast::Sender<Event>;
+pub type EventReceiver = broadcast::Receiver<Event>;
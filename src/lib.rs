@@ -4,10 +4,17 @@ pub mod blockchaintree;
 pub mod chain;
 pub mod dump_headers;
 pub mod errors;
+pub mod events;
 pub mod merkletree;
+pub mod miner;
+pub mod node;
+pub mod p2p;
+pub mod rpc;
 pub mod static_values;
 pub mod summary_db;
+pub mod token;
 pub mod tools;
 pub mod transaction;
 pub mod txpool;
 pub mod types;
+pub mod wallet;
@@ -0,0 +1,169 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::blockchaintree::BlockTemplate;
+use crate::tools;
+use crate::types::Hash;
+
+/// Amount of hashes a worker does between checks for cancellation
+static CHECK_INTERVAL: u64 = 1024;
+
+/// Pow preimage and difficulty the pow has to be found for
+///
+/// The preimage is the one of `block::pow_preimage` for main chain blocks,
+/// or the one of `DerivativeBlock::pow_preimage` for derivative ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MiningTarget {
+    pub pow_preimage: Hash,
+    pub difficulty: Hash,
+}
+
+impl From<&BlockTemplate> for MiningTarget {
+    fn from(template: &BlockTemplate) -> Self {
+        MiningTarget {
+            pow_preimage: template.pow_preimage,
+            difficulty: template.difficulty,
+        }
+    }
+}
+
+/// Found pow with the statistics of the run
+#[derive(Debug, Clone, Copy)]
+pub struct MiningResult {
+    pub pow: [u8; 32],
+    /// Amount of pows checked by all the workers
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningResult {
+    /// Hashes per second
+    pub fn hashrate(&self) -> f64 {
+        self.hashes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// CPU miner splitting the nonce space between worker threads
+///
+/// Worker `i` of `n` checks nonces `i, i + n, i + 2n, ...` put into the last 8 bytes of the pow.
+/// Clones share the state, so the run can be cancelled from another thread
+#[derive(Clone)]
+pub struct Miner {
+    threads: usize,
+    cancelled: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    hashes: Arc<AtomicU64>,
+}
+
+impl Default for Miner {
+    /// Miner with a worker for every available core
+    fn default() -> Self {
+        Miner::new(thread::available_parallelism().map_or(1, |threads| threads.get()))
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            threads: threads.max(1),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            hashes: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn get_threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Stops the current run, `mine` returns `None`
+    ///
+    /// A cancel sent after `reset` also stops the run that hasn't started yet
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Clears the cancel of the previous run, called when the next run is set up
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+
+    /// Stops the current run and all the following ones
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Amount of pows checked by the current run so far
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    /// Searches for the pow satisfying the target, blocking until it is found
+    ///
+    /// Returns `None` if the run was cancelled since the last `reset`, the miner is stopped
+    /// or the nonce space is exhausted
+    pub fn mine(&self, target: &MiningTarget) -> Option<MiningResult> {
+        if self.cancelled.load(Ordering::Relaxed) || self.is_stopped() {
+            return None;
+        }
+        self.hashes.store(0, Ordering::Relaxed);
+
+        let start = Instant::now();
+        let found: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            for worker in 0..self.threads {
+                let (found, stop) = (&found, &stop);
+                scope.spawn(move || self.work(worker as u64, target, found, stop));
+            }
+        });
+
+        let pow = found.into_inner().unwrap()?;
+        Some(MiningResult {
+            pow,
+            hashes: self.hashes(),
+            elapsed: start.elapsed(),
+        })
+    }
+
+    fn work(
+        &self,
+        first_nonce: u64,
+        target: &MiningTarget,
+        found: &Mutex<Option<[u8; 32]>>,
+        stop: &AtomicBool,
+    ) {
+        let step = self.threads as u64;
+        let mut pow = [0u8; 32];
+        let mut nonce = Some(first_nonce);
+        let mut done = 0u64;
+        while let Some(current) = nonce {
+            if done == CHECK_INTERVAL {
+                self.hashes.fetch_add(done, Ordering::Relaxed);
+                done = 0;
+                if stop.load(Ordering::Relaxed)
+                    || self.cancelled.load(Ordering::Relaxed)
+                    || self.is_stopped()
+                {
+                    return;
+                }
+            }
+
+            pow[24..].copy_from_slice(&current.to_be_bytes());
+            done += 1;
+            if tools::check_pow(&target.pow_preimage, &target.difficulty, &pow) {
+                *found.lock().unwrap() = Some(pow);
+                stop.store(true, Ordering::Relaxed);
+                break;
+            }
+            nonce = current.checked_add(step);
+        }
+        self.hashes.fetch_add(done, Ordering::Relaxed);
+    }
+}
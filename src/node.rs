@@ -0,0 +1,316 @@
+use std::fs;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use error_stack::{Report, Result, ResultExt};
+use log::{error, info, warn};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{watch, RwLock};
+use tokio::task::{JoinError, JoinSet};
+
+use crate::blockchaintree::BlockChainTree;
+use crate::errors::{NodeError, NodeErrorKind};
+use crate::events::Event;
+use crate::miner::{Miner, MiningTarget};
+use crate::p2p::sync::ChainSync;
+use crate::p2p::{Network, P2pConfig};
+use crate::rpc;
+use crate::static_values::BLOCKCHAIN_DIRECTORY;
+use crate::tools;
+use crate::txpool::TxPoolConfig;
+use crate::types::Address;
+
+/// Blockchain tree shared between the services of the node
+pub type SharedTree = Arc<RwLock<BlockChainTree>>;
+
+/// Settings of the node, read from a TOML file
+///
+/// Every field is optional, missing ones take the default values
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub data_directory: PathBuf,
+    /// Seconds between flushes of the databases and the pool to the disk
+    pub flush_interval: u64,
+    pub pool: TxPoolConfig,
+    pub miner: MinerConfig,
+    pub rpc: RpcConfig,
+    pub p2p: P2pConfig,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            data_directory: PathBuf::from(BLOCKCHAIN_DIRECTORY),
+            flush_interval: 60,
+            pool: TxPoolConfig::default(),
+            miner: MinerConfig::default(),
+            rpc: RpcConfig::default(),
+            p2p: P2pConfig::default(),
+        }
+    }
+}
+
+impl NodeConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<NodeConfig, NodeError> {
+        let content = fs::read_to_string(path.as_ref())
+            .change_context(NodeError::Node(NodeErrorKind::Config))
+            .attach_printable_lazy(|| format!("failed to read {}", path.as_ref().display()))?;
+        toml::from_str(&content).change_context(NodeError::Node(NodeErrorKind::Config))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MinerConfig {
+    pub enabled: bool,
+    /// Amount of worker threads, 0 for a worker per core
+    pub threads: usize,
+    /// Hex encoded address receiving the rewards
+    pub founder: Option<String>,
+}
+
+impl MinerConfig {
+    fn founder(&self) -> Result<Address, NodeError> {
+        let founder = self
+            .founder
+            .as_ref()
+            .ok_or(Report::new(NodeError::Node(NodeErrorKind::Config)))
+            .attach_printable("miner is enabled without the founder address")?;
+        hex::decode(founder)
+            .change_context(NodeError::Node(NodeErrorKind::Config))?
+            .try_into()
+            .map_err(|_| Report::new(NodeError::Node(NodeErrorKind::Config)))
+            .attach_printable("founder address should be 33 bytes long")
+    }
+
+    fn miner(&self) -> Miner {
+        match self.threads {
+            0 => Miner::default(),
+            threads => Miner::new(threads),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    pub enabled: bool,
+    pub listen: SocketAddr,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        RpcConfig {
+            enabled: false,
+            listen: SocketAddr::from(([127, 0, 0, 1], 8335)),
+        }
+    }
+}
+
+/// Long-running node, serving the blockchain tree until the shutdown
+pub struct Node {
+    config: NodeConfig,
+    tree: SharedTree,
+}
+
+impl Node {
+    pub fn open(config: NodeConfig) -> Result<Node, NodeError> {
+        if config.miner.enabled {
+            config.miner.founder()?;
+        }
+        let tree = BlockChainTree::open_with_pool_config(&config.data_directory, config.pool)
+            .change_context(NodeError::Node(NodeErrorKind::Open))?;
+
+        Ok(Node {
+            config,
+            tree: Arc::new(RwLock::new(tree)),
+        })
+    }
+
+    pub fn get_tree(&self) -> SharedTree {
+        self.tree.clone()
+    }
+
+    /// Runs the services of the node until `shutdown` completes or one of them fails
+    ///
+    /// The tree is flushed before returning
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> Result<(), NodeError> {
+        let rpc_server = if self.config.rpc.enabled {
+            let (address, handle) = rpc::start(self.config.rpc.listen, self.tree.clone())
+                .await
+                .change_context(NodeError::Node(NodeErrorKind::Rpc))?;
+            info!("RPC server is listening on {address}");
+            Some(handle)
+        } else {
+            None
+        };
+
+        let (network, sync) = if self.config.p2p.enabled {
+            let network = Network::start(&self.config.p2p, self.tree.clone())
+                .await
+                .change_context(NodeError::Node(NodeErrorKind::P2p))?;
+            info!("accepting peers on {}", network.local_addr());
+            let sync = ChainSync::new(network.clone())
+                .await
+                .change_context(NodeError::Node(NodeErrorKind::P2p))?;
+            (Some(network), Some(sync))
+        } else {
+            (None, None)
+        };
+
+        let (stop_sender, stop) = watch::channel(false);
+        let mut tasks: JoinSet<Result<(), NodeError>> = JoinSet::new();
+
+        let flush_interval = Duration::from_secs(self.config.flush_interval.max(1));
+        tasks.spawn(maintain(self.tree.clone(), flush_interval, stop.clone()));
+
+        if let Some(sync) = sync {
+            tasks.spawn(async move {
+                sync.run(stop).await;
+                Ok(())
+            });
+        }
+
+        let miner = if self.config.miner.enabled {
+            let miner = self.config.miner.miner();
+            let founder = self.config.miner.founder()?;
+            info!(
+                "mining with {} threads for {}",
+                miner.get_threads(),
+                hex::encode(founder)
+            );
+            tasks.spawn(mine_blocks(self.tree.clone(), miner.clone(), founder));
+            Some(miner)
+        } else {
+            None
+        };
+
+        let mut outcome = tokio::select! {
+            _ = shutdown => Ok(()),
+            Some(result) = tasks.join_next() => flatten(result),
+        };
+
+        info!("shutting down");
+        let _ = stop_sender.send(true);
+        if let Some(miner) = miner {
+            miner.stop();
+        }
+        if let Some(handle) = rpc_server {
+            // fails only if the server is already stopped
+            let _ = handle.stop();
+            handle.stopped().await;
+        }
+        if let Some(network) = network {
+            network.shutdown().await;
+        }
+        while let Some(result) = tasks.join_next().await {
+            if let Err(report) = flatten(result) {
+                error!("{report:?}");
+                outcome = outcome.and(Err(report));
+            }
+        }
+
+        self.tree
+            .read()
+            .await
+            .flush()
+            .await
+            .change_context(NodeError::Node(NodeErrorKind::Flush))?;
+        outcome
+    }
+}
+
+/// Merges the panic of the task into its result
+fn flatten(result: std::result::Result<Result<(), NodeError>, JoinError>) -> Result<(), NodeError> {
+    result.change_context(NodeError::Node(NodeErrorKind::Task))?
+}
+
+/// Removes expired transactions from the pool and flushes the tree periodically
+async fn maintain(
+    tree: SharedTree,
+    period: Duration,
+    mut stop: watch::Receiver<bool>,
+) -> Result<(), NodeError> {
+    let mut interval = tokio::time::interval(period);
+    // the first tick completes immediately
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = stop.changed() => return Ok(()),
+            _ = interval.tick() => {
+                let tree = tree.read().await;
+                let expired = tree
+                    .get_pool()
+                    .write()
+                    .await
+                    .remove_expired(tools::current_timestamp());
+                if expired > 0 {
+                    info!("{expired} transactions expired in the pool");
+                }
+                tree.flush()
+                    .await
+                    .change_context(NodeError::Node(NodeErrorKind::Flush))?;
+            }
+        }
+    }
+}
+
+/// Mines main chain blocks until the miner is stopped
+///
+/// The run is cancelled once another block is added, the template is stale then
+async fn mine_blocks(tree: SharedTree, miner: Miner, founder: Address) -> Result<(), NodeError> {
+    let mut events = tree.read().await.subscribe();
+    while !miner.is_stopped() {
+        // the blocks added so far are in the next template
+        while !matches!(
+            events.try_recv(),
+            Err(TryRecvError::Empty | TryRecvError::Closed)
+        ) {}
+        miner.reset();
+        let template = tree
+            .read()
+            .await
+            .block_template(&founder)
+            .await
+            .change_context(NodeError::Node(NodeErrorKind::Mining))?;
+
+        let target = MiningTarget::from(&template);
+        let run = miner.clone();
+        let mut mining = tokio::task::spawn_blocking(move || run.mine(&target));
+        let result = loop {
+            tokio::select! {
+                result = &mut mining => break result,
+                event = events.recv() => match event {
+                    Ok(Event::BlockAdded(_)) | Err(RecvError::Lagged(_)) => miner.cancel(),
+                    Ok(_) => {}
+                    Err(RecvError::Closed) => break (&mut mining).await,
+                },
+            }
+        };
+        let Some(result) = result.change_context(NodeError::Node(NodeErrorKind::Task))? else {
+            continue;
+        };
+
+        // the template could have become stale while mining
+        match tree
+            .write()
+            .await
+            .submit_template(&template, &result.pow)
+            .await
+        {
+            Ok(_) => info!(
+                "mined block {} with {} transactions, {:.0} H/s",
+                template.height,
+                template.transactions.len(),
+                result.hashrate()
+            ),
+            Err(report) => warn!("mined block was rejected: {report:?}"),
+        }
+    }
+    Ok(())
+}
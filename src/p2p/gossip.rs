@@ -0,0 +1,75 @@
+//! Relay of transactions and blocks between the peers
+//!
+//! New items are announced by their hashes with `Message::Inventory`, a peer fetches
+//! the ones it doesn't have with `Message::GetData`. Items known to a peer, either
+//! announced by it or to it, aren't announced to it again
+
+use std::collections::{HashSet, VecDeque};
+use std::time::Instant;
+
+use crate::events::Event;
+use crate::static_values::{INVENTORY_RATE, KNOWN_INVENTORY_SIZE};
+
+use super::message::{Inventory, InventoryKind};
+
+/// Relay state of a single peer
+pub(crate) struct PeerRelay {
+    known: HashSet<Inventory>,
+    /// Known items in the order they were added, the oldest ones are forgotten first
+    order: VecDeque<Inventory>,
+    /// Announced items the peer can still send, refilled with `INVENTORY_RATE` per second
+    allowance: f64,
+    updated: Instant,
+}
+
+impl PeerRelay {
+    pub fn new() -> PeerRelay {
+        PeerRelay {
+            known: HashSet::new(),
+            order: VecDeque::new(),
+            allowance: INVENTORY_RATE as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Remembers the item as known to the peer, returns `false` if it already was
+    pub fn mark_known(&mut self, item: Inventory) -> bool {
+        if !self.known.insert(item) {
+            return false;
+        }
+        self.order.push_back(item);
+        if self.order.len() > KNOWN_INVENTORY_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.known.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Takes the allowance for `amount` announced items, returns how many of them are allowed
+    pub fn allow(&mut self, amount: usize) -> usize {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * INVENTORY_RATE as f64;
+        self.allowance = (self.allowance + refill).min(INVENTORY_RATE as f64);
+        self.updated = now;
+
+        let allowed = (amount as f64).min(self.allowance.floor());
+        self.allowance -= allowed;
+        allowed as usize
+    }
+}
+
+/// Item announced to the peers on the event
+pub(crate) fn announced_item(event: &Event) -> Option<Inventory> {
+    match event {
+        Event::TransactionAccepted { hash, .. } => Some(Inventory {
+            kind: InventoryKind::Transaction,
+            hash: *hash,
+        }),
+        Event::BlockAdded(block) => block.hash().ok().map(|hash| Inventory {
+            kind: InventoryKind::Block,
+            hash,
+        }),
+        _ => None,
+    }
+}
@@ -0,0 +1,225 @@
+//! Known addresses of the peers and bans of the misbehaving ones
+//!
+//! Both are kept in the main chain database, so they survive a restart.
+//! Every peer has a score growing with its misbehaviour, once it reaches `BAN_SCORE`
+//! the address of the peer is banned for `BAN_DURATION` seconds
+
+use std::net::{IpAddr, SocketAddr};
+
+use error_stack::{Report, Result, ResultExt};
+use sled::Tree;
+
+use crate::chain::MainChain;
+use crate::errors::{P2pError, P2pErrorKind};
+use crate::static_values::{MAX_ADDRESS_FAILURES, PEERS_TREE, PEER_BANS_TREE};
+
+/// Misbehaviour of a peer, raising its score
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehaviour {
+    /// Message or dump that can't be parsed
+    Malformed,
+    /// Block failing the validation against its parent
+    InvalidBlock,
+    /// Transaction with a wrong signature
+    BadSignature,
+    /// Data not matching the request, like a block not matching its header
+    InvalidData,
+}
+
+impl Misbehaviour {
+    /// Misbehaviour the error is caused by, `None` if the peer isn't to blame
+    pub fn of(report: &Report<P2pError>) -> Option<Misbehaviour> {
+        match report.current_context() {
+            P2pError::P2p(
+                P2pErrorKind::Parse | P2pErrorKind::TooLarge | P2pErrorKind::Malformed,
+            ) => Some(Misbehaviour::Malformed),
+            P2pError::P2p(P2pErrorKind::InvalidBlock) => Some(Misbehaviour::InvalidBlock),
+            P2pError::P2p(P2pErrorKind::BadSignature) => Some(Misbehaviour::BadSignature),
+            P2pError::P2p(P2pErrorKind::InvalidData) => Some(Misbehaviour::InvalidData),
+            _ => None,
+        }
+    }
+
+    /// Score added to the peer, see `BAN_SCORE`
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Misbehaviour::Malformed => 50,
+            Misbehaviour::InvalidBlock => 100,
+            Misbehaviour::BadSignature => 100,
+            Misbehaviour::InvalidData => 20,
+        }
+    }
+}
+
+/// Address of a peer accepting connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownAddress {
+    pub address: SocketAddr,
+    /// Timestamp of the last successful connection, 0 if there was none
+    pub last_seen: u64,
+    /// Failed connection attempts since the last successful one
+    pub failures: u8,
+}
+
+fn dump_address(address: &KnownAddress) -> Vec<u8> {
+    let mut dump = Vec::with_capacity(9);
+    dump.extend(address.last_seen.to_be_bytes());
+    dump.push(address.failures);
+    dump
+}
+
+fn parse_address(key: &[u8], dump: &[u8]) -> Result<KnownAddress, P2pError> {
+    let address = std::str::from_utf8(key)
+        .ok()
+        .and_then(|address| address.parse().ok())
+        .ok_or(Report::new(P2pError::P2p(P2pErrorKind::Tree)))
+        .attach_printable("stored peer address can't be parsed")?;
+    if dump.len() != 9 {
+        return Err(Report::new(P2pError::P2p(P2pErrorKind::Tree)))
+            .attach_printable("stored peer address has a wrong size");
+    }
+    Ok(KnownAddress {
+        address,
+        last_seen: u64::from_be_bytes(dump[..8].try_into().unwrap()),
+        failures: dump[8],
+    })
+}
+
+/// Persisted addresses and bans, see the module docs
+pub struct PeerManager {
+    /// Known addresses by their text form
+    addresses: Tree,
+    /// Timestamps the bans end at, by the text form of the IP
+    bans: Tree,
+}
+
+impl PeerManager {
+    pub fn open(main_chain: &MainChain) -> Result<PeerManager, P2pError> {
+        Ok(PeerManager {
+            addresses: main_chain
+                .open_tree(PEERS_TREE)
+                .change_context(P2pError::P2p(P2pErrorKind::Tree))?,
+            bans: main_chain
+                .open_tree(PEER_BANS_TREE)
+                .change_context(P2pError::P2p(P2pErrorKind::Tree))?,
+        })
+    }
+
+    /// Known addresses, the most recently seen first
+    pub fn addresses(&self) -> Result<Vec<KnownAddress>, P2pError> {
+        let mut addresses = self
+            .addresses
+            .iter()
+            .map(|entry| {
+                let (key, dump) = entry.change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+                parse_address(&key, &dump)
+            })
+            .collect::<Result<Vec<_>, P2pError>>()?;
+        addresses.sort_by_key(|known| std::cmp::Reverse(known.last_seen));
+        Ok(addresses)
+    }
+
+    /// Remembers the address, keeping what is already known about it
+    pub fn add_address(&self, address: SocketAddr) -> Result<(), P2pError> {
+        let known = KnownAddress {
+            address,
+            last_seen: 0,
+            failures: 0,
+        };
+        // fails only if the address is already known
+        let _ = self
+            .addresses
+            .compare_and_swap(
+                address.to_string(),
+                None::<&[u8]>,
+                Some(dump_address(&known)),
+            )
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+        Ok(())
+    }
+
+    /// Records the successful connection to the address at the timestamp
+    pub fn connected(&self, address: SocketAddr, timestamp: u64) -> Result<(), P2pError> {
+        let known = KnownAddress {
+            address,
+            last_seen: timestamp,
+            failures: 0,
+        };
+        self.addresses
+            .insert(address.to_string(), dump_address(&known))
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+        Ok(())
+    }
+
+    /// Records the failed connection attempt,
+    /// the address is forgotten after `MAX_ADDRESS_FAILURES` of them in a row
+    pub fn connection_failed(&self, address: SocketAddr) -> Result<(), P2pError> {
+        let key = address.to_string();
+        let Some(dump) = self
+            .addresses
+            .get(&key)
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+        else {
+            return Ok(());
+        };
+        let mut known = parse_address(key.as_bytes(), &dump)?;
+        known.failures = known.failures.saturating_add(1);
+        if known.failures >= MAX_ADDRESS_FAILURES {
+            self.addresses.remove(&key)
+        } else {
+            self.addresses.insert(&key, dump_address(&known))
+        }
+        .change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+        Ok(())
+    }
+
+    /// Bans the IP until the timestamp, a longer existing ban is kept
+    pub fn ban(&self, ip: IpAddr, until: u64) -> Result<(), P2pError> {
+        let key = ip.to_string();
+        if self
+            .banned_until(&ip)?
+            .is_some_and(|banned| banned >= until)
+        {
+            return Ok(());
+        }
+        self.bans
+            .insert(key, &until.to_be_bytes())
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+        Ok(())
+    }
+
+    pub fn unban(&self, ip: &IpAddr) -> Result<(), P2pError> {
+        self.bans
+            .remove(ip.to_string())
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+        Ok(())
+    }
+
+    /// Whether the IP is banned at the timestamp, expired bans are removed
+    pub fn is_banned(&self, ip: &IpAddr, timestamp: u64) -> Result<bool, P2pError> {
+        match self.banned_until(ip)? {
+            Some(until) if until > timestamp => Ok(true),
+            Some(_) => {
+                self.unban(ip)?;
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn banned_until(&self, ip: &IpAddr) -> Result<Option<u64>, P2pError> {
+        let Some(dump) = self
+            .bans
+            .get(ip.to_string())
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+        else {
+            return Ok(None);
+        };
+        let until = dump
+            .as_ref()
+            .try_into()
+            .map_err(|_| Report::new(P2pError::P2p(P2pErrorKind::Tree)))
+            .attach_printable("stored ban has a wrong size")?;
+        Ok(Some(u64::from_be_bytes(until)))
+    }
+}
@@ -0,0 +1,373 @@
+use error_stack::{Report, Result, ResultExt};
+use primitive_types::U256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::block::BasicInfo;
+use crate::errors::{P2pError, P2pErrorKind};
+use crate::static_values::{MAX_HEADERS, MAX_INVENTORY_SIZE, MAX_MESSAGE_SIZE};
+use crate::tools;
+use crate::types::Hash;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageType {
+    Handshake = 0,
+    Inventory = 1,
+    GetData = 2,
+    Block = 3,
+    Transaction = 4,
+    NotFound = 5,
+    Ping = 6,
+    Pong = 7,
+    GetHeaders = 8,
+    Headers = 9,
+}
+
+impl MessageType {
+    fn from_u8(message_type: u8) -> Option<MessageType> {
+        match message_type {
+            0 => Some(MessageType::Handshake),
+            1 => Some(MessageType::Inventory),
+            2 => Some(MessageType::GetData),
+            3 => Some(MessageType::Block),
+            4 => Some(MessageType::Transaction),
+            5 => Some(MessageType::NotFound),
+            6 => Some(MessageType::Ping),
+            7 => Some(MessageType::Pong),
+            8 => Some(MessageType::GetHeaders),
+            9 => Some(MessageType::Headers),
+            _ => None,
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InventoryKind {
+    Transaction = 0,
+    Block = 1,
+}
+
+/// Transaction or block referenced by its hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Inventory {
+    pub kind: InventoryKind,
+    pub hash: Hash,
+}
+
+/// First message sent by both sides of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u32,
+    /// Hash of the first block of the main chain, peers have to share it
+    pub genesis_hash: Hash,
+    /// Port the peer accepts connections on, 0 if it doesn't
+    pub listen_port: u16,
+    /// Height of the main chain of the peer
+    pub height: U256,
+}
+
+/// Basic info of a main chain block with the hash of the block
+///
+/// The hash can't be derived from the basic info, it is checked once the block is downloaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub hash: Hash,
+    /// Root of the block's contents committed by its pow, see `Block::get_body_root`
+    pub body_root: Hash,
+    pub info: BasicInfo,
+}
+
+/// Message of the wire protocol
+///
+/// Blocks and transactions are carried as their `dump()`, starting with the header byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Handshake(Handshake),
+    /// Announces transactions and blocks the peer has
+    Inventory(Vec<Inventory>),
+    /// Requests the dumps of transactions and blocks
+    GetData(Vec<Inventory>),
+    Block(Vec<u8>),
+    Transaction(Vec<u8>),
+    /// Requested items the peer doesn't have
+    NotFound(Vec<Inventory>),
+    Ping(u64),
+    Pong(u64),
+    /// Requests headers of the main chain starting at the height
+    GetHeaders {
+        start: U256,
+        count: u32,
+    },
+    /// Answer to `GetHeaders`: consecutive headers starting at the requested height,
+    /// empty if the peer's chain is shorter
+    Headers {
+        start: U256,
+        headers: Vec<Header>,
+    },
+}
+
+fn parse_error(message: &'static str) -> Report<P2pError> {
+    Report::new(P2pError::P2p(P2pErrorKind::Parse)).attach_printable(message)
+}
+
+fn dump_inventory(items: &[Inventory], buffer: &mut Vec<u8>) {
+    buffer.extend((items.len() as u32).to_be_bytes());
+    for item in items {
+        buffer.push(item.kind as u8);
+        buffer.extend(item.hash);
+    }
+}
+
+fn parse_inventory(data: &[u8]) -> Result<Vec<Inventory>, P2pError> {
+    if data.len() < 4 {
+        return Err(parse_error("inventory is too short"));
+    }
+    let amount = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+    if amount > MAX_INVENTORY_SIZE {
+        return Err(Report::new(P2pError::P2p(P2pErrorKind::TooLarge))
+            .attach_printable(format!("inventory of {} items", amount)));
+    }
+    if data.len() != 4 + amount * 33 {
+        return Err(parse_error("wrong size of the inventory"));
+    }
+
+    let mut items = Vec::with_capacity(amount);
+    for item in data[4..].chunks_exact(33) {
+        let kind = match item[0] {
+            0 => InventoryKind::Transaction,
+            1 => InventoryKind::Block,
+            _ => return Err(parse_error("unknown inventory kind")),
+        };
+        items.push(Inventory {
+            kind,
+            hash: item[1..].try_into().unwrap(),
+        });
+    }
+    Ok(items)
+}
+
+fn parse_u64(data: &[u8]) -> Result<u64, P2pError> {
+    Ok(u64::from_be_bytes(
+        data.try_into()
+            .map_err(|_| parse_error("expected 8 bytes"))?,
+    ))
+}
+
+fn dump_headers(headers: &[Header], buffer: &mut Vec<u8>) {
+    buffer.extend((headers.len() as u32).to_be_bytes());
+    for header in headers {
+        let mut info = Vec::with_capacity(header.info.get_dump_size());
+        // dumping basic info can't fail
+        header.info.dump(&mut info).unwrap();
+        buffer.extend(header.hash);
+        buffer.extend(header.body_root);
+        buffer.extend((info.len() as u16).to_be_bytes());
+        buffer.extend(info);
+    }
+}
+
+fn parse_headers(data: &[u8]) -> Result<Vec<Header>, P2pError> {
+    if data.len() < 4 {
+        return Err(parse_error("headers are too short"));
+    }
+    let amount = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+    if amount > MAX_HEADERS {
+        return Err(Report::new(P2pError::P2p(P2pErrorKind::TooLarge))
+            .attach_printable(format!("{} headers", amount)));
+    }
+
+    let mut headers = Vec::with_capacity(amount);
+    let mut index = 4;
+    for _ in 0..amount {
+        if data.len() < index + 66 {
+            return Err(parse_error("headers are too short"));
+        }
+        let hash: Hash = data[index..index + 32].try_into().unwrap();
+        let body_root: Hash = data[index + 32..index + 64].try_into().unwrap();
+        let size = u16::from_be_bytes(data[index + 64..index + 66].try_into().unwrap()) as usize;
+        index += 66;
+        if data.len() < index + size {
+            return Err(parse_error("headers are too short"));
+        }
+        let info = BasicInfo::parse(&data[index..index + size])
+            .change_context(P2pError::P2p(P2pErrorKind::Parse))?;
+        index += size;
+        headers.push(Header {
+            hash,
+            body_root,
+            info,
+        });
+    }
+    if index != data.len() {
+        return Err(parse_error("wrong size of the headers"));
+    }
+    Ok(headers)
+}
+
+impl Handshake {
+    fn dump(&self, buffer: &mut Vec<u8>) {
+        buffer.extend(self.version.to_be_bytes());
+        buffer.extend(self.genesis_hash);
+        buffer.extend(self.listen_port.to_be_bytes());
+        // dumping U256 can't fail
+        tools::dump_u256(&self.height, buffer).unwrap();
+    }
+
+    fn parse(data: &[u8]) -> Result<Handshake, P2pError> {
+        if data.len() < 4 + 32 + 2 + 1 {
+            return Err(parse_error("handshake is too short"));
+        }
+        let version = u32::from_be_bytes(data[..4].try_into().unwrap());
+        let genesis_hash: Hash = data[4..36].try_into().unwrap();
+        let listen_port = u16::from_be_bytes(data[36..38].try_into().unwrap());
+        let (height, size) = tools::load_u256(&data[38..])
+            .change_context(P2pError::P2p(P2pErrorKind::Parse))
+            .attach_printable("failed to parse the height")?;
+        if 38 + size + 1 != data.len() {
+            return Err(parse_error("wrong size of the handshake"));
+        }
+
+        Ok(Handshake {
+            version,
+            genesis_hash,
+            listen_port,
+            height,
+        })
+    }
+}
+
+impl Message {
+    pub fn dump(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        match self {
+            Message::Handshake(handshake) => {
+                buffer.push(MessageType::Handshake as u8);
+                handshake.dump(&mut buffer);
+            }
+            Message::Inventory(items) => {
+                buffer.push(MessageType::Inventory as u8);
+                dump_inventory(items, &mut buffer);
+            }
+            Message::GetData(items) => {
+                buffer.push(MessageType::GetData as u8);
+                dump_inventory(items, &mut buffer);
+            }
+            Message::Block(dump) => {
+                buffer.push(MessageType::Block as u8);
+                buffer.extend(dump);
+            }
+            Message::Transaction(dump) => {
+                buffer.push(MessageType::Transaction as u8);
+                buffer.extend(dump);
+            }
+            Message::NotFound(items) => {
+                buffer.push(MessageType::NotFound as u8);
+                dump_inventory(items, &mut buffer);
+            }
+            Message::Ping(nonce) => {
+                buffer.push(MessageType::Ping as u8);
+                buffer.extend(nonce.to_be_bytes());
+            }
+            Message::Pong(nonce) => {
+                buffer.push(MessageType::Pong as u8);
+                buffer.extend(nonce.to_be_bytes());
+            }
+            Message::GetHeaders { start, count } => {
+                buffer.push(MessageType::GetHeaders as u8);
+                buffer.extend(count.to_be_bytes());
+                // dumping U256 can't fail
+                tools::dump_u256(start, &mut buffer).unwrap();
+            }
+            Message::Headers { start, headers } => {
+                buffer.push(MessageType::Headers as u8);
+                // dumping U256 can't fail
+                tools::dump_u256(start, &mut buffer).unwrap();
+                dump_headers(headers, &mut buffer);
+            }
+        }
+        buffer
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Message, P2pError> {
+        let Some((message_type, body)) = data.split_first() else {
+            return Err(parse_error("empty message"));
+        };
+        let message_type = MessageType::from_u8(*message_type)
+            .ok_or_else(|| parse_error("unknown message type"))?;
+
+        Ok(match message_type {
+            MessageType::Handshake => Message::Handshake(Handshake::parse(body)?),
+            MessageType::Inventory => Message::Inventory(parse_inventory(body)?),
+            MessageType::GetData => Message::GetData(parse_inventory(body)?),
+            MessageType::Block => Message::Block(body.to_vec()),
+            MessageType::Transaction => Message::Transaction(body.to_vec()),
+            MessageType::NotFound => Message::NotFound(parse_inventory(body)?),
+            MessageType::Ping => Message::Ping(parse_u64(body)?),
+            MessageType::Pong => Message::Pong(parse_u64(body)?),
+            MessageType::GetHeaders => {
+                if body.len() < 5 {
+                    return Err(parse_error("headers request is too short"));
+                }
+                let count = u32::from_be_bytes(body[..4].try_into().unwrap());
+                let (start, size) = tools::load_u256(&body[4..])
+                    .change_context(P2pError::P2p(P2pErrorKind::Parse))?;
+                if 4 + size + 1 != body.len() {
+                    return Err(parse_error("wrong size of the headers request"));
+                }
+                Message::GetHeaders { start, count }
+            }
+            MessageType::Headers => {
+                let (start, size) =
+                    tools::load_u256(body).change_context(P2pError::P2p(P2pErrorKind::Parse))?;
+                Message::Headers {
+                    start,
+                    headers: parse_headers(&body[size + 1..])?,
+                }
+            }
+        })
+    }
+}
+
+/// Reads a single frame: u32 length of the message followed by the message
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Message, P2pError> {
+    let size = reader
+        .read_u32()
+        .await
+        .change_context(P2pError::P2p(P2pErrorKind::Read))? as usize;
+    if size > MAX_MESSAGE_SIZE {
+        return Err(Report::new(P2pError::P2p(P2pErrorKind::TooLarge))
+            .attach_printable(format!("message of {} bytes", size)));
+    }
+
+    let mut data = vec![0u8; size];
+    reader
+        .read_exact(&mut data)
+        .await
+        .change_context(P2pError::P2p(P2pErrorKind::Read))?;
+    Message::parse(&data)
+}
+
+/// Writes the message as a single frame, see `read_message`
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> Result<(), P2pError> {
+    let data = message.dump();
+    if data.len() > MAX_MESSAGE_SIZE {
+        return Err(Report::new(P2pError::P2p(P2pErrorKind::TooLarge))
+            .attach_printable(format!("message of {} bytes", data.len())));
+    }
+
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend((data.len() as u32).to_be_bytes());
+    frame.extend(data);
+    writer
+        .write_all(&frame)
+        .await
+        .change_context(P2pError::P2p(P2pErrorKind::Write))?;
+    writer
+        .flush()
+        .await
+        .change_context(P2pError::P2p(P2pErrorKind::Write))
+}
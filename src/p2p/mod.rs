@@ -0,0 +1,1018 @@
+//! Peer-to-peer network of the nodes
+//!
+//! Peers talk over TCP with length prefixed frames, see `message`.
+//! Both sides start with a handshake and the connection is dropped
+//! if the protocol version or the genesis block differ.
+//! New transactions and blocks are relayed between the peers, see `gossip`.
+//! Misbehaving peers are banned and addresses of the peers are remembered, see `manager`
+
+mod gossip;
+pub mod manager;
+pub mod message;
+pub mod peer;
+pub mod sync;
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use error_stack::{Report, Result, ResultExt};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use primitive_types::U256;
+use serde::Deserialize;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+
+use crate::block;
+use crate::errors::{P2pError, P2pErrorKind};
+use crate::events::EventReceiver;
+use crate::node::SharedTree;
+use crate::static_values::{
+    BAN_DURATION, BAN_SCORE, MAX_HEADERS, MAX_INVENTORY_SIZE, PEER_MANAGER_INTERVAL,
+    PEER_QUEUE_SIZE, PEER_TIMEOUT, PROTOCOL_VERSION,
+};
+use crate::tools;
+use crate::types::Hash;
+
+use manager::{KnownAddress, Misbehaviour, PeerManager};
+use message::{Handshake, Header, Inventory, InventoryKind, Message};
+use peer::{PeerEvent, PeerHandle, PeerId, PeerInfo};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct P2pConfig {
+    pub enabled: bool,
+    pub listen: SocketAddr,
+    /// Peers connected to on the start, they are remembered with the other known addresses
+    pub peers: Vec<SocketAddr>,
+    /// Maximum amount of peers connected to us, the others are rejected
+    pub max_inbound: usize,
+    /// Amount of peers we keep connections to, picked from the known addresses
+    pub target_outbound: usize,
+}
+
+impl Default for P2pConfig {
+    fn default() -> Self {
+        P2pConfig {
+            enabled: false,
+            listen: SocketAddr::from(([0, 0, 0, 0], 8334)),
+            peers: Vec::new(),
+            max_inbound: 32,
+            target_outbound: 8,
+        }
+    }
+}
+
+/// Requests waiting for the answers of the peers, several requests can wait for the same answer
+type Waiting<K, T> = Mutex<HashMap<K, Vec<oneshot::Sender<T>>>>;
+
+fn wait_answer<K: Eq + std::hash::Hash, T>(
+    waiting: &Waiting<K, T>,
+    key: K,
+) -> oneshot::Receiver<T> {
+    let (sender, answer) = oneshot::channel();
+    waiting.lock().entry(key).or_default().push(sender);
+    answer
+}
+
+fn send_answer<K: Eq + std::hash::Hash, T: Clone>(waiting: &Waiting<K, T>, key: &K, answer: T) {
+    for sender in waiting.lock().remove(key).into_iter().flatten() {
+        let _ = sender.send(answer.clone());
+    }
+}
+
+/// Removes the requests that stopped waiting, called after dropping the receiver
+fn forget_answer<K: Eq + std::hash::Hash, T>(waiting: &Waiting<K, T>, key: &K) {
+    let mut waiting = waiting.lock();
+    if let Some(senders) = waiting.get_mut(key) {
+        senders.retain(|sender| !sender.is_closed());
+        if senders.is_empty() {
+            waiting.remove(key);
+        }
+    }
+}
+
+struct Shared {
+    tree: SharedTree,
+    genesis_hash: Hash,
+    local_address: SocketAddr,
+    max_inbound: usize,
+    target_outbound: usize,
+    manager: PeerManager,
+    peers: Mutex<HashMap<PeerId, PeerHandle>>,
+    next_id: AtomicU64,
+    /// Pings waiting for the pong, by the peer and the nonce
+    pings: Mutex<HashMap<(PeerId, u64), oneshot::Sender<()>>>,
+    /// Requested items waiting for the dump, `None` if the peer doesn't have it
+    requests: Waiting<(PeerId, Hash), Option<Vec<u8>>>,
+    /// Requested headers waiting for the answer, by the peer and the start height
+    header_requests: Waiting<(PeerId, U256), Vec<Header>>,
+    /// Announced items being downloaded, each one is fetched from a single peer
+    fetching: Mutex<HashSet<Hash>>,
+    events: mpsc::Sender<PeerEvent>,
+    stop: watch::Sender<bool>,
+    /// Every task holding the network, joined on the shutdown
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+/// Announced item being downloaded, leaves `fetching` when dropped, even on a panic
+struct Fetching {
+    shared: Arc<Shared>,
+    hash: Hash,
+}
+
+impl Drop for Fetching {
+    fn drop(&mut self) {
+        self.shared.fetching.lock().remove(&self.hash);
+    }
+}
+
+/// Handle of the running network, clones share the same network
+#[derive(Clone)]
+pub struct Network {
+    shared: Arc<Shared>,
+}
+
+impl Network {
+    /// Starts listening for peers and connects to the configured ones
+    pub async fn start(config: &P2pConfig, tree: SharedTree) -> Result<Network, P2pError> {
+        let genesis = tree
+            .read()
+            .await
+            .get_main_chain()
+            .find_by_height(&U256::zero())
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+            .ok_or(Report::new(P2pError::P2p(P2pErrorKind::Tree)))
+            .attach_printable("the main chain has no genesis block")?;
+        let genesis_hash = genesis
+            .hash()
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+
+        let manager = PeerManager::open(&tree.read().await.get_main_chain())?;
+        for address in config.peers.iter() {
+            manager.add_address(*address)?;
+        }
+
+        let listener = TcpListener::bind(config.listen)
+            .await
+            .change_context(P2pError::P2p(P2pErrorKind::Bind))
+            .attach_printable_lazy(|| format!("failed to listen on {}", config.listen))?;
+        let local_address = listener
+            .local_addr()
+            .change_context(P2pError::P2p(P2pErrorKind::Bind))?;
+
+        let tree_events = tree.read().await.subscribe();
+        let (events, received) = mpsc::channel(PEER_QUEUE_SIZE);
+        let network = Network {
+            shared: Arc::new(Shared {
+                tree,
+                genesis_hash,
+                local_address,
+                max_inbound: config.max_inbound,
+                target_outbound: config.target_outbound,
+                manager,
+                peers: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+                pings: Mutex::new(HashMap::new()),
+                requests: Mutex::new(HashMap::new()),
+                header_requests: Mutex::new(HashMap::new()),
+                fetching: Mutex::new(HashSet::new()),
+                events,
+                stop: watch::channel(false).0,
+                tasks: Mutex::new(Vec::new()),
+            }),
+        };
+
+        network.spawn(network.clone().accept(listener));
+        network.spawn(network.clone().dispatch(received));
+        network.spawn(network.clone().relay(tree_events));
+
+        for address in config.peers.iter() {
+            if let Err(report) = network.connect(*address).await {
+                warn!("failed to connect to {address}: {report}");
+            }
+        }
+        // started after the configured peers are connected, so it doesn't connect to them twice
+        network.spawn(network.clone().keep_outbound());
+
+        Ok(network)
+    }
+
+    /// Address the network accepts peers on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.local_address
+    }
+
+    pub async fn connect(&self, address: SocketAddr) -> Result<PeerId, P2pError> {
+        if self.is_banned(&address.ip())? {
+            return Err(Report::new(P2pError::P2p(P2pErrorKind::Banned)))
+                .attach_printable_lazy(|| format!("{} is banned", address.ip()));
+        }
+        let connected = async {
+            let stream = tokio::time::timeout(
+                Duration::from_secs(PEER_TIMEOUT),
+                TcpStream::connect(address),
+            )
+            .await
+            .change_context(P2pError::P2p(P2pErrorKind::Timeout))?
+            .change_context(P2pError::P2p(P2pErrorKind::Connect))
+            .attach_printable_lazy(|| format!("failed to connect to {}", address))?;
+            self.open_connection(stream, address, false).await
+        };
+        match connected.await {
+            Ok(id) => {
+                self.shared
+                    .manager
+                    .connected(address, tools::current_timestamp())?;
+                Ok(id)
+            }
+            Err(report) => {
+                self.shared.manager.connection_failed(address)?;
+                Err(report)
+            }
+        }
+    }
+
+    /// Known addresses of the peers accepting connections, the most recently seen first
+    pub fn known_addresses(&self) -> Result<Vec<KnownAddress>, P2pError> {
+        self.shared.manager.addresses()
+    }
+
+    /// Bans the IP for `duration` seconds and disconnects its peers
+    pub fn ban(&self, ip: IpAddr, duration: u64) -> Result<(), P2pError> {
+        self.shared
+            .manager
+            .ban(ip, tools::current_timestamp() + duration)?;
+        let banned: Vec<_> = self
+            .shared
+            .peers
+            .lock()
+            .values()
+            .filter(|peer| peer.info.address.ip() == ip)
+            .map(|peer| peer.info.id)
+            .collect();
+        for id in banned {
+            self.disconnect(id);
+        }
+        warn!("banned {ip} for {duration} seconds");
+        Ok(())
+    }
+
+    pub fn unban(&self, ip: &IpAddr) -> Result<(), P2pError> {
+        self.shared.manager.unban(ip)
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> Result<bool, P2pError> {
+        self.shared
+            .manager
+            .is_banned(ip, tools::current_timestamp())
+    }
+
+    /// Raises the score of the peer, banning it once the score reaches `BAN_SCORE`
+    pub fn misbehave(&self, id: PeerId, misbehaviour: Misbehaviour) -> Result<(), P2pError> {
+        let ip = {
+            let mut peers = self.shared.peers.lock();
+            let Some(peer) = peers.get_mut(&id) else {
+                return Ok(());
+            };
+            peer.info.score = peer.info.score.saturating_add(misbehaviour.penalty());
+            debug!(
+                "peer {id} misbehaved: {misbehaviour:?}, score {}",
+                peer.info.score
+            );
+            if peer.info.score < BAN_SCORE {
+                return Ok(());
+            }
+            peer.info.address.ip()
+        };
+        self.ban(ip, BAN_DURATION)
+    }
+
+    /// Raises the score of the peer if it caused the error, see `Misbehaviour::of`
+    pub(crate) fn punish(&self, id: PeerId, report: &Report<P2pError>) {
+        let Some(misbehaviour) = Misbehaviour::of(report) else {
+            return;
+        };
+        if let Err(report) = self.misbehave(id, misbehaviour) {
+            warn!("failed to score peer {id}: {report:?}");
+        }
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.shared
+            .peers
+            .lock()
+            .values()
+            .map(|peer| peer.info.clone())
+            .collect()
+    }
+
+    pub fn disconnect(&self, id: PeerId) {
+        if let Some(peer) = self.shared.peers.lock().remove(&id) {
+            info!("disconnected from peer {}", peer.info.address);
+        }
+        self.shared.pings.lock().retain(|(peer, _), _| *peer != id);
+        self.shared
+            .requests
+            .lock()
+            .retain(|(peer, _), _| *peer != id);
+        self.shared
+            .header_requests
+            .lock()
+            .retain(|(peer, _), _| *peer != id);
+    }
+
+    /// Queues the message to the peer, waiting if its queue is full
+    pub async fn send(&self, id: PeerId, message: Message) -> Result<(), P2pError> {
+        let sender = self
+            .shared
+            .peers
+            .lock()
+            .get(&id)
+            .map(|peer| peer.sender.clone())
+            .ok_or(Report::new(P2pError::P2p(P2pErrorKind::Disconnected)))?;
+        sender
+            .send(message)
+            .await
+            .map_err(|_| Report::new(P2pError::P2p(P2pErrorKind::Disconnected)))
+    }
+
+    /// Queues the message to every peer, skipping the ones with the full queue
+    pub fn broadcast(&self, message: &Message) {
+        for peer in self.shared.peers.lock().values() {
+            if peer.sender.try_send(message.clone()).is_err() {
+                debug!("peer {} is too slow, message is dropped", peer.info.id);
+            }
+        }
+    }
+
+    /// Measures the round trip to the peer
+    pub async fn ping(&self, id: PeerId) -> Result<Duration, P2pError> {
+        let nonce = secp256k1::rand::random();
+        let (sender, pong) = oneshot::channel();
+        self.shared.pings.lock().insert((id, nonce), sender);
+
+        let start = Instant::now();
+        if let Err(report) = self.send(id, Message::Ping(nonce)).await {
+            self.shared.pings.lock().remove(&(id, nonce));
+            return Err(report);
+        }
+        let answer = tokio::time::timeout(Duration::from_secs(PEER_TIMEOUT), pong).await;
+        self.shared.pings.lock().remove(&(id, nonce));
+        answer
+            .change_context(P2pError::P2p(P2pErrorKind::Timeout))?
+            .change_context(P2pError::P2p(P2pErrorKind::Disconnected))?;
+        Ok(start.elapsed())
+    }
+
+    /// Requests the dump of the transaction or the block from the peer
+    ///
+    /// Returns `None` if the peer doesn't have it
+    pub async fn get_data(&self, id: PeerId, item: Inventory) -> Result<Option<Vec<u8>>, P2pError> {
+        let mut dumps = self.get_data_batch(id, &[item]).await?;
+        Ok(dumps.remove(0))
+    }
+
+    /// Requests the items from the peer in a single message, `None` for the items
+    /// the peer doesn't have
+    ///
+    /// Dumps are returned in the order of the items
+    pub async fn get_data_batch(
+        &self,
+        id: PeerId,
+        items: &[Inventory],
+    ) -> Result<Vec<Option<Vec<u8>>>, P2pError> {
+        let keys: Vec<_> = items.iter().map(|item| (id, item.hash)).collect();
+        let answers: Vec<_> = keys
+            .iter()
+            .map(|key| wait_answer(&self.shared.requests, *key))
+            .collect();
+
+        let received = async {
+            self.send(id, Message::GetData(items.to_vec())).await?;
+            tokio::time::timeout(Duration::from_secs(PEER_TIMEOUT), async {
+                let mut dumps = Vec::with_capacity(answers.len());
+                for answer in answers {
+                    dumps.push(
+                        answer
+                            .await
+                            .change_context(P2pError::P2p(P2pErrorKind::Disconnected))?,
+                    );
+                }
+                Ok(dumps)
+            })
+            .await
+            .change_context(P2pError::P2p(P2pErrorKind::Timeout))?
+        }
+        .await;
+        for key in keys.iter() {
+            forget_answer(&self.shared.requests, key);
+        }
+        received
+    }
+
+    /// Requests up to `count` headers of the peer's main chain starting at the height
+    ///
+    /// The peer answers with at most `MAX_HEADERS` headers
+    pub async fn get_headers(
+        &self,
+        id: PeerId,
+        start: U256,
+        count: u32,
+    ) -> Result<Vec<Header>, P2pError> {
+        let key = (id, start);
+        let answer = wait_answer(&self.shared.header_requests, key);
+
+        if let Err(report) = self.send(id, Message::GetHeaders { start, count }).await {
+            drop(answer);
+            forget_answer(&self.shared.header_requests, &key);
+            return Err(report);
+        }
+        let answer = tokio::time::timeout(Duration::from_secs(PEER_TIMEOUT), answer).await;
+        forget_answer(&self.shared.header_requests, &key);
+        answer
+            .change_context(P2pError::P2p(P2pErrorKind::Timeout))?
+            .change_context(P2pError::P2p(P2pErrorKind::Disconnected))
+    }
+
+    /// Tree the network serves and synchronizes
+    pub(crate) fn tree(&self) -> &SharedTree {
+        &self.shared.tree
+    }
+
+    /// Disconnects all the peers and stops the network
+    ///
+    /// Returns once all the tasks of the network are finished, so the tree
+    /// is only held by the handles of the network
+    pub async fn shutdown(&self) {
+        self.shared.stop.send_replace(true);
+        // the tasks being joined can still spawn new ones
+        loop {
+            let tasks: Vec<_> = self.shared.tasks.lock().drain(..).collect();
+            if tasks.is_empty() {
+                break;
+            }
+            for task in tasks {
+                let _ = task.await;
+            }
+        }
+        self.shared.peers.lock().clear();
+        self.shared.pings.lock().clear();
+        self.shared.requests.lock().clear();
+        self.shared.header_requests.lock().clear();
+    }
+
+    /// Subscribes to the stop of the network, a stop sent before the subscription
+    /// is seen as a change too
+    fn stopped(&self) -> watch::Receiver<bool> {
+        let mut stop = self.shared.stop.subscribe();
+        if *stop.borrow() {
+            stop.mark_changed();
+        }
+        stop
+    }
+
+    /// Runs the task of the network, the task has to finish once the network is stopped
+    ///
+    /// Nothing is spawned after the stop
+    fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut tasks = self.shared.tasks.lock();
+        if *self.shared.stop.borrow() {
+            return;
+        }
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(tokio::spawn(task));
+    }
+
+    async fn local_handshake(&self) -> Handshake {
+        Handshake {
+            version: PROTOCOL_VERSION,
+            genesis_hash: self.shared.genesis_hash,
+            listen_port: self.shared.local_address.port(),
+            height: self.shared.tree.read().await.get_main_chain().get_height(),
+        }
+    }
+
+    async fn open_connection(
+        &self,
+        mut stream: TcpStream,
+        address: SocketAddr,
+        inbound: bool,
+    ) -> Result<PeerId, P2pError> {
+        if inbound {
+            if self.is_banned(&address.ip())? {
+                return Err(Report::new(P2pError::P2p(P2pErrorKind::Banned)));
+            }
+            if self.count_peers(true) >= self.shared.max_inbound {
+                return Err(Report::new(P2pError::P2p(P2pErrorKind::Disconnected)))
+                    .attach_printable("too many inbound peers");
+            }
+        }
+
+        let local = self.local_handshake().await;
+        let remote = peer::handshake(&mut stream, &local)
+            .await
+            .attach_printable_lazy(|| format!("peer {}", address))?;
+        if *self.shared.stop.borrow() {
+            return Err(Report::new(P2pError::P2p(P2pErrorKind::Disconnected)))
+                .attach_printable("the network is stopped");
+        }
+
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = PeerInfo {
+            id,
+            address,
+            inbound,
+            handshake: remote,
+            score: 0,
+        };
+        if inbound && info.handshake.listen_port != 0 {
+            let listening = SocketAddr::new(address.ip(), info.handshake.listen_port);
+            self.shared.manager.add_address(listening)?;
+        }
+        // the peer is registered before any of its messages can be handled
+        let mut peers = self.shared.peers.lock();
+        let (handle, requests) =
+            peer::spawn(info, stream, self.shared.events.clone(), PEER_QUEUE_SIZE);
+        peers.insert(id, handle);
+        drop(peers);
+        self.spawn(self.clone().serve(id, requests));
+        info!("connected to peer {address}");
+        Ok(id)
+    }
+
+    async fn accept(self, listener: TcpListener) {
+        let mut stop = self.stopped();
+        loop {
+            let (stream, address) = tokio::select! {
+                _ = stop.changed() => return,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(error) => {
+                        warn!("failed to accept a peer: {error}");
+                        continue;
+                    }
+                },
+            };
+            let network = self.clone();
+            let mut stop = stop.clone();
+            self.spawn(async move {
+                tokio::select! {
+                    _ = stop.changed() => {}
+                    opened = network.open_connection(stream, address, true) => {
+                        if let Err(report) = opened {
+                            debug!("rejected peer {address}: {report:?}");
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    async fn dispatch(self, mut received: mpsc::Receiver<PeerEvent>) {
+        let mut stop = self.stopped();
+        loop {
+            let event = tokio::select! {
+                _ = stop.changed() => return,
+                event = received.recv() => match event {
+                    Some(event) => event,
+                    None => return,
+                },
+            };
+            match event {
+                PeerEvent::Disconnected(id) => self.disconnect(id),
+                PeerEvent::Misbehaved(id, misbehaviour) => {
+                    if let Err(report) = self.misbehave(id, misbehaviour) {
+                        warn!("failed to score peer {id}: {report:?}");
+                    }
+                }
+                PeerEvent::Message(id, message) => {
+                    if let Err(report) = self.handle_message(id, message).await {
+                        warn!("failed to handle message of peer {id}: {report:?}");
+                        self.punish(id, &report);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_message(&self, id: PeerId, message: Message) -> Result<(), P2pError> {
+        match message {
+            Message::Handshake(_) => {
+                debug!("peer {id} sent a second handshake");
+                self.disconnect(id);
+            }
+            Message::Ping(_) | Message::GetData(_) | Message::GetHeaders { .. } => {
+                self.queue_request(id, message)
+            }
+            Message::Pong(nonce) => {
+                if let Some(waiting) = self.shared.pings.lock().remove(&(id, nonce)) {
+                    let _ = waiting.send(());
+                }
+            }
+            Message::Block(dump) | Message::Transaction(dump) => {
+                let hash = tools::hash(&dump);
+                send_answer(&self.shared.requests, &(id, hash), Some(dump));
+            }
+            Message::NotFound(items) => {
+                for item in items {
+                    send_answer(&self.shared.requests, &(id, item.hash), None);
+                }
+            }
+            Message::Inventory(items) => self.receive_inventory(id, items).await?,
+            Message::Headers { start, headers } => {
+                send_answer(&self.shared.header_requests, &(id, start), headers);
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes the request to the serving task of the peer, so the answers the peer
+    /// doesn't read hold up only its own requests
+    ///
+    /// A peer whose requests pile up is dropped
+    fn queue_request(&self, id: PeerId, message: Message) {
+        let Some(requests) = self
+            .shared
+            .peers
+            .lock()
+            .get(&id)
+            .map(|peer| peer.requests.clone())
+        else {
+            return;
+        };
+        if requests.try_send(message).is_err() {
+            debug!("peer {id} doesn't read the answers");
+            self.disconnect(id);
+        }
+    }
+
+    /// Serves the requests of the peer in order until it is disconnected
+    async fn serve(self, id: PeerId, mut requests: mpsc::Receiver<Message>) {
+        let mut stop = self.stopped();
+        if *stop.borrow() {
+            return;
+        }
+        loop {
+            let request = tokio::select! {
+                _ = stop.changed() => return,
+                request = requests.recv() => match request {
+                    Some(request) => request,
+                    None => return,
+                },
+            };
+            let served = tokio::select! {
+                _ = stop.changed() => return,
+                served = self.serve_request(id, request) => served,
+            };
+            if let Err(report) = served {
+                warn!("failed to serve peer {id}: {report:?}");
+                self.punish(id, &report);
+            }
+        }
+    }
+
+    async fn serve_request(&self, id: PeerId, request: Message) -> Result<(), P2pError> {
+        match request {
+            Message::Ping(nonce) => self.reply(id, Message::Pong(nonce)).await,
+            Message::GetData(items) => self.serve_data(id, items).await?,
+            Message::GetHeaders { start, count } => {
+                let headers = self.find_headers(start, count).await?;
+                self.reply(id, Message::Headers { start, headers }).await;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Queues the answer, a peer not reading its answers is dropped
+    async fn reply(&self, id: PeerId, message: Message) {
+        let Some(sender) = self
+            .shared
+            .peers
+            .lock()
+            .get(&id)
+            .map(|peer| peer.sender.clone())
+        else {
+            return;
+        };
+        if sender
+            .send_timeout(message, Duration::from_secs(PEER_TIMEOUT))
+            .await
+            .is_err()
+        {
+            debug!("peer {id} doesn't read the answers");
+            self.disconnect(id);
+        }
+    }
+
+    /// Announces the transactions accepted into the pool and the blocks added to the chain
+    async fn relay(self, mut events: EventReceiver) {
+        let mut stop = self.stopped();
+        loop {
+            let event = tokio::select! {
+                _ = stop.changed() => return,
+                event = events.recv() => event,
+            };
+            let mut items = Vec::new();
+            match event {
+                Ok(event) => items.extend(gossip::announced_item(&event)),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("{skipped} events weren't announced to the peers")
+                }
+                Err(RecvError::Closed) => return,
+            }
+            // events sent meanwhile are announced together
+            while items.len() < MAX_INVENTORY_SIZE {
+                match events.try_recv() {
+                    Ok(event) => items.extend(gossip::announced_item(&event)),
+                    Err(TryRecvError::Lagged(skipped)) => {
+                        warn!("{skipped} events weren't announced to the peers")
+                    }
+                    Err(_) => break,
+                }
+            }
+            if !items.is_empty() {
+                self.announce(&items);
+            }
+        }
+    }
+
+    /// Queues the items to every peer they aren't known to
+    fn announce(&self, items: &[Inventory]) {
+        for peer in self.shared.peers.lock().values_mut() {
+            let unknown: Vec<_> = items
+                .iter()
+                .copied()
+                .filter(|item| peer.relay.mark_known(*item))
+                .collect();
+            if !unknown.is_empty() && peer.sender.try_send(Message::Inventory(unknown)).is_err() {
+                debug!("peer {} is too slow, announcement is dropped", peer.info.id);
+            }
+        }
+    }
+
+    /// Starts downloading the announced items the node doesn't have
+    ///
+    /// Items over the rate limit of the peer are ignored
+    async fn receive_inventory(
+        &self,
+        id: PeerId,
+        mut items: Vec<Inventory>,
+    ) -> Result<(), P2pError> {
+        {
+            let mut peers = self.shared.peers.lock();
+            let Some(peer) = peers.get_mut(&id) else {
+                return Ok(());
+            };
+            let allowed = peer.relay.allow(items.len());
+            if allowed < items.len() {
+                debug!(
+                    "peer {id} announces too fast, {} items are ignored",
+                    items.len() - allowed
+                );
+                items.truncate(allowed);
+            }
+            for item in items.iter() {
+                peer.relay.mark_known(*item);
+            }
+        }
+
+        let mut unknown = Vec::new();
+        {
+            let tree = self.shared.tree.read().await;
+            let main_chain = tree.get_main_chain();
+            let pool = tree.get_pool();
+            let pool = pool.read().await;
+            for item in items {
+                let known = match item.kind {
+                    InventoryKind::Transaction => {
+                        pool.transaction_exists(&item.hash)
+                            || main_chain
+                                .transaction_exists(&item.hash)
+                                .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+                    }
+                    InventoryKind::Block => main_chain
+                        .get_chain_work(&item.hash)
+                        .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+                        .is_some(),
+                };
+                if !known {
+                    unknown.push(item);
+                }
+            }
+        }
+
+        let mut fetching = self.shared.fetching.lock();
+        for item in unknown {
+            if fetching.insert(item.hash) {
+                self.spawn(self.clone().fetch_announced(id, item));
+            }
+        }
+        Ok(())
+    }
+
+    async fn fetch_announced(self, id: PeerId, item: Inventory) {
+        let _fetching = Fetching {
+            shared: self.shared.clone(),
+            hash: item.hash,
+        };
+        let mut stop = self.stopped();
+        let accepted = tokio::select! {
+            _ = stop.changed() => return,
+            accepted = self.accept_announced(id, item) => accepted,
+        };
+        if let Err(report) = accepted {
+            debug!(
+                "failed to accept {} from peer {id}: {report:?}",
+                hex::encode(item.hash)
+            );
+            self.punish(id, &report);
+        }
+    }
+
+    /// Downloads the announced item and passes it to the tree
+    async fn accept_announced(&self, id: PeerId, item: Inventory) -> Result<(), P2pError> {
+        let Some(dump) = self.get_data(id, item).await? else {
+            return Ok(());
+        };
+        match item.kind {
+            InventoryKind::Transaction => {
+                let transaction = sync::parse_transaction(&dump)?;
+                sync::verify_transaction(transaction.as_ref())?;
+                self.shared
+                    .tree
+                    .read()
+                    .await
+                    .send_transaction_item(transaction)
+                    .await
+                    .change_context(P2pError::P2p(P2pErrorKind::Rejected))
+            }
+            InventoryKind::Block => {
+                let block = block::deserialize_main_chain_block(&dump)
+                    .change_context(P2pError::P2p(P2pErrorKind::Malformed))?;
+                let height = block.get_info().height;
+                let parent_known = self
+                    .shared
+                    .tree
+                    .read()
+                    .await
+                    .get_main_chain()
+                    .get_chain_work(&block.get_info().previous_hash)
+                    .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+                    .is_some();
+                // the chain sync catches up with a peer ahead by more than a block
+                if parent_known {
+                    sync::add_block(self, id, block, item.hash).await?;
+                }
+                self.raise_height(id, height + 1);
+                Ok(())
+            }
+        }
+    }
+
+    fn count_peers(&self, inbound: bool) -> usize {
+        self.shared
+            .peers
+            .lock()
+            .values()
+            .filter(|peer| peer.info.inbound == inbound)
+            .count()
+    }
+
+    /// Connects to the known addresses until there are `target_outbound` outbound peers
+    async fn keep_outbound(self) {
+        let mut stop = self.stopped();
+        let mut interval = tokio::time::interval(Duration::from_secs(PEER_MANAGER_INTERVAL));
+        loop {
+            tokio::select! {
+                _ = stop.changed() => return,
+                _ = interval.tick() => {}
+            }
+            let connecting = self.connect_known();
+            tokio::select! {
+                _ = stop.changed() => return,
+                result = connecting => if let Err(report) = result {
+                    warn!("failed to connect to the known peers: {report:?}");
+                },
+            }
+        }
+    }
+
+    async fn connect_known(&self) -> Result<(), P2pError> {
+        for known in self.shared.manager.addresses()? {
+            if self.count_peers(false) >= self.shared.target_outbound {
+                break;
+            }
+            let address = known.address;
+            // the addresses of inbound peers are their listening ones
+            let connected = self.shared.peers.lock().values().any(|peer| {
+                peer.info.address == address
+                    || SocketAddr::new(peer.info.address.ip(), peer.info.handshake.listen_port)
+                        == address
+            });
+            if connected || address == self.shared.local_address || self.is_banned(&address.ip())? {
+                continue;
+            }
+            if let Err(report) = self.connect(address).await {
+                debug!("failed to connect to known peer {address}: {report}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the height of the peer's main chain, see `ChainSync::run`
+    fn raise_height(&self, id: PeerId, height: U256) {
+        if let Some(peer) = self.shared.peers.lock().get_mut(&id) {
+            let known = &mut peer.info.handshake.height;
+            *known = height.max(*known);
+        }
+    }
+
+    /// Collects headers of the main chain starting at the height, at most `MAX_HEADERS` of them
+    async fn find_headers(&self, start: U256, count: u32) -> Result<Vec<Header>, P2pError> {
+        let tree = self.shared.tree.read().await;
+        let main_chain = tree.get_main_chain();
+        let count = (count as usize).min(MAX_HEADERS);
+
+        let mut headers = Vec::with_capacity(count.min(64));
+        let mut height = start;
+        while headers.len() < count {
+            let Some(dump) = main_chain
+                .find_raw_by_height(&height)
+                .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+            else {
+                break;
+            };
+            let block = block::deserialize_main_chain_block(&dump)
+                .change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+            headers.push(Header {
+                hash: tools::hash(&dump),
+                body_root: block.get_body_root(),
+                info: block.get_info().clone(),
+            });
+            height += U256::one();
+        }
+        Ok(headers)
+    }
+
+    /// Sends the requested transactions and blocks, the missing ones are listed in `NotFound`
+    async fn serve_data(&self, id: PeerId, items: Vec<Inventory>) -> Result<(), P2pError> {
+        let mut answers = Vec::with_capacity(items.len());
+        let mut not_found = Vec::new();
+        {
+            let tree = self.shared.tree.read().await;
+            let main_chain = tree.get_main_chain();
+            let pool = tree.get_pool();
+            let pool = pool.read().await;
+            for item in items {
+                let dump = match item.kind {
+                    // side blocks are served too, peers need them to switch to the branch
+                    InventoryKind::Block => match main_chain
+                        .find_raw_by_hash(&item.hash)
+                        .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+                    {
+                        Some(dump) => Some(dump),
+                        None => main_chain
+                            .find_raw_side_block(&item.hash)
+                            .change_context(P2pError::P2p(P2pErrorKind::Tree))?,
+                    }
+                    .map(Message::Block),
+                    InventoryKind::Transaction => match pool.get(&item.hash) {
+                        Some(entry) => Some(
+                            entry
+                                .transaction
+                                .dump()
+                                .change_context(P2pError::P2p(P2pErrorKind::Tree))?,
+                        ),
+                        None => main_chain
+                            .get_transaction_raw(&item.hash)
+                            .change_context(P2pError::P2p(P2pErrorKind::Tree))?,
+                    }
+                    .map(Message::Transaction),
+                };
+                match dump {
+                    Some(message) => answers.push(message),
+                    None => not_found.push(item),
+                }
+            }
+        }
+
+        if !not_found.is_empty() {
+            answers.push(Message::NotFound(not_found));
+        }
+        for answer in answers {
+            self.reply(id, answer).await;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use error_stack::{Report, Result, ResultExt};
+use log::debug;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+use crate::errors::{P2pError, P2pErrorKind};
+use crate::static_values::PEER_TIMEOUT;
+
+use super::gossip::PeerRelay;
+use super::manager::Misbehaviour;
+use super::message::{read_message, write_message, Handshake, Message};
+
+pub type PeerId = u64;
+
+/// Connected peer as seen after the handshake
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub id: PeerId,
+    pub address: SocketAddr,
+    /// Whether the peer has connected to us
+    pub inbound: bool,
+    pub handshake: Handshake,
+    /// Misbehaviour score, the peer is banned once it reaches `BAN_SCORE`
+    pub score: u32,
+}
+
+/// Message received from a peer or the end of its connection
+pub(crate) enum PeerEvent {
+    Message(PeerId, Message),
+    Misbehaved(PeerId, Misbehaviour),
+    Disconnected(PeerId),
+}
+
+/// Connection of a peer, dropping it closes the connection
+pub(crate) struct PeerHandle {
+    pub info: PeerInfo,
+    pub sender: mpsc::Sender<Message>,
+    /// Requests of the peer waiting to be served by its own task
+    pub requests: mpsc::Sender<Message>,
+    pub relay: PeerRelay,
+    reader: AbortHandle,
+}
+
+impl Drop for PeerHandle {
+    fn drop(&mut self) {
+        // the writer stops once the sender is dropped
+        self.reader.abort();
+    }
+}
+
+/// Exchanges handshakes and checks that the peer speaks the same protocol on the same chain
+pub async fn handshake(stream: &mut TcpStream, local: &Handshake) -> Result<Handshake, P2pError> {
+    let exchange = async {
+        write_message(stream, &Message::Handshake(local.clone())).await?;
+        match read_message(stream).await? {
+            Message::Handshake(remote) => Ok(remote),
+            _ => Err(Report::new(P2pError::P2p(P2pErrorKind::Handshake))
+                .attach_printable("expected a handshake")),
+        }
+    };
+    let remote = tokio::time::timeout(Duration::from_secs(PEER_TIMEOUT), exchange)
+        .await
+        .change_context(P2pError::P2p(P2pErrorKind::Timeout))?
+        .change_context(P2pError::P2p(P2pErrorKind::Handshake))?;
+
+    if remote.version != local.version {
+        return Err(Report::new(P2pError::P2p(P2pErrorKind::Handshake))
+            .attach_printable(format!("unsupported protocol version {}", remote.version)));
+    }
+    if remote.genesis_hash != local.genesis_hash {
+        return Err(Report::new(P2pError::P2p(P2pErrorKind::Handshake))
+            .attach_printable("peer is on another chain"));
+    }
+    Ok(remote)
+}
+
+/// Starts the tasks reading and writing messages of the peer
+///
+/// Received messages are passed to `events`, followed by `PeerEvent::Disconnected`.
+/// Returns the receiving end of the peer's requests queue, it is closed with the handle
+pub(crate) fn spawn(
+    info: PeerInfo,
+    stream: TcpStream,
+    events: mpsc::Sender<PeerEvent>,
+    queue_size: usize,
+) -> (PeerHandle, mpsc::Receiver<Message>) {
+    let (reader, writer) = stream.into_split();
+    let (sender, outgoing) = mpsc::channel(queue_size);
+    let (requests, queued) = mpsc::channel(queue_size);
+
+    tokio::spawn(write_peer(writer, outgoing));
+    let reader = tokio::spawn(read_peer(info.id, reader, events)).abort_handle();
+
+    let handle = PeerHandle {
+        info,
+        sender,
+        requests,
+        relay: PeerRelay::new(),
+        reader,
+    };
+    (handle, queued)
+}
+
+async fn read_peer(id: PeerId, mut reader: OwnedReadHalf, events: mpsc::Sender<PeerEvent>) {
+    loop {
+        match read_message(&mut reader).await {
+            Ok(message) => {
+                if events.send(PeerEvent::Message(id, message)).await.is_err() {
+                    return;
+                }
+            }
+            Err(report) => {
+                debug!("connection to peer {id} is closed: {report}");
+                if let Some(misbehaviour) = Misbehaviour::of(&report) {
+                    let _ = events.send(PeerEvent::Misbehaved(id, misbehaviour)).await;
+                }
+                break;
+            }
+        }
+    }
+    let _ = events.send(PeerEvent::Disconnected(id)).await;
+}
+
+async fn write_peer(mut writer: OwnedWriteHalf, mut outgoing: mpsc::Receiver<Message>) {
+    while let Some(message) = outgoing.recv().await {
+        if write_message(&mut writer, &message).await.is_err() {
+            return;
+        }
+    }
+}
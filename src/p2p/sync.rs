@@ -0,0 +1,437 @@
+//! Headers-first synchronization of the main chain
+//!
+//! Headers of the peer's chain are downloaded and checked first: linkage, height,
+//! difficulty retargeting and pow. Only then the blocks are downloaded, a few at a time,
+//! checked against their headers and applied with their transactions.
+//!
+//! Downloaded headers are kept in the main chain database until their blocks are applied,
+//! so an interrupted synchronization continues from them after a restart
+
+use std::time::Duration;
+
+use error_stack::{Report, Result, ResultExt};
+use log::{info, warn};
+use primitive_types::U256;
+use sled::Tree;
+use tokio::sync::watch;
+
+use crate::block::{self, BasicInfo, BlockArc};
+use crate::errors::{BlockChainTreeError, BlockError, P2pError, P2pErrorKind};
+use crate::static_values::{MAX_HEADERS, SYNC_HEADERS_TREE, SYNC_INTERVAL, SYNC_PARALLEL_REQUESTS};
+use crate::tools;
+use crate::transaction::{self, Transactionable, TransactionableItem};
+use crate::types::Hash;
+
+use super::message::{Header, Inventory, InventoryKind};
+use super::peer::PeerId;
+use super::Network;
+
+fn height_key(height: &U256) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    height.to_big_endian(&mut key);
+    key
+}
+
+fn dump_header(header: &Header) -> Vec<u8> {
+    let mut dump = Vec::with_capacity(64 + header.info.get_dump_size());
+    dump.extend(header.hash);
+    dump.extend(header.body_root);
+    // dumping basic info can't fail
+    header.info.dump(&mut dump).unwrap();
+    dump
+}
+
+fn parse_header(dump: &[u8]) -> Result<Header, P2pError> {
+    if dump.len() < 64 {
+        return Err(Report::new(P2pError::P2p(P2pErrorKind::Tree)))
+            .attach_printable("stored header is too short");
+    }
+    Ok(Header {
+        hash: dump[..32].try_into().unwrap(),
+        body_root: dump[32..64].try_into().unwrap(),
+        info: BasicInfo::parse(&dump[64..]).change_context(P2pError::P2p(P2pErrorKind::Tree))?,
+    })
+}
+
+pub(crate) fn invalid_data(message: String) -> Report<P2pError> {
+    Report::new(P2pError::P2p(P2pErrorKind::InvalidData)).attach_printable(message)
+}
+
+/// Parses the transaction dump, starting with the header byte
+pub(crate) fn parse_transaction(dump: &[u8]) -> Result<TransactionableItem, P2pError> {
+    transaction::parse_transactionable(dump).change_context(P2pError::P2p(P2pErrorKind::Malformed))
+}
+
+pub(crate) fn verify_transaction(transaction: &dyn Transactionable) -> Result<(), P2pError> {
+    if !transaction
+        .verify()
+        .change_context(P2pError::P2p(P2pErrorKind::BadSignature))?
+    {
+        return Err(Report::new(P2pError::P2p(P2pErrorKind::BadSignature)))
+            .attach_printable_lazy(|| format!("transaction {}", hex::encode(transaction.hash())));
+    }
+    Ok(())
+}
+
+/// Block rejected by the tree is invalid only if it fails the validation,
+/// otherwise it could be rejected for a reason the peer isn't aware of
+fn rejection(report: Report<BlockChainTreeError>) -> Report<P2pError> {
+    let kind = match report.downcast_ref::<BlockError>() {
+        Some(BlockError::Validation(_)) => P2pErrorKind::InvalidBlock,
+        _ => P2pErrorKind::Rejected,
+    };
+    report.change_context(P2pError::P2p(kind))
+}
+
+/// Downloads the main chain from the peers, see the module docs
+pub struct ChainSync {
+    network: Network,
+    /// Headers waiting for their blocks, by the height
+    headers: Tree,
+}
+
+impl ChainSync {
+    pub async fn new(network: Network) -> Result<ChainSync, P2pError> {
+        let headers = network
+            .tree()
+            .read()
+            .await
+            .get_main_chain()
+            .open_tree(SYNC_HEADERS_TREE)
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+        Ok(ChainSync { network, headers })
+    }
+
+    /// Headers downloaded but not applied yet, in the order of the heights
+    pub fn pending_headers(&self) -> Result<Vec<Header>, P2pError> {
+        self.headers
+            .iter()
+            .values()
+            .map(|dump| parse_header(&dump.change_context(P2pError::P2p(P2pErrorKind::Tree))?))
+            .collect()
+    }
+
+    /// Synchronizes the main chain with the peer
+    ///
+    /// Returns the amount of applied blocks
+    pub async fn sync(&self, peer: PeerId) -> Result<usize, P2pError> {
+        self.download_headers(peer).await?;
+        self.download_blocks(peer).await
+    }
+
+    /// Downloads and checks the headers of the peer's chain past the last common block
+    ///
+    /// Stored headers of another branch are replaced. Returns the amount of new headers
+    pub async fn download_headers(&self, peer: PeerId) -> Result<usize, P2pError> {
+        let mut prev = self.find_fork(peer).await?;
+        self.remove_headers_from(&(prev.info.height + 1))?;
+
+        let mut added = 0;
+        loop {
+            let headers = self
+                .network
+                .get_headers(peer, prev.info.height + 1, MAX_HEADERS as u32)
+                .await?;
+            let amount = headers.len();
+
+            let mut batch = sled::Batch::default();
+            for header in headers {
+                block::validate_header(&header.info, &header.body_root, &prev.info, &prev.hash)
+                    .change_context(P2pError::P2p(P2pErrorKind::InvalidBlock))
+                    .attach_printable_lazy(|| {
+                        format!("invalid header at height {}", header.info.height)
+                    })?;
+                batch.insert(&height_key(&header.info.height), dump_header(&header));
+                prev = header;
+            }
+            self.headers
+                .apply_batch(batch)
+                .change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+
+            added += amount;
+            if amount < MAX_HEADERS {
+                break;
+            }
+        }
+        if added > 0 {
+            info!("downloaded {added} headers from peer {peer}");
+        }
+        Ok(added)
+    }
+
+    /// Downloads the blocks of the stored headers and applies them with their transactions
+    ///
+    /// Returns the amount of applied blocks
+    pub async fn download_blocks(&self, peer: PeerId) -> Result<usize, P2pError> {
+        let mut applied = 0;
+        loop {
+            let headers = self
+                .headers
+                .iter()
+                .values()
+                .take(SYNC_PARALLEL_REQUESTS)
+                .map(|dump| parse_header(&dump.change_context(P2pError::P2p(P2pErrorKind::Tree))?))
+                .collect::<Result<Vec<Header>, P2pError>>()?;
+            if headers.is_empty() {
+                break;
+            }
+
+            let items = headers
+                .iter()
+                .map(|header| Inventory {
+                    kind: InventoryKind::Block,
+                    hash: header.hash,
+                })
+                .collect();
+            let dumps = fetch(&self.network, peer, items).await?;
+
+            for (header, dump) in headers.iter().zip(dumps) {
+                if let Err(report) = self.apply_block(peer, header, &dump).await {
+                    // the following headers build on the rejected block
+                    self.remove_headers_from(&header.info.height)?;
+                    return Err(report);
+                }
+                self.headers
+                    .remove(height_key(&header.info.height))
+                    .change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+                applied += 1;
+            }
+        }
+        if applied > 0 {
+            info!("applied {applied} blocks from peer {peer}");
+        }
+        Ok(applied)
+    }
+
+    /// Synchronizes with the peer with the longest chain until the network is stopped
+    pub async fn run(self, mut stop: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(SYNC_INTERVAL));
+        loop {
+            tokio::select! {
+                _ = stop.changed() => return,
+                _ = interval.tick() => {}
+            }
+
+            let height = self
+                .network
+                .tree()
+                .read()
+                .await
+                .get_main_chain()
+                .get_height();
+            // the next peer is tried when the longest chain fails
+            let mut peers: Vec<_> = self
+                .network
+                .peers()
+                .into_iter()
+                .filter(|peer| peer.handshake.height > height)
+                .collect();
+            peers.sort_by_key(|peer| std::cmp::Reverse(peer.handshake.height));
+            for peer in peers {
+                let synchronized = tokio::select! {
+                    _ = stop.changed() => return,
+                    synchronized = self.sync(peer.id) => synchronized,
+                };
+                match synchronized {
+                    Ok(_) => break,
+                    Err(report) => {
+                        warn!(
+                            "failed to synchronize with peer {}: {report:?}",
+                            peer.address
+                        );
+                        self.network.punish(peer.id, &report);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Finds the last block the local chain, together with the stored headers,
+    /// shares with the peer
+    ///
+    /// Steps back from the local top with growing steps, the genesis block is always shared
+    async fn find_fork(&self, peer: PeerId) -> Result<Header, P2pError> {
+        let chain_top = self
+            .network
+            .tree()
+            .read()
+            .await
+            .get_main_chain()
+            .get_height()
+            - 1;
+        let mut height = match self
+            .headers
+            .last()
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+        {
+            Some((key, _)) => U256::from_big_endian(&key).max(chain_top),
+            None => chain_top,
+        };
+
+        let mut step = U256::one();
+        loop {
+            let local = self.local_header(&height).await?;
+            let remote = self.network.get_headers(peer, height, 1).await?;
+            if let (Some(local), Some(remote)) = (local, remote.first()) {
+                if local == *remote {
+                    return Ok(local);
+                }
+            }
+
+            if height.is_zero() {
+                return Err(invalid_data("peer doesn't share the genesis block".into()));
+            }
+            height = height.saturating_sub(step);
+            step = step.saturating_mul(U256::from(2));
+        }
+    }
+
+    /// Stored header at the height, or the header of the main chain block
+    async fn local_header(&self, height: &U256) -> Result<Option<Header>, P2pError> {
+        if let Some(dump) = self
+            .headers
+            .get(height_key(height))
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+        {
+            return parse_header(&dump).map(Some);
+        }
+
+        let Some(dump) = self
+            .network
+            .tree()
+            .read()
+            .await
+            .get_main_chain()
+            .find_raw_by_height(height)
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+        else {
+            return Ok(None);
+        };
+        let block = block::deserialize_main_chain_block(&dump)
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?;
+        Ok(Some(Header {
+            hash: tools::hash(&dump),
+            body_root: block.get_body_root(),
+            info: block.get_info().clone(),
+        }))
+    }
+
+    fn remove_headers_from(&self, height: &U256) -> Result<(), P2pError> {
+        let mut batch = sled::Batch::default();
+        for key in self.headers.range(height_key(height)..).keys() {
+            batch.remove(key.change_context(P2pError::P2p(P2pErrorKind::Tree))?);
+        }
+        self.headers
+            .apply_batch(batch)
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))
+    }
+
+    /// Checks the block against its header and adds it to the main chain
+    async fn apply_block(
+        &self,
+        peer: PeerId,
+        header: &Header,
+        dump: &[u8],
+    ) -> Result<(), P2pError> {
+        let block = block::deserialize_main_chain_block(dump)
+            .change_context(P2pError::P2p(P2pErrorKind::Malformed))?;
+        if tools::hash(dump) != header.hash || *block.get_info() != header.info {
+            return Err(invalid_data(format!(
+                "block at height {} doesn't match its header",
+                header.info.height
+            )));
+        }
+        add_block(&self.network, peer, block, header.hash).await
+    }
+}
+
+/// Downloads the items from the peer, `SYNC_PARALLEL_REQUESTS` of them in a single request
+///
+/// Dumps are returned in the order of the items, every item has to be found
+pub(crate) async fn fetch(
+    network: &Network,
+    peer: PeerId,
+    items: Vec<Inventory>,
+) -> Result<Vec<Vec<u8>>, P2pError> {
+    let mut dumps = Vec::with_capacity(items.len());
+    for requested in items.chunks(SYNC_PARALLEL_REQUESTS) {
+        let answers = network.get_data_batch(peer, requested).await?;
+        for (item, dump) in requested.iter().zip(answers) {
+            dumps.push(dump.ok_or_else(|| {
+                invalid_data(format!("peer doesn't have {}", hex::encode(item.hash)))
+            })?);
+        }
+    }
+    Ok(dumps)
+}
+
+/// Adds the block with the hash to the main chain, downloading the transactions
+/// missing locally from the peer
+///
+/// Already known blocks are skipped, either applied before or kept as side blocks
+pub(crate) async fn add_block(
+    network: &Network,
+    peer: PeerId,
+    block: BlockArc,
+    hash: Hash,
+) -> Result<(), P2pError> {
+    let mut dumps = Vec::new();
+    let mut missing = Vec::new();
+    {
+        let tree = network.tree().read().await;
+        let main_chain = tree.get_main_chain();
+        if main_chain
+            .get_chain_work(&hash)
+            .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let pool = tree.get_pool();
+        let pool = pool.read().await;
+        // the coinbase transaction is derived from the block
+        for hash in block.get_transactions().iter().skip(1) {
+            if main_chain
+                .transaction_exists(hash)
+                .change_context(P2pError::P2p(P2pErrorKind::Tree))?
+            {
+                continue;
+            }
+            match pool.get(hash) {
+                Some(entry) => dumps.push(
+                    entry
+                        .transaction
+                        .dump()
+                        .change_context(P2pError::P2p(P2pErrorKind::Tree))?,
+                ),
+                None => missing.push(Inventory {
+                    kind: InventoryKind::Transaction,
+                    hash: *hash,
+                }),
+            }
+        }
+    }
+    let fetched = fetch(network, peer, missing).await?;
+
+    let mut transactions = Vec::with_capacity(dumps.len() + fetched.len());
+    for dump in dumps {
+        transactions.push(parse_transaction(&dump)?);
+    }
+    // transactions of the pool are verified when they enter it
+    for dump in fetched {
+        let transaction = parse_transaction(&dump)?;
+        verify_transaction(transaction.as_ref())?;
+        transactions.push(transaction);
+    }
+
+    let height = block.get_info().height;
+    network
+        .tree()
+        .read()
+        .await
+        .add_new_block_items(block, &transactions)
+        .await
+        .map_err(rejection)
+        .attach_printable_lazy(|| format!("block at height {} is rejected", height))
+}
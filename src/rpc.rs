@@ -0,0 +1,449 @@
+use std::net::SocketAddr;
+
+use error_stack::{Context, Report, Result, ResultExt};
+use jsonrpsee::core::SubscriptionResult;
+use jsonrpsee::server::{PendingSubscriptionSink, Server, ServerHandle, SubscriptionMessage};
+use jsonrpsee::types::error::{ErrorObjectOwned, INTERNAL_ERROR_CODE, INVALID_PARAMS_CODE};
+use jsonrpsee::types::Params;
+use jsonrpsee::RpcModule;
+use primitive_types::U256;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::block::{Block, DerivativeAnchor};
+use crate::dump_headers::Headers;
+use crate::errors::{RpcError, RpcErrorKind};
+use crate::events::{Event, EventReceiver};
+use crate::node::SharedTree;
+use crate::token::Token;
+use crate::transaction::{self, Transactionable};
+
+type RpcResult = std::result::Result<Value, ErrorObjectOwned>;
+
+fn invalid_params(message: impl Into<String>) -> ErrorObjectOwned {
+    ErrorObjectOwned::owned(INVALID_PARAMS_CODE, message.into(), None::<()>)
+}
+
+fn internal_error<C: Context>(report: Report<C>) -> ErrorObjectOwned {
+    ErrorObjectOwned::owned(INTERNAL_ERROR_CODE, report.to_string(), None::<()>)
+}
+
+/// Decodes hex string of exactly `N` bytes
+fn parse_hex<const N: usize>(value: &str) -> std::result::Result<[u8; N], ErrorObjectOwned> {
+    hex::decode(value.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid_params(format!("expected {} hex encoded bytes", N)))
+}
+
+/// Reads the only parameter, which has to be a hex string of `N` bytes
+fn hex_param<const N: usize>(params: &Params) -> std::result::Result<[u8; N], ErrorObjectOwned> {
+    parse_hex(&params.one::<String>()?)
+}
+
+/// Heights are accepted both as numbers and as decimal strings
+fn height_param(params: &Params) -> std::result::Result<U256, ErrorObjectOwned> {
+    match params.one::<Value>()? {
+        Value::Number(number) => number
+            .as_u64()
+            .map(U256::from)
+            .ok_or_else(|| invalid_params("height should be a non-negative integer")),
+        Value::String(height) => U256::from_dec_str(&height)
+            .map_err(|_| invalid_params("height should be a decimal number")),
+        _ => Err(invalid_params("height should be a number or a string")),
+    }
+}
+
+/// JSON representation of a block
+///
+/// Hashes and addresses are hex encoded, big numbers are decimal strings
+pub fn block_to_json(block: &dyn Block) -> RpcResult {
+    let hash = block.hash().map_err(internal_error)?;
+    let info = block.get_info();
+    let mut value = json!({
+        "hash": hex::encode(hash),
+        "type": format!("{:?}", block.get_type()),
+        "height": info.height.to_string(),
+        "timestamp": info.timestamp,
+        "previous_hash": hex::encode(info.previous_hash),
+        "pow": hex::encode(info.pow),
+        "difficulty": hex::encode(info.difficulty),
+        "founder": hex::encode(info.founder),
+        "fee": block.get_fee().to_string(),
+        "transactions": block
+            .get_transactions()
+            .iter()
+            .map(hex::encode)
+            .collect::<Vec<_>>(),
+        "anchors": block
+            .get_anchors()
+            .iter()
+            .map(anchor_to_json)
+            .collect::<std::result::Result<Vec<_>, _>>()?,
+    });
+    // derivative blocks commit to the payment transaction instead of the merkle root
+    let root = hex::encode(block.get_merkle_root());
+    if block.get_type() == Headers::DerivativeBlock {
+        value["payment_transaction"] = Value::String(root);
+    } else {
+        value["merkle_root"] = Value::String(root);
+    }
+    Ok(value)
+}
+
+/// JSON representation of the derivative chain blocks anchored by a main chain block
+fn anchor_to_json(anchor: &DerivativeAnchor) -> RpcResult {
+    Ok(json!({
+        "owner": hex::encode(anchor.owner),
+        "height": anchor.height().to_string(),
+        "hash": hex::encode(anchor.hash().map_err(internal_error)?),
+        "blocks": anchor
+            .blocks
+            .iter()
+            .map(|block| block_to_json(block))
+            .collect::<std::result::Result<Vec<_>, _>>()?,
+    }))
+}
+
+/// JSON representation of a transaction
+pub fn transaction_to_json(transaction: &dyn Transactionable) -> Value {
+    let mut value = json!({
+        "hash": hex::encode(transaction.hash()),
+        "sender": hex::encode(transaction.get_sender()),
+        "receiver": hex::encode(transaction.get_receiver()),
+        "timestamp": transaction.get_timestamp(),
+        "nonce": transaction.get_nonce(),
+        "amount": transaction.get_amount().map(|amount| amount.to_string()),
+        "fee": transaction.get_fee().to_string(),
+        "data": transaction.get_data().map(hex::encode),
+        "signature": hex::encode(transaction.get_signature()),
+    });
+    if let Some(action) = transaction.as_token_action() {
+        value["token_action"] = json!({
+            "action": format!("{:?}", action.get_action()),
+            "token_hash": hex::encode(action.get_token_hash()),
+            "amount": action.get_token_amount().to_string(),
+            "parameters": action.get_parameters().map(|parameters| json!({
+                "token_data": parameters.token_data,
+                "smol_contract": parameters.smol_contract,
+                "coin_supply": parameters.coin_supply.to_string(),
+                "transfer_fee": parameters.transfer_fee.to_string(),
+            })),
+        });
+    }
+    value
+}
+
+/// JSON representation of a registered token
+pub fn token_to_json(token: &Token) -> Value {
+    json!({
+        "token_hash": hex::encode(token.token_hash),
+        "owner": hex::encode(token.current_owner),
+        "assigned": token.assigned,
+        "token_data": token.parameters.token_data,
+        "smol_contract": token.parameters.smol_contract,
+        "coin_supply": token.parameters.coin_supply.to_string(),
+        "transfer_fee": token.parameters.transfer_fee.to_string(),
+        "fee_static": token.is_fee_static(),
+    })
+}
+
+/// Sends the events picked by `notification` to the subscriber until it unsubscribes
+///
+/// The subscription is closed with an error if the subscriber falls behind the events
+async fn forward_events<F>(
+    pending: PendingSubscriptionSink,
+    mut events: EventReceiver,
+    notification: F,
+) -> SubscriptionResult
+where
+    F: Fn(&Event) -> std::result::Result<Option<Value>, ErrorObjectOwned>,
+{
+    let sink = pending.accept().await?;
+    loop {
+        let event = tokio::select! {
+            _ = sink.closed() => return Ok(()),
+            event = events.recv() => event,
+        };
+        match event {
+            Ok(event) => {
+                if let Some(value) = notification(&event).map_err(|error| error.to_string())? {
+                    sink.send(SubscriptionMessage::from_json(&value)?).await?;
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                return Err(format!("subscriber is behind, {} events were dropped", skipped).into())
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Builds the module with all the methods of the RPC API
+pub fn rpc_module(tree: SharedTree) -> Result<RpcModule<SharedTree>, RpcError> {
+    let mut module = RpcModule::new(tree);
+
+    module
+        .register_async_method("getHeight", |_, tree, _| async move {
+            let height = tree.read().await.get_main_chain().get_height();
+            RpcResult::Ok(Value::String(height.to_string()))
+        })
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_async_method("getBlockByHeight", |params, tree, _| async move {
+            let height = height_param(&params)?;
+            let block = tree
+                .read()
+                .await
+                .get_main_chain()
+                .find_by_height(&height)
+                .map_err(internal_error)?;
+            match block {
+                Some(block) => block_to_json(block.as_ref()),
+                None => Ok(Value::Null),
+            }
+        })
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_async_method("getBlockByHash", |params, tree, _| async move {
+            let hash = hex_param::<32>(&params)?;
+            let block = tree
+                .read()
+                .await
+                .get_main_chain()
+                .find_by_hash(&hash)
+                .map_err(internal_error)?;
+            match block {
+                Some(block) => block_to_json(block.as_ref()),
+                None => Ok(Value::Null),
+            }
+        })
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_async_method("getTransaction", |params, tree, _| async move {
+            let hash = hex_param::<32>(&params)?;
+            let dump = tree
+                .read()
+                .await
+                .get_main_chain()
+                .get_transaction_raw(&hash)
+                .map_err(internal_error)?;
+            let Some(dump) = dump else {
+                return RpcResult::Ok(Value::Null);
+            };
+            let transaction = transaction::parse_transactionable(&dump).map_err(internal_error)?;
+            RpcResult::Ok(transaction_to_json(transaction.as_ref()))
+        })
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_async_method("getBalance", |params, tree, _| async move {
+            let address = hex_param::<33>(&params)?;
+            let amount = tree
+                .read()
+                .await
+                .get_amount(&address)
+                .map_err(internal_error)?;
+            RpcResult::Ok(Value::String(amount.to_string()))
+        })
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_async_method("getGas", |params, tree, _| async move {
+            let address = hex_param::<33>(&params)?;
+            let gas = tree
+                .read()
+                .await
+                .get_gas(&address)
+                .map_err(internal_error)?;
+            RpcResult::Ok(Value::String(gas.to_string()))
+        })
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_async_method("sendRawTransaction", |params, tree, _| async move {
+            let dump = hex::decode(params.one::<String>()?.trim_start_matches("0x"))
+                .map_err(|_| invalid_params("transaction should be hex encoded"))?;
+            let transaction = transaction::parse_transactionable(&dump)
+                .map_err(|report| invalid_params(report.to_string()))?;
+            let hash = transaction.hash();
+
+            tree.read()
+                .await
+                .send_transaction_item(transaction)
+                .await
+                .map_err(|report| invalid_params(format!("{report:#}")))?;
+            RpcResult::Ok(Value::String(hex::encode(hash)))
+        })
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_async_method("getDerivativeChain", |params, tree, _| async move {
+            let owner = hex_param::<33>(&params)?;
+            let Some(chain) = tree.read().await.find_derivative_chain(&owner) else {
+                return RpcResult::Ok(Value::Null);
+            };
+            let last_block = match chain.get_last_block().map_err(internal_error)? {
+                Some(block) => block_to_json(&block)?,
+                None => Value::Null,
+            };
+            Ok(json!({
+                "owner": hex::encode(owner),
+                "genesis_hash": hex::encode(*chain.genesis_hash),
+                "height": chain.get_height().to_string(),
+                "last_block": last_block,
+            }))
+        })
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_async_method("getToken", |params, tree, _| async move {
+            let token_hash = hex_param::<32>(&params)?;
+            let token = tree
+                .read()
+                .await
+                .get_token(&token_hash)
+                .map_err(internal_error)?;
+            RpcResult::Ok(token.as_ref().map_or(Value::Null, token_to_json))
+        })
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_async_method("getTokens", |_, tree, _| async move {
+            let tokens = tree.read().await.list_tokens().map_err(internal_error)?;
+            RpcResult::Ok(Value::Array(tokens.iter().map(token_to_json).collect()))
+        })
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_async_method("getTokenHoldings", |params, tree, _| async move {
+            let address = hex_param::<33>(&params)?;
+            let holdings = tree
+                .read()
+                .await
+                .get_token_holdings(&address)
+                .map_err(internal_error)?;
+            RpcResult::Ok(Value::Array(
+                holdings
+                    .iter()
+                    .map(|(token_hash, amount)| {
+                        json!({
+                            "token_hash": hex::encode(token_hash),
+                            "amount": amount.to_string(),
+                        })
+                    })
+                    .collect(),
+            ))
+        })
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_subscription(
+            "subscribeNewBlocks",
+            "newBlock",
+            "unsubscribeNewBlocks",
+            |_, pending, tree, _| async move {
+                let events = tree.read().await.subscribe();
+                forward_events(pending, events, |event| match event {
+                    Event::BlockAdded(block) => block_to_json(block.as_ref()).map(Some),
+                    _ => Ok(None),
+                })
+                .await
+            },
+        )
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_subscription(
+            "subscribeDerivativeBlocks",
+            "derivativeBlock",
+            "unsubscribeDerivativeBlocks",
+            |params, pending, tree, _| async move {
+                let owner = match hex_param::<33>(&params) {
+                    Ok(owner) => owner,
+                    Err(error) => {
+                        pending.reject(error).await;
+                        return Ok(());
+                    }
+                };
+                let events = tree.read().await.subscribe();
+                forward_events(pending, events, |event| match event {
+                    Event::DerivativeBlockAdded(block) if *block.get_founder() == owner => {
+                        block_to_json(block.as_ref()).map(Some)
+                    }
+                    _ => Ok(None),
+                })
+                .await
+            },
+        )
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_subscription(
+            "subscribeTransactions",
+            "transactionAccepted",
+            "unsubscribeTransactions",
+            |_, pending, tree, _| async move {
+                let events = tree.read().await.subscribe();
+                forward_events(pending, events, |event| match event {
+                    Event::TransactionAccepted {
+                        hash,
+                        sender,
+                        nonce,
+                        fee,
+                    } => Ok(Some(json!({
+                        "hash": hex::encode(hash),
+                        "sender": hex::encode(sender),
+                        "nonce": nonce,
+                        "fee": fee.to_string(),
+                    }))),
+                    _ => Ok(None),
+                })
+                .await
+            },
+        )
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    module
+        .register_subscription(
+            "subscribeEpochs",
+            "epochRotated",
+            "unsubscribeEpochs",
+            |_, pending, tree, _| async move {
+                let events = tree.read().await.subscribe();
+                forward_events(pending, events, |event| match event {
+                    Event::EpochRotated(block) => block_to_json(block.as_ref()).map(Some),
+                    _ => Ok(None),
+                })
+                .await
+            },
+        )
+        .change_context(RpcError::Rpc(RpcErrorKind::Register))?;
+
+    Ok(module)
+}
+
+/// Starts the JSON-RPC server, serving both HTTP and WebSocket on the same address
+///
+/// Subscriptions are available only over WebSocket
+///
+/// Returns the address the server is bound to, which matters for the port 0
+pub async fn start(
+    listen: SocketAddr,
+    tree: SharedTree,
+) -> Result<(SocketAddr, ServerHandle), RpcError> {
+    let module = rpc_module(tree)?;
+    let server = Server::builder()
+        .build(listen)
+        .await
+        .change_context(RpcError::Rpc(RpcErrorKind::Bind))
+        .attach_printable_lazy(|| format!("failed to listen on {}", listen))?;
+    let address = server
+        .local_addr()
+        .change_context(RpcError::Rpc(RpcErrorKind::Bind))?;
+
+    Ok((address, server.start(module)))
+}
@@ -1,22 +1,49 @@
 use lazy_static::lazy_static;
 use primitive_types::U256;
 
+/// Default data directory, used by `BlockChainTree::new()`
 pub static BLOCKCHAIN_DIRECTORY: &str = "./BlockChainTree/";
 
-pub static AMMOUNT_SUMMARY: &str = "./BlockChainTree/SUMMARY/";
-pub static OLD_AMMOUNT_SUMMARY: &str = "./BlockChainTree/SUMMARYOLD/";
+// all the paths below are relative to the data directory
 
-pub static GAS_SUMMARY: &str = "./BlockChainTree/GASSUMMARY/";
-pub static OLD_GAS_SUMMARY: &str = "./BlockChainTree/GASSUMMARYOLD/";
+pub static MAIN_CHAIN_DIRECTORY: &str = "MAIN/";
 
-pub static MAIN_CHAIN_DIRECTORY: &str = "./BlockChainTree/MAIN/";
-
-pub static DERIVATIVE_CHAINS_DIRECTORY: &str = "./BlockChainTree/DERIVATIVES/";
+pub static DERIVATIVE_CHAINS_DIRECTORY: &str = "DERIVATIVES/";
 pub static CHAINS_FOLDER: &str = "CHAINS/";
 
 pub static BLOCKS_FOLDER: &str = "BLOCKS/";
 pub static REFERENCES_FOLDER: &str = "REF/";
 pub static TRANSACTIONS_FOLDER: &str = "TRANSACTIONS/";
+pub static DATABASE_FOLDER: &str = "DB/";
+
+// trees of the main chain database
+
+pub static BLOCKS_TREE: &str = "BLOCKS";
+pub static REFERENCES_TREE: &str = "REF";
+pub static TRANSACTIONS_TREE: &str = "TRANSACTIONS";
+pub static BRANCHES_TREE: &str = "BRANCHES";
+pub static BRANCH_CHILDREN_TREE: &str = "CHILDREN";
+pub static BRANCH_TRANSACTIONS_TREE: &str = "BRANCHTRANSACTIONS";
+pub static WORK_TREE: &str = "WORK";
+pub static UNDO_TREE: &str = "UNDO";
+
+pub static AMMOUNT_SUMMARY: &str = "SUMMARY";
+pub static OLD_AMMOUNT_SUMMARY: &str = "SUMMARYOLD";
+
+pub static GAS_SUMMARY: &str = "GASSUMMARY";
+pub static OLD_GAS_SUMMARY: &str = "GASSUMMARYOLD";
+
+// nonces are never rotated, otherwise old transactions could be replayed
+pub static NONCE_SUMMARY: &str = "NONCES";
+
+/// Anchored derivative chain tips by owner and height
+pub static ANCHORS_TREE: &str = "ANCHORS";
+/// Last anchored height of every derivative chain
+pub static ANCHOR_HEIGHTS_TREE: &str = "ANCHORHEIGHTS";
+/// Registered tokens by their hashes
+pub static TOKENS_TREE: &str = "TOKENS";
+/// Token balances by owner and token hash
+pub static TOKEN_BALANCES_TREE: &str = "TOKENBALANCES";
 
 pub static CONFIG_FILE: &str = "Chain.config";
 pub static LOOKUP_TABLE_FILE: &str = "LookUpTable.dat";
@@ -44,6 +71,59 @@ pub static BLOCKS_PER_EPOCH: usize = 1000000;
 
 pub static TIME_PER_BLOCK: u64 = 600;
 
+/// Seconds the timestamp of a main chain block can be ahead of the local clock
+pub static MAX_FUTURE_TIMESTAMP: u64 = 2 * 60 * 60;
+
+/// Maximum total size of the transactions put into a block template
+pub static MAX_BLOCK_TRANSACTIONS_SIZE: usize = 1000000;
+/// Maximum amount of derivative blocks carried by a single anchor
+pub static MAX_ANCHORED_BLOCKS: usize = 100;
+
+// limits of the transactions pool
+
+pub static POOL_CAPACITY: usize = 100000;
+pub static POOL_SENDER_LIMIT: usize = 64;
+pub static POOL_EXPIRATION: u64 = 3 * 24 * 60 * 60;
+
+/// Amount of events kept for the subscribers that are behind
+pub static EVENTS_CAPACITY: usize = 1024;
+
+// peer-to-peer protocol
+
+/// Peers with a different version of the wire protocol are rejected
+pub static PROTOCOL_VERSION: u32 = 1;
+/// Maximum size of a message frame, without the length prefix
+pub static MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+/// Maximum amount of items in a single inventory message
+pub static MAX_INVENTORY_SIZE: usize = 50000;
+/// Seconds given to the peer to complete the handshake or answer a request
+pub static PEER_TIMEOUT: u64 = 10;
+/// Messages waiting to be sent to a single peer
+pub static PEER_QUEUE_SIZE: usize = 256;
+/// Announced items accepted from a single peer per second, also the burst size
+pub static INVENTORY_RATE: u64 = 1000;
+/// Items remembered as known to a single peer, they aren't announced to it again
+pub static KNOWN_INVENTORY_SIZE: usize = 50000;
+/// Maximum amount of headers in a single message
+pub static MAX_HEADERS: usize = 2000;
+/// Blocks and transactions downloaded at the same time during the synchronization
+pub static SYNC_PARALLEL_REQUESTS: usize = 16;
+/// Seconds between checks for peers with a longer chain
+pub static SYNC_INTERVAL: u64 = 10;
+/// Tree keeping the downloaded headers until their blocks are applied
+pub static SYNC_HEADERS_TREE: &str = "SYNCHEADERS";
+/// Trees keeping the known addresses of the peers and the banned IPs
+pub static PEERS_TREE: &str = "PEERS";
+pub static PEER_BANS_TREE: &str = "PEERBANS";
+/// Failed connections in a row after which the address is forgotten
+pub static MAX_ADDRESS_FAILURES: u8 = 5;
+/// Misbehaviour score of a peer getting it banned
+pub static BAN_SCORE: u32 = 100;
+/// Seconds a misbehaving peer stays banned
+pub static BAN_DURATION: u64 = 24 * 60 * 60;
+/// Seconds between attempts to connect to more peers
+pub static PEER_MANAGER_INTERVAL: u64 = 30;
+
 lazy_static! {
     pub static ref COIN_FRACTIONS: U256 = U256::from_dec_str("1000000000000000000").unwrap();
     pub static ref INITIAL_FEE: U256 = U256::from_dec_str("25000000000000000").unwrap(); // 100_000_000//4
@@ -51,4 +131,5 @@ lazy_static! {
     pub static ref MAIN_CHAIN_PAYMENT: U256 = *INITIAL_FEE;
     pub static ref COINS_PER_CYCLE: U256 = (*MAIN_CHAIN_PAYMENT*2000usize*BLOCKS_PER_EPOCH) + *COIN_FRACTIONS*10000usize;
     pub static ref BYTE_GAS_PRICE: U256 = U256::from_dec_str("625000000000").unwrap();
+    pub static ref GAS_REWARD_STEP: U256 = *MAIN_CHAIN_PAYMENT; // per leading zero of the difficulty
 }
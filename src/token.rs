@@ -0,0 +1,559 @@
+//! User-issued tokens
+//!
+//! Tokens are managed by signed token actions, included into the main chain blocks
+//! along with the coin transactions. A token is created empty by its owner, then assigned
+//! once with its data, contract, coin supply and transfer fee. The whole supply goes to the
+//! owner and can be sent and burnt afterwards.
+//!
+//! Tokens with a static fee take `transfer_fee` tokens from the sender on every transfer
+//! and pay them to the owner, transfers of the owner are free. Sum of all the balances
+//! of a token always equals its coin supply.
+//!
+//! Token actions take the nonces of their senders and pay their own fee and gas
+//! as the coin transactions
+
+use crate::dump_headers::Headers;
+use crate::errors::*;
+use crate::tools;
+use crate::transaction::Transactionable;
+use crate::types::{Address, Hash};
+use primitive_types::U256;
+use secp256k1::ecdsa::Signature;
+use secp256k1::PublicKey;
+use secp256k1::{Message, Secp256k1, SecretKey};
+
+use error_stack::{Report, Result, ResultExt};
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Creates an empty token owned by the sender, named by the hash of the action
+    Create = 0,
+    /// Moves tokens of the sender to the receiver
+    Send = 1,
+    /// Sets the parameters of the sender's token, once
+    Assign = 2,
+    /// Destroys tokens of the sender, decreasing the coin supply
+    Burn = 3,
+}
+
+impl Action {
+    pub fn from_u8(action: u8) -> Result<Action, TransactionError> {
+        match action {
+            0 => Ok(Action::Create),
+            1 => Ok(Action::Send),
+            2 => Ok(Action::Assign),
+            3 => Ok(Action::Burn),
+            _ => Err(Report::new(TransactionError::Token(
+                TokenErrorKind::UnknownAction,
+            )))
+            .attach_printable(format!("action {}", action)),
+        }
+    }
+}
+
+/// Parameters set by the assignment of a token
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TokenParameters {
+    pub token_data: String,
+    pub smol_contract: String,
+    pub coin_supply: U256,
+    /// Tokens paid to the owner on every transfer, zero for no fee
+    pub transfer_fee: U256,
+}
+
+fn dump_string(string: &str, buffer: &mut Vec<u8>) -> Result<(), TransactionError> {
+    let size = u32::try_from(string.len())
+        .change_context(TransactionError::Token(TokenErrorKind::Dump))
+        .attach_printable("string is too long")?;
+    buffer.extend(size.to_be_bytes());
+    buffer.extend(string.as_bytes());
+    Ok(())
+}
+
+/// Loads the string, returning it with the size of its dump
+fn load_string(data: &[u8]) -> Result<(String, usize), TransactionError> {
+    let size = data
+        .get(..4)
+        .ok_or(Report::new(TransactionError::Token(TokenErrorKind::Parse)))
+        .attach_printable("no size of the string")?;
+    let size = u32::from_be_bytes(size.try_into().unwrap()) as usize;
+    let string = data
+        .get(4..4 + size)
+        .ok_or(Report::new(TransactionError::Token(TokenErrorKind::Parse)))
+        .attach_printable("string is truncated")?;
+    let string = String::from_utf8(string.to_vec())
+        .change_context(TransactionError::Token(TokenErrorKind::Parse))?;
+    Ok((string, 4 + size))
+}
+
+impl TokenParameters {
+    fn get_dump_size(&self) -> usize {
+        tools::u256_size(&self.coin_supply)
+            + tools::u256_size(&self.transfer_fee)
+            + 4
+            + self.token_data.len()
+            + 4
+            + self.smol_contract.len()
+    }
+
+    fn dump(&self, buffer: &mut Vec<u8>) -> Result<(), TransactionError> {
+        tools::dump_u256(&self.coin_supply, buffer)
+            .change_context(TransactionError::Token(TokenErrorKind::Dump))?;
+        tools::dump_u256(&self.transfer_fee, buffer)
+            .change_context(TransactionError::Token(TokenErrorKind::Dump))?;
+        dump_string(&self.token_data, buffer)?;
+        dump_string(&self.smol_contract, buffer)
+    }
+
+    /// Parses the parameters, returning them with the size of their dump
+    fn parse(data: &[u8]) -> Result<(Self, usize), TransactionError> {
+        let mut index = 0;
+        let (coin_supply, size) = tools::load_u256(data)
+            .change_context(TransactionError::Token(TokenErrorKind::Parse))
+            .attach_printable("Couldn't parse coin supply")?;
+        index += size + 1;
+        let (transfer_fee, size) = tools::load_u256(&data[index..])
+            .change_context(TransactionError::Token(TokenErrorKind::Parse))
+            .attach_printable("Couldn't parse transfer fee")?;
+        index += size + 1;
+        let (token_data, size) = load_string(&data[index..])?;
+        index += size;
+        let (smol_contract, size) = load_string(&data[index..])?;
+        index += size;
+        Ok((
+            Self {
+                token_data,
+                smol_contract,
+                coin_supply,
+                transfer_fee,
+            },
+            index,
+        ))
+    }
+}
+
+/// Token as stored in the token registry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub token_hash: Hash,
+    pub current_owner: Address,
+    /// Whether the parameters are set, they are empty before
+    pub assigned: bool,
+    pub parameters: TokenParameters,
+}
+
+impl Token {
+    /// Empty token of the owner
+    pub fn new(token_hash: Hash, current_owner: Address) -> Token {
+        Token {
+            token_hash,
+            current_owner,
+            assigned: false,
+            parameters: TokenParameters::default(),
+        }
+    }
+
+    /// Whether every transfer pays the fixed `transfer_fee`
+    pub fn is_fee_static(&self) -> bool {
+        !self.parameters.transfer_fee.is_zero()
+    }
+
+    /// Fee the sender pays to the owner for a transfer
+    pub fn transfer_fee_of(&self, sender: &Address) -> U256 {
+        if self.is_fee_static() && self.current_owner != *sender {
+            self.parameters.transfer_fee
+        } else {
+            U256::zero()
+        }
+    }
+
+    pub fn get_dump_size(&self) -> usize {
+        32 + 33 + 1 + self.parameters.get_dump_size()
+    }
+
+    pub fn dump(&self) -> Result<Vec<u8>, TransactionError> {
+        let mut dump = Vec::with_capacity(self.get_dump_size());
+        dump.extend(self.token_hash.iter());
+        dump.extend(self.current_owner.iter());
+        dump.push(self.assigned as u8);
+        self.parameters.dump(&mut dump)?;
+        Ok(dump)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Token, TransactionError> {
+        if data.len() < 66 {
+            return Err(Report::new(TransactionError::Token(
+                TokenErrorKind::ParseToken,
+            )))
+            .attach_printable("Data length < 66");
+        }
+        let (parameters, size) = TokenParameters::parse(&data[66..])
+            .change_context(TransactionError::Token(TokenErrorKind::ParseToken))?;
+        if 66 + size != data.len() {
+            return Err(Report::new(TransactionError::Token(
+                TokenErrorKind::ParseToken,
+            )))
+            .attach_printable("Index != Token size");
+        }
+        Ok(Token {
+            token_hash: data[..32].try_into().unwrap(),
+            current_owner: data[32..65].try_into().unwrap(),
+            assigned: data[65] != 0,
+            parameters,
+        })
+    }
+}
+
+/// Signed action on a token, see the module docs
+#[derive(Debug, Clone)]
+pub struct TokenAction {
+    action: Action,
+    sender: Address,
+    /// Receiver of the sent tokens, the sender for the other actions
+    receiver: Address,
+    timestamp: u64,
+    nonce: u64,
+    signature: [u8; 64],
+    /// Token the action is done on, zeroes for the creation
+    token_hash: Hash,
+    /// Tokens sent or burnt
+    amount: U256,
+    /// Coins offered to the founder of the including block
+    fee: U256,
+    /// Set only for the assignment
+    parameters: Option<TokenParameters>,
+    hash: Hash,
+}
+
+impl TokenAction {
+    /// Creates an empty token, named by the hash of the returned action
+    pub fn create(
+        sender: Address,
+        fee: U256,
+        timestamp: u64,
+        nonce: u64,
+        private_key: &[u8; 32],
+    ) -> Result<Self, TransactionError> {
+        Self::unsigned(
+            Action::Create,
+            sender,
+            sender,
+            fee,
+            timestamp,
+            nonce,
+            [0; 32],
+        )
+        .sign(private_key)
+    }
+
+    /// Sets the parameters of the token owned by the sender, the coin supply goes to the sender
+    pub fn assign(
+        sender: Address,
+        token_hash: Hash,
+        parameters: TokenParameters,
+        fee: U256,
+        timestamp: u64,
+        nonce: u64,
+        private_key: &[u8; 32],
+    ) -> Result<Self, TransactionError> {
+        let mut action = Self::unsigned(
+            Action::Assign,
+            sender,
+            sender,
+            fee,
+            timestamp,
+            nonce,
+            token_hash,
+        );
+        action.parameters = Some(parameters);
+        action.sign(private_key)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn send(
+        sender: Address,
+        receiver: Address,
+        token_hash: Hash,
+        amount: U256,
+        fee: U256,
+        timestamp: u64,
+        nonce: u64,
+        private_key: &[u8; 32],
+    ) -> Result<Self, TransactionError> {
+        let mut action = Self::unsigned(
+            Action::Send,
+            sender,
+            receiver,
+            fee,
+            timestamp,
+            nonce,
+            token_hash,
+        );
+        action.amount = amount;
+        action.sign(private_key)
+    }
+
+    pub fn burn(
+        sender: Address,
+        token_hash: Hash,
+        amount: U256,
+        fee: U256,
+        timestamp: u64,
+        nonce: u64,
+        private_key: &[u8; 32],
+    ) -> Result<Self, TransactionError> {
+        let mut action = Self::unsigned(
+            Action::Burn,
+            sender,
+            sender,
+            fee,
+            timestamp,
+            nonce,
+            token_hash,
+        );
+        action.amount = amount;
+        action.sign(private_key)
+    }
+
+    fn unsigned(
+        action: Action,
+        sender: Address,
+        receiver: Address,
+        fee: U256,
+        timestamp: u64,
+        nonce: u64,
+        token_hash: Hash,
+    ) -> Self {
+        TokenAction {
+            action,
+            sender,
+            receiver,
+            timestamp,
+            nonce,
+            signature: [0; 64],
+            token_hash,
+            amount: U256::zero(),
+            fee,
+            parameters: None,
+            hash: [0; 32],
+        }
+    }
+
+    fn sign(mut self, private_key: &[u8; 32]) -> Result<Self, TransactionError> {
+        let message = Message::from_digest(self.hash_without_signature());
+        let secret_key = SecretKey::from_slice(private_key)
+            .change_context(TransactionError::Token(TokenErrorKind::Sign))?;
+        self.signature = Secp256k1::new()
+            .sign_ecdsa(&message, &secret_key)
+            .serialize_compact();
+        self.hash = tools::hash(&self.dump()?);
+        Ok(self)
+    }
+
+    pub fn get_action(&self) -> Action {
+        self.action
+    }
+
+    pub fn get_token_hash(&self) -> &Hash {
+        &self.token_hash
+    }
+
+    /// Tokens sent or burnt
+    pub fn get_token_amount(&self) -> &U256 {
+        &self.amount
+    }
+
+    pub fn get_parameters(&self) -> Option<&TokenParameters> {
+        self.parameters.as_ref()
+    }
+
+    /// Dump without the header and the signature
+    fn dump_body(&self, buffer: &mut Vec<u8>) -> Result<(), TransactionError> {
+        buffer.push(self.action as u8);
+        buffer.extend(self.sender.iter());
+        buffer.extend(self.receiver.iter());
+        buffer.extend(self.timestamp.to_be_bytes());
+        buffer.extend(self.nonce.to_be_bytes());
+        buffer.extend(self.token_hash.iter());
+        tools::dump_u256(&self.amount, buffer)
+            .change_context(TransactionError::Token(TokenErrorKind::Dump))?;
+        tools::dump_u256(&self.fee, buffer)
+            .change_context(TransactionError::Token(TokenErrorKind::Dump))?;
+        if let Some(parameters) = self.parameters.as_ref() {
+            parameters.dump(buffer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Transactionable for TokenAction {
+    fn hash_without_signature(&self) -> [u8; 32] {
+        let mut concatenated_input = Vec::with_capacity(self.get_dump_size() - 64);
+        concatenated_input.push(Headers::Token as u8);
+        // the amount can always be dumped
+        self.dump_body(&mut concatenated_input).unwrap();
+        tools::hash(&concatenated_input)
+    }
+
+    fn verify(&self) -> Result<bool, TransactionError> {
+        let signed_data_hash = self.hash_without_signature();
+
+        // load sender
+        let sender = PublicKey::from_slice(&self.sender)
+            .change_context(TransactionError::Tx(TxErrorKind::Verify))?;
+
+        // creating verifier
+        let verifier = Secp256k1::verification_only();
+
+        // load message
+        let message = Message::from_digest_slice(&signed_data_hash)
+            .change_context(TransactionError::Tx(TxErrorKind::Verify))?;
+
+        // load signature
+        let signature = Signature::from_compact(&self.signature)
+            .change_context(TransactionError::Tx(TxErrorKind::Verify))?;
+
+        Ok(verifier.verify_ecdsa(&message, &signature, &sender).is_ok())
+    }
+
+    fn dump(&self) -> Result<Vec<u8>, TransactionError> {
+        let mut dump = Vec::with_capacity(self.get_dump_size());
+
+        // header
+        dump.push(Headers::Token as u8);
+
+        // signature
+        dump.extend(self.signature.iter());
+
+        self.dump_body(&mut dump)?;
+
+        Ok(dump)
+    }
+
+    fn hash(&self) -> [u8; 32] {
+        self.hash
+    }
+
+    fn get_dump_size(&self) -> usize {
+        1 + 64
+            + 1
+            + 33
+            + 33
+            + 8
+            + 8
+            + 32
+            + tools::u256_size(&self.amount)
+            + tools::u256_size(&self.fee)
+            + self
+                .parameters
+                .as_ref()
+                .map_or(0, TokenParameters::get_dump_size)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, TransactionError> {
+        if data.len() < 181 {
+            return Err(Report::new(TransactionError::Token(TokenErrorKind::Parse)))
+                .attach_printable("Data length < 181");
+        }
+        let mut index = 0;
+
+        let signature: [u8; 64] = data[index..index + 64].try_into().unwrap();
+        index += 64;
+
+        let action = Action::from_u8(data[index])
+            .change_context(TransactionError::Token(TokenErrorKind::Parse))?;
+        index += 1;
+
+        let sender: Address = data[index..index + 33].try_into().unwrap();
+        index += 33;
+
+        let receiver: Address = data[index..index + 33].try_into().unwrap();
+        index += 33;
+
+        let timestamp = u64::from_be_bytes(data[index..index + 8].try_into().unwrap());
+        index += 8;
+
+        let nonce = u64::from_be_bytes(data[index..index + 8].try_into().unwrap());
+        index += 8;
+
+        let token_hash: Hash = data[index..index + 32].try_into().unwrap();
+        index += 32;
+
+        let (amount, size) = tools::load_u256(&data[index..])
+            .change_context(TransactionError::Token(TokenErrorKind::Parse))
+            .attach_printable("Couldn't parse amount")?;
+        index += size + 1;
+
+        let (fee, size) = tools::load_u256(&data[index..])
+            .change_context(TransactionError::Token(TokenErrorKind::Parse))
+            .attach_printable("Couldn't parse fee")?;
+        index += size + 1;
+
+        let parameters = if action == Action::Assign {
+            let (parameters, size) = TokenParameters::parse(&data[index..])?;
+            index += size;
+            Some(parameters)
+        } else {
+            None
+        };
+
+        if index != data.len() {
+            return Err(Report::new(TransactionError::Token(TokenErrorKind::Parse)))
+                .attach_printable("Index != Token action size");
+        }
+
+        let mut token_action = TokenAction {
+            action,
+            sender,
+            receiver,
+            timestamp,
+            nonce,
+            signature,
+            token_hash,
+            amount,
+            fee,
+            parameters,
+            hash: [0; 32],
+        };
+        token_action.hash = tools::hash(&token_action.dump()?);
+        Ok(token_action)
+    }
+
+    fn get_sender(&self) -> &[u8; 33] {
+        &self.sender
+    }
+
+    fn get_receiver(&self) -> &[u8; 33] {
+        &self.receiver
+    }
+
+    fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    fn get_nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    fn get_signature(&self) -> &[u8; 64] {
+        &self.signature
+    }
+
+    /// Token actions don't move coins
+    fn get_amount(&self) -> Option<U256> {
+        None
+    }
+
+    fn get_fee(&self) -> U256 {
+        self.fee
+    }
+
+    fn get_data(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn as_token_action(&self) -> Option<&TokenAction> {
+        Some(self)
+    }
+}
@@ -1,5 +1,5 @@
 use crate::errors::*;
-use crate::static_values::{FEE_STEP, TIME_PER_BLOCK};
+use crate::static_values::{FEE_STEP, GAS_REWARD_STEP, MAIN_CHAIN_PAYMENT, TIME_PER_BLOCK};
 use crate::types::Hash;
 use error_stack::{Report, Result, ResultExt};
 use num_bigint::BigUint;
@@ -11,6 +11,7 @@ use std::fs::File;
 use std::io::Read;
 use std::io::Write;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{fs, io};
 
 pub fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()> {
@@ -54,13 +55,16 @@ pub fn dump_u256(number: &U256, buffer: &mut Vec<u8>) -> Result<(), ToolsError>
 }
 
 pub fn load_u256(data: &[u8]) -> Result<(U256, usize), ToolsError> {
-    let amount_of_bytes: usize = data[0] as usize;
+    let Some(&amount_of_bytes) = data.first() else {
+        return Err(Report::new(ToolsError::Biguint(BiguintErrorKind::Load)));
+    };
+    let amount_of_bytes = amount_of_bytes as usize;
 
     if amount_of_bytes > 32 {
         return Err(Report::new(ToolsError::Biguint(BiguintErrorKind::Dump)));
     }
 
-    if data.len() < amount_of_bytes {
+    if data.len() < 1 + amount_of_bytes {
         return Err(
             Report::new(ToolsError::Biguint(BiguintErrorKind::Load)).attach_printable(format!(
                 "data = {} // bytes = {}",
@@ -143,6 +147,13 @@ pub fn hash(data: &[u8]) -> [u8; 32] {
     hasher.finalize().as_slice().try_into().unwrap()
 }
 
+/// Current unix time in seconds
+pub fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
 pub fn compress_to_file(output_file: String, data: &[u8]) -> Result<(), ToolsError> {
     let path = Path::new(&output_file);
     let target =
@@ -207,6 +218,13 @@ pub fn check_pow(hash: &[u8; 32], difficulty: &[u8; 32], pow: &[u8]) -> bool {
     false
 }
 
+/// Amount of work represented by a block mined with the given difficulty
+///
+/// Every leading zero of the difficulty doubles the expected amount of hashes
+pub fn block_work(difficulty: &Hash) -> U256 {
+    U256::one() << count_leading_zeros(difficulty).min(255)
+}
+
 pub fn recalculate_difficulty(prev_timestamp: u64, timestamp: u64, prev_difficulty: &mut Hash) {
     let mut non_zero_index: usize = 0;
     for (index, val) in prev_difficulty.iter().enumerate() {
@@ -241,6 +259,21 @@ pub fn recalculate_fee(current_difficulty: &Hash) -> U256 {
     *FEE_STEP * leading_zeros
 }
 
+/// Gas reward of the derivative block founder
+///
+/// Grows with the difficulty the block's pow was checked against
+pub fn recalculate_gas_reward(difficulty: &Hash) -> U256 {
+    *GAS_REWARD_STEP * count_leading_zeros(difficulty)
+}
+
+/// Reward of the main chain block founder
+///
+/// Consists of the fixed payment and the sum of the fees of all the transactions in the block,
+/// `None` if it overflows
+pub fn block_reward(transaction_fees: &U256) -> Option<U256> {
+    MAIN_CHAIN_PAYMENT.checked_add(*transaction_fees)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -1,4 +1,6 @@
 use crate::errors::*;
+use crate::static_values::{ROOT_PRIVATE_ADDRESS, ROOT_PUBLIC_ADDRESS};
+use crate::token::TokenAction;
 use crate::tools;
 use primitive_types::U256;
 use sha2::{Digest, Sha256};
@@ -68,9 +70,31 @@ pub trait Transactionable: Send + Sync {
     fn get_sender(&self) -> &[u8; 33];
     fn get_receiver(&self) -> &[u8; 33];
     fn get_timestamp(&self) -> u64;
+    fn get_nonce(&self) -> u64;
     fn get_signature(&self) -> &[u8; 64];
     fn get_amount(&self) -> Option<U256>;
+    /// Fee offered by the sender, paid to the founder of the including block
+    fn get_fee(&self) -> U256;
     fn get_data(&self) -> Option<&[u8]>;
+
+    /// Token action, if the transaction is one
+    fn as_token_action(&self) -> Option<&TokenAction> {
+        None
+    }
+}
+
+/// Parses the dump of any transactionable by its header
+pub fn parse_transactionable(dump: &[u8]) -> Result<TransactionableItem, TransactionError> {
+    let header = dump
+        .first()
+        .ok_or(Report::new(TransactionError::Tx(TxErrorKind::Parse)))
+        .attach_printable("Empty dump")?;
+    match Headers::from_u8(*header) {
+        Ok(Headers::Transaction) => Ok(Box::new(Transaction::parse(&dump[1..])?)),
+        Ok(Headers::Token) => Ok(Box::new(TokenAction::parse(&dump[1..])?)),
+        _ => Err(Report::new(TransactionError::Tx(TxErrorKind::Parse)))
+            .attach_printable(format!("Wrong header: {}", header)),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -78,27 +102,38 @@ pub struct Transaction {
     sender: [u8; 33],
     receiver: [u8; 33],
     timestamp: u64,
+    nonce: u64,
     signature: [u8; 64],
     amount: U256,
+    fee: U256,
     //gas_amount: U256,
     data: Option<Vec<u8>>,
     hash: [u8; 32],
 }
 
 impl Transaction {
+    #[allow(clippy::too_many_arguments)]
     pub fn generate_signature(
         sender: &[u8; 33],
         receiver: &[u8; 33],
         timestamp: u64,
+        nonce: u64,
         amount: &U256,
+        fee: &U256,
         //gas_amount: &U256,
         data: Option<&[u8]>,
         private_key: &[u8; 32],
     ) -> [u8; 64] {
         let mut hasher = Sha256::new();
 
-        let calculated_size: usize =
-            1 + 33 + 33 + 8 + tools::u256_size(amount) + data.map_or(0, |data| data.len());
+        let calculated_size: usize = 1
+            + 33
+            + 33
+            + 8
+            + 8
+            + tools::u256_size(amount)
+            + tools::u256_size(fee)
+            + data.map_or(0, |data| data.len());
 
         let mut concatenated_input: Vec<u8> = Vec::with_capacity(calculated_size);
         concatenated_input.push(Headers::Transaction as u8);
@@ -111,10 +146,15 @@ impl Transaction {
         for byte in timestamp.to_be_bytes().iter() {
             concatenated_input.push(*byte);
         }
+        concatenated_input.extend(nonce.to_be_bytes());
         tools::dump_u256(amount, &mut concatenated_input)
             .attach_printable("Error to dump amount")
             .change_context(TransactionError::Tx(TxErrorKind::Dump))
             .unwrap();
+        tools::dump_u256(fee, &mut concatenated_input)
+            .attach_printable("Error to dump fee")
+            .change_context(TransactionError::Tx(TxErrorKind::Dump))
+            .unwrap();
         if let Some(data) = data {
             concatenated_input.extend(data.iter());
         }
@@ -132,11 +172,14 @@ impl Transaction {
         signature.serialize_compact()
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         sender: [u8; 33],
         receiver: [u8; 33],
         timestamp: u64,
+        nonce: u64,
         amount: U256,
+        fee: U256,
         private_key: [u8; 32],
         data: Option<Vec<u8>>,
     ) -> Transaction {
@@ -144,7 +187,9 @@ impl Transaction {
             &sender,
             &receiver,
             timestamp,
+            nonce,
             &amount,
+            &fee,
             data.as_deref(),
             &private_key,
         );
@@ -152,8 +197,10 @@ impl Transaction {
             sender,
             receiver,
             timestamp,
+            nonce,
             signature,
             amount,
+            fee,
             data,
             hash: [0; 32],
         };
@@ -162,12 +209,15 @@ impl Transaction {
         tr
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new_signed(
         //hash: [u8; 32],
         sender: [u8; 33],
         receiver: [u8; 33],
         timestamp: u64,
+        nonce: u64,
         amount: U256,
+        fee: U256,
         data: Option<Vec<u8>>,
         signature: [u8; 64],
     ) -> Transaction {
@@ -175,8 +225,10 @@ impl Transaction {
             sender,
             receiver,
             timestamp,
+            nonce,
             signature,
             amount,
+            fee,
             data,
             hash: [0; 32],
         };
@@ -185,6 +237,32 @@ impl Transaction {
         tr
     }
 
+    /// Creates coinbase transaction paying the block reward from the root address
+    ///
+    /// The height of the block is put into the data and the signature is deterministic,
+    /// so every node derives the same coinbase for the same block.
+    /// Coinbase doesn't use the nonce of the root address and pays no fee
+    pub fn new_coinbase(
+        founder: [u8; 33],
+        height: &U256,
+        timestamp: u64,
+        amount: U256,
+    ) -> Transaction {
+        let mut height_bytes = [0u8; 32];
+        height.to_big_endian(&mut height_bytes);
+
+        Transaction::new(
+            ROOT_PUBLIC_ADDRESS,
+            founder,
+            timestamp,
+            0,
+            amount,
+            U256::zero(),
+            ROOT_PRIVATE_ADDRESS,
+            Some(height_bytes.to_vec()),
+        )
+    }
+
     pub fn get_amount(&self) -> &U256 {
         &self.amount
     }
@@ -198,7 +276,9 @@ impl Transactionable for Transaction {
             + 33
             + 33
             + 8
+            + 8
             + tools::u256_size(&self.amount)
+            + tools::u256_size(&self.fee)
             + self.data.as_ref().map_or(0, |data| data.len());
 
         let mut concatenated_input: Vec<u8> = Vec::with_capacity(calculated_size);
@@ -212,10 +292,15 @@ impl Transactionable for Transaction {
         for byte in self.timestamp.to_be_bytes().iter() {
             concatenated_input.push(*byte);
         }
+        concatenated_input.extend(self.nonce.to_be_bytes());
         tools::dump_u256(&self.amount, &mut concatenated_input)
             .attach_printable("Error to dump amount")
             .change_context(TransactionError::Tx(TxErrorKind::Dump))
             .unwrap();
+        tools::dump_u256(&self.fee, &mut concatenated_input)
+            .attach_printable("Error to dump fee")
+            .change_context(TransactionError::Tx(TxErrorKind::Dump))
+            .unwrap();
 
         if let Some(data) = self.data.as_ref() {
             concatenated_input.extend(data.iter());
@@ -273,6 +358,9 @@ impl Transactionable for Transaction {
         // timestamp
         transaction_dump.extend(self.timestamp.to_be_bytes().iter());
 
+        // nonce
+        transaction_dump.extend(self.nonce.to_be_bytes().iter());
+
         // signature
         for byte in self.signature.iter() {
             transaction_dump.push(*byte);
@@ -282,6 +370,10 @@ impl Transactionable for Transaction {
         tools::dump_u256(&self.amount, &mut transaction_dump)
             .change_context(TransactionError::Tx(TxErrorKind::Dump))?;
 
+        // fee
+        tools::dump_u256(&self.fee, &mut transaction_dump)
+            .change_context(TransactionError::Tx(TxErrorKind::Dump))?;
+
         // data
         if let Some(data) = self.data.as_ref() {
             transaction_dump.extend(data.iter());
@@ -294,17 +386,19 @@ impl Transactionable for Transaction {
         1 + 33
             + 33
             + 8
+            + 8
             + 64
             + tools::u256_size(&self.amount)
+            + tools::u256_size(&self.fee)
             + self.data.as_ref().map_or(0, |data| data.len())
     }
 
     fn parse(data: &[u8]) -> Result<Transaction, TransactionError> {
         let mut index: usize = 0;
 
-        if data.len() < 139 {
+        if data.len() < 148 {
             return Err(Report::new(TransactionError::Tx(TxErrorKind::Parse))
-                .attach_printable("Data length < 139"));
+                .attach_printable("Data length < 148"));
         }
 
         // parsing sender address
@@ -319,6 +413,10 @@ impl Transactionable for Transaction {
         let timestamp: u64 = u64::from_be_bytes(data[index..index + 8].try_into().unwrap());
         index += 8;
 
+        // parsing nonce
+        let nonce: u64 = u64::from_be_bytes(data[index..index + 8].try_into().unwrap());
+        index += 8;
+
         // parsing signature
         let signature: [u8; 64] = unsafe { data[index..index + 64].try_into().unwrap_unchecked() };
         index += 64;
@@ -330,6 +428,13 @@ impl Transactionable for Transaction {
 
         index += idx + 1;
 
+        // parsing fee
+        let (fee, idx) = tools::load_u256(&data[index..])
+            .attach_printable("Couldn't parse fee")
+            .change_context(TransactionError::Tx(TxErrorKind::Parse))?;
+
+        index += idx + 1;
+
         let tx_data = if index == data.len() {
             None
         } else {
@@ -345,7 +450,7 @@ impl Transactionable for Transaction {
         }
 
         Ok(Transaction::new_signed(
-            sender, receiver, timestamp, amount, tx_data, signature,
+            sender, receiver, timestamp, nonce, amount, fee, tx_data, signature,
         ))
     }
 
@@ -361,6 +466,10 @@ impl Transactionable for Transaction {
         self.timestamp
     }
 
+    fn get_nonce(&self) -> u64 {
+        self.nonce
+    }
+
     fn get_signature(&self) -> &[u8; 64] {
         &self.signature
     }
@@ -368,6 +477,10 @@ impl Transactionable for Transaction {
         Some(self.amount)
     }
 
+    fn get_fee(&self) -> U256 {
+        self.fee
+    }
+
     fn get_data(&self) -> Option<&[u8]> {
         self.data.as_deref()
     }
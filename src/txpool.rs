@@ -1,58 +1,328 @@
-use std::collections::binary_heap::Iter;
-use std::collections::{BinaryHeap, HashSet};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
 use std::sync::Arc;
 
+use error_stack::{Report, Result, ResultExt};
+use primitive_types::U256;
+use serde::Deserialize;
 use tokio::sync::RwLock;
 
-use crate::transaction::TransactionableItem;
+use crate::errors::{TransactionError, TxErrorKind};
+use crate::static_values::{POOL_CAPACITY, POOL_EXPIRATION, POOL_SENDER_LIMIT};
+use crate::transaction::{self, TransactionableItem};
+use crate::types::{Address, Hash};
 
 pub type SharedTxPool = Arc<RwLock<TxPool>>;
 
+/// Limits of the transactions pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TxPoolConfig {
+    /// Maximum amount of transactions in the pool
+    pub capacity: usize,
+    /// Maximum amount of pending transactions of a single sender
+    pub sender_limit: usize,
+    /// Seconds a transaction is kept in the pool
+    pub expiration: u64,
+}
+
+impl Default for TxPoolConfig {
+    fn default() -> Self {
+        TxPoolConfig {
+            capacity: POOL_CAPACITY,
+            sender_limit: POOL_SENDER_LIMIT,
+            expiration: POOL_EXPIRATION,
+        }
+    }
+}
+
+/// Transaction waiting in the pool
+pub struct PoolEntry {
+    pub transaction: TransactionableItem,
+    /// Unix time the transaction entered the pool
+    pub received: u64,
+}
+
+impl PoolEntry {
+    fn priority(&self) -> Priority {
+        Priority {
+            fee: self.transaction.get_fee(),
+            size: self.transaction.get_dump_size(),
+            timestamp: self.transaction.get_timestamp(),
+            hash: self.transaction.hash(),
+        }
+    }
+}
+
+/// Position of a transaction in the pool
+///
+/// The highest fee per byte comes first, then the oldest transaction.
+/// The fee is the one signed by the sender and paid to the founder of the block
+#[derive(PartialEq, Eq)]
+struct Priority {
+    fee: U256,
+    size: usize,
+    timestamp: u64,
+    hash: Hash,
+}
+
+impl Priority {
+    /// Compares fee per byte, the higher one is less
+    fn cmp_fee_rate(&self, other: &Self) -> Ordering {
+        // fee / size compared without division
+        other
+            .fee
+            .full_mul(U256::from(self.size))
+            .cmp(&self.fee.full_mul(U256::from(other.size)))
+    }
+}
+
+impl Ord for Priority {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cmp_fee_rate(other)
+            .then(self.timestamp.cmp(&other.timestamp))
+            .then(self.hash.cmp(&other.hash))
+    }
+}
+
+impl PartialOrd for Priority {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 #[derive(Default)]
 pub struct TxPool {
-    pool: BinaryHeap<TransactionableItem>,
-    hashes: HashSet<[u8; 32]>,
+    config: TxPoolConfig,
+    entries: HashMap<Hash, PoolEntry>,
+    order: BTreeSet<Priority>,
+    /// Pending nonces of every sender with the hashes of their transactions
+    senders: HashMap<Address, BTreeSet<(u64, Hash)>>,
 }
 
 impl TxPool {
     pub fn new() -> TxPool {
         TxPool::default()
     }
+
     pub fn with_capacity(capacity: usize) -> TxPool {
+        TxPool::with_config(TxPoolConfig {
+            capacity,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_config(config: TxPoolConfig) -> TxPool {
         TxPool {
-            pool: BinaryHeap::with_capacity(capacity),
-            hashes: HashSet::with_capacity(capacity),
+            config,
+            ..Default::default()
         }
     }
 
-    pub fn push(&mut self, transaction: TransactionableItem) -> bool {
-        if !self.hashes.insert(transaction.hash()) {
-            return false;
+    pub fn get_config(&self) -> &TxPoolConfig {
+        &self.config
+    }
+
+    /// Puts transaction into the pool
+    ///
+    /// When the pool is full, the transaction with the lowest fee per byte is evicted
+    /// with the later nonces of its sender, unless the new one is not better than it.
+    /// Transactions of the same sender are never evicted for the new one
+    pub fn push(
+        &mut self,
+        transaction: TransactionableItem,
+        received: u64,
+    ) -> Result<(), TransactionError> {
+        let entry = PoolEntry {
+            transaction,
+            received,
+        };
+        let hash = entry.transaction.hash();
+        if self.entries.contains_key(&hash) {
+            return Err(Report::new(TransactionError::Tx(
+                TxErrorKind::AlreadyInPool,
+            )));
+        }
+        let sender = *entry.transaction.get_sender();
+        if self.sender_pending(&sender) >= self.config.sender_limit {
+            return Err(Report::new(TransactionError::Tx(TxErrorKind::SenderLimit)));
+        }
+
+        let priority = entry.priority();
+        if self.entries.len() >= self.config.capacity {
+            match self.order.last() {
+                Some(lowest)
+                    if priority.cmp_fee_rate(lowest).is_lt()
+                        && self.entries[&lowest.hash].transaction.get_sender() != &sender =>
+                {
+                    let lowest = lowest.hash;
+                    self.evict(&lowest);
+                }
+                _ => return Err(Report::new(TransactionError::Tx(TxErrorKind::LowFee))),
+            }
         }
-        self.pool.push(transaction);
-        true
+
+        self.order.insert(priority);
+        self.senders
+            .entry(sender)
+            .or_default()
+            .insert((entry.transaction.get_nonce(), hash));
+        self.entries.insert(hash, entry);
+        Ok(())
     }
 
     pub fn len(&self) -> usize {
-        self.hashes.len()
+        self.entries.len()
     }
 
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
-    pub fn transactions_iter(&self) -> Iter<'_, TransactionableItem> {
-        self.pool.iter()
+    /// Iterates over the pool, the highest fee per byte first
+    pub fn entries_iter(&self) -> impl Iterator<Item = &PoolEntry> {
+        self.order
+            .iter()
+            .filter_map(|priority| self.entries.get(&priority.hash))
+    }
+
+    /// Iterates over the transactions, the highest fee per byte first
+    pub fn transactions_iter(&self) -> impl Iterator<Item = &TransactionableItem> {
+        self.entries_iter().map(|entry| &entry.transaction)
+    }
+
+    /// Amount of the pending transactions of the sender
+    pub fn sender_pending(&self, sender: &Address) -> usize {
+        self.senders.get(sender).map_or(0, BTreeSet::len)
     }
 
-    pub fn pop(&mut self) -> Option<([u8; 32], TransactionableItem)> {
-        let tr = self.pool.pop()?;
-        let hash = tr.hash();
-        self.hashes.remove(&hash);
-        Some((hash, tr))
+    /// Nonce following the latest pending nonce of the sender
+    pub fn next_nonce(&self, sender: &Address) -> Option<u64> {
+        self.senders
+            .get(sender)
+            .and_then(|nonces| nonces.last())
+            .map(|(nonce, _)| nonce + 1)
     }
 
-    pub fn transaction_exists(&self, hash: &[u8; 32]) -> bool {
-        self.hashes.contains(hash)
+    /// Takes the transaction with the highest fee per byte
+    pub fn pop(&mut self) -> Option<(Hash, TransactionableItem)> {
+        let hash = self.order.first()?.hash;
+        let entry = self.remove(&hash)?;
+        Some((hash, entry.transaction))
+    }
+
+    pub fn transaction_exists(&self, hash: &Hash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<&PoolEntry> {
+        self.entries.get(hash)
+    }
+
+    /// Removes transaction with the hash from the pool
+    pub fn remove(&mut self, hash: &Hash) -> Option<PoolEntry> {
+        let entry = self.entries.remove(hash)?;
+        self.order.remove(&entry.priority());
+
+        let sender = entry.transaction.get_sender();
+        if let Some(nonces) = self.senders.get_mut(sender) {
+            nonces.remove(&(entry.transaction.get_nonce(), *hash));
+            if nonces.is_empty() {
+                self.senders.remove(sender);
+            }
+        }
+
+        Some(entry)
+    }
+
+    /// Removes transaction with the hash from the pool with the later nonces of its sender,
+    /// they can't be included without it
+    ///
+    /// Returns amount of the removed transactions
+    fn evict(&mut self, hash: &Hash) -> usize {
+        let Some(entry) = self.remove(hash) else {
+            return 0;
+        };
+        let later: Vec<Hash> = self
+            .senders
+            .get(entry.transaction.get_sender())
+            .map(|nonces| {
+                nonces
+                    .range((
+                        Bound::Excluded((entry.transaction.get_nonce(), [u8::MAX; 32])),
+                        Bound::Unbounded,
+                    ))
+                    .map(|(_, hash)| *hash)
+                    .collect()
+            })
+            .unwrap_or_default();
+        for hash in later.iter() {
+            self.remove(hash);
+        }
+        1 + later.len()
+    }
+
+    /// Removes transactions which stayed in the pool longer than allowed
+    /// with the later nonces of their senders
+    ///
+    /// Returns amount of the removed transactions
+    pub fn remove_expired(&mut self, now: u64) -> usize {
+        let expired: Vec<Hash> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.received.saturating_add(self.config.expiration) < now)
+            .map(|(hash, _)| *hash)
+            .collect();
+        expired.iter().map(|hash| self.evict(hash)).sum()
+    }
+
+    /// Dumps all the transactions of the pool
+    ///
+    /// Every transaction is prefixed with the time it was received (u64 BE)
+    /// and the size of its dump (u32 BE)
+    pub fn dump(&self) -> Result<Vec<u8>, TransactionError> {
+        let mut to_return = Vec::new();
+        for entry in self.entries_iter() {
+            let dump = entry.transaction.dump()?;
+            to_return.extend(entry.received.to_be_bytes());
+            to_return.extend((dump.len() as u32).to_be_bytes());
+            to_return.extend(dump);
+        }
+        Ok(to_return)
+    }
+
+    /// Parses the pool dumped with `dump`
+    ///
+    /// Transactions not fitting into the limits of the config are dropped
+    pub fn parse(data: &[u8], config: TxPoolConfig) -> Result<TxPool, TransactionError> {
+        let mut pool = TxPool::with_config(config);
+        let mut index = 0;
+        while index < data.len() {
+            let received = data
+                .get(index..index + 8)
+                .ok_or(Report::new(TransactionError::Tx(TxErrorKind::Parse)))
+                .attach_printable("pool dump is truncated")?;
+            let received = u64::from_be_bytes(received.try_into().unwrap());
+            index += 8;
+
+            let size = data
+                .get(index..index + 4)
+                .ok_or(Report::new(TransactionError::Tx(TxErrorKind::Parse)))
+                .attach_printable("pool dump is truncated")?;
+            let size = u32::from_be_bytes(size.try_into().unwrap()) as usize;
+            index += 4;
+            let dump = data
+                .get(index..index + size)
+                .ok_or(Report::new(TransactionError::Tx(TxErrorKind::Parse)))
+                .attach_printable("pool dump is truncated")?;
+            index += size;
+
+            let transaction = transaction::parse_transactionable(dump)
+                .attach_printable("failed to parse transaction in pool dump")?;
+            // the config could have been tightened since the dump
+            let _ = pool.push(transaction, received);
+        }
+        Ok(pool)
     }
 }
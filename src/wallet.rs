@@ -0,0 +1,118 @@
+//! Keys of a single account
+//!
+//! Address of the wallet is the compressed secp256k1 public key of its private key
+
+use std::fmt;
+
+use crate::blockchaintree::BlockChainTree;
+use crate::errors::{BlockChainTreeError, WalletError, WalletErrorKind};
+use crate::tools;
+use crate::transaction::{Transaction, Transactionable};
+use crate::types::{Address, Hash};
+use error_stack::{Result, ResultExt};
+use primitive_types::U256;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Wallet {
+    private_key: [u8; 32],
+    address: Address,
+}
+
+impl Wallet {
+    /// Creates wallet with a random private key
+    pub fn generate() -> Wallet {
+        let (secret_key, public_key) =
+            Secp256k1::new().generate_keypair(&mut secp256k1::rand::thread_rng());
+        Wallet {
+            private_key: secret_key.secret_bytes(),
+            address: public_key.serialize(),
+        }
+    }
+
+    pub fn from_private_key(private_key: [u8; 32]) -> Result<Wallet, WalletError> {
+        Ok(Wallet {
+            address: Self::derive_address(&private_key)?,
+            private_key,
+        })
+    }
+
+    /// Compressed public key of the private key
+    pub fn derive_address(private_key: &[u8; 32]) -> Result<Address, WalletError> {
+        let secret_key = SecretKey::from_slice(private_key)
+            .change_context(WalletError::Wallet(WalletErrorKind::PrivateKey))?;
+        Ok(PublicKey::from_secret_key(&Secp256k1::new(), &secret_key).serialize())
+    }
+
+    pub fn get_address(&self) -> &Address {
+        &self.address
+    }
+
+    pub fn get_private_key(&self) -> &[u8; 32] {
+        &self.private_key
+    }
+
+    /// Signs the transaction from the wallet's address
+    pub fn transaction(
+        &self,
+        receiver: Address,
+        timestamp: u64,
+        nonce: u64,
+        amount: U256,
+        fee: U256,
+        data: Option<Vec<u8>>,
+    ) -> Transaction {
+        Transaction::new(
+            self.address,
+            receiver,
+            timestamp,
+            nonce,
+            amount,
+            fee,
+            self.private_key,
+            data,
+        )
+    }
+
+    /// Signs the transaction with the next free nonce and sends it to the tree's pool
+    ///
+    /// Returns the hash of the transaction
+    pub async fn send(
+        &self,
+        tree: &BlockChainTree,
+        receiver: Address,
+        amount: U256,
+        fee: U256,
+        data: Option<Vec<u8>>,
+    ) -> Result<Hash, BlockChainTreeError> {
+        let nonce = tree.next_nonce(&self.address).await?;
+        let transaction = self.transaction(
+            receiver,
+            tools::current_timestamp(),
+            nonce,
+            amount,
+            fee,
+            data,
+        );
+        let hash = transaction.hash();
+        tree.send_transaction(transaction).await?;
+        Ok(hash)
+    }
+
+    pub fn get_amount(&self, tree: &BlockChainTree) -> Result<U256, BlockChainTreeError> {
+        tree.get_amount(&self.address)
+    }
+
+    pub fn get_gas(&self, tree: &BlockChainTree) -> Result<U256, BlockChainTreeError> {
+        tree.get_gas(&self.address)
+    }
+}
+
+/// The private key is never printed
+impl fmt::Debug for Wallet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Wallet")
+            .field("address", &hex::encode(self.address))
+            .finish_non_exhaustive()
+    }
+}
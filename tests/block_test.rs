@@ -1,6 +1,12 @@
 use std::sync::Arc;
 
-use blockchaintree::block::{self, Block, DerivativeBlock};
+use blockchaintree::{
+    block::{self, Block, DerivativeBlock},
+    errors::{BlockError, ValidationErrorKind},
+    merkletree::MerkleTree,
+    static_values::{BEGINNING_DIFFICULTY, MAX_FUTURE_TIMESTAMP},
+    tools,
+};
 use primitive_types::U256;
 
 #[test]
@@ -30,6 +36,30 @@ fn dump_parse_basic_info() {
     println!("{:?}", basic_data_loaded)
 }
 
+#[test]
+fn parse_truncated_dumps() {
+    let basic_data = block::BasicInfo {
+        timestamp: 160000,
+        pow: [0; 32],
+        previous_hash: [5; 32],
+        height: U256::from(10),
+        difficulty: [101; 32],
+        founder: [6; 33],
+    };
+    let mut info = Vec::new();
+    basic_data.dump(&mut info).unwrap();
+    for size in [0, 106, 120, 136, 137] {
+        assert!(block::BasicInfo::parse(&info[..size]).is_err());
+    }
+
+    let block = block::TransactionBlock::new(U256::zero(), basic_data, [5; 32], vec![[1; 32]]);
+    let dump = block.dump().unwrap();
+    for size in [0, 10, 31, 32, 100, 169] {
+        assert!(block::TransactionBlock::parse(&dump[1..size + 1]).is_err());
+        assert!(block::deserialize_main_chain_block(&dump[..size + 1]).is_err());
+    }
+}
+
 #[test]
 fn dump_parse_block() {
     let basic_data = block::BasicInfo {
@@ -77,6 +107,58 @@ fn dump_parse_block() {
     println!("{:?}", block_loaded);
 }
 
+#[test]
+fn dump_parse_block_with_anchors() {
+    let basic_data = block::BasicInfo {
+        timestamp: 160000,
+        pow: [0; 32],
+        previous_hash: [5; 32],
+        height: U256::from(10),
+        difficulty: [101; 32],
+        founder: [6; 33],
+    };
+    let block =
+        block::TransactionBlock::new(U256::zero(), basic_data.clone(), [5; 32], vec![[1; 32]]);
+    let derivative_block = |height: u64, founder: [u8; 33]| block::DerivativeBlock {
+        default_info: block::BasicInfo {
+            timestamp: 160000 + height,
+            pow: [height as u8; 32],
+            previous_hash: [height as u8 + 1; 32],
+            height: U256::from(height),
+            difficulty: [102; 32],
+            founder,
+        },
+        payment_transaction: [height as u8 + 2; 32],
+    };
+    let anchors = vec![
+        block::DerivativeAnchor {
+            owner: [7; 33],
+            blocks: vec![derivative_block(1, [7; 33])],
+        },
+        block::DerivativeAnchor {
+            owner: [9; 33],
+            blocks: vec![derivative_block(20, [9; 33]), derivative_block(21, [9; 33])],
+        },
+    ];
+    let anchored = block::TransactionBlock::new(U256::zero(), basic_data, [5; 32], vec![[1; 32]])
+        .with_anchors(anchors.clone());
+
+    let dump = anchored.dump().unwrap();
+    let block_loaded = block::TransactionBlock::parse(&dump[1..]).unwrap();
+    assert_eq!(block_loaded.anchors, anchors);
+    assert_eq!(block_loaded.transactions, vec![[1; 32]]);
+    // the tip is the last anchored block
+    assert_eq!(anchors[1].height(), U256::from(21));
+    assert_eq!(
+        anchors[1].hash().unwrap(),
+        derivative_block(21, [9; 33]).hash().unwrap()
+    );
+
+    // the hash commits to the anchors
+    assert_ne!(block.hash().unwrap(), anchored.hash().unwrap());
+    assert!(block::TransactionBlock::parse(&dump[1..dump.len() - 40]).is_err());
+}
+
 #[test]
 fn dump_parse_summarize_block() {
     let basic_data = block::BasicInfo {
@@ -87,10 +169,7 @@ fn dump_parse_summarize_block() {
         difficulty: [101; 32],
         founder: [6; 33],
     };
-    let block = block::SummarizeBlock {
-        default_info: basic_data,
-        merkle_tree_root: [5; 32],
-    };
+    let block = block::SummarizeBlock::new(basic_data, [5; 32]);
 
     let dump = block.dump().unwrap();
 
@@ -152,7 +231,128 @@ fn validate_block_test() {
         vec![[1; 32], [2; 32], [3; 32]],
     );
 
-    assert!(!block.validate(Some(Arc::new(prev_block))).unwrap());
+    let report = block.validate(Some(Arc::new(prev_block))).unwrap_err();
+    assert!(matches!(
+        report.current_context(),
+        BlockError::Validation(ValidationErrorKind::MerkleRoot)
+    ));
+}
+
+#[test]
+fn validate_block_rejection_reasons_test() {
+    let transactions = vec![[1; 32], [2; 32], [3; 32]];
+    let merkle_tree_root = *MerkleTree::build_tree(&transactions).get_root();
+    let basic_data = block::BasicInfo {
+        timestamp: 160000,
+        pow: [0; 32],
+        previous_hash: [5; 32],
+        height: U256::one(),
+        difficulty: BEGINNING_DIFFICULTY,
+        founder: [6; 33],
+    };
+    let prev_block: block::BlockArc = Arc::new(block::TransactionBlock::new(
+        U256::zero(),
+        basic_data,
+        merkle_tree_root,
+        transactions.clone(),
+    ));
+    let prev_hash = prev_block.hash().unwrap();
+
+    let build = |timestamp: u64, pow: [u8; 32], height: U256, difficulty: [u8; 32]| {
+        block::TransactionBlock::new(
+            U256::zero(),
+            block::BasicInfo {
+                timestamp,
+                pow,
+                previous_hash: prev_hash,
+                height,
+                difficulty,
+                founder: [6; 33],
+            },
+            merkle_tree_root,
+            transactions.clone(),
+        )
+    };
+
+    let preimage = |block: &block::TransactionBlock| {
+        block::pow_preimage(&block.default_info, &block.get_body_root())
+    };
+
+    // pow of the block which doesn't satisfy the difficulty for another fee
+    let valid_preimage = preimage(&build(161000, [0; 32], U256::from(2), BEGINNING_DIFFICULTY));
+    let mut other_fee = build(161000, [0; 32], U256::from(2), BEGINNING_DIFFICULTY);
+    other_fee.fee = U256::one();
+    let other_preimage = preimage(&other_fee);
+    let mut pow = [0u8; 32];
+    for nonce in 0u64.. {
+        pow[..8].copy_from_slice(&nonce.to_be_bytes());
+        if tools::check_pow(&valid_preimage, &BEGINNING_DIFFICULTY, &pow)
+            && !tools::check_pow(&other_preimage, &BEGINNING_DIFFICULTY, &pow)
+        {
+            break;
+        }
+    }
+
+    // spaced out blocks keep the easiest difficulty
+    build(161000, pow, U256::from(2), BEGINNING_DIFFICULTY)
+        .validate(Some(prev_block.clone()))
+        .unwrap();
+
+    let expect = |block: block::TransactionBlock, kind: ValidationErrorKind| {
+        let report = block.validate(Some(prev_block.clone())).unwrap_err();
+        match report.current_context() {
+            BlockError::Validation(reason) => {
+                assert_eq!(
+                    std::mem::discriminant(reason),
+                    std::mem::discriminant(&kind)
+                )
+            }
+            other => panic!("unexpected error {other}"),
+        }
+    };
+    expect(
+        build(161000, pow, U256::from(3), BEGINNING_DIFFICULTY),
+        ValidationErrorKind::Height,
+    );
+    expect(
+        build(150000, pow, U256::from(2), BEGINNING_DIFFICULTY),
+        ValidationErrorKind::Timestamp,
+    );
+    expect(
+        build(
+            tools::current_timestamp() + MAX_FUTURE_TIMESTAMP + 1000,
+            pow,
+            U256::from(2),
+            BEGINNING_DIFFICULTY,
+        ),
+        ValidationErrorKind::FutureTimestamp,
+    );
+    expect(
+        build(161000, pow, U256::from(2), [0; 32]),
+        ValidationErrorKind::Difficulty,
+    );
+    let mut wrong_pow = pow;
+    while tools::check_pow(&valid_preimage, &BEGINNING_DIFFICULTY, &wrong_pow) {
+        wrong_pow[0] += 1;
+    }
+    expect(
+        build(161000, wrong_pow, U256::from(2), BEGINNING_DIFFICULTY),
+        ValidationErrorKind::Pow,
+    );
+    // the pow can't be reused for other contents of the block
+    other_fee.default_info.pow = pow;
+    expect(other_fee, ValidationErrorKind::Pow);
+}
+
+fn mine(prev_hash: &[u8; 32], difficulty: &[u8; 32]) -> [u8; 32] {
+    let mut pow = [0u8; 32];
+    for nonce in 0u64.. {
+        pow[..8].copy_from_slice(&nonce.to_be_bytes());
+        if tools::check_pow(prev_hash, difficulty, &pow) {
+            break;
+        }
+    }
+    pow
 }
 
 #[test]
@@ -160,14 +360,14 @@ fn dump_parse_derivative_block() {
     let basic_data = block::BasicInfo {
         timestamp: 160000,
         pow: [0; 32],
-        previous_hash: unsafe { [0; 32].try_into().unwrap_unchecked() },
+        previous_hash: [0; 32],
         height: U256::from_dec_str("2").unwrap(),
         difficulty: [101; 32],
         founder: [6; 33],
     };
     let derivative_block = DerivativeBlock {
         default_info: basic_data,
-        //payment_transaction,
+        payment_transaction: [7; 32],
     };
     let dumped_block = derivative_block.dump().unwrap();
     let parsed_block = DerivativeBlock::parse(&dumped_block[1..]).unwrap();
@@ -196,6 +396,11 @@ fn dump_parse_derivative_block() {
         derivative_block.default_info.founder,
         parsed_block.default_info.founder
     );
+    assert_eq!(
+        derivative_block.payment_transaction,
+        parsed_block.payment_transaction
+    );
+    assert_eq!(derivative_block.get_merkle_root(), [7; 32]);
 }
 
 #[test]
@@ -203,27 +408,140 @@ fn validate_derivative_block() {
     let basic_data = block::BasicInfo {
         timestamp: 160000,
         pow: [0; 32],
-        previous_hash: unsafe { [0; 32].try_into().unwrap_unchecked() },
+        previous_hash: [0; 32],
         height: U256::from_dec_str("2").unwrap(),
         difficulty: [101; 32],
         founder: [6; 33],
     };
     let prev_block = DerivativeBlock {
         default_info: basic_data,
+        payment_transaction: [0; 32],
     };
     let basic_data = block::BasicInfo {
         timestamp: 160000,
         pow: [0; 32],
-        previous_hash: unsafe { [0; 32].try_into().unwrap_unchecked() },
+        previous_hash: [0; 32],
         height: U256::from_dec_str("2").unwrap(),
         difficulty: [101; 32],
         founder: [6; 33],
     };
     let derivative_block = DerivativeBlock {
         default_info: basic_data,
+        payment_transaction: [0; 32],
     };
 
-    assert!(!derivative_block
+    let report = derivative_block
         .validate(Some(Arc::new(prev_block)))
-        .unwrap());
+        .unwrap_err();
+    assert!(matches!(
+        report.current_context(),
+        BlockError::Validation(ValidationErrorKind::PreviousHash)
+    ));
+}
+
+#[test]
+fn validate_derivative_block_pow_test() {
+    let founder = [6; 33];
+    let genesis_hash = [1; 32];
+    let mut first_block = DerivativeBlock {
+        default_info: block::BasicInfo {
+            timestamp: 160000,
+            pow: [0; 32],
+            previous_hash: genesis_hash,
+            height: U256::one(),
+            difficulty: BEGINNING_DIFFICULTY,
+            founder,
+        },
+        payment_transaction: [2; 32],
+    };
+    // pow for the payload and the timestamp which doesn't satisfy the difficulty
+    // for another payload or a moved timestamp
+    let preimage = DerivativeBlock::pow_preimage(&first_block.default_info, &[2; 32]);
+    let other_preimage = DerivativeBlock::pow_preimage(&first_block.default_info, &[3; 32]);
+    let mut moved = first_block.clone();
+    moved.default_info.timestamp += 1;
+    let moved_preimage = DerivativeBlock::pow_preimage(&moved.default_info, &[2; 32]);
+    let mut pow = [0u8; 32];
+    for nonce in 0u64.. {
+        pow[..8].copy_from_slice(&nonce.to_be_bytes());
+        if tools::check_pow(&preimage, &BEGINNING_DIFFICULTY, &pow)
+            && !tools::check_pow(&other_preimage, &BEGINNING_DIFFICULTY, &pow)
+            && !tools::check_pow(&moved_preimage, &BEGINNING_DIFFICULTY, &pow)
+        {
+            break;
+        }
+    }
+    first_block.default_info.pow = pow;
+    first_block.validate(None).unwrap();
+
+    let mut other_payment = first_block.clone();
+    other_payment.payment_transaction = [3; 32];
+    let report = other_payment.validate(None).unwrap_err();
+    assert!(matches!(
+        report.current_context(),
+        BlockError::Validation(ValidationErrorKind::Pow)
+    ));
+
+    moved.default_info.pow = pow;
+    let report = moved.validate(None).unwrap_err();
+    assert!(matches!(
+        report.current_context(),
+        BlockError::Validation(ValidationErrorKind::Pow)
+    ));
+
+    let mut future = first_block.clone();
+    future.default_info.timestamp = tools::current_timestamp() + MAX_FUTURE_TIMESTAMP + 1000;
+    let report = future.validate(None).unwrap_err();
+    assert!(matches!(
+        report.current_context(),
+        BlockError::Validation(ValidationErrorKind::FutureTimestamp)
+    ));
+
+    let mut wrong_height = first_block.clone();
+    wrong_height.default_info.height = U256::from(2);
+    let report = wrong_height.validate(None).unwrap_err();
+    assert!(matches!(
+        report.current_context(),
+        BlockError::Validation(ValidationErrorKind::Height)
+    ));
+
+    let mut wrong_difficulty = first_block.clone();
+    wrong_difficulty.default_info.difficulty = [0; 32];
+    let report = wrong_difficulty.validate(None).unwrap_err();
+    assert!(matches!(
+        report.current_context(),
+        BlockError::Validation(ValidationErrorKind::Difficulty)
+    ));
+
+    let timestamp = 160000 + 1;
+    let previous_hash = first_block.hash().unwrap();
+    let mut second_block = DerivativeBlock {
+        default_info: block::BasicInfo {
+            timestamp,
+            pow: [0; 32],
+            previous_hash,
+            height: U256::from(2),
+            difficulty: DerivativeBlock::next_difficulty(
+                Some(&first_block.default_info),
+                timestamp,
+            ),
+            founder,
+        },
+        payment_transaction: [0; 32],
+    };
+    second_block.default_info.pow = mine(
+        &DerivativeBlock::pow_preimage(&second_block.default_info, &[0; 32]),
+        &BEGINNING_DIFFICULTY,
+    );
+    second_block
+        .validate(Some(Arc::new(first_block.clone())))
+        .unwrap();
+
+    let mut earlier = second_block.clone();
+    earlier.default_info.timestamp = 100;
+    let report = earlier.validate(Some(Arc::new(first_block))).unwrap_err();
+    assert!(matches!(
+        report.current_context(),
+        BlockError::Validation(ValidationErrorKind::Timestamp)
+    ));
 }
@@ -1,9 +1,30 @@
-use blockchaintree::blockchaintree::BlockChainTree;
+use std::sync::Arc;
+
+use blockchaintree::{
+    block::{self, Block as _, BlockArc},
+    blockchaintree::BlockChainTree,
+    dump_headers::Headers,
+    errors::{BCTreeErrorKind, BlockChainTreeError, BlockError, ValidationErrorKind},
+    events::{Balance, Event},
+    merkletree::MerkleTree,
+    static_values::{
+        BEGINNING_DIFFICULTY, COINS_PER_CYCLE, GAS_REWARD_STEP, INCEPTION_TIMESTAMP,
+        MAIN_CHAIN_PAYMENT, ROOT_PRIVATE_ADDRESS, ROOT_PUBLIC_ADDRESS, UNDO_TREE,
+    },
+    token::{TokenAction, TokenParameters},
+    tools,
+    transaction::{self, Transaction, Transactionable, TransactionableItem},
+    txpool::TxPoolConfig,
+};
 use primitive_types::U256;
 
+/// Fee offered by the test transactions, above the fee of any test block
+const TRANSACTION_FEE: u64 = 100_000_000;
+
 #[tokio::test]
 async fn test_amounts() {
-    let tree = BlockChainTree::new().unwrap();
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
 
     let address_a = [0; 33];
     let address_b = [1; 33];
@@ -23,4 +44,1666 @@ async fn test_amounts() {
         U256::from_dec_str("10000000000").unwrap() - U256::from_dec_str("100").unwrap()
     );
     assert_eq!(amount_b, U256::from_dec_str("100").unwrap());
+
+    // spending more than the balance fails without changing it
+    assert!(tree
+        .send_amount(&address_b, &address_a, U256::from_dec_str("101").unwrap())
+        .is_err());
+    assert!(tree
+        .sub_gas(&address_b, U256::from_dec_str("1").unwrap())
+        .is_err());
+    assert_eq!(tree.get_amount(&address_b).unwrap(), amount_b);
+    assert_eq!(tree.get_amount(&address_a).unwrap(), amount_a);
+}
+
+#[tokio::test]
+async fn test_open_isolated_directories() {
+    let directory_a = tempfile::tempdir().unwrap();
+    let directory_b = tempfile::tempdir().unwrap();
+    let tree_a = BlockChainTree::open(directory_a.path()).unwrap();
+    let tree_b = BlockChainTree::open(directory_b.path()).unwrap();
+
+    let address = [7; 33];
+    tree_a
+        .add_amount(&address, U256::from_dec_str("500").unwrap())
+        .unwrap();
+
+    assert_eq!(
+        tree_a.get_amount(&address).unwrap(),
+        U256::from_dec_str("500").unwrap()
+    );
+    assert_eq!(tree_b.get_amount(&address).unwrap(), U256::zero());
+
+    tree_a.flush().await.unwrap();
+    drop(tree_a);
+
+    let tree_a = BlockChainTree::open(directory_a.path()).unwrap();
+    assert_eq!(
+        tree_a.get_amount(&address).unwrap(),
+        U256::from_dec_str("500").unwrap()
+    );
+    assert!(directory_a
+        .path()
+        .join("MAIN")
+        .join("Chain.config")
+        .exists());
+}
+
+#[tokio::test]
+async fn test_reorganization_reverts_balances() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+
+    let receiver = [7; 33];
+    let root_amount = tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+    let sender_amount = tree.get_amount(&sender).unwrap();
+
+    let transaction = Transaction::new(
+        sender,
+        receiver,
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        [2; 32],
+        None,
+    );
+    tree.send_transaction(transaction.clone()).await.unwrap();
+    // funds are not moved until the transaction is in a block
+    assert_eq!(tree.get_amount(&receiver).unwrap(), U256::zero());
+
+    let block_a1 = child_block(&genesis, [1; 33], vec![transaction.hash()]);
+    tree.add_new_block(block_a1.clone(), std::slice::from_ref(&transaction))
+        .await
+        .unwrap();
+    assert!(main_chain.transaction_exists(&transaction.hash()).unwrap());
+    assert!(!tree
+        .get_pool()
+        .read()
+        .await
+        .transaction_exists(&transaction.hash()));
+    assert_eq!(tree.get_nonce(&sender).unwrap(), 1);
+    assert_eq!(tree.get_amount(&receiver).unwrap(), U256::from(100));
+    assert_eq!(
+        tree.get_amount(&sender).unwrap(),
+        sender_amount - U256::from(100) - U256::from(TRANSACTION_FEE)
+    );
+    assert_eq!(
+        tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap(),
+        root_amount - *MAIN_CHAIN_PAYMENT
+    );
+    // the founder is paid the fee of the transaction
+    assert_eq!(
+        tree.get_amount(&[1; 33]).unwrap(),
+        *MAIN_CHAIN_PAYMENT + U256::from(TRANSACTION_FEE)
+    );
+
+    // heavier branch without the transaction
+    let block_b1 = child_block(&genesis, [2; 33], vec![]);
+    let block_b2 = child_block(&block_b1, [2; 33], vec![]);
+    tree.add_new_block(block_b1, &[]).await.unwrap();
+    assert_eq!(tree.get_amount(&receiver).unwrap(), U256::from(100));
+    tree.add_new_block(block_b2.clone(), &[]).await.unwrap();
+
+    assert_eq!(
+        main_chain
+            .get_last_block()
+            .unwrap()
+            .unwrap()
+            .hash()
+            .unwrap(),
+        block_b2.hash().unwrap()
+    );
+    assert_eq!(tree.get_nonce(&sender).unwrap(), 0);
+    assert_eq!(tree.get_amount(&sender).unwrap(), sender_amount);
+    // the undone transaction waits for the new chain
+    assert!(tree
+        .get_pool()
+        .read()
+        .await
+        .transaction_exists(&transaction.hash()));
+    assert!(!main_chain.transaction_exists(&transaction.hash()).unwrap());
+    assert!(main_chain
+        .get_transaction(&transaction.hash())
+        .unwrap()
+        .is_none());
+    assert_eq!(tree.get_amount(&receiver).unwrap(), U256::zero());
+    assert_eq!(tree.get_amount(&[1; 33]).unwrap(), U256::zero());
+    assert_eq!(tree.get_amount(&[2; 33]).unwrap(), *MAIN_CHAIN_PAYMENT * 2);
+    assert_eq!(
+        tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap(),
+        root_amount - *MAIN_CHAIN_PAYMENT * 2
+    );
+
+    // back onto the first branch, its transaction is in the chain again
+    let block_a2 = child_block(&block_a1, [1; 33], vec![]);
+    let block_a3 = child_block(&block_a2, [1; 33], vec![]);
+    tree.add_new_block(block_a2, &[]).await.unwrap();
+    tree.add_new_block(block_a3, &[]).await.unwrap();
+    assert!(main_chain.transaction_exists(&transaction.hash()).unwrap());
+    assert_eq!(tree.get_amount(&receiver).unwrap(), U256::from(100));
+    assert_eq!(tree.get_nonce(&sender).unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_corrupt_undo_journal_is_rejected() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+
+    let block_a1 = child_block(&genesis, [1; 33], vec![]);
+    tree.add_new_block(block_a1.clone(), &[]).await.unwrap();
+    let undo = main_chain.open_tree(UNDO_TREE).unwrap();
+    let journal = undo.get(block_a1.hash().unwrap()).unwrap().unwrap();
+
+    let block_b1 = child_block(&genesis, [2; 33], vec![]);
+    let block_b2 = child_block(&block_b1, [2; 33], vec![]);
+    tree.add_new_block(block_b1, &[]).await.unwrap();
+
+    // unknown kind and truncated entry
+    let mut unknown = journal.to_vec();
+    unknown[0] = 9;
+    let truncated = journal[..journal.len() - 1].to_vec();
+    for corrupt in [unknown, truncated] {
+        undo.insert(block_a1.hash().unwrap(), corrupt).unwrap();
+        let report = tree.add_new_block(block_b2.clone(), &[]).await.unwrap_err();
+        assert!(matches!(
+            report.current_context(),
+            BlockChainTreeError::BlockChainTree(BCTreeErrorKind::RevertBlock)
+        ));
+        assert_eq!(
+            main_chain
+                .get_last_block()
+                .unwrap()
+                .unwrap()
+                .hash()
+                .unwrap(),
+            block_a1.hash().unwrap()
+        );
+    }
+
+    undo.insert(block_a1.hash().unwrap(), journal).unwrap();
+    tree.add_new_block(block_b2, &[]).await.unwrap();
+    assert_eq!(tree.get_amount(&[1; 33]).unwrap(), U256::zero());
+}
+
+#[tokio::test]
+async fn test_reorganization_reverts_summarization() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+    let root_amount = tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap();
+
+    let block_a1 = child_block(&genesis, [1; 33], vec![]);
+    tree.add_new_block(block_a1.clone(), &[]).await.unwrap();
+    let block_a2 = summarize_block(&block_a1, [3; 33], tree.summarize().unwrap());
+    tree.add_new_block(block_a2.clone(), &[]).await.unwrap();
+    assert_eq!(
+        tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap(),
+        *COINS_PER_CYCLE - *MAIN_CHAIN_PAYMENT
+    );
+    assert_eq!(tree.get_amount(&[3; 33]).unwrap(), *MAIN_CHAIN_PAYMENT);
+    // the founder is paid by the coinbase of the block
+    let coinbase = block_a2.get_transactions()[0];
+    assert_eq!(
+        main_chain
+            .get_transaction(&coinbase)
+            .unwrap()
+            .unwrap()
+            .get_amount(),
+        &*MAIN_CHAIN_PAYMENT
+    );
+
+    // heavier branch without the summarization block
+    let block_b1 = child_block(&genesis, [2; 33], vec![]);
+    let block_b2 = child_block(&block_b1, [2; 33], vec![]);
+    let block_b3 = child_block(&block_b2, [2; 33], vec![]);
+    tree.add_new_block(block_b1, &[]).await.unwrap();
+    tree.add_new_block(block_b2, &[]).await.unwrap();
+    tree.add_new_block(block_b3.clone(), &[]).await.unwrap();
+    assert_eq!(
+        main_chain
+            .get_last_block()
+            .unwrap()
+            .unwrap()
+            .hash()
+            .unwrap(),
+        block_b3.hash().unwrap()
+    );
+    assert_eq!(
+        tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap(),
+        root_amount - *MAIN_CHAIN_PAYMENT * 3
+    );
+    assert_eq!(tree.get_amount(&[1; 33]).unwrap(), U256::zero());
+    assert_eq!(tree.get_amount(&[3; 33]).unwrap(), U256::zero());
+    assert!(!main_chain.transaction_exists(&coinbase).unwrap());
+
+    // back onto the summarizing branch, the summaries are rotated again
+    let block_a3 = child_block(&block_a2, [1; 33], vec![]);
+    let block_a4 = child_block(&block_a3, [1; 33], vec![]);
+    tree.add_new_block(block_a3, &[]).await.unwrap();
+    tree.add_new_block(block_a4.clone(), &[]).await.unwrap();
+    assert_eq!(
+        main_chain
+            .get_last_block()
+            .unwrap()
+            .unwrap()
+            .hash()
+            .unwrap(),
+        block_a4.hash().unwrap()
+    );
+    assert_eq!(
+        tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap(),
+        *COINS_PER_CYCLE - *MAIN_CHAIN_PAYMENT * 3
+    );
+    assert_eq!(tree.get_amount(&[1; 33]).unwrap(), *MAIN_CHAIN_PAYMENT * 3);
+    assert_eq!(tree.get_amount(&[2; 33]).unwrap(), U256::zero());
+    assert_eq!(tree.get_amount(&[3; 33]).unwrap(), *MAIN_CHAIN_PAYMENT);
+    assert!(main_chain.transaction_exists(&coinbase).unwrap());
+}
+
+#[tokio::test]
+async fn test_summarization_with_wrong_root_is_rejected() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+    let root_amount = tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap();
+
+    // the root was computed before the reward of the previous block
+    let summary_root = tree.summarize().unwrap();
+    let block_1 = child_block(&genesis, [1; 33], vec![]);
+    tree.add_new_block(block_1.clone(), &[]).await.unwrap();
+    let block_2 = summarize_block(&block_1, [3; 33], summary_root);
+
+    let report = tree.add_new_block(block_2, &[]).await.unwrap_err();
+    assert!(matches!(
+        report.downcast_ref::<BlockError>(),
+        Some(BlockError::Validation(ValidationErrorKind::MerkleRoot))
+    ));
+    assert_eq!(main_chain.get_height(), U256::from(2));
+    assert_eq!(
+        tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap(),
+        root_amount - *MAIN_CHAIN_PAYMENT
+    );
+    assert_eq!(tree.get_amount(&[3; 33]).unwrap(), U256::zero());
+}
+
+#[tokio::test]
+async fn test_side_block_transactions_are_not_in_chain() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+
+    let transaction = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        [2; 32],
+        None,
+    );
+    tree.add_new_block(child_block(&genesis, [1; 33], vec![]), &[])
+        .await
+        .unwrap();
+    let side_block = child_block(&genesis, [2; 33], vec![transaction.hash()]);
+    tree.add_new_block(side_block, std::slice::from_ref(&transaction))
+        .await
+        .unwrap();
+
+    assert!(!main_chain.transaction_exists(&transaction.hash()).unwrap());
+    assert!(main_chain
+        .find_raw_side_transaction(&transaction.hash())
+        .unwrap()
+        .is_some());
+    tree.send_transaction(transaction).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_only_block_transactions_are_stored() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+
+    let transaction = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        [2; 32],
+        None,
+    );
+
+    // the block has to come with all of its transactions
+    let block = child_block(&genesis, [1; 33], vec![transaction.hash()]);
+    assert!(tree.add_new_block(block, &[]).await.is_err());
+    assert_eq!(main_chain.get_height(), U256::one());
+
+    // the transactions the block doesn't list are skipped
+    let block = child_block(&genesis, [1; 33], vec![]);
+    tree.add_new_block(block, std::slice::from_ref(&transaction))
+        .await
+        .unwrap();
+    assert_eq!(main_chain.get_height(), U256::from(2));
+    assert!(!main_chain.transaction_exists(&transaction.hash()).unwrap());
+    assert_eq!(tree.get_amount(&[7; 33]).unwrap(), U256::zero());
+    tree.send_transaction(transaction).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_events() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let mut tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let genesis: BlockArc = tree.get_main_chain().get_last_block().unwrap().unwrap();
+    let mut events = tree.subscribe();
+
+    tree.add_gas(&ROOT_PUBLIC_ADDRESS, U256::from(1000))
+        .unwrap();
+    assert!(matches!(
+        events.try_recv().unwrap(),
+        Event::BalanceChanged {
+            owner,
+            balance: Balance::Gas,
+            value,
+        } if owner == ROOT_PUBLIC_ADDRESS && value == U256::from(1000)
+    ));
+
+    let block_a1 = child_block(&genesis, [1; 33], vec![]);
+    tree.add_new_block(block_a1.clone(), &[]).await.unwrap();
+    assert!(matches!(
+        events.try_recv().unwrap(),
+        Event::BlockAdded(block) if block.hash().unwrap() == block_a1.hash().unwrap()
+    ));
+    let mut changed = Vec::new();
+    while let Ok(Event::BalanceChanged {
+        owner,
+        balance: Balance::Amount,
+        value,
+    }) = events.try_recv()
+    {
+        changed.push((owner, value));
+    }
+    changed.sort();
+    assert_eq!(
+        changed,
+        vec![
+            ([1; 33], *MAIN_CHAIN_PAYMENT),
+            (
+                ROOT_PUBLIC_ADDRESS,
+                tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap()
+            ),
+        ]
+    );
+
+    // heavier branch replaces the block
+    let block_b1 = child_block(&genesis, [2; 33], vec![]);
+    let block_b2 = child_block(&block_b1, [2; 33], vec![]);
+    tree.add_new_block(block_b1, &[]).await.unwrap();
+    tree.add_new_block(block_b2, &[]).await.unwrap();
+    let mut reverted = Vec::new();
+    let mut added = 0;
+    while let Ok(event) = events.try_recv() {
+        match event {
+            Event::BlockReverted(block) => reverted.push(block.hash().unwrap()),
+            Event::BlockAdded(_) => added += 1,
+            Event::BalanceChanged {
+                owner: [1, ..],
+                value,
+                ..
+            } => assert!(value.is_zero()),
+            _ => {}
+        }
+    }
+    assert_eq!(reverted, vec![block_a1.hash().unwrap()]);
+    assert_eq!(added, 2);
+
+    let founder = [5; 33];
+    tree.get_derivative_chain(&founder).unwrap();
+    assert!(matches!(
+        events.try_recv().unwrap(),
+        Event::DerivativeChainCreated { owner, .. } if owner == founder
+    ));
+    // the chain is created only once
+    tree.get_derivative_chain(&founder).unwrap();
+    assert!(events.try_recv().is_err());
+}
+
+/// Address of the private key with coins and gas to send transactions
+fn funded_account(tree: &BlockChainTree, private_key: [u8; 32]) -> [u8; 33] {
+    let address = secp256k1::PublicKey::from_secret_key(
+        &secp256k1::Secp256k1::new(),
+        &secp256k1::SecretKey::from_slice(&private_key).unwrap(),
+    )
+    .serialize();
+    tree.add_amount(&address, U256::from_dec_str("1000000000000000000").unwrap())
+        .unwrap();
+    tree.add_gas(&address, U256::from_dec_str("1000000000000000000").unwrap())
+        .unwrap();
+    address
+}
+
+fn child_block(parent: &BlockArc, founder: [u8; 33], transactions: Vec<[u8; 32]>) -> BlockArc {
+    anchoring_block(parent, founder, transactions, Vec::new())
+}
+
+fn anchoring_block(
+    parent: &BlockArc,
+    founder: [u8; 33],
+    transactions: Vec<[u8; 32]>,
+    anchors: Vec<block::DerivativeAnchor>,
+) -> BlockArc {
+    paying_block(parent, founder, U256::zero(), transactions, anchors)
+}
+
+fn paying_block(
+    parent: &BlockArc,
+    founder: [u8; 33],
+    fee: U256,
+    transactions: Vec<[u8; 32]>,
+    anchors: Vec<block::DerivativeAnchor>,
+) -> BlockArc {
+    let height = parent.get_info().height + 1;
+    let previous_hash = parent.hash().unwrap();
+    let timestamp = parent.get_info().timestamp + 1000;
+    let mut difficulty = parent.get_info().difficulty;
+    tools::recalculate_difficulty(parent.get_info().timestamp, timestamp, &mut difficulty);
+
+    let basic_data = block::BasicInfo {
+        timestamp,
+        pow: [0; 32],
+        previous_hash,
+        height,
+        difficulty,
+        founder,
+    };
+
+    let transaction_fees = U256::from(TRANSACTION_FEE) * transactions.len();
+    let coinbase = Transaction::new_coinbase(
+        founder,
+        &height,
+        timestamp,
+        tools::block_reward(&transaction_fees).unwrap(),
+    );
+    let transactions: Vec<[u8; 32]> = std::iter::once(coinbase.hash())
+        .chain(transactions)
+        .collect();
+    let merkle_tree = MerkleTree::build_tree(&transactions);
+    let mut block =
+        block::TransactionBlock::new(fee, basic_data, *merkle_tree.get_root(), transactions)
+            .with_anchors(anchors);
+
+    let preimage = block::pow_preimage(&block.default_info, &block.get_body_root());
+    let mut pow = [0u8; 32];
+    for nonce in 0u64.. {
+        pow[..8].copy_from_slice(&nonce.to_be_bytes());
+        if tools::check_pow(&preimage, &parent.get_info().difficulty, &pow) {
+            break;
+        }
+    }
+    block.default_info.pow = pow;
+    Arc::new(block)
+}
+
+fn summarize_block(parent: &BlockArc, founder: [u8; 33], merkle_tree_root: [u8; 32]) -> BlockArc {
+    let timestamp = parent.get_info().timestamp + 1000;
+    let mut difficulty = parent.get_info().difficulty;
+    tools::recalculate_difficulty(parent.get_info().timestamp, timestamp, &mut difficulty);
+    let mut block = block::SummarizeBlock::new(
+        block::BasicInfo {
+            timestamp,
+            pow: [0; 32],
+            previous_hash: parent.hash().unwrap(),
+            height: parent.get_info().height + 1,
+            difficulty,
+            founder,
+        },
+        merkle_tree_root,
+    );
+
+    let preimage = block::pow_preimage(&block.default_info, &block.get_body_root());
+    let mut pow = [0u8; 32];
+    for nonce in 0u64.. {
+        pow[..8].copy_from_slice(&nonce.to_be_bytes());
+        if tools::check_pow(&preimage, &parent.get_info().difficulty, &pow) {
+            break;
+        }
+    }
+    block.default_info.pow = pow;
+    Arc::new(block)
+}
+
+#[tokio::test]
+async fn test_failed_block_leaves_no_changes() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+
+    let receiver = [7; 33];
+    let root_amount = tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+    let sender_amount = tree.get_amount(&sender).unwrap();
+    let gas = tree.get_gas(&sender).unwrap();
+
+    // the first transaction is fine, the second one overspends
+    let transactions = [
+        Transaction::new(
+            sender,
+            receiver,
+            INCEPTION_TIMESTAMP + 1,
+            0,
+            U256::from(100),
+            U256::from(TRANSACTION_FEE),
+            [2; 32],
+            None,
+        ),
+        Transaction::new(
+            sender,
+            receiver,
+            INCEPTION_TIMESTAMP + 2,
+            1,
+            sender_amount,
+            U256::from(TRANSACTION_FEE),
+            [2; 32],
+            None,
+        ),
+    ];
+    let block = child_block(
+        &genesis,
+        [1; 33],
+        transactions.iter().map(|tx| tx.hash()).collect(),
+    );
+
+    assert!(tree.add_new_block(block, &transactions).await.is_err());
+
+    assert_eq!(main_chain.get_height(), U256::one());
+    assert!(main_chain
+        .get_transaction(&transactions[0].hash())
+        .unwrap()
+        .is_none());
+    assert_eq!(tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap(), root_amount);
+    assert_eq!(tree.get_amount(&sender).unwrap(), sender_amount);
+    assert_eq!(tree.get_amount(&receiver).unwrap(), U256::zero());
+    assert_eq!(tree.get_gas(&sender).unwrap(), gas);
+}
+
+#[tokio::test]
+async fn test_overflowing_block_transaction_is_rejected() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+    let sender_amount = tree.get_amount(&sender).unwrap();
+
+    let transaction = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::MAX,
+        U256::from(TRANSACTION_FEE),
+        [2; 32],
+        None,
+    );
+    let block = paying_block(
+        &genesis,
+        [1; 33],
+        U256::one(),
+        vec![transaction.hash()],
+        Vec::new(),
+    );
+
+    assert!(tree
+        .add_new_block(block, std::slice::from_ref(&transaction))
+        .await
+        .is_err());
+    assert_eq!(main_chain.get_height(), U256::one());
+    assert_eq!(tree.get_amount(&sender).unwrap(), sender_amount);
+}
+
+#[tokio::test]
+async fn test_forged_block_transaction_is_rejected() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+    let sender_amount = tree.get_amount(&sender).unwrap();
+
+    // signed with a key other than the sender's one
+    let transaction = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        [3; 32],
+        None,
+    );
+    let block = child_block(&genesis, [1; 33], vec![transaction.hash()]);
+
+    let report = tree
+        .add_new_block(block, std::slice::from_ref(&transaction))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        report.downcast_ref::<BlockError>(),
+        Some(BlockError::Validation(ValidationErrorKind::Signature))
+    ));
+    assert_eq!(main_chain.get_height(), U256::one());
+    assert_eq!(tree.get_amount(&sender).unwrap(), sender_amount);
+    assert_eq!(tree.get_amount(&[7; 33]).unwrap(), U256::zero());
+}
+
+#[tokio::test]
+async fn test_root_transactions_are_rejected() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+
+    let root_amount = tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap();
+    tree.add_gas(
+        &ROOT_PUBLIC_ADDRESS,
+        U256::from_dec_str("1000000000000000000").unwrap(),
+    )
+    .unwrap();
+
+    // the root key is public, anyone could sign a transfer with it
+    let transfer = Transaction::new(
+        ROOT_PUBLIC_ADDRESS,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        root_amount,
+        U256::from(TRANSACTION_FEE),
+        ROOT_PRIVATE_ADDRESS,
+        None,
+    );
+    assert!(tree.send_transaction(transfer.clone()).await.is_err());
+    assert!(tree.get_pool().read().await.is_empty());
+
+    let block = child_block(&genesis, [1; 33], vec![transfer.hash()]);
+    assert!(tree
+        .add_new_block(block, std::slice::from_ref(&transfer))
+        .await
+        .is_err());
+    assert_eq!(main_chain.get_height(), U256::one());
+    assert_eq!(tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap(), root_amount);
+    assert_eq!(tree.get_amount(&[7; 33]).unwrap(), U256::zero());
+}
+
+#[tokio::test]
+async fn test_emitted_block_pays_founder() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let mut tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+    let genesis_hash = genesis.hash().unwrap();
+
+    let founder = [5; 33];
+    let root_amount = tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap();
+
+    let template = tree
+        .block_template_with(&founder, vec![], INCEPTION_TIMESTAMP + 1000)
+        .await
+        .unwrap();
+    assert_eq!(template.previous_hash, genesis_hash);
+    let mut pow = [0u8; 32];
+    for nonce in 0u64.. {
+        pow[..8].copy_from_slice(&nonce.to_be_bytes());
+        if tools::check_pow(&template.pow_preimage, &template.difficulty, &pow) {
+            break;
+        }
+    }
+
+    let block = tree
+        .emmit_new_main_block(&pow, &founder, &[], INCEPTION_TIMESTAMP + 1000)
+        .await
+        .unwrap();
+
+    assert_eq!(tree.get_amount(&founder).unwrap(), *MAIN_CHAIN_PAYMENT);
+    assert_eq!(
+        tree.get_amount(&ROOT_PUBLIC_ADDRESS).unwrap(),
+        root_amount - *MAIN_CHAIN_PAYMENT
+    );
+
+    // the payout is recorded in the block
+    let coinbase = main_chain
+        .get_transaction(&block.get_transactions()[0])
+        .unwrap()
+        .unwrap();
+    assert_eq!(coinbase.get_sender(), &ROOT_PUBLIC_ADDRESS);
+    assert_eq!(coinbase.get_receiver(), &founder);
+    assert_eq!(*coinbase.get_amount(), *MAIN_CHAIN_PAYMENT);
+    assert!(coinbase.verify().unwrap());
+}
+
+#[tokio::test]
+async fn test_derivative_block_rewards_gas() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let mut tree = BlockChainTree::open(data_directory.path()).unwrap();
+
+    let genesis: BlockArc = tree.get_main_chain().get_last_block().unwrap().unwrap();
+    let founder = [5; 33];
+    let chain = tree.get_derivative_chain(&founder).unwrap();
+
+    let pow = derivative_pow(&mut tree, &founder, INCEPTION_TIMESTAMP + 1000);
+    tree.emmit_new_derivative_block(&pow, &founder, &[0; 32], INCEPTION_TIMESTAMP + 1000)
+        .await
+        .unwrap();
+    assert_eq!(chain.get_height(), U256::one());
+    // the gas is credited once the block is anchored
+    assert_eq!(tree.get_gas(&founder).unwrap(), U256::zero());
+
+    let anchor = tree.block_template(&[1; 33]).await.unwrap().anchors[0].clone();
+    assert_eq!(anchor.height(), U256::one());
+
+    // the anchored blocks have to be valid
+    let mut wrong = anchor.clone();
+    wrong.blocks[0].default_info.difficulty = [0; 32];
+    let wrong = anchoring_block(&genesis, [1; 33], vec![], vec![wrong]);
+    assert!(tree.add_new_block(wrong, &[]).await.is_err());
+    assert_eq!(tree.get_gas(&founder).unwrap(), U256::zero());
+
+    let anchoring = anchoring_block(&genesis, [1; 33], vec![], vec![anchor]);
+    tree.add_new_block(anchoring.clone(), &[]).await.unwrap();
+    assert_eq!(
+        tree.get_gas(&founder).unwrap(),
+        tools::recalculate_gas_reward(&BEGINNING_DIFFICULTY)
+    );
+    assert_eq!(tree.get_gas(&founder).unwrap(), *GAS_REWARD_STEP);
+
+    // the next anchor carries only the new blocks, their gas is credited
+    let pow = derivative_pow(&mut tree, &founder, INCEPTION_TIMESTAMP + 2000);
+    tree.emmit_new_derivative_block(&pow, &founder, &[0; 32], INCEPTION_TIMESTAMP + 2000)
+        .await
+        .unwrap();
+    let pow = derivative_pow(&mut tree, &founder, INCEPTION_TIMESTAMP + 3000);
+    tree.emmit_new_derivative_block(&pow, &founder, &[0; 32], INCEPTION_TIMESTAMP + 3000)
+        .await
+        .unwrap();
+    let reward = |height: u64| {
+        tools::recalculate_gas_reward(
+            &chain
+                .find_by_height(&U256::from(height))
+                .unwrap()
+                .unwrap()
+                .get_info()
+                .difficulty,
+        )
+    };
+    let anchor = tree.block_template(&[1; 33]).await.unwrap().anchors[0].clone();
+    assert_eq!(anchor.blocks.len(), 2);
+    assert_eq!(anchor.height(), U256::from(3));
+    let anchoring = anchoring_block(&anchoring, [1; 33], vec![], vec![anchor]);
+    tree.add_new_block(anchoring, &[]).await.unwrap();
+    assert_eq!(
+        tree.get_gas(&founder).unwrap(),
+        *GAS_REWARD_STEP + reward(0) + reward(1)
+    );
+
+    // the gas is reverted together with the anchoring blocks
+    let block_b1 = child_block(&genesis, [2; 33], vec![]);
+    let block_b2 = child_block(&block_b1, [2; 33], vec![]);
+    let block_b3 = child_block(&block_b2, [2; 33], vec![]);
+    tree.add_new_block(block_b1, &[]).await.unwrap();
+    tree.add_new_block(block_b2, &[]).await.unwrap();
+    tree.add_new_block(block_b3, &[]).await.unwrap();
+    assert_eq!(tree.get_gas(&founder).unwrap(), U256::zero());
+}
+
+/// Finds pow for the next block of the founder's derivative chain without a payload
+fn derivative_pow(tree: &mut BlockChainTree, founder: &[u8; 33], timestamp: u64) -> [u8; 32] {
+    let target = tree
+        .derivative_target(founder, &[0; 32], timestamp)
+        .unwrap();
+    let mut pow = [0u8; 32];
+    for nonce in 0u64.. {
+        pow[..8].copy_from_slice(&nonce.to_be_bytes());
+        if tools::check_pow(&target.pow_preimage, &target.difficulty, &pow) {
+            break;
+        }
+    }
+    pow
+}
+
+#[tokio::test]
+async fn test_derivative_chains_are_loaded() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let mut tree = BlockChainTree::open(data_directory.path()).unwrap();
+    assert!(tree.list_derivative_chains().is_empty());
+    assert!(tree.find_derivative_chain(&[5; 33]).is_none());
+
+    let chain = tree.create_derivative_chain(&[5; 33]).unwrap();
+    let genesis_hash = *chain.genesis_hash;
+    assert!(tree.create_derivative_chain(&[5; 33]).is_err());
+    tree.create_derivative_chain(&[3; 33]).unwrap();
+    assert_eq!(tree.list_derivative_chains(), vec![[3; 33], [5; 33]]);
+
+    // the main chain moves on, the existing chain keeps its genesis block
+    let last_block = tree.get_main_chain().get_last_block().unwrap().unwrap();
+    let block = child_block(&last_block, [1; 33], Vec::new());
+    tree.add_new_block(block, &[]).await.unwrap();
+    drop(chain);
+    drop(tree);
+
+    let mut tree = BlockChainTree::open(data_directory.path()).unwrap();
+    assert_eq!(tree.list_derivative_chains(), vec![[3; 33], [5; 33]]);
+    let chain = tree.find_derivative_chain(&[5; 33]).unwrap();
+    assert_eq!(*chain.genesis_hash, genesis_hash);
+    assert_eq!(
+        *tree.get_derivative_chain(&[5; 33]).unwrap().genesis_hash,
+        genesis_hash
+    );
+}
+
+#[tokio::test]
+async fn test_derivative_chain_is_anchored() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let mut tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+    let genesis: BlockArc = main_chain.get_last_block().unwrap().unwrap();
+
+    let owner = [5; 33];
+    let chain = tree.get_derivative_chain(&owner).unwrap();
+    let pow = derivative_pow(&mut tree, &owner, INCEPTION_TIMESTAMP + 1000);
+    tree.emmit_new_derivative_block(&pow, &owner, &[0; 32], INCEPTION_TIMESTAMP + 1000)
+        .await
+        .unwrap();
+    let tip = chain.get_last_block().unwrap().unwrap();
+    let tip_hash = tip.hash().unwrap();
+    let anchor = block::DerivativeAnchor {
+        owner,
+        blocks: vec![tip],
+    };
+    assert_eq!(tree.verify_derivative_chain(&owner).unwrap(), U256::zero());
+
+    let template = tree.block_template(&[1; 33]).await.unwrap();
+    assert_eq!(template.anchors, vec![anchor.clone()]);
+    let mut pow = [0u8; 32];
+    for nonce in 0u64.. {
+        pow[..8].copy_from_slice(&nonce.to_be_bytes());
+        if tools::check_pow(&template.pow_preimage, &template.difficulty, &pow) {
+            break;
+        }
+    }
+    let block = tree.submit_template(&template, &pow).await.unwrap();
+    assert_eq!(block.get_anchors(), std::slice::from_ref(&anchor));
+    assert_eq!(
+        tree.get_anchors(&owner).unwrap(),
+        vec![(U256::one(), tip_hash)]
+    );
+    assert_eq!(tree.last_anchored_height(&owner).unwrap(), U256::one());
+    assert_eq!(tree.verify_derivative_chain(&owner).unwrap(), U256::one());
+    // nothing new to anchor
+    assert!(tree
+        .block_template(&[1; 33])
+        .await
+        .unwrap()
+        .anchors
+        .is_empty());
+
+    // heavier branch without the anchor
+    let block_b1 = child_block(&genesis, [2; 33], vec![]);
+    let block_b2 = child_block(&block_b1, [2; 33], vec![]);
+    tree.add_new_block(block_b1.clone(), &[]).await.unwrap();
+    tree.add_new_block(block_b2.clone(), &[]).await.unwrap();
+    assert!(tree.get_anchors(&owner).unwrap().is_empty());
+    assert_eq!(tree.last_anchored_height(&owner).unwrap(), U256::zero());
+    assert_eq!(
+        tree.block_template(&[1; 33]).await.unwrap().anchors,
+        vec![anchor.clone()]
+    );
+
+    // a chain can be anchored once per block, at a growing height
+    let twice = anchoring_block(&block_b2, [2; 33], vec![], vec![anchor.clone(); 2]);
+    assert!(tree.add_new_block(twice, &[]).await.is_err());
+    let block_b3 = anchoring_block(&block_b2, [2; 33], vec![], vec![anchor.clone()]);
+    tree.add_new_block(block_b3.clone(), &[]).await.unwrap();
+    assert_eq!(tree.verify_derivative_chain(&owner).unwrap(), U256::one());
+    let again = anchoring_block(&block_b3, [2; 33], vec![], vec![anchor.clone()]);
+    assert!(tree.add_new_block(again, &[]).await.is_err());
+
+    // the first anchored block has to be built on top of the main chain
+    let forged = block::DerivativeAnchor {
+        owner: [3; 33],
+        blocks: vec![block::DerivativeBlock {
+            default_info: block::BasicInfo {
+                timestamp: INCEPTION_TIMESTAMP + 1000,
+                pow: [0; 32],
+                previous_hash: [9; 32],
+                height: U256::one(),
+                difficulty: BEGINNING_DIFFICULTY,
+                founder: [3; 33],
+            },
+            payment_transaction: [0; 32],
+        }],
+    };
+    let block_b4 = anchoring_block(&block_b3, [2; 33], vec![], vec![forged]);
+    assert!(tree.add_new_block(block_b4, &[]).await.is_err());
+    assert_eq!(tree.get_gas(&[3; 33]).unwrap(), U256::zero());
+    assert_eq!(tree.last_anchored_height(&[3; 33]).unwrap(), U256::zero());
+
+    // the anchored blocks are checked without the derivative chain by any other node
+    let other_directory = tempfile::tempdir().unwrap();
+    let other = BlockChainTree::open(other_directory.path()).unwrap();
+    assert!(other.find_derivative_chain(&owner).is_none());
+    other.add_new_block(block_b1, &[]).await.unwrap();
+    other.add_new_block(block_b2, &[]).await.unwrap();
+    other.add_new_block(block_b3, &[]).await.unwrap();
+    assert_eq!(
+        other.get_anchors(&owner).unwrap(),
+        vec![(U256::one(), tip_hash)]
+    );
+    assert_eq!(other.last_anchored_height(&owner).unwrap(), U256::one());
+    assert_eq!(other.get_gas(&owner).unwrap(), *GAS_REWARD_STEP);
+}
+
+#[tokio::test]
+async fn test_pool_is_persisted() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+
+    let transaction = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        [2; 32],
+        None,
+    );
+    let hash = transaction.hash();
+    tree.send_transaction(transaction.clone()).await.unwrap();
+    // the same transaction can't be sent twice
+    assert!(tree.send_transaction(transaction).await.is_err());
+
+    tree.flush().await.unwrap();
+    drop(tree);
+
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    assert!(tree.get_pool().read().await.transaction_exists(&hash));
+}
+
+#[tokio::test]
+async fn test_send_transaction_rejects_invalid() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let sender = funded_account(&tree, [4; 32]);
+
+    // signed by a key that doesn't match the sender
+    let forged = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        [3; 32],
+        None,
+    );
+    assert!(tree.send_transaction(forged).await.is_err());
+
+    // sender without any funds
+    let sender_private = [2; 32];
+    let sender = secp256k1::PublicKey::from_secret_key(
+        &secp256k1::Secp256k1::new(),
+        &secp256k1::SecretKey::from_slice(&sender_private).unwrap(),
+    )
+    .serialize();
+    let unfunded = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        sender_private,
+        None,
+    );
+    assert!(tree.send_transaction(unfunded).await.is_err());
+
+    assert!(tree.get_pool().read().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_send_transaction_rejects_overflowing_amount() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+
+    let transaction = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::MAX,
+        U256::from(TRANSACTION_FEE),
+        [2; 32],
+        None,
+    );
+    assert!(tree.send_transaction(transaction).await.is_err());
+    assert!(tree.get_pool().read().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_send_transaction_checks_nonce() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let genesis: BlockArc = tree.get_main_chain().get_last_block().unwrap().unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+
+    let payment = |nonce: u64| {
+        Transaction::new(
+            sender,
+            [7; 33],
+            INCEPTION_TIMESTAMP + 1,
+            nonce,
+            U256::from(100),
+            U256::from(TRANSACTION_FEE),
+            [2; 32],
+            None,
+        )
+    };
+
+    // skipping a nonce is not allowed
+    assert!(tree.send_transaction(payment(1)).await.is_err());
+    tree.send_transaction(payment(0)).await.unwrap();
+    tree.send_transaction(payment(1)).await.unwrap();
+
+    let included = [payment(0), payment(1)];
+    let block = child_block(
+        &genesis,
+        [1; 33],
+        included.iter().map(|tx| tx.hash()).collect(),
+    );
+    tree.add_new_block(block, &included).await.unwrap();
+    assert_eq!(tree.get_nonce(&sender).unwrap(), 2);
+    assert!(tree.get_pool().read().await.is_empty());
+
+    // identical payment, but with a used nonce
+    assert!(tree.send_transaction(payment(0)).await.is_err());
+    tree.send_transaction(payment(2)).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_evicted_nonces_are_resubmitted() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open_with_pool_config(
+        data_directory.path(),
+        TxPoolConfig {
+            capacity: 2,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+    let other = funded_account(&tree, [3; 32]);
+
+    let payment = |nonce: u64, data: Option<Vec<u8>>| {
+        Transaction::new(
+            sender,
+            [7; 33],
+            INCEPTION_TIMESTAMP + 1,
+            nonce,
+            U256::from(100),
+            U256::from(TRANSACTION_FEE),
+            [2; 32],
+            data,
+        )
+    };
+    tree.send_transaction(payment(0, Some(vec![0; 1000])))
+        .await
+        .unwrap();
+    tree.send_transaction(payment(1, None)).await.unwrap();
+
+    // the big transaction is evicted, so is the next nonce relying on it
+    tree.send_transaction(Transaction::new(
+        other,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        [3; 32],
+        None,
+    ))
+    .await
+    .unwrap();
+    assert_eq!(tree.get_pool().read().await.len(), 1);
+    assert_eq!(tree.next_nonce(&sender).await.unwrap(), 0);
+
+    assert!(tree.send_transaction(payment(1, None)).await.is_err());
+    tree.send_transaction(payment(0, None)).await.unwrap();
+    assert_eq!(tree.next_nonce(&sender).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn test_block_template() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let mut tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+
+    let receiver = [7; 33];
+    // the later nonce is older, so it goes first in the pool
+    let first = Transaction::new(
+        sender,
+        receiver,
+        INCEPTION_TIMESTAMP + 2,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        [2; 32],
+        None,
+    );
+    let second = Transaction::new(
+        sender,
+        receiver,
+        INCEPTION_TIMESTAMP + 1,
+        1,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        [2; 32],
+        None,
+    );
+    let hashes = vec![first.hash(), second.hash()];
+    tree.send_transaction(first).await.unwrap();
+    tree.send_transaction(second).await.unwrap();
+
+    let founder = [5; 33];
+    let template = tree.block_template(&founder).await.unwrap();
+    assert_eq!(template.kind, Headers::TransactionBlock);
+    assert_eq!(template.height, U256::one());
+    assert_eq!(template.transactions, hashes);
+    assert_eq!(template.transaction_fees, U256::from(TRANSACTION_FEE) * 2);
+
+    let mut pow = [0u8; 32];
+    for nonce in 0u64.. {
+        pow[..8].copy_from_slice(&nonce.to_be_bytes());
+        if tools::check_pow(&template.pow_preimage, &template.difficulty, &pow) {
+            break;
+        }
+    }
+    let block = tree.submit_template(&template, &pow).await.unwrap();
+
+    assert_eq!(block.get_transactions()[1..], hashes[..]);
+    assert_eq!(tree.get_amount(&receiver).unwrap(), U256::from(200));
+    assert_eq!(
+        tree.get_amount(&founder).unwrap(),
+        *MAIN_CHAIN_PAYMENT + U256::from(TRANSACTION_FEE) * 2
+    );
+    assert_eq!(tree.get_nonce(&sender).unwrap(), 2);
+    assert!(tree.get_pool().read().await.is_empty());
+
+    // the chain has moved on
+    assert!(tree.submit_template(&template, &pow).await.is_err());
+}
+
+#[tokio::test]
+async fn test_transaction_fees() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let genesis: BlockArc = tree.get_main_chain().get_last_block().unwrap().unwrap();
+    let cheap_sender = funded_account(&tree, [2; 32]);
+    let generous_sender = funded_account(&tree, [3; 32]);
+    let minimal_fee = tree.minimal_fee().unwrap();
+
+    let payment = |sender: [u8; 33], private_key: [u8; 32], fee: U256| {
+        Transaction::new(
+            sender,
+            [7; 33],
+            INCEPTION_TIMESTAMP + 1,
+            0,
+            U256::from(100),
+            fee,
+            private_key,
+            None,
+        )
+    };
+    assert!(tree
+        .send_transaction(payment(cheap_sender, [2; 32], minimal_fee - 1))
+        .await
+        .is_err());
+    let cheap = payment(cheap_sender, [2; 32], minimal_fee);
+    let generous = payment(generous_sender, [3; 32], minimal_fee * 10);
+    tree.send_transaction(cheap.clone()).await.unwrap();
+    tree.send_transaction(generous.clone()).await.unwrap();
+
+    // the highest fee goes first
+    let template = tree.block_template(&[5; 33]).await.unwrap();
+    assert_eq!(template.transactions, vec![generous.hash(), cheap.hash()]);
+    assert_eq!(template.transaction_fees, minimal_fee * 11);
+
+    // the block can't include transactions offering less than its fee
+    let transaction = payment(generous_sender, [3; 32], U256::from(TRANSACTION_FEE));
+    let block = paying_block(
+        &genesis,
+        [1; 33],
+        U256::from(TRANSACTION_FEE + 1),
+        vec![transaction.hash()],
+        vec![],
+    );
+    assert!(tree
+        .add_new_block(block, std::slice::from_ref(&transaction))
+        .await
+        .is_err());
+    assert_eq!(tree.get_nonce(&generous_sender).unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_block_template_rejects_earlier_timestamp() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let mut tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let main_chain = tree.get_main_chain();
+
+    assert!(tree
+        .block_template_with(&[5; 33], vec![], INCEPTION_TIMESTAMP - 1)
+        .await
+        .is_err());
+    assert!(tree
+        .emmit_new_main_block(&[0; 32], &[5; 33], &[], INCEPTION_TIMESTAMP - 1)
+        .await
+        .is_err());
+    assert_eq!(main_chain.get_height(), U256::one());
+}
+
+#[tokio::test]
+async fn test_block_template_skips_unaffordable() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+
+    let payment = |nonce: u64| {
+        Transaction::new(
+            sender,
+            [7; 33],
+            INCEPTION_TIMESTAMP + 1,
+            nonce,
+            U256::from(100),
+            U256::from(TRANSACTION_FEE),
+            [2; 32],
+            None,
+        )
+    };
+    let first = payment(0);
+    tree.send_transaction(first.clone()).await.unwrap();
+    tree.send_transaction(payment(1)).await.unwrap();
+
+    // the sender spends the coins after the transactions are accepted
+    let amount = tree.get_amount(&sender).unwrap();
+    tree.send_amount(
+        &sender,
+        &[9; 33],
+        amount - U256::from(TRANSACTION_FEE) - U256::from(100),
+    )
+    .unwrap();
+
+    let template = tree.block_template(&[5; 33]).await.unwrap();
+    assert_eq!(template.transactions, vec![first.hash()]);
+    assert_eq!(tree.get_pool().read().await.len(), 2);
+}
+
+#[tokio::test]
+async fn test_overflowing_pool_transactions_are_skipped() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+
+    // loaded from an old pool dump, before the amounts were checked
+    let overflowing = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::MAX,
+        U256::from(TRANSACTION_FEE),
+        [2; 32],
+        None,
+    );
+    tree.get_pool()
+        .write()
+        .await
+        .push(Box::new(overflowing), 0)
+        .unwrap();
+
+    let template = tree.block_template(&[5; 33]).await.unwrap();
+    assert!(template.transactions.is_empty());
+
+    let next = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        1,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        [2; 32],
+        None,
+    );
+    assert!(tree.send_transaction(next).await.is_err());
+}
+
+#[tokio::test]
+async fn test_token_lifecycle() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let genesis: BlockArc = tree.get_main_chain().get_last_block().unwrap().unwrap();
+    let sender = funded_account(&tree, [2; 32]);
+
+    let receiver = [7; 33];
+    let create = TokenAction::create(
+        sender,
+        U256::from(TRANSACTION_FEE),
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        &[2; 32],
+    )
+    .unwrap();
+    let token_hash = create.hash();
+    let parameters = TokenParameters {
+        token_data: "test token".to_string(),
+        smol_contract: String::new(),
+        coin_supply: U256::from(1000),
+        transfer_fee: U256::zero(),
+    };
+    let actions: Vec<TransactionableItem> = vec![
+        Box::new(create),
+        Box::new(
+            TokenAction::assign(
+                sender,
+                token_hash,
+                parameters,
+                U256::from(TRANSACTION_FEE),
+                INCEPTION_TIMESTAMP + 1,
+                1,
+                &[2; 32],
+            )
+            .unwrap(),
+        ),
+        Box::new(
+            TokenAction::send(
+                sender,
+                receiver,
+                token_hash,
+                U256::from(300),
+                U256::from(TRANSACTION_FEE),
+                INCEPTION_TIMESTAMP + 1,
+                2,
+                &[2; 32],
+            )
+            .unwrap(),
+        ),
+        Box::new(
+            TokenAction::burn(
+                sender,
+                token_hash,
+                U256::from(200),
+                U256::from(TRANSACTION_FEE),
+                INCEPTION_TIMESTAMP + 1,
+                3,
+                &[2; 32],
+            )
+            .unwrap(),
+        ),
+    ];
+    // pending actions are taken into account
+    for action in actions.iter() {
+        tree.send_transaction_item(
+            transaction::parse_transactionable(&action.dump().unwrap()).unwrap(),
+        )
+        .await
+        .unwrap();
+    }
+    let overdraft = TokenAction::send(
+        sender,
+        receiver,
+        token_hash,
+        U256::from(501),
+        U256::from(TRANSACTION_FEE),
+        INCEPTION_TIMESTAMP + 1,
+        4,
+        &[2; 32],
+    )
+    .unwrap();
+    assert!(tree.send_transaction(overdraft).await.is_err());
+
+    let block_a1 = child_block(
+        &genesis,
+        [1; 33],
+        actions.iter().map(|action| action.hash()).collect(),
+    );
+    tree.add_new_block_items(block_a1, &actions).await.unwrap();
+    assert!(tree.get_pool().read().await.is_empty());
+    assert_eq!(tree.get_nonce(&sender).unwrap(), 4);
+    assert_eq!(
+        tree.get_token_balance(&sender, &token_hash).unwrap(),
+        U256::from(500)
+    );
+    assert_eq!(
+        tree.get_token_balance(&receiver, &token_hash).unwrap(),
+        U256::from(300)
+    );
+    // token actions don't move coins
+    assert_eq!(tree.get_amount(&receiver).unwrap(), U256::zero());
+
+    // the token can't be assigned twice
+    let reassign = TokenAction::assign(
+        sender,
+        token_hash,
+        TokenParameters::default(),
+        U256::from(TRANSACTION_FEE),
+        INCEPTION_TIMESTAMP + 2,
+        4,
+        &[2; 32],
+    )
+    .unwrap();
+    assert!(tree.send_transaction(reassign).await.is_err());
+
+    // heavier branch without the token
+    let block_b1 = child_block(&genesis, [2; 33], vec![]);
+    let block_b2 = child_block(&block_b1, [2; 33], vec![]);
+    tree.add_new_block(block_b1, &[]).await.unwrap();
+    tree.add_new_block(block_b2, &[]).await.unwrap();
+
+    assert_eq!(tree.get_nonce(&sender).unwrap(), 0);
+    assert_eq!(
+        tree.get_token_balance(&sender, &token_hash).unwrap(),
+        U256::zero()
+    );
+    assert_eq!(
+        tree.get_token_balance(&receiver, &token_hash).unwrap(),
+        U256::zero()
+    );
+    let send = TokenAction::send(
+        sender,
+        receiver,
+        token_hash,
+        U256::from(1),
+        U256::from(TRANSACTION_FEE),
+        INCEPTION_TIMESTAMP + 2,
+        0,
+        &[2; 32],
+    )
+    .unwrap();
+    assert!(tree.send_transaction(send).await.is_err());
+}
+
+#[tokio::test]
+async fn test_token_registry() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let genesis: BlockArc = tree.get_main_chain().get_last_block().unwrap().unwrap();
+    let sender = funded_account(&tree, [4; 32]);
+    let holder_private = [2; 32];
+    let holder = funded_account(&tree, holder_private);
+    let receiver = [7; 33];
+
+    let create = TokenAction::create(
+        sender,
+        U256::from(TRANSACTION_FEE),
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        &[4; 32],
+    )
+    .unwrap();
+    let token_hash = create.hash();
+    let parameters = TokenParameters {
+        token_data: "fee token".to_string(),
+        smol_contract: String::new(),
+        coin_supply: U256::from(1000),
+        transfer_fee: U256::from(10),
+    };
+    let owner_actions: Vec<TransactionableItem> = vec![
+        Box::new(create),
+        Box::new(
+            TokenAction::assign(
+                sender,
+                token_hash,
+                parameters.clone(),
+                U256::from(TRANSACTION_FEE),
+                INCEPTION_TIMESTAMP + 1,
+                1,
+                &[4; 32],
+            )
+            .unwrap(),
+        ),
+        // transfers of the owner are free
+        Box::new(
+            TokenAction::send(
+                sender,
+                holder,
+                token_hash,
+                U256::from(300),
+                U256::from(TRANSACTION_FEE),
+                INCEPTION_TIMESTAMP + 1,
+                2,
+                &[4; 32],
+            )
+            .unwrap(),
+        ),
+    ];
+    let block_1 = child_block(
+        &genesis,
+        [1; 33],
+        owner_actions.iter().map(|action| action.hash()).collect(),
+    );
+    tree.add_new_block_items(block_1.clone(), &owner_actions)
+        .await
+        .unwrap();
+
+    let token = tree.get_token(&token_hash).unwrap().unwrap();
+    assert!(token.assigned);
+    assert!(token.is_fee_static());
+    assert_eq!(token.current_owner, sender);
+    assert_eq!(token.parameters, parameters);
+    assert_eq!(tree.list_tokens().unwrap(), vec![token]);
+    assert!(tree.get_token(&[1; 32]).unwrap().is_none());
+
+    // the fee doesn't fit into the balance
+    let overdraft = TokenAction::send(
+        holder,
+        receiver,
+        token_hash,
+        U256::from(291),
+        U256::from(TRANSACTION_FEE),
+        INCEPTION_TIMESTAMP + 2,
+        0,
+        &holder_private,
+    )
+    .unwrap();
+    assert!(tree.send_transaction(overdraft).await.is_err());
+
+    // the fee overflows the amount
+    let overflow: Vec<TransactionableItem> = vec![Box::new(
+        TokenAction::send(
+            holder,
+            receiver,
+            token_hash,
+            U256::MAX,
+            U256::from(TRANSACTION_FEE),
+            INCEPTION_TIMESTAMP + 2,
+            0,
+            &holder_private,
+        )
+        .unwrap(),
+    )];
+    assert!(tree
+        .send_transaction_item(
+            transaction::parse_transactionable(&overflow[0].dump().unwrap()).unwrap()
+        )
+        .await
+        .is_err());
+    let overflow_block = child_block(&block_1, [1; 33], vec![overflow[0].hash()]);
+    assert!(tree
+        .add_new_block_items(overflow_block, &overflow)
+        .await
+        .is_err());
+
+    let holder_actions: Vec<TransactionableItem> = vec![
+        Box::new(
+            TokenAction::send(
+                holder,
+                receiver,
+                token_hash,
+                U256::from(100),
+                U256::from(TRANSACTION_FEE),
+                INCEPTION_TIMESTAMP + 2,
+                0,
+                &holder_private,
+            )
+            .unwrap(),
+        ),
+        Box::new(
+            TokenAction::burn(
+                holder,
+                token_hash,
+                U256::from(50),
+                U256::from(TRANSACTION_FEE),
+                INCEPTION_TIMESTAMP + 2,
+                1,
+                &holder_private,
+            )
+            .unwrap(),
+        ),
+    ];
+    let block_2 = child_block(
+        &block_1,
+        [1; 33],
+        holder_actions.iter().map(|action| action.hash()).collect(),
+    );
+    tree.add_new_block_items(block_2, &holder_actions)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        tree.get_token_holdings(&holder).unwrap(),
+        vec![(token_hash, U256::from(140))]
+    );
+    assert_eq!(
+        tree.get_token_holdings(&receiver).unwrap(),
+        vec![(token_hash, U256::from(100))]
+    );
+    assert_eq!(
+        tree.get_token_holdings(&sender).unwrap(),
+        vec![(token_hash, U256::from(710))]
+    );
+    assert_eq!(tree.audit_token(&token_hash).unwrap(), U256::from(950));
+    assert!(tree.audit_token(&[1; 32]).is_err());
 }
@@ -1,40 +1,53 @@
 use std::sync::Arc;
 
 use blockchaintree::{
-    block, chain, tools,
+    block::{self, Block as _},
+    chain,
+    errors::{BlockChainTreeError, BlockError, ChainErrorKind, ValidationErrorKind},
+    merkletree::MerkleTree,
+    static_values, tools,
     transaction::{self, Transactionable},
 };
 use primitive_types::U256;
 
 #[tokio::test]
 async fn init_flush_get_block_by_height_chain_test() {
-    let main_chain = chain::MainChain::new().unwrap();
+    let data_directory = tempfile::tempdir().unwrap();
+    let main_chain = chain::MainChain::open(data_directory.path()).unwrap();
 
     main_chain.flush().await.unwrap();
 
     drop(main_chain);
 
-    let main_chain = chain::MainChain::new().unwrap();
-
-    let height = main_chain.get_height();
+    let main_chain = chain::MainChain::open(data_directory.path()).unwrap();
 
+    let previous_block = main_chain.get_last_block().unwrap().unwrap();
+    let previous_hash = previous_block.hash().unwrap();
+    let timestamp = previous_block.get_info().timestamp + 1000;
+    let mut difficulty = previous_block.get_info().difficulty;
+    tools::recalculate_difficulty(
+        previous_block.get_info().timestamp,
+        timestamp,
+        &mut difficulty,
+    );
     // generate block
     let basic_data = block::BasicInfo {
-        timestamp: 160000,
+        timestamp,
         pow: [0; 32],
-        previous_hash: unsafe { [0; 32].try_into().unwrap_unchecked() },
-        height,
-        difficulty: [101; 32],
+        previous_hash,
+        height: previous_block.get_info().height + 1,
+        difficulty,
         founder: [6; 33],
     };
-    let main_block = block::TransactionBlock::new(
-        U256::from_dec_str("1").unwrap(),
-        basic_data,
-        [0; 32],
-        vec![[0; 32], [1; 32]],
+    let transactions = vec![[0; 32], [1; 32]];
+    let merkle_tree_root = *MerkleTree::build_tree(&transactions).get_root();
+    let main_block = mined(
+        block::TransactionBlock::new(U256::one(), basic_data, merkle_tree_root, transactions),
+        &previous_block,
     );
+    let pow = main_block.get_info().pow;
 
-    main_chain.add_block(Arc::new(main_block)).unwrap();
+    main_chain.add_block(main_block).unwrap();
 
     let height = main_chain.get_height();
     let block = main_chain.find_by_height(&(height - 1)).unwrap();
@@ -44,29 +57,58 @@ async fn init_flush_get_block_by_height_chain_test() {
     let block = block.unwrap();
 
     assert_eq!([6; 33], *block.get_founder());
-    assert_eq!(160000, block.get_info().timestamp);
-    assert_eq!([0; 32], block.get_info().pow);
+    assert_eq!(timestamp, block.get_info().timestamp);
+    assert_eq!(pow, block.get_info().pow);
     assert_eq!(height - 1, block.get_info().height);
-    assert_eq!([101; 32], block.get_info().difficulty);
-    assert_eq!(U256::from_dec_str("1").unwrap(), block.get_fee());
-    assert_eq!([0; 32], block.get_merkle_root());
+    assert_eq!(difficulty, block.get_info().difficulty);
+    assert_eq!(U256::one(), block.get_fee());
+    assert_eq!(merkle_tree_root, block.get_merkle_root());
+}
+
+#[tokio::test]
+async fn rejects_invalid_block_chain_test() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let main_chain = chain::MainChain::open(data_directory.path()).unwrap();
+    let genesis: block::BlockArc = main_chain.get_last_block().unwrap().unwrap();
+
+    let mut block = child_info(&genesis, [1; 33]);
+    block.timestamp = genesis.get_info().timestamp - 1;
+    let block = Arc::new(block::TransactionBlock::new(
+        U256::zero(),
+        block,
+        *MerkleTree::build_tree(&[]).get_root(),
+        vec![],
+    ));
+
+    let Err(report) = main_chain.add_block(block) else {
+        panic!("block with an earlier timestamp was accepted");
+    };
+    assert!(matches!(
+        report.downcast_ref::<BlockError>(),
+        Some(BlockError::Validation(ValidationErrorKind::Timestamp))
+    ));
+    assert_eq!(main_chain.get_height(), U256::one());
 }
 
 #[tokio::test]
 async fn init_get_transaction_chain_test() {
-    let main_chain = chain::MainChain::new().unwrap();
+    let data_directory = tempfile::tempdir().unwrap();
+    let main_chain = chain::MainChain::open(data_directory.path()).unwrap();
 
     let transaction = transaction::Transaction::new_signed(
         [10; 33],
         [20; 33],
         100,
+        0,
         U256::from_dec_str("3627836287").unwrap(),
-        U256::from_dec_str("3627836287").unwrap(),
+        U256::from(10),
         Some(vec![228, 123]),
         [33; 64],
     );
 
-    main_chain.add_transactions(&[transaction.clone()]).unwrap();
+    main_chain
+        .add_transactions(std::slice::from_ref(&transaction))
+        .unwrap();
 
     let got_transaction = main_chain
         .get_transaction(&tools::hash(&transaction.dump().unwrap()))
@@ -84,38 +126,36 @@ async fn init_get_transaction_chain_test() {
 
 #[tokio::test]
 async fn init_flush_get_block_by_height_deriv_chain_test() {
-    let deriv_chain = chain::DerivativeChain::new(
-        "deadbeef",
-        &[
-            57, 26, 43, 126, 188, 137, 234, 205, 234, 97, 128, 221, 242, 186, 198, 206, 3, 25, 250,
-            35, 169, 60, 208, 8, 94, 13, 60, 218, 72, 73, 207, 80,
-        ],
-    )
-    .unwrap();
+    let data_directory = tempfile::tempdir().unwrap();
+    let genesis_hash = [
+        57, 26, 43, 126, 188, 137, 234, 205, 234, 97, 128, 221, 242, 186, 198, 206, 3, 25, 250, 35,
+        169, 60, 208, 8, 94, 13, 60, 218, 72, 73, 207, 80,
+    ];
+    let deriv_chain =
+        chain::DerivativeChain::open(data_directory.path(), "deadbeef", &genesis_hash).unwrap();
 
     deriv_chain.flush().await.unwrap();
     drop(deriv_chain);
 
-    let deriv_chain = chain::DerivativeChain::new(
-        "deadbeef",
-        &[
-            57, 26, 43, 126, 188, 137, 234, 205, 234, 97, 128, 221, 242, 186, 198, 206, 3, 25, 250,
-            35, 169, 60, 208, 8, 94, 13, 60, 218, 72, 73, 207, 80,
-        ],
-    )
-    .unwrap();
+    let deriv_chain =
+        chain::DerivativeChain::open(data_directory.path(), "deadbeef", &genesis_hash).unwrap();
 
     // generate block
-    let basic_data = block::BasicInfo {
+    let mut basic_data = block::BasicInfo {
         timestamp: 160000,
         pow: [0; 32],
-        previous_hash: unsafe { [0; 32].try_into().unwrap_unchecked() },
-        height: U256::from_dec_str("0").unwrap(),
-        difficulty: [101; 32],
+        previous_hash: genesis_hash,
+        height: U256::from_dec_str("1").unwrap(),
+        difficulty: static_values::BEGINNING_DIFFICULTY,
         founder: [6; 33],
     };
+    basic_data.pow = mine(
+        &block::DerivativeBlock::pow_preimage(&basic_data, &[3; 32]),
+        &static_values::BEGINNING_DIFFICULTY,
+    );
     let derivative_block = block::DerivativeBlock {
         default_info: basic_data,
+        payment_transaction: [3; 32],
     };
     deriv_chain.add_block(&derivative_block).unwrap();
 
@@ -146,4 +186,331 @@ async fn init_flush_get_block_by_height_deriv_chain_test() {
         derivative_block.default_info.founder,
         block.default_info.founder
     );
+    assert_eq!(
+        derivative_block.payment_transaction,
+        block.payment_transaction
+    );
+}
+
+#[tokio::test]
+async fn deriv_chain_validates_blocks_test() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let genesis_hash = [7; 32];
+    let deriv_chain =
+        chain::DerivativeChain::create(data_directory.path(), "deadbeef", &genesis_hash).unwrap();
+    let founder = [6; 33];
+
+    // pow found for another founder
+    let mut first_block = block::DerivativeBlock {
+        default_info: block::BasicInfo {
+            timestamp: 160000,
+            pow: [0; 32],
+            previous_hash: genesis_hash,
+            height: U256::one(),
+            difficulty: static_values::BEGINNING_DIFFICULTY,
+            founder,
+        },
+        payment_transaction: [1; 32],
+    };
+    let preimage = block::DerivativeBlock::pow_preimage(&first_block.default_info, &[1; 32]);
+    let other_preimage = block::DerivativeBlock::pow_preimage(
+        &block::BasicInfo {
+            founder: [8; 33],
+            ..first_block.default_info.clone()
+        },
+        &[1; 32],
+    );
+    for nonce in 0u64.. {
+        first_block.default_info.pow[..8].copy_from_slice(&nonce.to_be_bytes());
+        let pow = &first_block.default_info.pow;
+        if !tools::check_pow(&preimage, &static_values::BEGINNING_DIFFICULTY, pow)
+            && tools::check_pow(&other_preimage, &static_values::BEGINNING_DIFFICULTY, pow)
+        {
+            break;
+        }
+    }
+    assert!(deriv_chain.add_block(&first_block).is_err());
+    assert_eq!(deriv_chain.get_height(), U256::zero());
+
+    first_block.default_info.pow = mine(&preimage, &static_values::BEGINNING_DIFFICULTY);
+    deriv_chain.add_block(&first_block).unwrap();
+    let first_hash = first_block.hash().unwrap();
+
+    // difficulty is retargeted by the time passed since the previous block of the chain
+    let timestamp = 160000 + 1;
+    let mut second_block = block::DerivativeBlock {
+        default_info: block::BasicInfo {
+            timestamp,
+            pow: [0; 32],
+            previous_hash: first_hash,
+            height: U256::from(2),
+            difficulty: static_values::BEGINNING_DIFFICULTY,
+            founder,
+        },
+        payment_transaction: [2; 32],
+    };
+    second_block.default_info.pow = mine(
+        &block::DerivativeBlock::pow_preimage(&second_block.default_info, &[2; 32]),
+        &static_values::BEGINNING_DIFFICULTY,
+    );
+    let report = deriv_chain.add_block(&second_block).unwrap_err();
+    assert!(matches!(
+        report.downcast_ref::<BlockError>(),
+        Some(BlockError::Validation(ValidationErrorKind::Difficulty))
+    ));
+    let difficulty =
+        block::DerivativeBlock::next_difficulty(Some(&first_block.default_info), timestamp);
+    assert_ne!(difficulty, static_values::BEGINNING_DIFFICULTY);
+    second_block.default_info.difficulty = difficulty;
+    second_block.default_info.pow = mine(
+        &block::DerivativeBlock::pow_preimage(&second_block.default_info, &[2; 32]),
+        &static_values::BEGINNING_DIFFICULTY,
+    );
+    deriv_chain.add_block(&second_block).unwrap();
+    assert_eq!(deriv_chain.get_height(), U256::from(2));
+    assert_eq!(deriv_chain.get_difficulty(), difficulty);
+    drop(deriv_chain);
+
+    let deriv_chain = chain::DerivativeChain::open_existing(data_directory.path(), "deadbeef")
+        .unwrap()
+        .unwrap();
+    assert_eq!(deriv_chain.get_difficulty(), difficulty);
+}
+
+#[tokio::test]
+async fn create_open_existing_deriv_chain_test() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let genesis_hash = [7; 32];
+    assert!(
+        chain::DerivativeChain::open_existing(data_directory.path(), "deadbeef")
+            .unwrap()
+            .is_none()
+    );
+
+    let deriv_chain =
+        chain::DerivativeChain::create(data_directory.path(), "deadbeef", &genesis_hash).unwrap();
+    let mut derivative_block = block::DerivativeBlock {
+        default_info: block::BasicInfo {
+            timestamp: 160000,
+            pow: [0; 32],
+            previous_hash: genesis_hash,
+            height: U256::one(),
+            difficulty: static_values::BEGINNING_DIFFICULTY,
+            founder: [6; 33],
+        },
+        payment_transaction: [0; 32],
+    };
+    derivative_block.default_info.pow = mine(
+        &block::DerivativeBlock::pow_preimage(&derivative_block.default_info, &[0; 32]),
+        &static_values::BEGINNING_DIFFICULTY,
+    );
+    deriv_chain.add_block(&derivative_block).unwrap();
+    // not flushed
+    drop(deriv_chain);
+
+    assert!(
+        chain::DerivativeChain::create(data_directory.path(), "deadbeef", &genesis_hash).is_err()
+    );
+    let deriv_chain = chain::DerivativeChain::open_existing(data_directory.path(), "deadbeef")
+        .unwrap()
+        .unwrap();
+    assert_eq!(*deriv_chain.genesis_hash, genesis_hash);
+    assert_eq!(deriv_chain.get_height(), U256::one());
+    drop(deriv_chain);
+
+    // the existing chain isn't anchored to another genesis block
+    let deriv_chain =
+        chain::DerivativeChain::open(data_directory.path(), "deadbeef", &[8; 32]).unwrap();
+    assert_eq!(*deriv_chain.genesis_hash, genesis_hash);
+}
+
+fn mine(previous_hash: &[u8; 32], difficulty: &[u8; 32]) -> [u8; 32] {
+    let mut pow = [0u8; 32];
+    for nonce in 0u64.. {
+        pow[..8].copy_from_slice(&nonce.to_be_bytes());
+        if tools::check_pow(previous_hash, difficulty, &pow) {
+            break;
+        }
+    }
+    pow
+}
+
+/// Sets the pow of the block on top of the parent
+fn mined(mut block: block::TransactionBlock, parent: &block::BlockArc) -> block::BlockArc {
+    block.default_info.pow = mine(
+        &block::pow_preimage(&block.default_info, &block.get_body_root()),
+        &parent.get_info().difficulty,
+    );
+    Arc::new(block)
+}
+
+/// Basic info of the next block after the parent, without the pow
+fn child_info(parent: &block::BlockArc, founder: [u8; 33]) -> block::BasicInfo {
+    let previous_hash = parent.hash().unwrap();
+    let timestamp = parent.get_info().timestamp + 1000;
+    let mut difficulty = parent.get_info().difficulty;
+    tools::recalculate_difficulty(parent.get_info().timestamp, timestamp, &mut difficulty);
+    block::BasicInfo {
+        timestamp,
+        pow: [0; 32],
+        previous_hash,
+        height: parent.get_info().height + 1,
+        difficulty,
+        founder,
+    }
+}
+
+fn child_block(parent: &block::BlockArc, founder: [u8; 33]) -> block::BlockArc {
+    mined(
+        block::TransactionBlock::new(
+            U256::zero(),
+            child_info(parent, founder),
+            *MerkleTree::build_tree(&[]).get_root(),
+            vec![],
+        ),
+        parent,
+    )
+}
+
+#[tokio::test]
+async fn fork_reorganization_chain_test() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let main_chain = chain::MainChain::open(data_directory.path()).unwrap();
+    let genesis: block::BlockArc = main_chain.get_last_block().unwrap().unwrap();
+    let genesis_hash = genesis.hash().unwrap();
+
+    let block_a1 = child_block(&genesis, [1; 33]);
+    let update = main_chain.add_block(block_a1.clone()).unwrap();
+    assert_eq!(update.applied.len(), 1);
+    assert!(update.reverted.is_empty());
+    // the work of a block is the one of the difficulty its pow was checked against
+    assert_eq!(
+        main_chain
+            .get_chain_work(&block_a1.hash().unwrap())
+            .unwrap()
+            .unwrap(),
+        main_chain.get_chain_work(&genesis_hash).unwrap().unwrap()
+            + tools::block_work(&genesis.get_info().difficulty)
+    );
+
+    // same amount of work, the current tip stays
+    let block_b1 = child_block(&genesis, [2; 33]);
+    let update = main_chain.add_block(block_b1.clone()).unwrap();
+    assert!(update.is_empty());
+    assert_eq!(main_chain.get_height(), U256::from(2));
+    assert_eq!(
+        main_chain.find_side_children(&genesis_hash).unwrap(),
+        vec![block_b1.hash().unwrap()]
+    );
+
+    // heavier branch triggers reorganization
+    let block_b2 = child_block(&block_b1, [2; 33]);
+    let update = main_chain.add_block(block_b2.clone()).unwrap();
+    assert_eq!(update.reverted.len(), 1);
+    assert_eq!(update.reverted[0].hash().unwrap(), block_a1.hash().unwrap());
+    assert_eq!(update.applied.len(), 2);
+    assert_eq!(update.applied[0].hash().unwrap(), block_b1.hash().unwrap());
+
+    assert_eq!(main_chain.get_height(), U256::from(3));
+    assert_eq!(
+        main_chain
+            .get_last_block()
+            .unwrap()
+            .unwrap()
+            .hash()
+            .unwrap(),
+        block_b2.hash().unwrap()
+    );
+    assert!(main_chain
+        .find_by_hash(&block_a1.hash().unwrap())
+        .unwrap()
+        .is_none());
+    assert!(main_chain
+        .find_side_block(&block_a1.hash().unwrap())
+        .unwrap()
+        .is_some());
+    assert!(main_chain
+        .find_side_block(&block_b1.hash().unwrap())
+        .unwrap()
+        .is_none());
+    assert!(
+        main_chain
+            .get_chain_work(&block_b2.hash().unwrap())
+            .unwrap()
+            .unwrap()
+            > main_chain
+                .get_chain_work(&block_a1.hash().unwrap())
+                .unwrap()
+                .unwrap()
+    );
+
+    // blocks with unknown parents are rejected
+    let mut orphan = child_info(&block_b2, [3; 33]);
+    orphan.previous_hash = [42; 32];
+    let orphan = Arc::new(block::TransactionBlock::new(
+        U256::zero(),
+        orphan,
+        *MerkleTree::build_tree(&[]).get_root(),
+        vec![],
+    ));
+    assert!(main_chain.add_block(orphan).is_err());
+}
+
+#[test]
+fn outdated_layout_chain_test() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let root = data_directory
+        .path()
+        .join(static_values::MAIN_CHAIN_DIRECTORY);
+    // older versions kept every part of the chain in a separate database
+    let blocks = sled::open(root.join(static_values::BLOCKS_FOLDER)).unwrap();
+    blocks.insert([0; 32], vec![1, 2, 3]).unwrap();
+    blocks.flush().unwrap();
+    drop(blocks);
+    std::fs::write(root.join(static_values::CONFIG_FILE), [0; 64]).unwrap();
+
+    let Err(report) = chain::MainChain::open(data_directory.path()) else {
+        panic!("the outdated layout is opened");
+    };
+    assert!(matches!(
+        report.current_context(),
+        BlockChainTreeError::Chain(ChainErrorKind::OutdatedLayout)
+    ));
+    // the old chain is left untouched
+    assert!(!root.join(static_values::DATABASE_FOLDER).exists());
+}
+
+#[tokio::test]
+async fn reopen_without_flush_chain_test() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let main_chain = chain::MainChain::open(data_directory.path()).unwrap();
+    let genesis: block::BlockArc = main_chain.get_last_block().unwrap().unwrap();
+
+    let block_1 = child_block(&genesis, [1; 33]);
+    let block_2 = child_block(&block_1, [1; 33]);
+    main_chain.add_block(block_1).unwrap();
+    main_chain.add_block(block_2.clone()).unwrap();
+    // the config was never written, the height comes from the stored blocks
+    drop(main_chain);
+
+    let main_chain = chain::MainChain::open(data_directory.path()).unwrap();
+    assert_eq!(main_chain.get_height(), U256::from(3));
+    assert_eq!(
+        main_chain
+            .get_last_block()
+            .unwrap()
+            .unwrap()
+            .hash()
+            .unwrap(),
+        block_2.hash().unwrap()
+    );
+    assert_eq!(
+        main_chain
+            .find_by_height(&U256::zero())
+            .unwrap()
+            .unwrap()
+            .hash()
+            .unwrap(),
+        genesis.hash().unwrap()
+    );
 }
@@ -0,0 +1,93 @@
+use std::thread;
+use std::time::Duration;
+
+use blockchaintree::blockchaintree::BlockChainTree;
+use blockchaintree::miner::{Miner, MiningTarget};
+use blockchaintree::static_values::{BEGINNING_DIFFICULTY, INCEPTION_TIMESTAMP, MAX_DIFFICULTY};
+use blockchaintree::tools;
+use primitive_types::U256;
+
+#[test]
+fn miner_finds_pow() {
+    let target = MiningTarget {
+        pow_preimage: [3; 32],
+        difficulty: BEGINNING_DIFFICULTY,
+    };
+    let miner = Miner::new(4);
+
+    let result = miner.mine(&target).unwrap();
+
+    assert!(tools::check_pow(
+        &target.pow_preimage,
+        &target.difficulty,
+        &result.pow
+    ));
+    assert!(result.hashes > 0);
+    assert!(result.hashrate() > 0.0);
+}
+
+#[test]
+fn miner_cancel() {
+    // no pow satisfies it
+    let target = MiningTarget {
+        pow_preimage: [3; 32],
+        difficulty: MAX_DIFFICULTY,
+    };
+    let miner = Miner::new(2);
+
+    let handle = {
+        let miner = miner.clone();
+        thread::spawn(move || miner.mine(&target))
+    };
+    thread::sleep(Duration::from_millis(50));
+    miner.cancel();
+
+    assert!(handle.join().unwrap().is_none());
+}
+
+#[test]
+fn miner_cancel_before_run() {
+    let target = MiningTarget {
+        pow_preimage: [3; 32],
+        difficulty: BEGINNING_DIFFICULTY,
+    };
+    let miner = Miner::new(2);
+
+    // the cancel sent before the run started isn't lost
+    miner.cancel();
+    assert!(miner.mine(&target).is_none());
+
+    miner.reset();
+    assert!(miner.mine(&target).is_some());
+}
+
+#[tokio::test]
+async fn miner_derivative_block() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let mut tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let founder = [5; 33];
+
+    let timestamp = INCEPTION_TIMESTAMP + 1000;
+    // the chain is not created implicitly
+    assert!(tree
+        .derivative_target(&founder, &[0; 32], timestamp)
+        .is_err());
+    assert!(tree.find_derivative_chain(&founder).is_none());
+    tree.create_derivative_chain(&founder).unwrap();
+
+    let target = tree
+        .derivative_target(&founder, &[0; 32], timestamp)
+        .unwrap();
+    let result = Miner::new(2).mine(&target).unwrap();
+    tree.emmit_new_derivative_block(&result.pow, &founder, &[0; 32], timestamp)
+        .await
+        .unwrap();
+
+    let chain = tree.get_derivative_chain(&founder).unwrap();
+    assert_eq!(chain.get_height(), U256::one());
+    assert_ne!(
+        tree.derivative_target(&founder, &[0; 32], timestamp)
+            .unwrap(),
+        target
+    );
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use blockchaintree::blockchaintree::BlockChainTree;
+use blockchaintree::node::{Node, NodeConfig};
+use primitive_types::U256;
+use tokio::sync::oneshot;
+
+fn write_config(directory: &std::path::Path, content: &str) -> std::path::PathBuf {
+    let path = directory.join("node.toml");
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+#[test]
+fn node_config_load() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let path = write_config(
+        directory.path(),
+        "flush_interval = 5\n[pool]\ncapacity = 10\n",
+    );
+    let config = NodeConfig::load(path).unwrap();
+    assert_eq!(config.flush_interval, 5);
+    assert_eq!(config.pool.capacity, 10);
+    assert_eq!(
+        config.pool.sender_limit,
+        NodeConfig::default().pool.sender_limit
+    );
+    assert!(!config.miner.enabled);
+
+    let path = write_config(directory.path(), "unknown_setting = 1\n");
+    assert!(NodeConfig::load(path).is_err());
+
+    // the miner needs an address to pay to
+    let path = write_config(
+        directory.path(),
+        &format!(
+            "data_directory = {:?}\n[miner]\nenabled = true\n",
+            directory.path().join("data")
+        ),
+    );
+    assert!(Node::open(NodeConfig::load(path).unwrap()).is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn node_mines_and_shuts_down() {
+    let directory = tempfile::tempdir().unwrap();
+    let founder = [5u8; 33];
+    let path = write_config(
+        directory.path(),
+        &format!(
+            "data_directory = {:?}\n[miner]\nenabled = true\nthreads = 2\nfounder = \"{}\"\n",
+            directory.path().join("data"),
+            hex::encode(founder)
+        ),
+    );
+
+    let node = Node::open(NodeConfig::load(path).unwrap()).unwrap();
+    let tree = node.get_tree();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let handle = tokio::spawn(node.run(async {
+        let _ = stopped.await;
+    }));
+
+    while tree.read().await.get_main_chain().get_height() < U256::from(3) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    stop.send(()).unwrap();
+    handle.await.unwrap().unwrap();
+    drop(tree);
+
+    // everything was flushed on the shutdown
+    let tree = BlockChainTree::open(directory.path().join("data")).unwrap();
+    assert!(tree.get_main_chain().get_height() >= U256::from(3));
+    assert!(tree.get_amount(&founder).unwrap() > U256::zero());
+}
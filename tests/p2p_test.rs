@@ -0,0 +1,937 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use blockchaintree::{
+    block::{BasicInfo, BlockArc},
+    blockchaintree::BlockChainTree,
+    miner::{Miner, MiningTarget},
+    node::SharedTree,
+    p2p::{
+        manager::Misbehaviour,
+        message::{
+            read_message, write_message, Handshake, Header, Inventory, InventoryKind, Message,
+        },
+        sync::ChainSync,
+        Network, P2pConfig,
+    },
+    static_values::{
+        BAN_SCORE, BEGINNING_DIFFICULTY, INCEPTION_TIMESTAMP, INVENTORY_RATE, MAX_INVENTORY_SIZE,
+        PEER_TIMEOUT, PROTOCOL_VERSION, TIME_PER_BLOCK,
+    },
+    transaction::{Transaction, Transactionable},
+    types::{Address, Hash},
+    wallet::Wallet,
+};
+use primitive_types::U256;
+use tokio::net::TcpStream;
+use tokio::sync::RwLock;
+
+fn open_tree(directory: &tempfile::TempDir) -> SharedTree {
+    Arc::new(RwLock::new(BlockChainTree::open(directory.path()).unwrap()))
+}
+
+/// Opens the tree of a dropped one
+///
+/// Sled releases the lock of the database from its own threads once their writes are done,
+/// which can happen right after the last handle is dropped
+async fn reopen_tree(directory: &tempfile::TempDir) -> SharedTree {
+    for _ in 0..50 {
+        if let Ok(tree) = BlockChainTree::open(directory.path()) {
+            return Arc::new(RwLock::new(tree));
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    open_tree(directory)
+}
+
+fn local_config() -> P2pConfig {
+    P2pConfig {
+        enabled: true,
+        listen: ([127, 0, 0, 1], 0).into(),
+        peers: Vec::new(),
+        ..Default::default()
+    }
+}
+
+/// Connects to the node and completes the handshake, without a listening port
+async fn raw_peer(network: &Network) -> TcpStream {
+    let mut stream = TcpStream::connect(network.local_addr()).await.unwrap();
+    let Message::Handshake(local) = read_message(&mut stream).await.unwrap() else {
+        panic!("expected a handshake");
+    };
+    write_message(
+        &mut stream,
+        &Message::Handshake(Handshake {
+            listen_port: 0,
+            ..local
+        }),
+    )
+    .await
+    .unwrap();
+    stream
+}
+
+async fn wait_for_peers(network: &Network, amount: usize) {
+    while network.peers().len() != amount {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Polls the tree until the condition holds
+async fn wait_for(tree: &SharedTree, condition: impl Fn(&BlockChainTree) -> bool) {
+    let waiting = async {
+        while !condition(&*tree.read().await) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), waiting)
+        .await
+        .unwrap();
+}
+
+/// Counts the items requested by the node until it stays silent for a while
+async fn requested_items(stream: &mut TcpStream) -> usize {
+    let mut requested = 0;
+    while let Ok(message) =
+        tokio::time::timeout(Duration::from_millis(500), read_message(stream)).await
+    {
+        if let Message::GetData(items) = message.unwrap() {
+            requested += items.len();
+        }
+    }
+    requested
+}
+
+static SENDER_PRIVATE: [u8; 32] = [2; 32];
+/// Fee offered by the test transactions, above the fee of any test block
+const TRANSACTION_FEE: u64 = 100_000_000;
+
+/// Opens the tree with coins and gas on the address of the sender key
+fn funded_tree(directory: &tempfile::TempDir) -> SharedTree {
+    let tree = open_tree(directory);
+    fund_sender(&tree.try_read().unwrap());
+    tree
+}
+
+fn fund_sender(tree: &BlockChainTree) {
+    let sender = sender();
+    tree.add_amount(&sender, U256::from_dec_str("1000000000000000000").unwrap())
+        .unwrap();
+    tree.add_gas(&sender, U256::from_dec_str("1000000000000000000").unwrap())
+        .unwrap();
+}
+
+fn sender() -> Address {
+    Wallet::derive_address(&SENDER_PRIVATE).unwrap()
+}
+
+/// Mines a block on top of the main chain, keeping the difficulty
+async fn mine_block(tree: &SharedTree, founder: &Address, transactions: &[Hash]) -> BlockArc {
+    let mut tree = tree.write().await;
+    let last_block = tree.get_main_chain().get_last_block().unwrap().unwrap();
+    let template = tree
+        .block_template_with(
+            founder,
+            transactions.to_vec(),
+            last_block.get_info().timestamp + TIME_PER_BLOCK,
+        )
+        .await
+        .unwrap();
+    let result = Miner::new(1).mine(&MiningTarget::from(&template)).unwrap();
+    tree.submit_template(&template, &result.pow).await.unwrap()
+}
+
+#[test]
+fn message_dump_parse() {
+    let items = vec![
+        Inventory {
+            kind: InventoryKind::Transaction,
+            hash: [1; 32],
+        },
+        Inventory {
+            kind: InventoryKind::Block,
+            hash: [2; 32],
+        },
+    ];
+    let messages = [
+        Message::Handshake(Handshake {
+            version: PROTOCOL_VERSION,
+            genesis_hash: [3; 32],
+            listen_port: 8334,
+            height: U256::from(123456),
+        }),
+        Message::Inventory(items.clone()),
+        Message::GetData(items.clone()),
+        Message::NotFound(items),
+        Message::Block(vec![2, 5, 6]),
+        Message::Transaction(vec![0, 7]),
+        Message::Ping(42),
+        Message::Pong(42),
+        Message::GetHeaders {
+            start: U256::from(10),
+            count: 2000,
+        },
+        Message::Headers {
+            start: U256::from(10),
+            headers: vec![Header {
+                hash: [4; 32],
+                body_root: [8; 32],
+                info: BasicInfo {
+                    timestamp: INCEPTION_TIMESTAMP,
+                    pow: [5; 32],
+                    previous_hash: [6; 32],
+                    height: U256::from(10),
+                    difficulty: BEGINNING_DIFFICULTY,
+                    founder: [7; 33],
+                },
+            }],
+        },
+        Message::Headers {
+            start: U256::zero(),
+            headers: vec![Header {
+                hash: [4; 32],
+                body_root: [0; 32],
+                info: BasicInfo {
+                    timestamp: INCEPTION_TIMESTAMP,
+                    pow: [0; 32],
+                    previous_hash: [0; 32],
+                    height: U256::zero(),
+                    difficulty: BEGINNING_DIFFICULTY,
+                    founder: [0; 33],
+                },
+            }],
+        },
+        Message::Headers {
+            start: U256::from(100),
+            headers: vec![],
+        },
+    ];
+    for message in messages {
+        let dump = message.dump();
+        assert_eq!(Message::parse(&dump).unwrap(), message);
+    }
+
+    assert!(Message::parse(&[]).is_err());
+    assert!(Message::parse(&[100]).is_err());
+    assert!(Message::parse(&Message::Ping(1).dump()[..5]).is_err());
+    let mut inventory = Message::GetData(vec![]).dump();
+    inventory.push(0);
+    assert!(Message::parse(&inventory).is_err());
+}
+
+#[tokio::test]
+async fn message_frames() {
+    let (mut client, mut server) = tokio::io::duplex(1024);
+    write_message(&mut client, &Message::Ping(1)).await.unwrap();
+    write_message(&mut client, &Message::Block(vec![1; 100]))
+        .await
+        .unwrap();
+    assert_eq!(read_message(&mut server).await.unwrap(), Message::Ping(1));
+    assert_eq!(
+        read_message(&mut server).await.unwrap(),
+        Message::Block(vec![1; 100])
+    );
+
+    // oversized frames are refused before reading them
+    tokio::io::AsyncWriteExt::write_all(&mut client, &u32::MAX.to_be_bytes())
+        .await
+        .unwrap();
+    assert!(read_message(&mut server).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nodes_connect_and_ping() {
+    let directories: Vec<_> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+    let mut networks = Vec::new();
+    for directory in directories.iter() {
+        networks.push(
+            Network::start(&local_config(), open_tree(directory))
+                .await
+                .unwrap(),
+        );
+    }
+
+    // the others connect to the first node
+    for network in networks[1..].iter() {
+        let id = network.connect(networks[0].local_addr()).await.unwrap();
+        let peer = &network.peers()[0];
+        assert_eq!(peer.id, id);
+        assert!(!peer.inbound);
+        assert_eq!(peer.handshake.listen_port, networks[0].local_addr().port());
+        assert_eq!(peer.handshake.height, U256::one());
+        network.ping(id).await.unwrap();
+    }
+    wait_for_peers(&networks[0], 2).await;
+    for peer in networks[0].peers() {
+        assert!(peer.inbound);
+        networks[0].ping(peer.id).await.unwrap();
+    }
+
+    networks[1].disconnect(networks[1].peers()[0].id);
+    wait_for_peers(&networks[0], 1).await;
+
+    for network in networks {
+        network.shutdown().await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nodes_serve_data() {
+    let directories = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    let tree = open_tree(&directories[0]);
+    let serving = Network::start(&local_config(), tree.clone()).await.unwrap();
+    let requesting = Network::start(&local_config(), open_tree(&directories[1]))
+        .await
+        .unwrap();
+    let peer = requesting.connect(serving.local_addr()).await.unwrap();
+
+    let genesis = tree
+        .read()
+        .await
+        .get_main_chain()
+        .find_raw_by_height(&U256::zero())
+        .unwrap()
+        .unwrap();
+    let block = Inventory {
+        kind: InventoryKind::Block,
+        hash: blockchaintree::tools::hash(&genesis),
+    };
+    assert_eq!(
+        requesting.get_data(peer, block).await.unwrap(),
+        Some(genesis)
+    );
+
+    // the block of a competing branch is kept as a side block and served too
+    let other_directory = tempfile::tempdir().unwrap();
+    let other = open_tree(&other_directory);
+    let side_block = mine_block(&other, &[4; 33], &[]).await;
+    let side_hash = side_block.hash().unwrap();
+    let (side_dump, coinbase) = {
+        let other = other.read().await;
+        let main_chain = other.get_main_chain();
+        (
+            main_chain.find_raw_by_hash(&side_hash).unwrap().unwrap(),
+            main_chain
+                .get_transaction(&side_block.get_transactions()[0])
+                .unwrap()
+                .unwrap(),
+        )
+    };
+    mine_block(&tree, &[1; 33], &[]).await;
+    {
+        let tree = tree.read().await;
+        tree.add_new_block(side_block, &[coinbase]).await.unwrap();
+        assert!(tree
+            .get_main_chain()
+            .find_side_block(&side_hash)
+            .unwrap()
+            .is_some());
+    }
+    let side = Inventory {
+        kind: InventoryKind::Block,
+        hash: side_hash,
+    };
+    assert_eq!(
+        requesting.get_data(peer, side).await.unwrap(),
+        Some(side_dump)
+    );
+
+    let transaction = Transaction::new(
+        sender(),
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        SENDER_PRIVATE,
+        None,
+    );
+    {
+        let tree = tree.read().await;
+        fund_sender(&tree);
+        tree.send_transaction(transaction.clone()).await.unwrap();
+    }
+    let pooled = Inventory {
+        kind: InventoryKind::Transaction,
+        hash: transaction.hash(),
+    };
+    assert_eq!(
+        requesting.get_data(peer, pooled).await.unwrap(),
+        Some(transaction.dump().unwrap())
+    );
+
+    let unknown = Inventory {
+        kind: InventoryKind::Transaction,
+        hash: [9; 32],
+    };
+    assert_eq!(requesting.get_data(peer, unknown).await.unwrap(), None);
+
+    requesting.shutdown().await;
+    serving.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_not_reading_answers_holds_up_only_itself() {
+    let directories = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    let tree = open_tree(&directories[0]);
+    let serving = Network::start(&local_config(), tree.clone()).await.unwrap();
+    let requesting = Network::start(&local_config(), open_tree(&directories[1]))
+        .await
+        .unwrap();
+    let peer = requesting.connect(serving.local_addr()).await.unwrap();
+
+    let genesis = tree
+        .read()
+        .await
+        .get_main_chain()
+        .find_raw_by_height(&U256::zero())
+        .unwrap()
+        .unwrap();
+    let block = Inventory {
+        kind: InventoryKind::Block,
+        hash: blockchaintree::tools::hash(&genesis),
+    };
+    // more answers than fit into the queue and the socket buffers
+    let mut stalled = raw_peer(&serving).await;
+    for _ in 0..5 {
+        write_message(
+            &mut stalled,
+            &Message::GetData(vec![block; MAX_INVENTORY_SIZE]),
+        )
+        .await
+        .unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let answered = tokio::time::timeout(
+        Duration::from_secs(PEER_TIMEOUT / 2),
+        requesting.get_data(peer, block),
+    )
+    .await
+    .unwrap();
+    assert_eq!(answered.unwrap(), Some(genesis));
+
+    requesting.shutdown().await;
+    serving.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn handshake_rejects_other_chains() {
+    let directory = tempfile::tempdir().unwrap();
+    let network = Network::start(&local_config(), open_tree(&directory))
+        .await
+        .unwrap();
+
+    // the node sends its handshake first
+    let mut stream = TcpStream::connect(network.local_addr()).await.unwrap();
+    let Message::Handshake(local) = read_message(&mut stream).await.unwrap() else {
+        panic!("expected a handshake");
+    };
+    assert_eq!(local.version, PROTOCOL_VERSION);
+    drop(stream);
+
+    let wrong = [
+        Handshake {
+            genesis_hash: [0; 32],
+            ..local.clone()
+        },
+        Handshake {
+            version: PROTOCOL_VERSION + 1,
+            ..local.clone()
+        },
+    ];
+    for handshake in wrong {
+        let mut stream = TcpStream::connect(network.local_addr()).await.unwrap();
+        write_message(&mut stream, &Message::Handshake(handshake))
+            .await
+            .unwrap();
+        // the node sends its handshake and drops the connection
+        assert!(matches!(
+            read_message(&mut stream).await.unwrap(),
+            Message::Handshake(_)
+        ));
+        assert!(read_message(&mut stream).await.is_err());
+    }
+    assert!(network.peers().is_empty());
+
+    network.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chain_sync() {
+    let directories = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    let serving_tree = funded_tree(&directories[0]);
+    let syncing_tree = funded_tree(&directories[1]);
+    let founder = [5; 33];
+    let receiver = [7; 33];
+
+    mine_block(&serving_tree, &founder, &[]).await;
+    let transaction = Transaction::new(
+        sender(),
+        receiver,
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        SENDER_PRIVATE,
+        None,
+    );
+    serving_tree
+        .read()
+        .await
+        .send_transaction(transaction.clone())
+        .await
+        .unwrap();
+    mine_block(&serving_tree, &founder, &[transaction.hash()]).await;
+    for _ in 0..3 {
+        mine_block(&serving_tree, &founder, &[]).await;
+    }
+    // the syncing node has a shorter branch of its own, replaced by the longer one
+    mine_block(&syncing_tree, &[6; 33], &[]).await;
+
+    let serving = Network::start(&local_config(), serving_tree.clone())
+        .await
+        .unwrap();
+    let syncing = Network::start(&local_config(), syncing_tree.clone())
+        .await
+        .unwrap();
+    let peer = syncing.connect(serving.local_addr()).await.unwrap();
+
+    let sync = ChainSync::new(syncing.clone()).await.unwrap();
+    assert_eq!(sync.sync(peer).await.unwrap(), 5);
+    assert!(sync.pending_headers().unwrap().is_empty());
+
+    {
+        let serving_tree = serving_tree.read().await;
+        let syncing_tree = syncing_tree.read().await;
+        let serving_chain = serving_tree.get_main_chain();
+        let syncing_chain = syncing_tree.get_main_chain();
+        assert_eq!(syncing_chain.get_height(), serving_chain.get_height());
+        assert_eq!(
+            syncing_chain.get_last_raw_block().unwrap(),
+            serving_chain.get_last_raw_block().unwrap()
+        );
+        assert!(syncing_chain
+            .transaction_exists(&transaction.hash())
+            .unwrap());
+        for owner in [founder, receiver, [6; 33], sender()] {
+            assert_eq!(
+                syncing_tree.get_amount(&owner).unwrap(),
+                serving_tree.get_amount(&owner).unwrap()
+            );
+        }
+    }
+
+    // nothing is left to download
+    assert_eq!(sync.sync(peer).await.unwrap(), 0);
+
+    syncing.shutdown().await;
+    serving.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chain_sync_resumes() {
+    let directories = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    let serving_tree = funded_tree(&directories[0]);
+    for _ in 0..3 {
+        mine_block(&serving_tree, &[5; 33], &[]).await;
+    }
+    let serving = Network::start(&local_config(), serving_tree.clone())
+        .await
+        .unwrap();
+
+    {
+        let syncing = Network::start(&local_config(), open_tree(&directories[1]))
+            .await
+            .unwrap();
+        let peer = syncing.connect(serving.local_addr()).await.unwrap();
+        let sync = ChainSync::new(syncing.clone()).await.unwrap();
+        assert_eq!(sync.download_headers(peer).await.unwrap(), 3);
+        syncing.shutdown().await;
+    }
+
+    // the headers are kept after the restart
+    let syncing_tree = open_tree(&directories[1]);
+    let syncing = Network::start(&local_config(), syncing_tree.clone())
+        .await
+        .unwrap();
+    let sync = ChainSync::new(syncing.clone()).await.unwrap();
+    let headers = sync.pending_headers().unwrap();
+    assert_eq!(headers.len(), 3);
+    assert_eq!(headers[0].info.height, U256::one());
+
+    let peer = syncing.connect(serving.local_addr()).await.unwrap();
+    assert_eq!(sync.download_blocks(peer).await.unwrap(), 3);
+    assert!(sync.pending_headers().unwrap().is_empty());
+    assert_eq!(
+        syncing_tree.read().await.get_main_chain().get_height(),
+        serving_tree.read().await.get_main_chain().get_height()
+    );
+
+    syncing.shutdown().await;
+    serving.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chain_sync_skips_malformed_peer() {
+    let directories = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    let serving_tree = open_tree(&directories[0]);
+    for _ in 0..3 {
+        mine_block(&serving_tree, &[5; 33], &[]).await;
+    }
+    let syncing_tree = open_tree(&directories[1]);
+    let serving = Network::start(&local_config(), serving_tree.clone())
+        .await
+        .unwrap();
+    let syncing = Network::start(&local_config(), syncing_tree.clone())
+        .await
+        .unwrap();
+    syncing.connect(serving.local_addr()).await.unwrap();
+
+    // the malformed peer claims the longest chain and answers the headers request
+    // with a truncated header
+    let mut stream = TcpStream::connect(syncing.local_addr()).await.unwrap();
+    let Message::Handshake(local) = read_message(&mut stream).await.unwrap() else {
+        panic!("expected a handshake");
+    };
+    write_message(
+        &mut stream,
+        &Message::Handshake(Handshake {
+            listen_port: 0,
+            height: U256::from(100),
+            ..local
+        }),
+    )
+    .await
+    .unwrap();
+    wait_for_peers(&syncing, 2).await;
+
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    let sync = tokio::spawn(ChainSync::new(syncing.clone()).await.unwrap().run(stopped));
+    let start = loop {
+        if let Message::GetHeaders { start, .. } = read_message(&mut stream).await.unwrap() {
+            break start;
+        }
+    };
+    write_raw(&mut stream, &truncated_headers(start)).await;
+
+    // the malformed peer is dropped and the chain comes from the other one
+    assert!(read_message(&mut stream).await.is_err());
+    let height = serving_tree.read().await.get_main_chain().get_height();
+    wait_for(&syncing_tree, |tree| {
+        tree.get_main_chain().get_height() == height
+    })
+    .await;
+    assert_eq!(syncing.peers().len(), 1);
+
+    stop.send(true).unwrap();
+    sync.await.unwrap();
+    syncing.shutdown().await;
+    serving.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn gossip_relays_transactions_and_blocks() {
+    let directories: Vec<_> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+    let trees: Vec<_> = directories.iter().map(funded_tree).collect();
+    let mut networks = Vec::new();
+    for tree in trees.iter() {
+        networks.push(Network::start(&local_config(), tree.clone()).await.unwrap());
+    }
+    // the nodes are connected in a line, the middle one relays
+    networks[1].connect(networks[0].local_addr()).await.unwrap();
+    networks[2].connect(networks[1].local_addr()).await.unwrap();
+    wait_for_peers(&networks[0], 1).await;
+    wait_for_peers(&networks[1], 2).await;
+
+    let transaction = Transaction::new(
+        sender(),
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        SENDER_PRIVATE,
+        None,
+    );
+    let hash = transaction.hash();
+    trees[0]
+        .read()
+        .await
+        .send_transaction(transaction)
+        .await
+        .unwrap();
+    wait_for(&trees[2], |tree| {
+        tree.get_pool()
+            .try_read()
+            .is_ok_and(|pool| pool.transaction_exists(&hash))
+    })
+    .await;
+
+    let block = mine_block(&trees[0], &[5; 33], &[hash]).await;
+    let block_hash = block.hash().unwrap();
+    for tree in trees[1..].iter() {
+        wait_for(tree, |tree| {
+            tree.get_main_chain()
+                .get_chain_work(&block_hash)
+                .unwrap()
+                .is_some()
+        })
+        .await;
+        let tree = tree.read().await;
+        assert!(tree.get_main_chain().transaction_exists(&hash).unwrap());
+        assert!(!tree.get_pool().read().await.transaction_exists(&hash));
+        assert_eq!(tree.get_amount(&[7; 33]).unwrap(), U256::from(100));
+    }
+
+    for network in networks {
+        network.shutdown().await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn gossip_deduplicates_and_limits_announcements() {
+    let directory = tempfile::tempdir().unwrap();
+    let network = Network::start(&local_config(), open_tree(&directory))
+        .await
+        .unwrap();
+
+    let mut stream = raw_peer(&network).await;
+
+    let announce = |range: std::ops::Range<u32>| {
+        Message::Inventory(
+            range
+                .map(|index| {
+                    let mut hash = [0; 32];
+                    hash[..4].copy_from_slice(&index.to_be_bytes());
+                    Inventory {
+                        kind: InventoryKind::Transaction,
+                        hash,
+                    }
+                })
+                .collect(),
+        )
+    };
+
+    write_message(&mut stream, &announce(0..10)).await.unwrap();
+    assert_eq!(requested_items(&mut stream).await, 10);
+
+    // items already being fetched aren't requested again
+    write_message(&mut stream, &announce(0..10)).await.unwrap();
+    assert_eq!(requested_items(&mut stream).await, 0);
+
+    // items over the rate limit are ignored
+    let sent = INVENTORY_RATE as u32 + 500;
+    write_message(&mut stream, &announce(10..10 + sent))
+        .await
+        .unwrap();
+    let requested = requested_items(&mut stream).await;
+    assert!(requested <= INVENTORY_RATE as usize);
+    assert!(requested >= INVENTORY_RATE as usize / 2);
+
+    network.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_sending_bad_signature_is_banned() {
+    let directory = tempfile::tempdir().unwrap();
+    let network = Network::start(&local_config(), open_tree(&directory))
+        .await
+        .unwrap();
+    let mut stream = raw_peer(&network).await;
+    wait_for_peers(&network, 1).await;
+
+    let transaction = Transaction::new(
+        sender(),
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        SENDER_PRIVATE,
+        None,
+    );
+    let mut dump = transaction.dump().unwrap();
+    // byte of the signature
+    dump[100] ^= 1;
+    let item = Inventory {
+        kind: InventoryKind::Transaction,
+        hash: blockchaintree::tools::hash(&dump),
+    };
+    write_message(&mut stream, &Message::Inventory(vec![item]))
+        .await
+        .unwrap();
+    assert_eq!(
+        read_message(&mut stream).await.unwrap(),
+        Message::GetData(vec![item])
+    );
+    write_message(&mut stream, &Message::Transaction(dump))
+        .await
+        .unwrap();
+
+    // the connection is closed and the address is banned
+    assert!(read_message(&mut stream).await.is_err());
+    wait_for_peers(&network, 0).await;
+    let ip = network.local_addr().ip();
+    assert!(network.is_banned(&ip).unwrap());
+
+    let mut stream = TcpStream::connect(network.local_addr()).await.unwrap();
+    assert!(read_message(&mut stream).await.is_err());
+
+    network.unban(&ip).unwrap();
+    let _stream = raw_peer(&network).await;
+    wait_for_peers(&network, 1).await;
+
+    network.shutdown().await;
+}
+
+/// Dump of a headers message with the info of its header cut to 120 bytes
+fn truncated_headers(start: U256) -> Vec<u8> {
+    let info = BasicInfo {
+        timestamp: INCEPTION_TIMESTAMP,
+        pow: [5; 32],
+        previous_hash: [6; 32],
+        height: start,
+        difficulty: BEGINNING_DIFFICULTY,
+        founder: [7; 33],
+    };
+    let mut info_dump = Vec::new();
+    info.dump(&mut info_dump).unwrap();
+    let message = Message::Headers {
+        start,
+        headers: vec![Header {
+            hash: [4; 32],
+            body_root: [8; 32],
+            info,
+        }],
+    };
+    let mut dump = message.dump();
+    dump.truncate(dump.len() - info_dump.len() - 2);
+    dump.extend(120u16.to_be_bytes());
+    dump.extend(&info_dump[..120]);
+    dump
+}
+
+/// Sends the message dump in a frame, without parsing it
+async fn write_raw(stream: &mut TcpStream, dump: &[u8]) {
+    let mut frame = (dump.len() as u32).to_be_bytes().to_vec();
+    frame.extend(dump);
+    tokio::io::AsyncWriteExt::write_all(stream, &frame)
+        .await
+        .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_sending_truncated_headers_is_dropped() {
+    let directory = tempfile::tempdir().unwrap();
+    let config = P2pConfig {
+        max_inbound: 1,
+        ..local_config()
+    };
+    let network = Network::start(&config, open_tree(&directory))
+        .await
+        .unwrap();
+    let mut stream = raw_peer(&network).await;
+    wait_for_peers(&network, 1).await;
+
+    let dump = truncated_headers(U256::from(10));
+    let report = Message::parse(&dump).unwrap_err();
+    assert_eq!(Misbehaviour::of(&report), Some(Misbehaviour::Malformed));
+    write_raw(&mut stream, &dump).await;
+
+    // the connection is closed and its inbound slot is freed
+    assert!(read_message(&mut stream).await.is_err());
+    wait_for_peers(&network, 0).await;
+    let _stream = raw_peer(&network).await;
+    wait_for_peers(&network, 1).await;
+
+    network.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn misbehaviour_adds_up_to_a_ban() {
+    let directory = tempfile::tempdir().unwrap();
+    let network = Network::start(&local_config(), open_tree(&directory))
+        .await
+        .unwrap();
+    let _stream = raw_peer(&network).await;
+    wait_for_peers(&network, 1).await;
+    let id = network.peers()[0].id;
+
+    network.misbehave(id, Misbehaviour::InvalidData).unwrap();
+    let peers = network.peers();
+    assert_eq!(peers[0].score, Misbehaviour::InvalidData.penalty());
+    assert!(peers[0].score < BAN_SCORE);
+    assert!(!network.is_banned(&peers[0].address.ip()).unwrap());
+
+    network.misbehave(id, Misbehaviour::Malformed).unwrap();
+    network.misbehave(id, Misbehaviour::Malformed).unwrap();
+    assert!(network.peers().is_empty());
+    assert!(network.is_banned(&peers[0].address.ip()).unwrap());
+
+    network.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn inbound_peers_are_limited() {
+    let directory = tempfile::tempdir().unwrap();
+    let config = P2pConfig {
+        max_inbound: 1,
+        ..local_config()
+    };
+    let network = Network::start(&config, open_tree(&directory))
+        .await
+        .unwrap();
+
+    let _stream = raw_peer(&network).await;
+    wait_for_peers(&network, 1).await;
+
+    let mut rejected = TcpStream::connect(network.local_addr()).await.unwrap();
+    assert!(read_message(&mut rejected).await.is_err());
+    assert_eq!(network.peers().len(), 1);
+
+    network.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn known_addresses_are_persisted_and_reconnected() {
+    let serving_directory = tempfile::tempdir().unwrap();
+    let serving = Network::start(&local_config(), open_tree(&serving_directory))
+        .await
+        .unwrap();
+
+    let directory = tempfile::tempdir().unwrap();
+    let config = P2pConfig {
+        peers: vec![serving.local_addr()],
+        ..local_config()
+    };
+    let tree = open_tree(&directory);
+    let network = Network::start(&config, tree.clone()).await.unwrap();
+    wait_for_peers(&network, 1).await;
+
+    let known = network.known_addresses().unwrap();
+    assert_eq!(known.len(), 1);
+    assert_eq!(known[0].address, serving.local_addr());
+    assert!(known[0].last_seen > 0);
+
+    network.shutdown().await;
+    drop(network);
+    // the network doesn't hold the tree anymore
+    drop(Arc::into_inner(tree).unwrap());
+    wait_for_peers(&serving, 0).await;
+
+    // the node connects to the remembered address by itself
+    let config = P2pConfig {
+        target_outbound: 1,
+        ..local_config()
+    };
+    let network = Network::start(&config, reopen_tree(&directory).await)
+        .await
+        .unwrap();
+    wait_for_peers(&network, 1).await;
+    assert_eq!(network.peers()[0].address, serving.local_addr());
+    assert!(!network.peers()[0].inbound);
+
+    network.shutdown().await;
+    serving.shutdown().await;
+}
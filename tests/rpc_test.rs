@@ -0,0 +1,274 @@
+use std::sync::Arc;
+
+use blockchaintree::{
+    blockchaintree::BlockChainTree,
+    miner::{Miner, MiningTarget},
+    rpc,
+    static_values::INCEPTION_TIMESTAMP,
+    token::TokenAction,
+    tools,
+    transaction::{Transaction, Transactionable},
+    types::Address,
+    wallet::Wallet,
+};
+use jsonrpsee::core::client::{ClientT, Subscription, SubscriptionClientT};
+use jsonrpsee::http_client::HttpClientBuilder;
+use jsonrpsee::rpc_params;
+use jsonrpsee::ws_client::WsClientBuilder;
+use primitive_types::U256;
+use serde_json::Value;
+use tokio::sync::RwLock;
+
+static SENDER_PRIVATE: [u8; 32] = [2; 32];
+/// Fee offered by the test transactions, above the fee of any test block
+const TRANSACTION_FEE: u64 = 100_000_000;
+
+/// Address of the sender key with coins and gas to send transactions
+fn funded_sender(tree: &BlockChainTree) -> Address {
+    let sender = Wallet::derive_address(&SENDER_PRIVATE).unwrap();
+    tree.add_amount(&sender, U256::from_dec_str("1000000000000000000").unwrap())
+        .unwrap();
+    tree.add_gas(&sender, U256::from_dec_str("1000000000000000000").unwrap())
+        .unwrap();
+    sender
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rpc_queries() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let sender = funded_sender(&tree);
+    let tree = Arc::new(RwLock::new(tree));
+
+    let (address, handle) = rpc::start(([127, 0, 0, 1], 0).into(), tree.clone())
+        .await
+        .unwrap();
+    let client = HttpClientBuilder::default()
+        .build(format!("http://{address}"))
+        .unwrap();
+
+    let height: Value = client.request("getHeight", rpc_params![]).await.unwrap();
+    let genesis_height = tree.read().await.get_main_chain().get_height();
+    assert_eq!(height, Value::String(genesis_height.to_string()));
+
+    let genesis: Value = client
+        .request("getBlockByHeight", rpc_params![0])
+        .await
+        .unwrap();
+    let by_hash: Value = client
+        .request("getBlockByHash", rpc_params![genesis["hash"].clone()])
+        .await
+        .unwrap();
+    assert_eq!(genesis, by_hash);
+    let missing: Value = client
+        .request("getBlockByHeight", rpc_params!["1000"])
+        .await
+        .unwrap();
+    assert_eq!(missing, Value::Null);
+
+    let account = hex::encode(sender);
+    let balance: Value = client
+        .request("getBalance", rpc_params![account.clone()])
+        .await
+        .unwrap();
+    let amount = tree.read().await.get_amount(&sender).unwrap();
+    assert_eq!(balance, Value::String(amount.to_string()));
+    let gas: Value = client
+        .request("getGas", rpc_params![account.clone()])
+        .await
+        .unwrap();
+    assert_eq!(gas, Value::String("1000000000000000000".into()));
+
+    let transaction = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        SENDER_PRIVATE,
+        None,
+    );
+    let raw = hex::encode(transaction.dump().unwrap());
+    let hash: Value = client
+        .request("sendRawTransaction", rpc_params![raw.clone()])
+        .await
+        .unwrap();
+    assert_eq!(hash, Value::String(hex::encode(transaction.hash())));
+    // already in the pool
+    assert!(client
+        .request::<Value, _>("sendRawTransaction", rpc_params![raw])
+        .await
+        .is_err());
+    let create = TokenAction::create(
+        sender,
+        U256::from(TRANSACTION_FEE),
+        INCEPTION_TIMESTAMP + 1,
+        1,
+        &SENDER_PRIVATE,
+    )
+    .unwrap();
+    let token_hash: Value = client
+        .request(
+            "sendRawTransaction",
+            rpc_params![hex::encode(create.dump().unwrap())],
+        )
+        .await
+        .unwrap();
+
+    {
+        let mut tree = tree.write().await;
+        let template = tree.block_template(&[5; 33]).await.unwrap();
+        let result = Miner::new(2).mine(&MiningTarget::from(&template)).unwrap();
+        tree.submit_template(&template, &result.pow).await.unwrap();
+    }
+    let found: Value = client
+        .request("getTransaction", rpc_params![hash])
+        .await
+        .unwrap();
+    assert_eq!(found["sender"], Value::String(account.clone()));
+    assert_eq!(found["amount"], Value::String("100".into()));
+
+    let token: Value = client
+        .request("getToken", rpc_params![token_hash.clone()])
+        .await
+        .unwrap();
+    assert_eq!(token["token_hash"], token_hash);
+    assert_eq!(token["owner"], Value::String(account.clone()));
+    assert_eq!(token["assigned"], Value::Bool(false));
+    let tokens: Value = client.request("getTokens", rpc_params![]).await.unwrap();
+    assert_eq!(tokens, Value::Array(vec![token]));
+    let action: Value = client
+        .request("getTransaction", rpc_params![token_hash])
+        .await
+        .unwrap();
+    assert_eq!(
+        action["token_action"]["action"],
+        Value::String("Create".into())
+    );
+    // unassigned token has no supply
+    let holdings: Value = client
+        .request("getTokenHoldings", rpc_params![account.clone()])
+        .await
+        .unwrap();
+    assert_eq!(holdings, Value::Array(vec![]));
+
+    let chain: Value = client
+        .request("getDerivativeChain", rpc_params![account])
+        .await
+        .unwrap();
+    assert_eq!(chain, Value::Null);
+
+    assert!(client
+        .request::<Value, _>("getBalance", rpc_params!["not hex"])
+        .await
+        .is_err());
+    assert!(client
+        .request::<Value, _>("getBlockByHash", rpc_params![hex::encode([1u8; 31])])
+        .await
+        .is_err());
+
+    handle.stop().unwrap();
+    handle.stopped().await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rpc_subscriptions() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let sender = funded_sender(&tree);
+    let tree = Arc::new(RwLock::new(tree));
+
+    let (address, handle) = rpc::start(([127, 0, 0, 1], 0).into(), tree.clone())
+        .await
+        .unwrap();
+    let client = WsClientBuilder::default()
+        .build(format!("ws://{address}"))
+        .await
+        .unwrap();
+
+    let mut blocks: Subscription<Value> = client
+        .subscribe("subscribeNewBlocks", rpc_params![], "unsubscribeNewBlocks")
+        .await
+        .unwrap();
+    let mut transactions: Subscription<Value> = client
+        .subscribe(
+            "subscribeTransactions",
+            rpc_params![],
+            "unsubscribeTransactions",
+        )
+        .await
+        .unwrap();
+    let founder = [5u8; 33];
+    let mut derivative_blocks: Subscription<Value> = client
+        .subscribe(
+            "subscribeDerivativeBlocks",
+            rpc_params![hex::encode(founder)],
+            "unsubscribeDerivativeBlocks",
+        )
+        .await
+        .unwrap();
+    assert!(client
+        .subscribe::<Value, _>(
+            "subscribeDerivativeBlocks",
+            rpc_params!["not hex"],
+            "unsubscribeDerivativeBlocks",
+        )
+        .await
+        .is_err());
+
+    let transaction = Transaction::new(
+        sender,
+        [7; 33],
+        INCEPTION_TIMESTAMP + 1,
+        0,
+        U256::from(100),
+        U256::from(TRANSACTION_FEE),
+        SENDER_PRIVATE,
+        None,
+    );
+    let hash = transaction.hash();
+    tree.read()
+        .await
+        .send_transaction(transaction)
+        .await
+        .unwrap();
+    let accepted = transactions.next().await.unwrap().unwrap();
+    assert_eq!(accepted["hash"], Value::String(hex::encode(hash)));
+    assert_eq!(accepted["nonce"], Value::from(0));
+
+    let block = {
+        let mut tree = tree.write().await;
+        let template = tree.block_template(&founder).await.unwrap();
+        let result = Miner::new(2).mine(&MiningTarget::from(&template)).unwrap();
+        tree.submit_template(&template, &result.pow).await.unwrap()
+    };
+    let added = blocks.next().await.unwrap().unwrap();
+    assert_eq!(
+        added["hash"],
+        Value::String(hex::encode(block.hash().unwrap()))
+    );
+    assert_eq!(added["transactions"][1], Value::String(hex::encode(hash)));
+
+    let block = {
+        let mut tree = tree.write().await;
+        tree.create_derivative_chain(&founder).unwrap();
+        let timestamp = tools::current_timestamp();
+        let target = tree
+            .derivative_target(&founder, &[0; 32], timestamp)
+            .unwrap();
+        let result = Miner::new(2).mine(&target).unwrap();
+        tree.emmit_new_derivative_block(&result.pow, &founder, &[0; 32], timestamp)
+            .await
+            .unwrap()
+    };
+    let added = derivative_blocks.next().await.unwrap().unwrap();
+    assert_eq!(
+        added["hash"],
+        Value::String(hex::encode(block.hash().unwrap()))
+    );
+
+    blocks.unsubscribe().await.unwrap();
+    handle.stop().unwrap();
+    handle.stopped().await;
+}
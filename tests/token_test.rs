@@ -0,0 +1,121 @@
+use blockchaintree::{
+    token::{Action, Token, TokenAction, TokenParameters},
+    transaction::{self, Transactionable},
+};
+use primitive_types::U256;
+use secp256k1::Secp256k1;
+
+fn parameters() -> TokenParameters {
+    TokenParameters {
+        token_data: "test token".to_string(),
+        smol_contract: "contract".to_string(),
+        coin_supply: U256::from_dec_str("1000000").unwrap(),
+        transfer_fee: U256::from(10),
+    }
+}
+
+#[test]
+fn sign_with_invalid_key() {
+    // zero is not a valid secp256k1 secret key
+    assert!(TokenAction::create([2; 33], U256::from(10), 100, 0, &[0; 32]).is_err());
+}
+
+#[test]
+fn dump_parse_token_actions() {
+    let secp = Secp256k1::new();
+    let (secret_key, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+    let sender = public_key.serialize();
+    let private_key = secret_key.secret_bytes();
+
+    let create = TokenAction::create(sender, U256::from(10), 100, 0, &private_key).unwrap();
+    let token_hash = create.hash();
+    let actions = [
+        create,
+        TokenAction::assign(
+            sender,
+            token_hash,
+            parameters(),
+            U256::from(10),
+            101,
+            1,
+            &private_key,
+        )
+        .unwrap(),
+        TokenAction::send(
+            sender,
+            [7; 33],
+            token_hash,
+            U256::from(500),
+            U256::from(10),
+            102,
+            2,
+            &private_key,
+        )
+        .unwrap(),
+        TokenAction::burn(
+            sender,
+            token_hash,
+            U256::zero(),
+            U256::from(10),
+            103,
+            3,
+            &private_key,
+        )
+        .unwrap(),
+    ];
+
+    for action in actions {
+        assert!(action.verify().unwrap());
+
+        let dump = action.dump().unwrap();
+        let parsed = transaction::parse_transactionable(&dump).unwrap();
+        let parsed_action = parsed.as_token_action().unwrap();
+
+        assert_eq!(parsed.hash(), action.hash());
+        assert!(parsed.verify().unwrap());
+        assert_eq!(parsed_action.get_action(), action.get_action());
+        assert_eq!(parsed_action.get_token_hash(), action.get_token_hash());
+        assert_eq!(parsed_action.get_token_amount(), action.get_token_amount());
+        assert_eq!(parsed_action.get_parameters(), action.get_parameters());
+        assert_eq!(parsed.get_receiver(), action.get_receiver());
+        assert_eq!(parsed.get_nonce(), action.get_nonce());
+        assert_eq!(parsed.get_amount(), None);
+    }
+}
+
+#[test]
+fn forged_token_action() {
+    let secp = Secp256k1::new();
+    let (secret_key, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+    let action = TokenAction::burn(
+        public_key.serialize(),
+        [1; 32],
+        U256::from(5),
+        U256::from(10),
+        100,
+        0,
+        &secret_key.secret_bytes(),
+    )
+    .unwrap();
+    let mut dump = action.dump().unwrap();
+    // fee is the last byte
+    *dump.last_mut().unwrap() += 1;
+
+    let parsed = transaction::parse_transactionable(&dump).unwrap();
+    assert_eq!(parsed.as_token_action().unwrap().get_action(), Action::Burn);
+    assert!(!parsed.verify().unwrap());
+}
+
+#[test]
+fn dump_parse_token() {
+    let mut token = Token::new([5; 32], [6; 33]);
+    assert_eq!(Token::parse(&token.dump().unwrap()).unwrap(), token);
+
+    token.assigned = true;
+    token.parameters = parameters();
+    let parsed = Token::parse(&token.dump().unwrap()).unwrap();
+    assert_eq!(parsed, token);
+    assert!(parsed.is_fee_static());
+
+    assert!(Token::parse(&[0; 40]).is_err());
+}
@@ -8,7 +8,9 @@ fn dump_parse_transaction() {
         [10; 33],
         [20; 33],
         100,
+        5,
         U256::from_dec_str("3627836287").unwrap(),
+        U256::from(10),
         None,
         [33; 64],
     );
@@ -32,6 +34,7 @@ fn dump_parse_transaction() {
         transaction.get_timestamp(),
         parsed_transaction.get_timestamp()
     );
+    assert_eq!(transaction.get_nonce(), parsed_transaction.get_nonce());
 
     println!("{:?}", parsed_transaction);
 }
@@ -42,7 +45,9 @@ fn hash_transaction() {
         [10; 33],
         [20; 33],
         100,
+        0,
         U256::from_dec_str("3627836287").unwrap(),
+        U256::from(10),
         None,
         [33; 64],
     );
@@ -61,7 +66,9 @@ fn sign_verify_transaction() {
         public_key.serialize(),
         public_key.serialize(),
         100,
+        0,
         U256::from_dec_str("3627836287").unwrap(),
+        U256::from(10),
         secret_key.secret_bytes(),
         Some(vec![1, 3, 3, 3, 3, 3, 3]),
     );
@@ -0,0 +1,133 @@
+use blockchaintree::transaction::{Transaction, TransactionableItem};
+use blockchaintree::txpool::{TxPool, TxPoolConfig};
+use primitive_types::U256;
+
+fn transaction(sender: u8, timestamp: u64, data_size: usize, fee: u64) -> TransactionableItem {
+    Box::new(Transaction::new_signed(
+        [sender; 33],
+        [20; 33],
+        timestamp,
+        0,
+        U256::from(100),
+        U256::from(fee),
+        Some(vec![0; data_size]).filter(|data| !data.is_empty()),
+        [33; 64],
+    ))
+}
+
+#[test]
+fn pool_orders_by_fee_rate() {
+    let mut pool = TxPool::new();
+    let big = transaction(1, 100, 1000, 1000);
+    let small = transaction(2, 200, 0, 1000);
+    let expensive = transaction(3, 300, 1000, 100000);
+    let (big_hash, small_hash, expensive_hash) = (big.hash(), small.hash(), expensive.hash());
+
+    pool.push(big, 0).unwrap();
+    pool.push(small, 0).unwrap();
+    pool.push(expensive, 0).unwrap();
+
+    let order: Vec<[u8; 32]> = pool.transactions_iter().map(|tx| tx.hash()).collect();
+    assert_eq!(order, vec![expensive_hash, small_hash, big_hash]);
+
+    assert_eq!(pool.pop().unwrap().0, expensive_hash);
+    assert_eq!(pool.len(), 2);
+}
+
+#[test]
+fn pool_evicts_lowest_fee_rate() {
+    let mut pool = TxPool::with_capacity(2);
+    let cheap = transaction(1, 100, 0, 10);
+    let cheap_hash = cheap.hash();
+    pool.push(cheap, 0).unwrap();
+    pool.push(transaction(2, 100, 0, 20), 0).unwrap();
+
+    // not better than the worst one
+    assert!(pool.push(transaction(3, 100, 0, 10), 0).is_err());
+    assert_eq!(pool.len(), 2);
+
+    pool.push(transaction(4, 100, 0, 30), 0).unwrap();
+    assert_eq!(pool.len(), 2);
+    assert!(!pool.transaction_exists(&cheap_hash));
+    assert_eq!(pool.sender_pending(&[1; 33]), 0);
+}
+
+#[test]
+fn pool_eviction_drops_later_nonces() {
+    let mut pool = TxPool::with_capacity(3);
+    let nonce = |nonce: u64, data_size: usize| -> TransactionableItem {
+        Box::new(Transaction::new_signed(
+            [1; 33],
+            [20; 33],
+            100,
+            nonce,
+            U256::from(100),
+            U256::from(10),
+            Some(vec![0; data_size]),
+            [33; 64],
+        ))
+    };
+    pool.push(nonce(0, 1000), 0).unwrap();
+    pool.push(nonce(1, 10), 0).unwrap();
+    assert_eq!(pool.next_nonce(&[1; 33]), Some(2));
+    pool.push(transaction(2, 100, 0, 10), 0).unwrap();
+
+    // the sender's own transactions are not evicted for its new one
+    assert!(pool.push(nonce(2, 10), 0).is_err());
+
+    pool.push(transaction(3, 100, 0, 10), 0).unwrap();
+    assert_eq!(pool.len(), 2);
+    assert_eq!(pool.sender_pending(&[1; 33]), 0);
+    assert_eq!(pool.next_nonce(&[1; 33]), None);
+}
+
+#[test]
+fn pool_limits_senders_and_expires() {
+    let mut pool = TxPool::with_config(TxPoolConfig {
+        capacity: 10,
+        sender_limit: 2,
+        expiration: 100,
+    });
+    let duplicate = transaction(1, 100, 0, 1);
+    pool.push(transaction(1, 100, 0, 1), 1000).unwrap();
+    assert!(pool.push(duplicate, 1000).is_err());
+    pool.push(transaction(1, 101, 0, 1), 1050).unwrap();
+    assert!(pool.push(transaction(1, 102, 0, 1), 1050).is_err());
+    pool.push(transaction(2, 100, 0, 1), 1050).unwrap();
+
+    assert_eq!(pool.remove_expired(1100), 0);
+    assert_eq!(pool.remove_expired(1101), 1);
+    assert_eq!(pool.sender_pending(&[1; 33]), 1);
+    assert_eq!(pool.len(), 2);
+}
+
+#[test]
+fn pool_dump_parse() {
+    let mut pool = TxPool::new();
+    pool.push(transaction(1, 100, 10, 500), 1000).unwrap();
+    pool.push(transaction(2, 100, 0, 7), 2000).unwrap();
+
+    let parsed = TxPool::parse(&pool.dump().unwrap(), TxPoolConfig::default()).unwrap();
+
+    let entries: Vec<_> = pool
+        .entries_iter()
+        .map(|entry| {
+            (
+                entry.transaction.hash(),
+                entry.transaction.get_fee(),
+                entry.received,
+            )
+        })
+        .collect();
+    let parsed_entries: Vec<_> = parsed
+        .entries_iter()
+        .map(|entry| {
+            (
+                entry.transaction.hash(),
+                entry.transaction.get_fee(),
+                entry.received,
+            )
+        })
+        .collect();
+    assert_eq!(entries, parsed_entries);
+}
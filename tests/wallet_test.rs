@@ -0,0 +1,81 @@
+use blockchaintree::{
+    blockchaintree::BlockChainTree, transaction::Transactionable, wallet::Wallet,
+};
+use primitive_types::U256;
+
+#[test]
+fn derive_wallet_address() {
+    let wallet = Wallet::from_private_key([
+        25, 53, 50, 224, 180, 250, 177, 186, 87, 47, 28, 80, 183, 208, 219, 119, 101, 60, 173, 157,
+        190, 29, 208, 231, 98, 69, 82, 211, 107, 185, 192, 224,
+    ])
+    .unwrap();
+    assert_eq!(
+        *wallet.get_address(),
+        [
+            2, 178, 140, 81, 31, 206, 208, 171, 143, 240, 128, 134, 115, 82, 188, 63, 146, 189, 14,
+            59, 85, 8, 11, 28, 137, 161, 145, 216, 251, 95, 93, 137, 159,
+        ]
+    );
+
+    let generated = Wallet::generate();
+    assert_eq!(
+        Wallet::derive_address(generated.get_private_key()).unwrap(),
+        *generated.get_address()
+    );
+    assert!(!format!("{:?}", generated).contains(&hex::encode(generated.get_private_key())));
+
+    // zero is not a valid secp256k1 key
+    assert!(Wallet::from_private_key([0; 32]).is_err());
+}
+
+#[test]
+fn wallet_signs_transactions() {
+    let wallet = Wallet::generate();
+    let transaction = wallet.transaction([7; 33], 100, 0, U256::from(10), U256::one(), None);
+
+    assert_eq!(transaction.get_sender(), wallet.get_address());
+    assert!(transaction.verify().unwrap());
+}
+
+#[tokio::test]
+async fn wallet_tracks_balances() {
+    let data_directory = tempfile::tempdir().unwrap();
+    let tree = BlockChainTree::open(data_directory.path()).unwrap();
+    let wallet = Wallet::generate();
+    assert_eq!(wallet.get_amount(&tree).unwrap(), U256::zero());
+
+    tree.add_amount(
+        wallet.get_address(),
+        U256::from_dec_str("1000000000000").unwrap(),
+    )
+    .unwrap();
+    tree.add_gas(
+        wallet.get_address(),
+        U256::from_dec_str("1000000000000000000").unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        wallet.get_amount(&tree).unwrap(),
+        U256::from_dec_str("1000000000000").unwrap()
+    );
+    assert_eq!(
+        wallet.get_gas(&tree).unwrap(),
+        U256::from_dec_str("1000000000000000000").unwrap()
+    );
+
+    // the second transaction takes the nonce after the pending one
+    let fee = tree.minimal_fee().unwrap();
+    let first = wallet
+        .send(&tree, [7; 33], U256::from(100), fee, None)
+        .await
+        .unwrap();
+    let second = wallet
+        .send(&tree, [7; 33], U256::from(100), fee, None)
+        .await
+        .unwrap();
+    let pool = tree.get_pool();
+    let pool = pool.read().await;
+    assert_eq!(pool.get(&first).unwrap().transaction.get_nonce(), 0);
+    assert_eq!(pool.get(&second).unwrap().transaction.get_nonce(), 1);
+}